@@ -0,0 +1,254 @@
+use rust_htslib::bam;
+use rust_htslib::bam::record::Cigar;
+
+/// Minimum number of reads supporting a minor allele for a position to be
+/// counted as an SNV, so that single sequencing errors are not counted.
+pub const MIN_SNV_ALLELE_READS: u32 = 2;
+
+/// Counts of each of A, C, G and T at each position of a reference sequence,
+/// from the bases of reads aligned there, along with the number of reads with
+/// a deletion of the position or an insertion immediately after it.
+#[derive(Debug, Clone)]
+pub struct AlleleCounts {
+    contig_name: String,
+    counts: Vec<[u32; 4]>,
+    deletions: Vec<u32>,
+    insertions: Vec<u32>,
+}
+
+/// Allele frequency statistics of a single reference position.
+#[derive(Debug, PartialEq)]
+pub struct SiteStatistics {
+    pub is_snv: bool,
+    pub nucleotide_diversity: f64,
+    pub major_allele_frequency: f64,
+}
+
+impl AlleleCounts {
+    pub fn new(contig_name: &str, reference_length: usize) -> AlleleCounts {
+        AlleleCounts {
+            contig_name: contig_name.to_string(),
+            counts: vec![[0; 4]; reference_length],
+            deletions: vec![0; reference_length],
+            insertions: vec![0; reference_length],
+        }
+    }
+
+    pub fn contig_name(&self) -> &str {
+        &self.contig_name
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Number of each of A, C, G and T observed at the given position.
+    pub fn base_counts(&self, position: usize) -> [u32; 4] {
+        self.counts[position]
+    }
+
+    pub fn deletions(&self, position: usize) -> u32 {
+        self.deletions[position]
+    }
+
+    pub fn insertions(&self, position: usize) -> u32 {
+        self.insertions[position]
+    }
+
+    /// Mean number of A, C, G and T bases observed per position.
+    pub fn mean_depth(&self) -> f64 {
+        if self.counts.is_empty() {
+            return 0.0;
+        }
+        let total: u64 = self
+            .counts
+            .iter()
+            .map(|c| c.iter().map(|n| *n as u64).sum::<u64>())
+            .sum();
+        total as f64 / self.counts.len() as f64
+    }
+
+    /// Add the bases of a mapped record which are aligned to the reference
+    /// i.e. M, = or X CIGAR operations, and its insertions and deletions.
+    /// Ambiguous bases are ignored.
+    pub fn add_record(&mut self, record: &bam::Record) {
+        let seq = record.seq();
+        if seq.is_empty() {
+            // Sequence not stored e.g. in secondary alignments.
+            return;
+        }
+        let mut read_cursor: usize = 0;
+        let mut reference_cursor: usize = record.pos() as usize;
+        for cig in record.cigar().iter() {
+            let len = cig.len() as usize;
+            match cig {
+                Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
+                    for i in 0..len {
+                        if reference_cursor + i >= self.counts.len() {
+                            break;
+                        }
+                        if let Some(allele) = allele_index(seq.encoded_base(read_cursor + i)) {
+                            self.counts[reference_cursor + i][allele] += 1;
+                        }
+                    }
+                    read_cursor += len;
+                    reference_cursor += len;
+                }
+                Cigar::Ins(_) => {
+                    if reference_cursor > 0 && reference_cursor <= self.insertions.len() {
+                        self.insertions[reference_cursor - 1] += 1;
+                    }
+                    read_cursor += len;
+                }
+                Cigar::SoftClip(_) => {
+                    read_cursor += len;
+                }
+                Cigar::Del(_) => {
+                    for i in 0..len {
+                        if reference_cursor + i >= self.deletions.len() {
+                            break;
+                        }
+                        self.deletions[reference_cursor + i] += 1;
+                    }
+                    reference_cursor += len;
+                }
+                Cigar::RefSkip(_) => {
+                    reference_cursor += len;
+                }
+                Cigar::HardClip(_) | Cigar::Pad(_) => {}
+            }
+        }
+    }
+
+    /// Statistics of the given position, or None if fewer than min_depth
+    /// bases were observed there. The position is an SNV if an allele other
+    /// than the reference base has at least min_allele_frequency of the bases
+    /// and MIN_SNV_ALLELE_READS reads. Positions where the reference base is
+    /// unknown or ambiguous are never SNVs.
+    pub fn site_statistics(
+        &self,
+        position: usize,
+        reference_base: Option<u8>,
+        min_depth: u32,
+        min_allele_frequency: f32,
+    ) -> Option<SiteStatistics> {
+        let counts = &self.counts[position];
+        let depth: u32 = counts.iter().sum();
+        if depth == 0 || depth < min_depth {
+            return None;
+        }
+        let major_allele = (1..4).fold(0, |major, allele| {
+            if counts[allele] > counts[major] {
+                allele
+            } else {
+                major
+            }
+        });
+        let is_snv = match reference_base.and_then(reference_allele_index) {
+            Some(reference_allele) => (0..4).any(|allele| {
+                allele != reference_allele
+                    && counts[allele] >= MIN_SNV_ALLELE_READS
+                    && counts[allele] as f32 / depth as f32 >= min_allele_frequency
+            }),
+            None => false,
+        };
+
+        // Unbiased estimate of the probability that two reads differ at this
+        // position.
+        let nucleotide_diversity = match depth {
+            1 => 0.0,
+            _ => {
+                let sum_squares: f64 = counts
+                    .iter()
+                    .map(|c| (*c as f64 / depth as f64).powi(2))
+                    .sum();
+                (1.0 - sum_squares) * depth as f64 / (depth - 1) as f64
+            }
+        };
+        Some(SiteStatistics {
+            is_snv,
+            nucleotide_diversity,
+            major_allele_frequency: counts[major_allele] as f64 / depth as f64,
+        })
+    }
+}
+
+/// Index of an A, C, G or T reference base, None for other bases.
+fn reference_allele_index(base: u8) -> Option<usize> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// Index of A, C, G or T in 4-bit BAM base encoding, None for other bases.
+fn allele_index(encoded_base: u8) -> Option<usize> {
+    match encoded_base {
+        1 => Some(0),
+        2 => Some(1),
+        4 => Some(2),
+        8 => Some(3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts_at(counts: [u32; 4]) -> AlleleCounts {
+        AlleleCounts {
+            contig_name: "contig".to_string(),
+            counts: vec![counts],
+            deletions: vec![0],
+            insertions: vec![0],
+        }
+    }
+
+    #[test]
+    fn test_site_statistics_monomorphic() {
+        assert_eq!(
+            Some(SiteStatistics {
+                is_snv: false,
+                nucleotide_diversity: 0.0,
+                major_allele_frequency: 1.0,
+            }),
+            counts_at([0, 10, 0, 0]).site_statistics(0, Some(b'C'), 5, 0.05)
+        );
+    }
+
+    #[test]
+    fn test_site_statistics_fixed_difference_from_reference() {
+        let stats = counts_at([0, 10, 0, 0]).site_statistics(0, Some(b'a'), 5, 0.05).unwrap();
+        assert!(stats.is_snv);
+        assert_eq!(0.0, stats.nucleotide_diversity);
+        assert!(!counts_at([0, 10, 0, 0]).site_statistics(0, Some(b'N'), 5, 0.05).unwrap().is_snv);
+        assert!(!counts_at([0, 10, 0, 0]).site_statistics(0, None, 5, 0.05).unwrap().is_snv);
+    }
+
+    #[test]
+    fn test_site_statistics_snv() {
+        let stats = counts_at([6, 0, 2, 0]).site_statistics(0, Some(b'A'), 5, 0.05).unwrap();
+        assert!(stats.is_snv);
+        assert_eq!(0.75, stats.major_allele_frequency);
+        // (1 - (0.75^2 + 0.25^2)) * 8/7
+        assert!((stats.nucleotide_diversity - 0.375 * 8.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_site_statistics_single_read_minor_allele() {
+        assert!(!counts_at([9, 1, 0, 0]).site_statistics(0, Some(b'A'), 5, 0.05).unwrap().is_snv);
+    }
+
+    #[test]
+    fn test_site_statistics_low_depth() {
+        assert_eq!(None, counts_at([2, 2, 0, 0]).site_statistics(0, Some(b'A'), 5, 0.05));
+    }
+}
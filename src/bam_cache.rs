@@ -0,0 +1,436 @@
+use std;
+use std::io::{Read, Write};
+use std::time::UNIX_EPOCH;
+
+use flate2::Crc;
+
+use bam_generator::MappingProgram;
+use mapping_parameters::ReadFormat;
+
+/// Description of everything that went into generating a cached BAM file.
+/// It is written alongside the BAM file once mapping has successfully
+/// completed, so that a later run with identical inputs can reuse the BAM
+/// instead of mapping again, and a run with different inputs knows to remap.
+#[derive(Debug, PartialEq)]
+pub struct BamCacheManifest {
+    entries: Vec<(String, String)>,
+    // Reads from STDIN cannot be checked to be the same as those of a
+    // previous run, so are always mapped again.
+    reads_from_stdin: bool,
+}
+
+/// A BAM file to be written to the cache directory, together with the
+/// manifest describing how it is generated.
+pub struct CachedBamFile {
+    pub path: String,
+    pub manifest: BamCacheManifest,
+}
+
+impl CachedBamFile {
+    pub fn is_valid(&self) -> bool {
+        self.manifest.matches_cache(&self.path)
+    }
+
+    pub fn invalidate(&self) {
+        BamCacheManifest::invalidate(&self.path)
+    }
+
+    pub fn write_manifest(&self) {
+        self.manifest.write(&self.path)
+    }
+}
+
+impl BamCacheManifest {
+    /// The references are the files the user specified, rather than any
+    /// temporary index or concatenated FASTA file generated from them, since
+    /// those are regenerated with each run.
+    pub fn new(
+        mapping_program: MappingProgram,
+        references: &[&str],
+        read1_paths: &[&str],
+        read2_paths: &[&str],
+        read_format: &ReadFormat,
+        mapping_options: Option<&str>,
+        discard_unmapped: bool,
+    ) -> BamCacheManifest {
+        let mut entries = vec![
+            (
+                "coverm_version".to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+            ),
+            ("mapper".to_string(), format!("{:?}", mapping_program)),
+            (
+                "mapping_options".to_string(),
+                mapping_options.unwrap_or("").to_string(),
+            ),
+            (
+                "read_format".to_string(),
+                match read_format {
+                    ReadFormat::Coupled => "coupled",
+                    ReadFormat::Interleaved => "interleaved",
+                    ReadFormat::Single => "single",
+                }
+                .to_string(),
+            ),
+            (
+                "discard_unmapped".to_string(),
+                format!("{}", discard_unmapped),
+            ),
+        ];
+        // A reference may be given as the prefix of a pre-built index rather
+        // than a FASTA file, in which case there is nothing to fingerprint.
+        for reference in references {
+            add_file_entries(&mut entries, "reference", reference, false);
+        }
+        let mut reads_from_stdin = false;
+        for read1 in read1_paths {
+            match *read1 {
+                "-" => {
+                    entries.push(("read1".to_string(), "-".to_string()));
+                    reads_from_stdin = true;
+                }
+                _ => add_file_entries(&mut entries, "read1", read1, true),
+            }
+        }
+        if read2_paths.is_empty() {
+            entries.push(("read2".to_string(), "".to_string()));
+        }
+        for read2 in read2_paths {
+            match *read2 {
+                "-" => {
+                    entries.push(("read2".to_string(), "-".to_string()));
+                    reads_from_stdin = true;
+                }
+                _ => add_file_entries(&mut entries, "read2", read2, true),
+            }
+        }
+        BamCacheManifest {
+            entries: entries,
+            reads_from_stdin: reads_from_stdin,
+        }
+    }
+
+    /// Record the host reference that reads were depleted against before
+    /// mapping.
+    pub fn add_host_reference(&mut self, host_reference: &str) {
+        add_file_entries(&mut self.entries, "host_reference", host_reference, false);
+    }
+
+    /// Record that the BAM file is sorted by read name, as when mapping to
+    /// sharded references, so that it is never mistaken for a coordinate
+    /// sorted BAM file of the same mapping.
+    pub fn set_name_sorted(&mut self) {
+        self.entries.push(("sort_order".to_string(), "name".to_string()));
+    }
+
+    /// Short hash of the inputs and parameters, for naming the cached BAM
+    /// file so that different mappings of the same reads are cached
+    /// separately. File sizes and modification times are left out, so that
+    /// when an input file changes its stale cache entry is replaced rather
+    /// than left behind.
+    pub fn name_hash(&self) -> String {
+        let mut crc = Crc::new();
+        for (key, value) in self.entries.iter() {
+            if key == "coverm_version" || key.ends_with("_size") || key.ends_with("_mtime") {
+                continue;
+            }
+            crc.update(format!("{}\t{}\n", key, value).as_bytes());
+        }
+        format!("{:08x}", crc.sum())
+    }
+
+    fn to_tsv(&self) -> String {
+        let mut s = String::new();
+        for (key, value) in self.entries.iter() {
+            s.push_str(&format!("{}\t{}\n", key, value));
+        }
+        s
+    }
+
+    /// Path of the manifest file that accompanies the given cached BAM file.
+    pub fn manifest_path(bam_path: &str) -> String {
+        format!("{}.manifest", bam_path)
+    }
+
+    /// Returns true if the BAM file exists and its manifest matches this
+    /// one exactly.
+    pub fn matches_cache(&self, bam_path: &str) -> bool {
+        if self.reads_from_stdin {
+            debug!("Reads are from STDIN, so not reusing cached BAM file {}", bam_path);
+            return false;
+        }
+        if !std::path::Path::new(bam_path).is_file() {
+            debug!("Cached BAM file {} does not exist", bam_path);
+            return false;
+        }
+        let manifest_path = BamCacheManifest::manifest_path(bam_path);
+        let mut contents = String::new();
+        match std::fs::File::open(&manifest_path) {
+            Ok(mut f) => {
+                if f.read_to_string(&mut contents).is_err() {
+                    warn!("Unable to read BAM cache manifest {}", manifest_path);
+                    return false;
+                }
+            }
+            Err(_) => {
+                debug!("No BAM cache manifest found at {}", manifest_path);
+                return false;
+            }
+        }
+        let expected = self.to_tsv();
+        if contents == expected {
+            true
+        } else {
+            info!(
+                "Cached BAM file {} was generated from different inputs or \
+                 parameters, so mapping again",
+                bam_path
+            );
+            debug!("Old manifest: {:?}, new manifest: {:?}", contents, expected);
+            false
+        }
+    }
+
+    /// Remove any manifest for the given BAM file, so that if the BAM is
+    /// regenerated but mapping is interrupted, the partial BAM is not later
+    /// mistaken for a valid cache.
+    pub fn invalidate(bam_path: &str) {
+        let manifest_path = BamCacheManifest::manifest_path(bam_path);
+        if std::path::Path::new(&manifest_path).exists() {
+            std::fs::remove_file(&manifest_path).expect(&format!(
+                "Failed to remove stale BAM cache manifest {}",
+                manifest_path
+            ));
+        }
+    }
+
+    pub fn write(&self, bam_path: &str) {
+        let manifest_path = BamCacheManifest::manifest_path(bam_path);
+        let mut f = std::fs::File::create(&manifest_path).expect(&format!(
+            "Failed to create BAM cache manifest {}",
+            manifest_path
+        ));
+        f.write_all(self.to_tsv().as_bytes()).expect(&format!(
+            "Failed to write BAM cache manifest {}",
+            manifest_path
+        ));
+        debug!("Wrote BAM cache manifest {}", manifest_path);
+    }
+}
+
+/// Remove cached BAM files, and their manifests, which were generated by
+/// earlier runs from the same reference and read1 file but with different
+/// inputs or parameters, so that they do not pile up in the cache directory.
+/// The given cached BAM files of the current run are kept.
+pub fn remove_stale_cached_bam_files(current_bam_paths: &[String]) {
+    for bam_path in current_bam_paths {
+        let path = std::path::Path::new(bam_path);
+        let stem = match path.file_name().and_then(|f| f.to_str()).and_then(cached_bam_file_stem) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let directory = path.parent().expect("Unable to find cached BAM file directory");
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Unable to list cache directory {:?}: {}", directory, e);
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry_path = entry.expect("Failed to read cache directory entry").path();
+            let is_stale = match entry_path.file_name().and_then(|f| f.to_str()) {
+                Some(file_name) => {
+                    let bam_file_name = match file_name.ends_with(".manifest") {
+                        true => &file_name[..file_name.len() - ".manifest".len()],
+                        false => file_name,
+                    };
+                    cached_bam_file_stem(bam_file_name) == Some(stem)
+                        && !current_bam_paths
+                            .iter()
+                            .any(|p| std::path::Path::new(p) == directory.join(bam_file_name))
+                }
+                None => false,
+            };
+            if is_stale {
+                info!("Removing stale cached BAM file {:?}", entry_path);
+                if let Err(e) = std::fs::remove_file(&entry_path) {
+                    warn!("Unable to remove stale cached BAM file {:?}: {}", entry_path, e);
+                }
+            }
+        }
+    }
+}
+
+// Cached BAM files are named <reference>.<read1>.<hash>.bam, where the hash
+// is that of the manifest. Returns the part before the hash.
+fn cached_bam_file_stem(file_name: &str) -> Option<&str> {
+    if !file_name.ends_with(".bam") {
+        return None;
+    }
+    let without_extension = &file_name[..file_name.len() - ".bam".len()];
+    match without_extension.rfind('.') {
+        Some(i) => {
+            let hash = &without_extension[i + 1..];
+            match hash.len() == 8 && hash.chars().all(|c| c.is_digit(16)) {
+                true => Some(&without_extension[..i]),
+                false => None,
+            }
+        }
+        None => None,
+    }
+}
+
+/// Size and modification time of a file, which together are taken to
+/// identify its contents.
+pub fn file_size_and_mtime(path: &str) -> std::io::Result<(u64, String)> {
+    let metadata = std::fs::metadata(path)?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()))
+        .unwrap_or("".to_string());
+    Ok((metadata.len(), mtime))
+}
+
+fn add_file_entries(
+    entries: &mut Vec<(String, String)>,
+    description: &str,
+    path: &str,
+    required: bool,
+) {
+    let absolute = match std::fs::canonicalize(path) {
+        Ok(p) => p.to_str().unwrap_or(path).to_string(),
+        Err(_) => path.to_string(),
+    };
+    entries.push((description.to_string(), absolute));
+    match file_size_and_mtime(path) {
+        Ok((size, mtime)) => {
+            entries.push((format!("{}_size", description), format!("{}", size)));
+            entries.push((format!("{}_mtime", description), mtime));
+        }
+        Err(e) => {
+            if required {
+                error!("Unable to read metadata of {} file {}: {}", description, path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("a.bam");
+        let bam_path = bam.to_str().unwrap();
+        std::fs::File::create(&bam).unwrap();
+
+        let manifest = BamCacheManifest::new(
+            MappingProgram::MINIMAP2_SR,
+            &["tests/data/7seqs.fna"],
+            &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+            &["tests/data/reads_for_seq1_and_seq2.2.fq.gz"],
+            &ReadFormat::Coupled,
+            None,
+            false,
+        );
+        assert!(!manifest.matches_cache(bam_path));
+        manifest.write(bam_path);
+        assert!(manifest.matches_cache(bam_path));
+
+        let different_mapper = BamCacheManifest::new(
+            MappingProgram::BWA_MEM,
+            &["tests/data/7seqs.fna"],
+            &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+            &["tests/data/reads_for_seq1_and_seq2.2.fq.gz"],
+            &ReadFormat::Coupled,
+            None,
+            false,
+        );
+        assert!(!different_mapper.matches_cache(bam_path));
+
+        let different_read2 = BamCacheManifest::new(
+            MappingProgram::MINIMAP2_SR,
+            &["tests/data/7seqs.fna"],
+            &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+            &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+            &ReadFormat::Coupled,
+            None,
+            false,
+        );
+        assert!(!different_read2.matches_cache(bam_path));
+
+        let mut host_depleted = BamCacheManifest::new(
+            MappingProgram::MINIMAP2_SR,
+            &["tests/data/7seqs.fna"],
+            &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+            &["tests/data/reads_for_seq1_and_seq2.2.fq.gz"],
+            &ReadFormat::Coupled,
+            None,
+            false,
+        );
+        host_depleted.add_host_reference("tests/data/2seqs.fasta");
+        assert!(!host_depleted.matches_cache(bam_path));
+
+        assert_eq!(8, manifest.name_hash().len());
+        for other in &[&different_mapper, &different_read2, &host_depleted] {
+            assert_ne!(manifest.name_hash(), other.name_hash());
+        }
+
+        BamCacheManifest::invalidate(bam_path);
+        assert!(!manifest.matches_cache(bam_path));
+    }
+
+    #[test]
+    fn test_manifest_reads_from_stdin() {
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("a.bam");
+        let bam_path = bam.to_str().unwrap();
+        std::fs::File::create(&bam).unwrap();
+
+        let manifest = BamCacheManifest::new(
+            MappingProgram::MINIMAP2_SR,
+            &["tests/data/7seqs.fna"],
+            &["-"],
+            &[],
+            &ReadFormat::Interleaved,
+            None,
+            false,
+        );
+        manifest.write(bam_path);
+        assert!(!manifest.matches_cache(bam_path));
+    }
+
+    #[test]
+    fn test_remove_stale_cached_bam_files() {
+        let td = tempfile::TempDir::new().unwrap();
+        let path = |name: &str| td.path().join(name).to_str().unwrap().to_string();
+        let names = [
+            "7seqs.fna.reads.1.fq.gz.0123abcd.bam",
+            "7seqs.fna.reads.1.fq.gz.0123abcd.bam.manifest",
+            "7seqs.fna.reads.1.fq.gz.89abcdef.bam",
+            "7seqs.fna.reads.1.fq.gz.89abcdef.bam.manifest",
+            "7seqs.fna.reads.1.fq.gz.bam",
+            "7seqs.fna.other.1.fq.gz.01234567.bam",
+            "2seqs.fasta.reads.1.fq.gz.01234567.bam",
+        ];
+        for name in names.iter() {
+            std::fs::File::create(path(name)).unwrap();
+        }
+
+        remove_stale_cached_bam_files(&[
+            path("7seqs.fna.reads.1.fq.gz.89abcdef.bam"),
+            path("2seqs.fasta.reads.1.fq.gz.01234567.bam"),
+        ]);
+        let remaining: Vec<bool> = names
+            .iter()
+            .map(|name| std::path::Path::new(&path(name)).exists())
+            .collect();
+        assert_eq!(vec![false, false, true, true, true, true, true], remaining);
+    }
+}
@@ -3,9 +3,11 @@ use std::io::Read;
 use std::process;
 use std::sync::atomic::{compiler_fence, Ordering};
 
+use bam_cache::CachedBamFile;
 use filter::*;
 use mapping_index_maintenance::MappingIndex;
 use mapping_parameters::ReadFormat;
+use stoit_naming::StoitNaming;
 use FlagFilter;
 
 use rust_htslib::bam;
@@ -40,6 +42,13 @@ pub trait NamedBamReader {
 pub trait NamedBamReaderGenerator<T> {
     // For readers that map, start the process of mapping
     fn start(self) -> T;
+
+    // Name of the stoit that will be generated, known before starting
+    fn stoit_name(&self) -> &str;
+
+    // Read or BAM files that the stoit is generated from, so that results
+    // are only reused while these are unchanged. '-' means STDIN.
+    fn input_paths(&self) -> &[String];
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -52,8 +61,17 @@ pub enum MappingProgram {
     MINIMAP2_NO_PRESET,
 }
 
+/// A host (or contaminant) reference which reads are mapped to before the
+/// main reference. Reads (pairs) mapping to the host are removed, so they
+/// count towards neither the mapped nor the total number of reads.
+pub struct HostDepletion {
+    pub mapping_program: MappingProgram,
+    pub index: Box<dyn MappingIndex>,
+}
+
 pub struct BamFileNamedReader {
     stoit_name: String,
+    input_paths: Vec<String>,
     bam_reader: bam::Reader,
     num_detected_primary_alignments: u64,
 }
@@ -87,9 +105,16 @@ impl NamedBamReader for BamFileNamedReader {
 }
 
 impl NamedBamReaderGenerator<BamFileNamedReader> for BamFileNamedReader {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.input_paths
+    }
     fn start(self) -> BamFileNamedReader {
         BamFileNamedReader {
             stoit_name: self.stoit_name,
+            input_paths: self.input_paths,
             bam_reader: self.bam_reader,
             num_detected_primary_alignments: 0,
         }
@@ -105,19 +130,33 @@ pub struct StreamingNamedBamReader {
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
     num_detected_primary_alignments: u64,
+    pending_cache_manifest: Option<CachedBamFile>,
+    host_depletion_count: Option<tempfile::NamedTempFile>,
 }
 
 pub struct StreamingNamedBamReaderGenerator {
     stoit_name: String,
+    input_paths: Vec<String>,
     tempdir: TempDir,
     fifo_path: std::path::PathBuf,
     pre_processes: Vec<std::process::Command>,
     command_strings: Vec<String>,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
+    // Manifest of the cached BAM file, written once mapping succeeds
+    pending_cache_manifest: Option<CachedBamFile>,
+    // Number of reads removed by mapping to the host, written once mapping
+    // finishes
+    host_depletion_count: Option<tempfile::NamedTempFile>,
 }
 
 impl NamedBamReaderGenerator<StreamingNamedBamReader> for StreamingNamedBamReaderGenerator {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.input_paths
+    }
     fn start(self) -> StreamingNamedBamReader {
         debug!("Starting mapping processes");
         let mut processes = vec![];
@@ -154,6 +193,8 @@ impl NamedBamReaderGenerator<StreamingNamedBamReader> for StreamingNamedBamReade
             log_file_descriptions: self.log_file_descriptions,
             log_files: self.log_files,
             num_detected_primary_alignments: 0,
+            pending_cache_manifest: self.pending_cache_manifest,
+            host_depletion_count: self.host_depletion_count,
         }
     }
 }
@@ -231,7 +272,9 @@ impl NamedBamReader for StreamingNamedBamReader {
             self.command_strings,
             self.log_file_descriptions,
             self.log_files,
-            Some(self.tempdir))
+            Some(self.tempdir));
+        write_pending_cache_manifest(self.pending_cache_manifest);
+        log_host_depletion(&self.stoit_name, self.host_depletion_count);
     }
 
     fn set_threads(&mut self, n_threads: usize) {
@@ -247,32 +290,109 @@ impl NamedBamReader for StreamingNamedBamReader {
 }
 
 pub fn generate_named_bam_readers_from_bam_files(
-    bam_paths: Vec<&str>) -> Vec<BamFileNamedReader>{
-
-    bam_paths.iter().map(
-        |path|
-
-       BamFileNamedReader {
-           stoit_name: std::path::Path::new(path).file_stem().unwrap().to_str().expect(
-               "failure to convert bam file name to stoit name - UTF8 error maybe?").to_string(),
-           bam_reader: bam::Reader::from_path(path).expect(
-               &format!("Unable to find BAM file {}", path)),
-           num_detected_primary_alignments: 0,
-       }
+    bam_paths: Vec<&str>,
+    stoit_naming: &StoitNaming) -> Vec<BamFileNamedReader>{
+
+    bam_paths.iter().enumerate().map(
+        |(i, path)| {
+            BamFileNamedReader {
+                stoit_name: bam_file_stoit_name(path, stoit_naming, stoit_naming.sample_name(i)),
+                input_paths: vec![path.to_string()],
+                bam_reader: bam::Reader::from_path(path).expect(
+                    &format!("Unable to find BAM file {}", path)),
+                num_detected_primary_alignments: 0,
+            }
+        }
     ).collect()
 }
 
+/// Merge the coordinate-sorted BAM files of one sample with samtools, so
+/// that they are read as a single stoit e.g. when a sample was sequenced
+/// over several runs.
+pub fn generate_named_bam_readers_from_merged_bam_files(
+    sample_name: &str,
+    bam_paths: &[&str],
+    threads: u16,
+    stoit_naming: &StoitNaming) -> StreamingNamedBamReaderGenerator {
+
+    let tmp_dir = TempDir::new("coverm_fifo")
+        .expect("Unable to create temporary directory");
+    let fifo_path = tmp_dir.path().join("foo.pipe");
+    unistd::mkfifo(&fifo_path, stat::Mode::S_IRWXU)
+        .expect(&format!("Error creating named pipe {:?}", fifo_path));
+
+    let merge_log = tempfile::NamedTempFile::new()
+        .expect("Failed to create samtools merge log tempfile");
+    let cmd_string = format!(
+        "set -e -o pipefail; samtools merge -c -p -u -@ {} - {} 2>{} > {:?}",
+        threads-1,
+        bam_paths.iter().map(|p| format!("'{}'", p)).collect::<Vec<_>>().join(" "),
+        merge_log.path().to_str().expect("Failed to convert tempfile path to str"),
+        fifo_path);
+    debug!("Queuing cmd_string: {}", cmd_string);
+    let mut cmd = std::process::Command::new("bash");
+    cmd
+        .arg("-c")
+        .arg(&cmd_string)
+        .stderr(std::process::Stdio::piped());
+
+    let stoit_name = stoit_naming.stoit_name(None, sample_name, Some(sample_name));
+    return StreamingNamedBamReaderGenerator {
+        stoit_name: stoit_name,
+        input_paths: bam_paths.iter().map(|p| p.to_string()).collect(),
+        tempdir: tmp_dir,
+        fifo_path: fifo_path,
+        pre_processes: vec![cmd],
+        command_strings: vec![format!("bash -c \"{}\"", cmd_string)],
+        log_file_descriptions: vec!["samtools merge".to_string()],
+        log_files: vec![merge_log],
+        pending_cache_manifest: None,
+        host_depletion_count: None,
+    }
+}
+
+pub fn generate_filtered_named_bam_readers_from_merged_bam_files(
+    sample_name: &str,
+    bam_paths: &[&str],
+    threads: u16,
+    stoit_naming: &StoitNaming,
+    flag_filters: FlagFilter,
+    min_aligned_length_single: u32,
+    min_percent_identity_single: f32,
+    min_aligned_percent_single: f32,
+    min_aligned_length_pair: u32,
+    min_percent_identity_pair: f32,
+    min_aligned_percent_pair: f32,
+    mate_genomes: MateGenomes)
+    -> StreamingFilteredNamedBamReaderGenerator {
+
+    filter_streaming_generator(
+        generate_named_bam_readers_from_merged_bam_files(
+            sample_name, bam_paths, threads, stoit_naming),
+        flag_filters,
+        min_aligned_length_single,
+        min_percent_identity_single,
+        min_aligned_percent_single,
+        min_aligned_length_pair,
+        min_percent_identity_pair,
+        min_aligned_percent_pair,
+        mate_genomes)
+}
+
 pub fn generate_named_bam_readers_from_reads(
     mapping_program: MappingProgram,
     reference: &str,
-    read1_path: &str,
-    read2_path: Option<&str>,
+    read1_paths: &[&str],
+    read2_paths: &[&str],
+    sample_name: Option<&str>,
+    stoit_naming: &StoitNaming,
     read_format: ReadFormat,
     threads: u16,
-    cached_bam_file: Option<&str>,
+    cached_bam_file: Option<CachedBamFile>,
     discard_unmapped: bool,
     mapping_options: Option<&str>,
-    include_reference_in_stoit_name: bool) -> StreamingNamedBamReaderGenerator {
+    include_reference_in_stoit_name: bool,
+    host_depletion: Option<&HostDepletion>) -> StreamingNamedBamReaderGenerator {
 
     let tmp_dir = TempDir::new("coverm_fifo")
         .expect("Unable to create temporary directory");
@@ -293,8 +413,52 @@ pub fn generate_named_bam_readers_from_reads(
     let samtools_view_cache_log = tempfile::NamedTempFile::new()
         .expect("Failed to create cache samtools view log tempfile");
 
+    let stoit_name = generate_stoit_name(
+        reference, read1_paths[0], sample_name, stoit_naming, include_reference_in_stoit_name);
+    let input_paths: Vec<String> = read1_paths.iter().chain(read2_paths.iter())
+        .map(|p| p.to_string()).collect();
+
+    // A cached BAM file can only be reused if it retains unmapped reads,
+    // since they are needed to count the total number of reads.
+    let reuse_cache = match cached_bam_file {
+        Some(ref cache) => !discard_unmapped && cache.is_valid(),
+        None => false
+    };
+    if reuse_cache {
+        let path = &cached_bam_file.as_ref().unwrap().path;
+        info!("Reusing previously cached BAM file {}", path);
+        let cat_log = tempfile::NamedTempFile::new()
+            .expect("Failed to create cached BAM reading log tempfile");
+        let cmd_string = format!(
+            "set -e -o pipefail; cat '{}' 2>{} > {:?}",
+            path,
+            cat_log.path().to_str().expect("Failed to convert tempfile path to str"),
+            fifo_path);
+        debug!("Queuing cmd_string: {}", cmd_string);
+        let mut cmd = std::process::Command::new("bash");
+        cmd
+            .arg("-c")
+            .arg(&cmd_string)
+            .stderr(std::process::Stdio::piped());
+        return StreamingNamedBamReaderGenerator {
+            stoit_name: stoit_name,
+            input_paths: input_paths,
+            tempdir: tmp_dir,
+            fifo_path: fifo_path,
+            pre_processes: vec![cmd],
+            command_strings: vec![format!("bash -c \"{}\"", cmd_string)],
+            log_file_descriptions: vec!["reading cached BAM".to_string()],
+            log_files: vec![cat_log],
+            pending_cache_manifest: None,
+            host_depletion_count: None,
+        }
+    }
+    if let Some(ref cache) = cached_bam_file {
+        cache.invalidate();
+    }
+
     let cached_bam_file_args = match cached_bam_file {
-        Some(path) => {
+        Some(ref cache) => {
             format!(
                 "|tee {:?} |samtools view {} -@ {} -b -o '{}' 2>{}",
                 // tee
@@ -302,31 +466,58 @@ pub fn generate_named_bam_readers_from_reads(
                 // samtools view
                 match discard_unmapped { true => "-F4", false => "" },
                 threads-1,
-                path,
+                cache.path,
                 samtools_view_cache_log.path().to_str()
                     .expect("Failed to convert tempfile path to str"))
         },
         None => format!("> {:?}", fifo_path)
     };
 
-    let mapping_command = build_mapping_command(
-        mapping_program,
-        read_format,
-        threads,
-        read1_path,
-        reference,
-        read2_path,
-        mapping_options
-    );
+    let mut log_descriptions = vec![];
+    let mut log_files = vec![];
+    let (depletion_command, mapping_command, host_depletion_count) = match host_depletion {
+        Some(host) => {
+            let depletion = build_host_depletion_command(
+                host, read_format.clone(), threads, read1_paths, read2_paths, tmp_dir.path());
+            log_descriptions.extend(depletion.log_file_descriptions);
+            log_files.extend(depletion.log_files);
+            // Reads left after depletion are streamed in on STDIN
+            (depletion.command,
+             build_mapping_command(
+                 mapping_program,
+                 match read_format {
+                     ReadFormat::Single => ReadFormat::Single,
+                     ReadFormat::Coupled | ReadFormat::Interleaved => ReadFormat::Interleaved,
+                 },
+                 threads,
+                 &["-"],
+                 reference,
+                 &[],
+                 mapping_options),
+             Some(depletion.count_file))
+        },
+        None => ("".to_string(),
+                 build_mapping_command(
+                     mapping_program,
+                     read_format,
+                     threads,
+                     read1_paths,
+                     reference,
+                     read2_paths,
+                     mapping_options),
+                 None)
+    };
     let bwa_sort_prefix = tempfile::Builder::new()
         .prefix("coverm-make-samtools-sort")
         .tempfile_in(tmp_dir.path())
         .expect("Failed to create tempfile as samtools sort prefix");
     let cmd_string = format!(
         "set -e -o pipefail; \
-         {} 2>{} {}\
+         {}{} 2>{} {}\
          | samtools sort -T '{}' -l0 -@ {} 2>{} \
-         {}",
+         {}{}",
+        // Host depletion
+        depletion_command,
         // Mapping program
         mapping_command,
         mapping_log.path().to_str().expect("Failed to convert tempfile path to str"),
@@ -342,7 +533,16 @@ pub fn generate_named_bam_readers_from_reads(
         threads-1,
         samtools2_log.path().to_str().expect("Failed to convert tempfile path to str"),
         // Caching (or not)
-        cached_bam_file_args);
+        cached_bam_file_args,
+        // Count reads removed by host depletion
+        match host_depletion_count {
+            Some(ref count_file) => format!(
+                "; samtools view -c -F 0x900 '{}' > {}",
+                tmp_dir.path().join(HOST_READS_BAM_NAME).to_str()
+                    .expect("Failed to convert host reads BAM path to str"),
+                count_file.path().to_str().expect("Failed to convert tempfile path to str")),
+            None => "".to_string()
+        });
     debug!("Queuing cmd_string: {}", cmd_string);
     let mut cmd = std::process::Command::new("bash");
     cmd
@@ -350,38 +550,167 @@ pub fn generate_named_bam_readers_from_reads(
         .arg(&cmd_string)
         .stderr(std::process::Stdio::piped());
 
-    let mut log_descriptions = vec![
-        format!("{:?}", mapping_program).to_string(),
-        "samtools sort".to_string()];
-    let mut log_files = vec![mapping_log, samtools2_log];
+    log_descriptions.push(format!("{:?}", mapping_program).to_string());
+    log_descriptions.push("samtools sort".to_string());
+    log_files.push(mapping_log);
+    log_files.push(samtools2_log);
     if cached_bam_file.is_some() {
         log_descriptions.push("samtools view for cache".to_string());
         log_files.push(samtools_view_cache_log);
     }
 
-    let stoit_name = match include_reference_in_stoit_name {
-        true => std::path::Path::new(reference).file_name()
-            .expect("Unable to convert reference to file name").to_str()
-            .expect("Unable to covert file name into str").to_string()+"/",
-        false => "".to_string()
-    } + &std::path::Path::new(read1_path).file_name()
-        .expect("Unable to convert read1 name to file name").to_str()
-        .expect("Unable to covert file name into str").to_string();
-
     return StreamingNamedBamReaderGenerator {
         stoit_name: stoit_name,
+        input_paths: input_paths,
         tempdir: tmp_dir,
         fifo_path: fifo_path,
         pre_processes: vec![cmd],
         command_strings: vec![format!("bash -c \"{}\"", cmd_string)],
         log_file_descriptions: log_descriptions,
         log_files: log_files,
+        pending_cache_manifest: cached_bam_file,
+        host_depletion_count: host_depletion_count,
     }
 }
 
+/// File name of the BAM file of reads mapped to the host, within the
+/// temporary directory of each sample.
+const HOST_READS_BAM_NAME: &str = "host_reads.bam";
+
+struct HostDepletionCommand {
+    // Prefix of a pipeline, outputting reads not mapped to the host as FASTQ
+    command: String,
+    log_file_descriptions: Vec<String>,
+    log_files: Vec<tempfile::NamedTempFile>,
+    count_file: tempfile::NamedTempFile,
+}
+
+/// Build the start of a pipeline that maps reads to the host, sets aside
+/// reads mapped to it, and outputs the remaining reads to STDOUT as FASTQ,
+/// interleaved if paired. A pair is removed if either read maps to the host.
+fn build_host_depletion_command(
+    host: &HostDepletion,
+    read_format: ReadFormat,
+    threads: u16,
+    read1_paths: &[&str],
+    read2_paths: &[&str],
+    tmp_dir: &std::path::Path) -> HostDepletionCommand {
+
+    let host_mapping_log = tempfile::NamedTempFile::new()
+        .expect("Failed to create host mapping log tempfile");
+    let host_view_log = tempfile::NamedTempFile::new()
+        .expect("Failed to create host samtools view log tempfile");
+    let host_fastq_log = tempfile::NamedTempFile::new()
+        .expect("Failed to create host samtools fastq log tempfile");
+    let count_file = tempfile::NamedTempFile::new()
+        .expect("Failed to create host read count tempfile");
+
+    let unmapped_flags = match read_format {
+        ReadFormat::Single => "-f 4",
+        // Both the read and its mate are unmapped
+        ReadFormat::Coupled | ReadFormat::Interleaved => "-f 12",
+    };
+    let host_mapping_command = build_mapping_command(
+        host.mapping_program,
+        read_format,
+        threads,
+        read1_paths,
+        host.index.index_path(),
+        read2_paths,
+        None);
+    let command = format!(
+        "{} 2>{} {}\
+         | samtools view -b {} -U '{}' -@ {} - 2>{} \
+         | samtools fastq -n - 2>{} | ",
+        host_mapping_command,
+        host_mapping_log.path().to_str().expect("Failed to convert tempfile path to str"),
+        match host.mapping_program {
+            MappingProgram::BWA_MEM => "",
+            _ => " | remove_minimap2_duplicated_headers"
+        },
+        unmapped_flags,
+        tmp_dir.join(HOST_READS_BAM_NAME).to_str()
+            .expect("Failed to convert host reads BAM path to str"),
+        threads-1,
+        host_view_log.path().to_str().expect("Failed to convert tempfile path to str"),
+        host_fastq_log.path().to_str().expect("Failed to convert tempfile path to str"));
+
+    HostDepletionCommand {
+        command: command,
+        log_file_descriptions: vec![
+            format!("{:?} host mapping", host.mapping_program),
+            "samtools view for host depletion".to_string(),
+            "samtools fastq for host depletion".to_string()],
+        log_files: vec![host_mapping_log, host_view_log, host_fastq_log],
+        count_file: count_file,
+    }
+}
+
+fn generate_stoit_name(
+    reference: &str,
+    read1_path: &str,
+    sample_name: Option<&str>,
+    stoit_naming: &StoitNaming,
+    include_reference_in_stoit_name: bool) -> String {
+
+    stoit_naming.stoit_name(
+        match include_reference_in_stoit_name {
+            true => Some(std::path::Path::new(reference).file_name()
+                .expect("Unable to convert reference to file name").to_str()
+                .expect("Unable to covert file name into str")),
+            false => None
+        },
+        match read1_path {
+            // Reads from STDIN have no file name
+            "-" => "stdin",
+            _ => std::path::Path::new(read1_path).file_name()
+                .expect("Unable to convert read1 name to file name").to_str()
+                .expect("Unable to covert file name into str"),
+        },
+        sample_name)
+}
+
+/// Stoit name of a BAM file, which is its file name without the extension
+/// unless another name was given.
+fn bam_file_stoit_name(path: &str, stoit_naming: &StoitNaming, sample_name: Option<&str>) -> String {
+    stoit_naming.stoit_name(
+        None,
+        std::path::Path::new(path).file_stem().unwrap().to_str().expect(
+            "failure to convert bam file name to stoit name - UTF8 error maybe?"),
+        sample_name)
+}
+
+fn write_pending_cache_manifest(pending: Option<CachedBamFile>) {
+    if let Some(cache) = pending {
+        cache.write_manifest();
+    }
+}
+
+fn log_host_depletion(stoit_name: &str, host_depletion_count: Option<tempfile::NamedTempFile>) {
+    if let Some(count_file) = host_depletion_count {
+        let mut contents = String::new();
+        count_file.into_file().read_to_string(&mut contents)
+            .expect("Failed to read number of reads mapped to the host");
+        let num_removed: u64 = contents.trim().parse()
+            .expect(&format!("Failed to parse number of reads mapped to the host: {}", contents));
+        info!("In sample '{}', removed {} reads mapped to the host reference",
+              stoit_name, num_removed);
+    }
+}
+
+
+/// Report reads which could not be filtered as pairs because their mate was
+/// not found.
+pub fn log_unresolved_pairs(stoit_name: &str, filtered_stream: &ReferenceSortedBamFilter) {
+    if filtered_stream.num_unresolved_pairs > 0 {
+        warn!("In sample '{}', {} reads were excluded because their mate was not \
+               found where expected", stoit_name, filtered_stream.num_unresolved_pairs);
+    }
+}
 
 pub struct FilteredBamReader {
     stoit_name: String,
+    input_paths: Vec<String>,
     filtered_stream: ReferenceSortedBamFilter,
 }
 
@@ -395,7 +724,9 @@ impl NamedBamReader for FilteredBamReader {
     fn header(&self) -> &bam::HeaderView {
         &self.filtered_stream.reader.header()
     }
-    fn finish(self) {}
+    fn finish(self) {
+        log_unresolved_pairs(&self.stoit_name, &self.filtered_stream);
+    }
     fn set_threads(&mut self, n_threads: usize) {
         if n_threads > 1 {
             self.filtered_stream.reader.set_threads(n_threads-1).unwrap();
@@ -407,9 +738,16 @@ impl NamedBamReader for FilteredBamReader {
 }
 
 impl NamedBamReaderGenerator<FilteredBamReader> for FilteredBamReader {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.input_paths
+    }
     fn start(self) -> FilteredBamReader {
         FilteredBamReader {
             stoit_name: self.stoit_name,
+            input_paths: self.input_paths,
             filtered_stream: self.filtered_stream,
         }
     }
@@ -423,29 +761,33 @@ pub fn generate_filtered_bam_readers_from_bam_files(
     min_aligned_percent_single: f32,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
-    min_aligned_percent_pair: f32) -> Vec<FilteredBamReader>{
+    min_aligned_percent_pair: f32,
+    mate_genomes: &MateGenomes,
+    stoit_naming: &StoitNaming) -> Vec<FilteredBamReader>{
 
     let mut generators: Vec<FilteredBamReader> = vec![];
 
-    for path in bam_paths {
+    for (i, path) in bam_paths.into_iter().enumerate() {
         let filtered: FilteredBamReader;
-        let stoit_name = std::path::Path::new(path).file_stem().unwrap().to_str().expect(
-            "failure to convert bam file name to stoit name - UTF8 error maybe?").to_string();
+        let stoit_name = bam_file_stoit_name(path, stoit_naming, stoit_naming.sample_name(i));
         let reader = bam::Reader::from_path(path).expect(
             &format!("Unable to find BAM file {}", path));
 
+        let mut filtered_stream = ReferenceSortedBamFilter::new(
+            reader,
+            flag_filters.clone(),
+            min_aligned_length_single,
+            min_percent_identity_single,
+            min_aligned_percent_single,
+            min_aligned_length_pair,
+            min_percent_identity_pair,
+            min_aligned_percent_pair,
+            true);
+        filtered_stream.set_mate_genomes(mate_genomes);
         filtered = FilteredBamReader {
             stoit_name: stoit_name,
-            filtered_stream: ReferenceSortedBamFilter::new(
-                reader,
-                flag_filters.clone(),
-                min_aligned_length_single,
-                min_percent_identity_single,
-                min_aligned_percent_single,
-                min_aligned_length_pair,
-                min_percent_identity_pair,
-                min_aligned_percent_pair,
-                true),
+            input_paths: vec![path.to_string()],
+            filtered_stream: filtered_stream,
         };
 
         generators.push(
@@ -477,10 +819,13 @@ pub struct StreamingFilteredNamedBamReader {
     command_strings: Vec<String>,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
+    pending_cache_manifest: Option<CachedBamFile>,
+    host_depletion_count: Option<tempfile::NamedTempFile>,
 }
 
 pub struct StreamingFilteredNamedBamReaderGenerator {
     stoit_name: String,
+    input_paths: Vec<String>,
     tempdir: TempDir,
     fifo_path: std::path::PathBuf,
     pre_processes: Vec<std::process::Command>,
@@ -492,11 +837,20 @@ pub struct StreamingFilteredNamedBamReaderGenerator {
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    mate_genomes: MateGenomes,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
+    pending_cache_manifest: Option<CachedBamFile>,
+    host_depletion_count: Option<tempfile::NamedTempFile>,
 }
 
 impl NamedBamReaderGenerator<StreamingFilteredNamedBamReader> for StreamingFilteredNamedBamReaderGenerator {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.input_paths
+    }
     fn start(self) -> StreamingFilteredNamedBamReader {
         debug!("Starting mapping processes");
         let mut processes = vec![];
@@ -522,7 +876,7 @@ impl NamedBamReaderGenerator<StreamingFilteredNamedBamReader> for StreamingFilte
             }
         };
 
-        let filtered_stream = ReferenceSortedBamFilter::new(
+        let mut filtered_stream = ReferenceSortedBamFilter::new(
             bam_reader,
             self.flag_filters,
             self.min_aligned_length_single,
@@ -532,6 +886,7 @@ impl NamedBamReaderGenerator<StreamingFilteredNamedBamReader> for StreamingFilte
             self.min_percent_identity_pair,
             self.min_aligned_percent_pair,
             true);
+        filtered_stream.set_mate_genomes(&self.mate_genomes);
         return StreamingFilteredNamedBamReader {
             stoit_name: self.stoit_name,
             filtered_stream: filtered_stream,
@@ -540,6 +895,8 @@ impl NamedBamReaderGenerator<StreamingFilteredNamedBamReader> for StreamingFilte
             command_strings: self.command_strings,
             log_file_descriptions: self.log_file_descriptions,
             log_files: self.log_files,
+            pending_cache_manifest: self.pending_cache_manifest,
+            host_depletion_count: self.host_depletion_count,
         }
     }
 }
@@ -561,7 +918,10 @@ impl NamedBamReader for StreamingFilteredNamedBamReader {
             self.command_strings,
             self.log_file_descriptions,
             self.log_files,
-            Some(self.tempdir))
+            Some(self.tempdir));
+        write_pending_cache_manifest(self.pending_cache_manifest);
+        log_host_depletion(&self.stoit_name, self.host_depletion_count);
+        log_unresolved_pairs(&self.stoit_name, &self.filtered_stream);
     }
 
     fn set_threads(&mut self, n_threads: usize) {
@@ -578,11 +938,13 @@ impl NamedBamReader for StreamingFilteredNamedBamReader {
 pub fn generate_filtered_named_bam_readers_from_reads(
     mapping_program: MappingProgram,
     reference: &str,
-    read1_path: &str,
-    read2_path: Option<&str>,
+    read1_paths: &[&str],
+    read2_paths: &[&str],
+    sample_name: Option<&str>,
+    stoit_naming: &StoitNaming,
     read_format: ReadFormat,
     threads: u16,
-    cached_bam_file: Option<&str>,
+    cached_bam_file: Option<CachedBamFile>,
     flag_filters: FlagFilter,
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
@@ -590,24 +952,53 @@ pub fn generate_filtered_named_bam_readers_from_reads(
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    mate_genomes: MateGenomes,
     bwa_options: Option<&str>,
     discard_unmapped: bool,
-    include_reference_in_stoit_name: bool)
+    include_reference_in_stoit_name: bool,
+    host_depletion: Option<&HostDepletion>)
     -> StreamingFilteredNamedBamReaderGenerator {
 
     let streaming = generate_named_bam_readers_from_reads(
         mapping_program,
-        reference, read1_path, read2_path, read_format, threads,
+        reference, read1_paths, read2_paths, sample_name, stoit_naming, read_format, threads,
         cached_bam_file, discard_unmapped, bwa_options,
-        include_reference_in_stoit_name);
+        include_reference_in_stoit_name, host_depletion);
+    return filter_streaming_generator(
+        streaming,
+        flag_filters,
+        min_aligned_length_single,
+        min_percent_identity_single,
+        min_aligned_percent_single,
+        min_aligned_length_pair,
+        min_percent_identity_pair,
+        min_aligned_percent_pair,
+        mate_genomes);
+}
+
+fn filter_streaming_generator(
+    streaming: StreamingNamedBamReaderGenerator,
+    flag_filters: FlagFilter,
+    min_aligned_length_single: u32,
+    min_percent_identity_single: f32,
+    min_aligned_percent_single: f32,
+    min_aligned_length_pair: u32,
+    min_percent_identity_pair: f32,
+    min_aligned_percent_pair: f32,
+    mate_genomes: MateGenomes)
+    -> StreamingFilteredNamedBamReaderGenerator {
+
     return StreamingFilteredNamedBamReaderGenerator {
         stoit_name: streaming.stoit_name,
+        input_paths: streaming.input_paths,
         tempdir: streaming.tempdir,
         fifo_path: streaming.fifo_path,
         pre_processes: streaming.pre_processes,
         command_strings: streaming.command_strings,
         log_file_descriptions: streaming.log_file_descriptions,
         log_files: streaming.log_files,
+        pending_cache_manifest: streaming.pending_cache_manifest,
+        host_depletion_count: streaming.host_depletion_count,
         flag_filters: flag_filters,
         min_aligned_length_single: min_aligned_length_single,
         min_percent_identity_single: min_percent_identity_single,
@@ -615,6 +1006,7 @@ pub fn generate_filtered_named_bam_readers_from_reads(
         min_aligned_length_pair: min_aligned_length_pair,
         min_percent_identity_pair: min_percent_identity_pair,
         min_aligned_percent_pair: min_aligned_percent_pair,
+        mate_genomes: mate_genomes,
     }
 }
 
@@ -634,27 +1026,50 @@ pub struct NamedBamMaker {
     command_strings: Vec<String>,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
+    pending_cache_manifest: Option<CachedBamFile>,
 }
 
 pub struct NamedBamMakerGenerator {
     stoit_name: String,
+    input_paths: Vec<String>,
     pre_processes: Vec<std::process::Command>,
     command_strings: Vec<String>,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
+    pending_cache_manifest: Option<CachedBamFile>,
 }
 
 pub fn generate_bam_maker_generator_from_reads(
     mapping_program: MappingProgram,
     reference: &str,
-    read1_path: &str,
-    read2_path: Option<&str>,
+    read1_paths: &[&str],
+    read2_paths: &[&str],
+    sample_name: Option<&str>,
+    stoit_naming: &StoitNaming,
     read_format: ReadFormat,
     threads: u16,
-    cached_bam_file: &str,
+    cached_bam_file: CachedBamFile,
     discard_unmapped: bool,
     mapping_options: Option<&str>) -> NamedBamMakerGenerator {
 
+    let stoit_name = generate_stoit_name(
+        reference, read1_paths[0], sample_name, stoit_naming, true);
+    let input_paths: Vec<String> = read1_paths.iter().chain(read2_paths.iter())
+        .map(|p| p.to_string()).collect();
+    if cached_bam_file.is_valid() {
+        info!("BAM file {} is already up to date, not mapping again", cached_bam_file.path);
+        return NamedBamMakerGenerator {
+            stoit_name: stoit_name,
+            input_paths: input_paths,
+            pre_processes: vec![],
+            command_strings: vec![],
+            log_file_descriptions: vec![],
+            log_files: vec![],
+            pending_cache_manifest: None,
+        }
+    }
+    cached_bam_file.invalidate();
+
     let mapping_log = tempfile::NamedTempFile::new()
         .expect(&format!("Failed to create {:?} log tempfile", mapping_program));
     let samtools2_log = tempfile::NamedTempFile::new()
@@ -668,9 +1083,9 @@ pub fn generate_bam_maker_generator_from_reads(
         mapping_program,
         read_format,
         threads,
-        read1_path,
+        read1_paths,
         reference,
-        read2_path,
+        read2_paths,
         mapping_options
     );
 
@@ -700,7 +1115,7 @@ pub fn generate_bam_maker_generator_from_reads(
         // samtools view
         match discard_unmapped { true => "-F4", false => ""},
         threads-1,
-        cached_bam_file,
+        cached_bam_file.path,
         samtools_view_cache_log.path().to_str()
             .expect("Failed to convert tempfile path to str"));
     debug!("Queuing cmd_string: {}", cmd_string);
@@ -720,20 +1135,31 @@ pub fn generate_bam_maker_generator_from_reads(
         samtools_view_cache_log];
 
     return NamedBamMakerGenerator {
-        stoit_name: std::path::Path::new(reference).file_name()
-            .expect("Unable to convert reference to file name").to_str()
-            .expect("Unable to covert file name into str").to_string()+"/"+
-            &std::path::Path::new(read1_path).file_name()
-            .expect("Unable to convert read1 name to file name").to_str()
-            .expect("Unable to covert file name into str").to_string(),
+        stoit_name: stoit_name,
+        input_paths: input_paths,
         pre_processes: vec![cmd],
         command_strings: vec![format!("bash -c \"{}\"", cmd_string)],
         log_file_descriptions: log_descriptions,
         log_files: log_files,
+        pending_cache_manifest: Some(cached_bam_file),
+    }
+}
+
+impl NamedBamMakerGenerator {
+    /// Shell commands which generate the BAM file, empty if it is already up
+    /// to date.
+    pub fn command_strings(&self) -> &Vec<String> {
+        &self.command_strings
     }
 }
 
 impl NamedBamReaderGenerator<NamedBamMaker> for NamedBamMakerGenerator {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.input_paths
+    }
     fn start(self) -> NamedBamMaker {
         debug!("Starting mapping processes");
         let mut processes = vec![];
@@ -751,6 +1177,7 @@ impl NamedBamReaderGenerator<NamedBamMaker> for NamedBamMakerGenerator {
             command_strings: self.command_strings,
             log_file_descriptions: self.log_file_descriptions,
             log_files: self.log_files,
+            pending_cache_manifest: self.pending_cache_manifest,
         }
     }
 }
@@ -767,7 +1194,44 @@ impl NamedBamMaker {
             self.command_strings,
             self.log_file_descriptions,
             self.log_files,
-            None)
+            None);
+        write_pending_cache_manifest(self.pending_cache_manifest);
+    }
+}
+
+/// True if reads in the file must be decompressed before being given to the
+/// mapper, which only reads uncompressed and gzipped files.
+pub fn read_file_needs_decompression(path: &str) -> bool {
+    path.ends_with(".zst") || path.ends_with(".zstd") || path.ends_with(".bz2")
+}
+
+/// Command writing the reads in a file to STDOUT, decompressing them if they
+/// are compressed. A path of '-' means STDIN.
+fn read_file_decompression_command(path: &str) -> String {
+    if path.ends_with(".bz2") {
+        format!("bzip2 -dc '{}'", path)
+    } else if read_file_needs_decompression(path) {
+        format!("zstd -dc '{}'", path)
+    } else if path == "-" {
+        "gzip -cdf".to_string()
+    } else {
+        format!("gzip -cdf '{}'", path)
+    }
+}
+
+/// Mapper argument for reads in the given files. Reads in multiple files are
+/// concatenated, and zstd or bzip2 compressed reads decompressed, through a
+/// pipe.
+fn read_files_argument(paths: &[&str]) -> String {
+    if paths.len() == 1 && !read_file_needs_decompression(paths[0]) {
+        format!("'{}'", paths[0])
+    } else {
+        format!(
+            "<({})",
+            paths.iter()
+                .map(|p| read_file_decompression_command(p))
+                .collect::<Vec<_>>()
+                .join("; "))
     }
 }
 
@@ -775,9 +1239,9 @@ pub fn build_mapping_command(
     mapping_program: MappingProgram,
     read_format: ReadFormat,
     threads: u16,
-    read1_path: &str,
+    read1_paths: &[&str],
     reference: &str,
-    read2_path: Option<&str>,
+    read2_paths: &[&str],
     mapping_options: Option<&str>
 ) -> String {
 
@@ -794,9 +1258,10 @@ pub fn build_mapping_command(
     };
 
     let read_params2 = match read_format {
-        ReadFormat::Interleaved => format!("'{}'", read1_path),
-        ReadFormat::Coupled => format!("'{}' '{}'", read1_path, read2_path.unwrap()),
-        ReadFormat::Single => format!("'{}'", read1_path),
+        ReadFormat::Interleaved => read_files_argument(read1_paths),
+        ReadFormat::Coupled => format!(
+            "{} {}", read_files_argument(read1_paths), read_files_argument(read2_paths)),
+        ReadFormat::Single => read_files_argument(read1_paths),
     };
 
     return format!(
@@ -825,4 +1290,19 @@ pub fn build_mapping_command(
         reference,
         read_params2
     )
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_files_argument() {
+        assert_eq!("'r1.fq.gz'", read_files_argument(&["r1.fq.gz"]));
+        assert_eq!("'-'", read_files_argument(&["-"]));
+        assert_eq!("<(zstd -dc 'r1.fq.zst')", read_files_argument(&["r1.fq.zst"]));
+        assert_eq!(
+            "<(gzip -cdf 'L1.fa'; bzip2 -dc 'L2.fq.bz2'; gzip -cdf)",
+            read_files_argument(&["L1.fa", "L2.fq.bz2", "-"]));
+    }
+}
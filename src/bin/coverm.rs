@@ -1,14 +1,21 @@
 extern crate coverm;
+use coverm::bam_cache::*;
 use coverm::bam_generator::*;
+use coverm::checkpoint::*;
 use coverm::coverage_printer::*;
 use coverm::coverage_takers::*;
 use coverm::external_command_checker;
 use coverm::filter;
+use coverm::filter::MateGenomes;
 use coverm::genome_exclusion::*;
 use coverm::genomes_and_contigs::GenomesAndContigs;
+use coverm::provenance::Provenance;
+use coverm::random;
+use coverm::sample_sheet::*;
 use coverm::mapping_parameters::*;
 use coverm::mosdepth_genome_coverage_estimators::*;
 use coverm::shard_bam_reader::*;
+use coverm::stoit_naming::StoitNaming;
 use coverm::FlagFilter;
 use coverm::CONCATENATED_FASTA_FILE_SEPARATOR;
 use coverm::cli::*;
@@ -17,14 +24,19 @@ extern crate rust_htslib;
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env;
 use std::process;
+use std::rc::Rc;
 use std::str;
 
 extern crate clap;
 use clap::*;
 
+extern crate regex;
+use regex::bytes::Regex;
+
 #[macro_use]
 extern crate log;
 extern crate env_logger;
@@ -50,14 +62,78 @@ const MAPPER_HELP: &'static str =
                                          [default: \"minimap2-sr\"]";
 
 fn filter_full_help() -> &'static str {
-    "coverm filter: Remove alignments with insufficient identity.
+    lazy_static! {
+        static ref FILTER_FULL_HELP: String = format!(
+        "coverm filter: Remove alignments with insufficient identity.
 
-Only primary, non-supplementary alignments are considered, and output files
+Only primary, non-supplementary alignments are considered (supplementary
+alignments are kept with --long-read), and output files
 are grouped by reference, but not sorted by position.
 
-Files (both required):
+Define mapping(s) (required):
+  Either define BAM:
    -b, --bam-files <PATH> ..             Path to reference-sorted BAM file(s)
-   -o, --output-bam-files <PATH> ..      Path to corresponding output file(s)
+
+  Or do mapping, without writing unfiltered BAM files:
+   -r, --reference <PATH> ..             FASTA file of contigs e.g. concatenated
+                                         genomes or assembly, or minimap2 index
+                                         (with --minimap2-reference-is-index),
+                                         or BWA index stem (with -p bwa-mem).
+   -1 <PATH> ..                          Forward FASTA/Q file(s) for mapping
+   -2 <PATH> ..                          Reverse FASTA/Q file(s) for mapping
+   -c, --coupled <PATH> <PATH> ..        One or more pairs of forward and reverse
+                                         FASTA/Q files for mapping in order
+                                         <sample1_R1.fq.gz> <sample1_R2.fq.gz>
+                                         <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
+   --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
+   --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
+{}
+   --minimap2-params PARAMS              Extra parameters to provide to minimap2,
+                                         both indexing command (if used) and for
+                                         mapping. Note that usage of this parameter
+                                         has security implications if untrusted input
+                                         is specified. '-a' is always specified.
+                                         [default \"\"]
+   --minimap2-reference-is-index         Treat reference as a minimap2 database, not
+                                         as a FASTA file.
+   --bwa-params PARAMS                   Extra parameters to provide to BWA. Note
+                                         that usage of this parameter has security
+                                         implications if untrusted input is specified.
+                                         [default \"\"]
+
+Output (one type required):
+   -o, --output-bam-files <PATH> ..      Path to corresponding output file(s).
+                                         When mapping, one file is needed for
+                                         each set of reads against each reference,
+                                         with sets of reads in the order -1/-2,
+                                         -c, --interleaved, --single then
+                                         --sample-sheet samples.
+   --output-fastq <PATH> ..              Write the reads of alignments passing
+                                         the thresholds to corresponding
+                                         gzip-compressed FASTQ file(s) instead.
+                                         Each read is written once, and a pair
+                                         only when both of its reads pass. Pairs
+                                         are interleaved unless
+                                         --output-fastq-2 is specified.
+   --output-fastq-2 <PATH> ..            Write the second read of each pair to
+                                         these file(s), and the first read to
+                                         --output-fastq.
+
+Restricting to genomes (optional):
+   --genome-definition <FILE>            Only keep alignments to contigs in this
+                                         file of genome and contig names, one
+                                         pair per line separated by a tab.
+   -f, --genome-fasta-files <PATH> ..    Only keep alignments to contigs of these
+                                         genome FASTA files.
 
 Thresholds:
    --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
@@ -69,24 +145,31 @@ Thresholds:
                                          bases must be aligned. [default 0.0]
    --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
                                          aligned bases.
-                                         Implies --proper-pairs-only. [default: 0]
+                                         Mates must map to the same contig.
+                                         [default: 0]
    --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
                                          identity e.g. 0.95 for 95%.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
                                          bases e.g. 0.95 means 95% of the read's
                                          bases must be aligned.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are kept and percent identity
+                                         is gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
 
 Other:
-   -t, --threads <INT>                   Number of threads for output compression
-                                         [default 1]
+   -t, --threads <INT>                   Number of threads for mapping and output
+                                         compression [default 1]
    --inverse                             Only keep reads which are unmapped or
                                          align below thresholds. Note that output
                                          records may still be marked as mapped
                                          if they do not meet the thresholds.
-                                         [default false]
+                                         Requires --bam-files. [default false]
    --verbose                             Print extra debugging information
    -q, --quiet                           Unless there is an error, do not print
                                          log messages
@@ -95,7 +178,16 @@ Example usage:
 
   coverm filter -b in.bam -o out.bam --min-read-aligned-length 75
 
-Ben J. Woodcroft <benjwoodcroft near gmail.com>"
+  coverm filter -b in.bam --output-fastq out.1.fq.gz --output-fastq-2
+    out.2.fq.gz --min-read-percent-identity 0.95 -f genome1.fna genome2.fna
+
+  coverm filter -r assembly.fna -1 read1.fq.gz -2 read2.fq.gz -o out.bam
+    --min-read-percent-identity 0.95 --threads 16
+
+Ben J. Woodcroft <benjwoodcroft near gmail.com>
+", MAPPER_HELP);
+    }
+    &FILTER_FULL_HELP
 }
 
 fn contig_full_help() -> &'static str {
@@ -109,7 +201,24 @@ Define mapping(s) (required):
                                          reference sorted (e.g. with samtools sort)
                                          unless --sharded is specified, in which
                                          case they must be read name sorted (e.g.
-                                         with samtools sort -n).
+                                         with samtools sort -n) or all coordinate
+                                         sorted.
+   --sample-sheet <PATH>                 Sample sheet as described below, with
+                                         read format 'bam' and a coordinate
+                                         sorted BAM file as read1. BAM files on
+                                         lines with the same sample name (e.g.
+                                         sequencing runs) are merged into one
+                                         sample.
+
+  Or define per-base depth:
+   --depth-files <PATH> ..               Path to per-base depth file(s) from
+                                         'samtools depth -aa' (of a single BAM
+                                         file each) or mosdepth (.per-base.bed.gz),
+                                         instead of BAM files. Methods requiring
+                                         read counts (count, rpkm, reads_per_base)
+                                         and alignment filtering are unavailable,
+                                         and relative_abundance does not account
+                                         for unmapped reads.
 
   Or do mapping:
    -r, --reference <PATH> ..             FASTA file of contigs e.g. concatenated 
@@ -129,6 +238,15 @@ Define mapping(s) (required):
                                          <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
    --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
    --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
 {}
    --minimap2-params PARAMS              Extra parameters to provide to minimap2,
                                          both indexing command (if used) and for
@@ -142,17 +260,47 @@ Define mapping(s) (required):
                                          that usage of this parameter has security
                                          implications if untrusted input is specified.
                                          [default \"\"]
+   --host-reference <PATH>               FASTA file of a host or contaminant
+                                         genome (or BWA index stem with -p
+                                         bwa-mem). Reads are mapped to it first,
+                                         and reads (or pairs) mapping to it are
+                                         removed before mapping to the reference,
+                                         so they are not counted as mapped or
+                                         unmapped. The number of reads removed is
+                                         logged for each sample.
 
 Sharding i.e. multiple reference sets (optional):
    --sharded                             If -b/--bam-files was used:
-                                           Input BAM files are read-sorted alignments
-                                           of a set of reads mapped to multiple
-                                           reference contig sets. Choose the best
-                                           hit for each read pair.
+                                           Input BAM files are alignments of a set
+                                           of reads mapped to multiple reference
+                                           contig sets, all sorted by read name or
+                                           all coordinate sorted (as declared in
+                                           their headers). Choose the best hit for
+                                           each read pair.
 
                                          Otherwise if mapping was carried out:
                                            Map reads to each reference, choosing the
                                            best hit for each pair.
+   --deshard-by <METHOD>                 How to choose the best hit for each read
+                                         (pair): 'alignment-score' (highest AS
+                                         tag), 'edit-distance' (lowest NM tag) or
+                                         'percent-identity'.
+                                         [default: alignment-score]
+   --discard-deshard-ties                Discard reads whose best hit is tied
+                                         between shards, rather than choosing one
+                                         of the tied shards at random.
+
+Excluding contigs (optional):
+   --exclude-list <FILE>                 Exclude contigs whose name appears in
+                                         this newline-separated file e.g. host
+                                         or PhiX contigs.
+   --exclude-regex <REGEX>               Exclude contigs whose name matches this
+                                         regular expression.
+   --excluded-reads <TREATMENT>          What happens to reads mapped to excluded
+                                         contigs: 'unmapped' to count them as
+                                         unmapped, or 'remove' to remove them
+                                         from all read counts.
+                                         [default: unmapped]
 
 Alignment filtering (optional):
    --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
@@ -164,15 +312,33 @@ Alignment filtering (optional):
                                          bases must be aligned. [default 0.0]
    --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
                                          aligned bases.
-                                         Implies --proper-pairs-only. [default: 0]
+                                         Mates must map to the same contig.
+                                         [default: 0]
    --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
                                          identity e.g. 0.95 for 95%.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
                                          bases e.g. 0.95 means 95% of the read's
                                          bases must be aligned.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are included, each read is
+                                         counted once, and bpkm is available in
+                                         place of rpkm. Percent identity is
+                                         gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
+
+MetaBAT adjusted coverage (optional, with -m metabat):
+   Options of MetaBAT's jgi_summarize_bam_contig_depths correspond to:
+     --percentIdentity <INT>             --min-read-percent-identity
+                                         [default in metabat mode: 97]
+     --includeEdgeBases                  --contig-end-exclusion 0
+     --maxEdgeBases <INT>                --contig-end-exclusion <INT>
+     --noIntraDepthVariance              --no-intra-depth-variance
+   --no-intra-depth-variance             Omit the variance column of each sample.
 
 Other arguments (optional):
    -m, --methods <METHOD> [METHOD ..]    Method(s) for calculating coverage.
@@ -188,13 +354,41 @@ Other arguments (optional):
                                            metabat (\"MetaBAT adjusted coverage\")
                                            reads_per_base
                                            rpkm
+                                           bpkm (long reads, bases per kilobase
+                                             per million aligned bases)
+                                           snv_count (positions differing from
+                                             the reference, see --snv-reference)
+                                           snv_density (SNVs per kb)
+                                           nucleotide_diversity (mean pi)
+                                           major_allele_frequency
                                          A more thorough description of the different
                                          methods is available at
                                          https://github.com/wwood/CoverM
 
    --output-format FORMAT                Shape of output: 'sparse' for long format,
-                                         'dense' for species-by-site.
+                                         'dense' for species-by-site, or the
+                                         coverage input of a binner:
+                                           metabat (same as -m metabat)
+                                           concoct
+                                           vamb (for --abundance_tsv)
+                                           maxbin2 (for -abund)
+                                           semibin (for --abundance)
+                                         Binner formats other than metabat
+                                         report mean coverage. The maxbin2 and
+                                         semibin formats write one file per
+                                         sample to --output-directory.
+                                         Alternatively, 'pileup' for counts of
+                                         each base, deletions and insertions at
+                                         each position of each contig.
                                          [default: dense]
+   --output-directory <DIR>              Directory for per-sample abundance files
+                                         of the maxbin2 and semibin formats.
+   --pileup-reference <FASTA>            Reference sequences of the contigs, to
+                                         report reference bases in pileup output.
+                                         Required for --output-format pileup.
+   --pileup-min-coverage <FLOAT>         Only output the pileup of contigs with
+                                         at least this mean depth in a sample
+                                         [default: 0]
    --min-covered-fraction FRACTION       Contigs with less coverage than this
                                          reported as having zero coverage.
                                          [default: 0]
@@ -205,11 +399,59 @@ Other arguments (optional):
                                          [default: 0.05]
    --trim-max FRACTION                   Maximum fraction for trimmed_mean
                                          calculations [default: 0.95]
+   --allele-min-depth <INT>              Only use positions with at least this
+                                         many aligned bases for snv_count,
+                                         snv_density, nucleotide_diversity and
+                                         major_allele_frequency. [default: 5]
+   --snv-min-allele-frequency <FLOAT>    Count positions as SNVs when an allele
+                                         other than the reference base has at
+                                         least this frequency, and is seen in
+                                         at least 2 reads. [default: 0.05]
+   --snv-reference <FASTA> ..            Reference sequences of the contigs,
+                                         which SNVs are called against.
+                                         Required for snv_count and
+                                         snv_density.
    --no-zeros                            Omit printing of genomes that have zero
                                          coverage
+   --sample-names <NAME> ..              Name each sample, in the order of -b,
+                                         --depth-files, or -1/-2, -c,
+                                         --interleaved then --single read sets,
+                                         instead of by its file name.
+   --sample-name-template <TEMPLATE>     Name samples by this template, where
+                                         {{reference}} is the reference file name
+                                         (empty when no reference is used),
+                                         {{file}} is the read1, BAM or depth file
+                                         name, and {{sample}} is the sample name,
+                                         or the file name without read direction
+                                         (e.g. _R1) or extensions. For instance
+                                         '{{sample}}' drops the reference prefix.
+                                         [default: {{reference}}/{{file}} when
+                                         mapping, otherwise {{file}}]
    --bam-file-cache-directory            Output BAM files generated during
-                                         alignment to this directory
+                                         alignment to this directory. A manifest
+                                         of the inputs and mapping parameters
+                                         is stored alongside each BAM file, so
+                                         that it is reused by later runs with
+                                         unchanged inputs. BAM files cached from
+                                         the same reference and reads with other
+                                         inputs or parameters are removed.
    --discard-unmapped                    Exclude unmapped reads from cached BAM files.
+   --checkpoint-directory <DIR>          Write the results of each sample to this
+                                         directory as it is completed.
+   --resume                              Reuse results in the checkpoint directory
+                                         from samples already completed with the
+                                         same parameters and unchanged input
+                                         files, rather than calculating them
+                                         again.
+   --seed <INT>                          Seed for all random choices (e.g. between
+                                         tied shards), so that reruns give
+                                         identical results. The seed used is
+                                         logged. [default: random]
+   --provenance-header                   Print lines starting with '#' before the
+                                         output table recording the CoverM
+                                         version, command line, mapper version,
+                                         read filters, genome definition and the
+                                         input files of each sample.
    -v, --verbose                         Print extra debugging information
    -q, --quiet                           Unless there is an error, do not print
                                          log messages
@@ -246,7 +488,24 @@ Define mapping(s) (required):
                                          reference sorted (e.g. with samtools sort)
                                          unless --sharded is specified, in which
                                          case they must be read name sorted (e.g.
-                                         with samtools sort -n).
+                                         with samtools sort -n) or all coordinate
+                                         sorted.
+   --sample-sheet <PATH>                 Sample sheet as described below, with
+                                         read format 'bam' and a coordinate
+                                         sorted BAM file as read1. BAM files on
+                                         lines with the same sample name (e.g.
+                                         sequencing runs) are merged into one
+                                         sample.
+
+  Or define per-base depth:
+   --depth-files <PATH> ..               Path to per-base depth file(s) from
+                                         'samtools depth -aa' (of a single BAM
+                                         file each) or mosdepth (.per-base.bed.gz),
+                                         instead of BAM files. Methods requiring
+                                         read counts (count, rpkm, reads_per_base)
+                                         and alignment filtering are unavailable,
+                                         and relative_abundance does not account
+                                         for unmapped reads.
 
   Or do mapping:
 {}
@@ -267,6 +526,15 @@ Define mapping(s) (required):
                                          <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
    --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
    --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
    --minimap2-params PARAMS              Extra parameters to provide to minimap2,
                                          both indexing command (if used) and for
                                          mapping. Note that usage of this parameter
@@ -279,21 +547,53 @@ Define mapping(s) (required):
                                          that usage of this parameter has security
                                          implications if untrusted input is specified.
                                          [default \"\"]
+   --host-reference <PATH>               FASTA file of a host or contaminant
+                                         genome (or BWA index stem with -p
+                                         bwa-mem). Reads are mapped to it first,
+                                         and reads (or pairs) mapping to it are
+                                         removed before mapping to the reference,
+                                         so they are not counted as mapped or
+                                         unmapped. The number of reads removed is
+                                         logged for each sample.
 
 Sharding i.e. multiple reference sets (optional):
    --sharded                             If -b/--bam-files was used:
-                                           Input BAM files are read-sorted alignments
-                                           of a set of reads mapped to multiple
-                                           reference contig sets. Choose the best
-                                           hit for each read pair.
+                                           Input BAM files are alignments of a set
+                                           of reads mapped to multiple reference
+                                           contig sets, all sorted by read name or
+                                           all coordinate sorted (as declared in
+                                           their headers). Choose the best hit for
+                                           each read pair.
 
                                          Otherwise if mapping was carried out:
                                            Map reads to each reference, choosing the
                                            best hit for each pair.
+   --deshard-by <METHOD>                 How to choose the best hit for each read
+                                         (pair): 'alignment-score' (highest AS
+                                         tag), 'edit-distance' (lowest NM tag) or
+                                         'percent-identity'.
+                                         [default: alignment-score]
+   --discard-deshard-ties                Discard reads whose best hit is tied
+                                         between shards, rather than choosing one
+                                         of the tied shards at random.
    --exclude-genomes-from-deshard <FILE> Ignore genomes whose name appears in this
                                          newline-separated file when combining shards.
 
 
+Excluding genomes and contigs (optional):
+   --exclude-list <FILE>                 Exclude genomes or contigs whose name
+                                         appears in this newline-separated file
+                                         e.g. host or PhiX genomes.
+   --exclude-regex <REGEX>               Exclude genomes or contigs whose name
+                                         matches this regular expression.
+   --excluded-reads <TREATMENT>          What happens to reads mapped to excluded
+                                         genomes or contigs: 'unmapped' to count
+                                         them as unmapped e.g. as part of the
+                                         unmapped fraction in relative
+                                         abundance, or 'remove' to remove them
+                                         from all read counts.
+                                         [default: unmapped]
+
 Alignment filtering (optional):
    --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
                                          aligned bases [default: 0]
@@ -304,15 +604,24 @@ Alignment filtering (optional):
                                          bases must be aligned. [default 0.0]
    --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
                                          aligned bases.
-                                         Implies --proper-pairs-only. [default: 0]
+                                         Mates must map to the same genome.
+                                         [default: 0]
    --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
                                          identity e.g. 0.95 for 95%.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same genome.
+                                         [default 0.0]
    --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
                                          bases e.g. 0.95 means 95% of the read's
                                          bases must be aligned.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same genome.
+                                         [default 0.0]
    --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are included, each read is
+                                         counted once, and bpkm is available in
+                                         place of rpkm. Percent identity is
+                                         gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
 
 Other arguments (optional):
    -m, --methods <METHOD> [METHOD ..]    Method(s) for calculating coverage.
@@ -328,6 +637,13 @@ Other arguments (optional):
                                               count
                                               reads_per_base
                                               rpkm
+                                              bpkm (long reads, bases per kilobase
+                                                per million aligned bases)
+                                              snv_count (positions differing from
+                                                the reference, see --snv-reference)
+                                              snv_density (SNVs per kb)
+                                              nucleotide_diversity (mean pi)
+                                              major_allele_frequency
                                          A more thorough description of the different
                                          methods is available at
                                          https://github.com/wwood/CoverM
@@ -345,11 +661,59 @@ Other arguments (optional):
                                          [default: 0.05]
    --trim-max FRACTION                   Maximum fraction for trimmed_mean
                                          calculations [default: 0.95]
+   --allele-min-depth <INT>              Only use positions with at least this
+                                         many aligned bases for snv_count,
+                                         snv_density, nucleotide_diversity and
+                                         major_allele_frequency. [default: 5]
+   --snv-min-allele-frequency <FLOAT>    Count positions as SNVs when an allele
+                                         other than the reference base has at
+                                         least this frequency, and is seen in
+                                         at least 2 reads. [default: 0.05]
+   --snv-reference <FASTA> ..            Reference sequences of the contigs,
+                                         which SNVs are called against.
+                                         Required for snv_count and
+                                         snv_density.
    --no-zeros                            Omit printing of genomes that have zero
                                          coverage
+   --sample-names <NAME> ..              Name each sample, in the order of -b,
+                                         --depth-files, or -1/-2, -c,
+                                         --interleaved then --single read sets,
+                                         instead of by its file name.
+   --sample-name-template <TEMPLATE>     Name samples by this template, where
+                                         {{reference}} is the reference file name
+                                         (empty when no reference is used),
+                                         {{file}} is the read1, BAM or depth file
+                                         name, and {{sample}} is the sample name,
+                                         or the file name without read direction
+                                         (e.g. _R1) or extensions. For instance
+                                         '{{sample}}' drops the reference prefix.
+                                         [default: {{reference}}/{{file}} when
+                                         mapping, otherwise {{file}}]
    --bam-file-cache-directory            Output BAM files generated during
-                                         alignment to this directory
+                                         alignment to this directory. A manifest
+                                         of the inputs and mapping parameters
+                                         is stored alongside each BAM file, so
+                                         that it is reused by later runs with
+                                         unchanged inputs. BAM files cached from
+                                         the same reference and reads with other
+                                         inputs or parameters are removed.
    --discard-unmapped                    Exclude unmapped reads from cached BAM files.
+   --checkpoint-directory <DIR>          Write the results of each sample to this
+                                         directory as it is completed.
+   --resume                              Reuse results in the checkpoint directory
+                                         from samples already completed with the
+                                         same parameters and unchanged input
+                                         files, rather than calculating them
+                                         again.
+   --seed <INT>                          Seed for all random choices (e.g. between
+                                         tied shards), so that reruns give
+                                         identical results. The seed used is
+                                         logged. [default: random]
+   --provenance-header                   Print lines starting with '#' before the
+                                         output table recording the CoverM
+                                         version, command line, mapper version,
+                                         read filters, genome definition and the
+                                         input files of each sample.
    -v, --verbose                         Print extra debugging information
    -q, --quiet                           Unless there is an error, do not print
                                          log messages
@@ -374,14 +738,18 @@ fn main() {
                 process::exit(1);
             }
             set_log_level(m, true);
+            let seed = set_random_seed(m);
+            let stoit_naming = setup_stoit_naming(m);
+            let sample_sheet = parse_sample_sheet(m);
 
             let genome_names_content: Vec<u8>;
 
             let mut estimators_and_taker = EstimatorsAndTaker::generate_from_clap(m, print_stream);
+            let filter_params = FilterParameters::generate_from_clap(m);
+            print_provenance_header(m, "genome", &filter_params, seed, &sample_sheet);
             estimators_and_taker =
                 estimators_and_taker.print_headers(&"Genome", &mut std::io::stdout());
-            let filter_params = FilterParameters::generate_from_clap(m);
-            let separator = parse_separator(m);
+            let separator = parse_separator(m, &sample_sheet);
 
             let single_genome = m.is_present("single-genome");
             let genomes_and_contigs_option = match separator.is_some() || single_genome {
@@ -471,7 +839,32 @@ fn main() {
                 }
             };
 
-            if m.is_present("bam-files") {
+            if let Some(bam_samples) = sample_sheet_bam_samples(&sample_sheet) {
+                if filter_params.doing_filtering() {
+                    run_genome(
+                        get_merged_filtered_bam_readers(
+                            m,
+                            bam_samples,
+                            &filter_params,
+                            &parse_mate_genomes(
+                                single_genome, separator, &genomes_and_contigs_option),
+                            &stoit_naming,
+                        ),
+                        m,
+                        &mut estimators_and_taker,
+                        separator,
+                        &genomes_and_contigs_option,
+                    );
+                } else {
+                    run_genome(
+                        get_merged_bam_readers(m, bam_samples, &stoit_naming),
+                        m,
+                        &mut estimators_and_taker,
+                        separator,
+                        &genomes_and_contigs_option,
+                    );
+                }
+            } else if m.is_present("bam-files") {
                 let bam_files: Vec<&str> = m.values_of("bam-files").unwrap().collect();
                 if filter_params.doing_filtering() {
                     run_genome(
@@ -484,6 +877,9 @@ fn main() {
                             filter_params.min_aligned_length_pair,
                             filter_params.min_percent_identity_pair,
                             filter_params.min_aligned_percent_pair,
+                            &parse_mate_genomes(
+                                single_genome, separator, &genomes_and_contigs_option),
+                            &stoit_naming,
                         ),
                         m,
                         &mut estimators_and_taker,
@@ -491,57 +887,62 @@ fn main() {
                         &genomes_and_contigs_option,
                     );
                 } else if m.is_present("sharded") {
-                    external_command_checker::check_for_samtools();
-                    let sort_threads = m.value_of("threads").unwrap().parse::<i32>().unwrap();
                     // Seems crazy, but I cannot work out how to make this more
                     // DRY, without making GenomeExclusion into an enum.
                     match genome_exclusion_type {
                         GenomeExclusionTypes::NoneType => {
-                            run_genome(
-                                coverm::shard_bam_reader::generate_sharded_bam_reader_from_bam_files(
-                                    bam_files,
-                                    sort_threads,
-                                    &genome_exclusion_filter_non_type.unwrap()),
+                            run_genome_from_sharded_bam_files(
+                                bam_files,
+                                &genome_exclusion_filter_non_type.unwrap(),
                                 m,
                                 &mut estimators_and_taker,
                                 separator,
-                                &genomes_and_contigs_option);
+                                &genomes_and_contigs_option,
+                                &stoit_naming);
                         }
                         GenomeExclusionTypes::SeparatorType => {
-                            run_genome(
-                                coverm::shard_bam_reader::generate_sharded_bam_reader_from_bam_files(
-                                    bam_files,
-                                    sort_threads,
-                                    &genome_exclusion_filter_separator_type.unwrap()),
+                            run_genome_from_sharded_bam_files(
+                                bam_files,
+                                &genome_exclusion_filter_separator_type.unwrap(),
                                 m,
                                 &mut estimators_and_taker,
                                 separator,
-                                &genomes_and_contigs_option);
+                                &genomes_and_contigs_option,
+                                &stoit_naming);
                         }
                         GenomeExclusionTypes::GenomesAndContigsType => {
-                            run_genome(
-                                coverm::shard_bam_reader::generate_sharded_bam_reader_from_bam_files(
-                                    bam_files,
-                                    sort_threads,
-                                    &genome_exclusion_genomes_and_contigs.unwrap()),
+                            run_genome_from_sharded_bam_files(
+                                bam_files,
+                                &genome_exclusion_genomes_and_contigs.unwrap(),
                                 m,
                                 &mut estimators_and_taker,
                                 separator,
-                                &genomes_and_contigs_option);
+                                &genomes_and_contigs_option,
+                                &stoit_naming);
                         }
                     }
                 } else {
                     run_genome(
-                        coverm::bam_generator::generate_named_bam_readers_from_bam_files(bam_files),
+                        coverm::bam_generator::generate_named_bam_readers_from_bam_files(
+                            bam_files, &stoit_naming),
                         m,
                         &mut estimators_and_taker,
                         separator,
                         &genomes_and_contigs_option,
                     );
                 }
+            } else if m.is_present("depth-files") {
+                run_genome_from_depth_files(
+                    m,
+                    &mut estimators_and_taker,
+                    separator,
+                    &genomes_and_contigs_option,
+                    &stoit_naming,
+                );
             } else {
                 let mapping_program = parse_mapping_program(&m);
                 external_command_checker::check_for_samtools();
+                let host_depletion = setup_host_depletion(m, mapping_program);
 
                 // Generate a temporary file of concatenated genomes if needed.
                 let mut concatenated_genomes: Option<NamedTempFile> = None;
@@ -564,7 +965,12 @@ fn main() {
                         m,
                         mapping_program,
                         &concatenated_genomes,
+                        &sample_sheet,
                         &filter_params,
+                        &parse_mate_genomes(
+                            single_genome, separator, &genomes_and_contigs_option),
+                        host_depletion.as_ref(),
+                        &stoit_naming,
                     );
                     let mut all_generators = vec![];
                     let mut indices = vec![]; // Prevent indices from being dropped
@@ -589,7 +995,9 @@ fn main() {
                                     m,
                                     mapping_program,
                                     &concatenated_genomes,
+                                    &sample_sheet,
                                     &genome_exclusion_filter_non_type.unwrap(),
+                                    &stoit_naming,
                                 ),
                                 m,
                                 &mut estimators_and_taker,
@@ -603,7 +1011,9 @@ fn main() {
                                     m,
                                     mapping_program,
                                     &concatenated_genomes,
+                                    &sample_sheet,
                                     &genome_exclusion_filter_separator_type.unwrap(),
+                                    &stoit_naming,
                                 ),
                                 m,
                                 &mut estimators_and_taker,
@@ -617,7 +1027,9 @@ fn main() {
                                     m,
                                     mapping_program,
                                     &concatenated_genomes,
+                                    &sample_sheet,
                                     &genome_exclusion_genomes_and_contigs.unwrap(),
+                                    &stoit_naming,
                                 ),
                                 m,
                                 &mut estimators_and_taker,
@@ -628,7 +1040,14 @@ fn main() {
                     }
                 } else {
                     let generator_sets =
-                        get_streamed_bam_readers(m, mapping_program, &concatenated_genomes);
+                        get_streamed_bam_readers(
+                            m,
+                            mapping_program,
+                            &concatenated_genomes,
+                            &sample_sheet,
+                            host_depletion.as_ref(),
+                            &stoit_naming,
+                        );
                     let mut all_generators = vec![];
                     let mut indices = vec![]; // Prevent indices from being dropped
                     for set in generator_sets {
@@ -655,50 +1074,110 @@ fn main() {
             }
             set_log_level(m, true);
 
-            let bam_files: Vec<&str> = m.values_of("bam-files").unwrap().collect();
-            let output_bam_files: Vec<&str> = m.values_of("output-bam-files").unwrap().collect();
-            if bam_files.len() != output_bam_files.len() {
-                error!("The number of input BAM files must be the same as the number output");
-                process::exit(1);
+            let fastq_output = m.is_present("output-fastq");
+            let output_files: Vec<&str> = match fastq_output {
+                true => m.values_of("output-fastq"),
+                false => m.values_of("output-bam-files"),
+            }.unwrap().collect();
+            let output_fastq2_files: Option<Vec<&str>> =
+                m.values_of("output-fastq-2").map(|v| v.collect());
+            if let Some(ref files) = output_fastq2_files {
+                if files.len() != output_files.len() {
+                    error!("The number of --output-fastq-2 files must be the same as the \
+                            number of --output-fastq files");
+                    process::exit(1);
+                }
             }
 
             let filter_params = FilterParameters::generate_from_clap(m);
+            let restricted_contigs = parse_filter_contig_restriction(m);
 
             let num_threads = value_t!(m.value_of("threads"), u16).unwrap();
+            let filter_output = |i: usize| -> FilterOutputSpec {
+                FilterOutputSpec {
+                    fastq: fastq_output,
+                    output: output_files[i],
+                    output2: output_fastq2_files.as_ref().map(|files| files[i]),
+                    restricted_contigs: restricted_contigs.as_ref(),
+                    threads: num_threads as usize,
+                }
+            };
 
-            for (bam, output) in bam_files.iter().zip(output_bam_files.iter()) {
-                let reader =
-                    bam::Reader::from_path(bam).expect(&format!("Unable to find BAM file {}", bam));
-                let header = bam::header::Header::from_template(reader.header());
-                let mut writer = bam::Writer::from_path(
-                    output,
-                    &header,
-                    rust_htslib::bam::Format::BAM)
-                    .expect(&format!("Failed to write BAM file {}", output));
-                writer
-                    .set_threads(num_threads as usize)
-                    .expect("Failed to set num threads in writer");
-                let mut filtered = filter::ReferenceSortedBamFilter::new(
-                    reader,
-                    filter_params.flag_filters.clone(),
-                    filter_params.min_aligned_length_single,
-                    filter_params.min_percent_identity_single,
-                    filter_params.min_aligned_percent_single,
-                    filter_params.min_aligned_length_pair,
-                    filter_params.min_percent_identity_pair,
-                    filter_params.min_aligned_percent_pair,
-                    !m.is_present("inverse"),
+            let mut num_reads_unwritten: u64 = 0;
+            if m.is_present("bam-files") {
+                let bam_files: Vec<&str> = m.values_of("bam-files").unwrap().collect();
+                if bam_files.len() != output_files.len() {
+                    error!("The number of input BAM files must be the same as the number output");
+                    process::exit(1);
+                }
+                for (i, bam) in bam_files.iter().enumerate() {
+                    let reader = bam::Reader::from_path(bam)
+                        .expect(&format!("Unable to find BAM file {}", bam));
+                    let header = reader.header().clone();
+                    let mut filtered = filter::ReferenceSortedBamFilter::new(
+                        reader,
+                        filter_params.flag_filters.clone(),
+                        filter_params.min_aligned_length_single,
+                        filter_params.min_percent_identity_single,
+                        filter_params.min_aligned_percent_single,
+                        filter_params.min_aligned_length_pair,
+                        filter_params.min_percent_identity_pair,
+                        filter_params.min_aligned_percent_pair,
+                        !m.is_present("inverse"),
+                    );
+                    num_reads_unwritten += write_filtered_records(
+                        &mut |record: &mut bam::Record| filtered
+                            .read(record)
+                            .expect("Failure to read filtered BAM record"),
+                        &header,
+                        &filter_output(i),
+                    );
+                    coverm::bam_generator::log_unresolved_pairs(bam, &filtered);
+                }
+            } else {
+                let mapping_program = parse_mapping_program(&m);
+                external_command_checker::check_for_samtools();
+                let sample_sheet = parse_sample_sheet(m);
+                let generator_sets = get_streamed_filtered_bam_readers(
+                    m,
+                    mapping_program,
+                    &None,
+                    &sample_sheet,
+                    &filter_params,
+                    &MateGenomes::SameReference,
+                    None,
+                    &StoitNaming::default(),
                 );
-
-                let mut record = bam::record::Record::new();
-                while filtered
-                    .read(&mut record)
-                    .expect("Failure to read filtered BAM record") == true {
-                        
-                    debug!("Writing.. {:?}", record.qname());
-                    writer.write(&record).expect("Failed to write BAM record");
+                let num_mappings: usize =
+                    generator_sets.iter().map(|set| set.generators.len()).sum();
+                if num_mappings != output_files.len() {
+                    error!("The number of output files ({}) must be the same as the number of \
+                            read sets multiplied by the number of references ({})",
+                           output_files.len(), num_mappings);
+                    process::exit(1);
+                }
+                let mut i = 0;
+                for generator_set in generator_sets {
+                    for generator in generator_set.generators {
+                        let mut reader = generator.start();
+                        reader.set_threads(num_threads as usize);
+                        let header = reader.header().clone();
+                        num_reads_unwritten += write_filtered_records(
+                            &mut |record: &mut bam::Record| reader
+                                .read(record)
+                                .expect("Failure to read filtered BAM record"),
+                            &header,
+                            &filter_output(i),
+                        );
+                        reader.finish();
+                        i += 1;
+                    }
                 }
             }
+            if num_reads_unwritten > 0 {
+                warn!("{} paired reads were not written to FASTQ because their mate \
+                       did not pass the filter", num_reads_unwritten);
+            }
         }
         Some("contig") => {
             let m = matches.subcommand_matches("contig").unwrap();
@@ -707,16 +1186,50 @@ fn main() {
                 process::exit(1);
             }
             set_log_level(m, true);
+            let seed = set_random_seed(m);
+            let stoit_naming = setup_stoit_naming(m);
+            let sample_sheet = parse_sample_sheet(m);
             let print_zeros = !m.is_present("no-zeros");
             let filter_params = FilterParameters::generate_from_clap(m);
             let threads = m.value_of("threads").unwrap().parse().unwrap();
 
             let mut estimators_and_taker =
                 EstimatorsAndTaker::generate_from_clap(m, &mut print_stream);
-            estimators_and_taker =
-                estimators_and_taker.print_headers(&"Contig", &mut std::io::stdout());
+            print_provenance_header(m, "contig", &filter_params, seed, &sample_sheet);
+            if doing_pileup(m) {
+                coverm::pileup::print_pileup_header(&mut std::io::stdout());
+            } else {
+                estimators_and_taker =
+                    estimators_and_taker.print_headers(&"Contig", &mut std::io::stdout());
+            }
 
-            if m.is_present("bam-files") {
+            if let Some(bam_samples) = sample_sheet_bam_samples(&sample_sheet) {
+                if filter_params.doing_filtering() {
+                    run_contig(
+                        &mut estimators_and_taker,
+                        get_merged_filtered_bam_readers(
+                            m,
+                            bam_samples,
+                            &filter_params,
+                            &MateGenomes::SameReference,
+                            &stoit_naming,
+                        ),
+                        m,
+                        print_zeros,
+                        filter_params.flag_filters,
+                        threads,
+                    );
+                } else {
+                    run_contig(
+                        &mut estimators_and_taker,
+                        get_merged_bam_readers(m, bam_samples, &stoit_naming),
+                        m,
+                        print_zeros,
+                        filter_params.flag_filters,
+                        threads,
+                    );
+                }
+            } else if m.is_present("bam-files") {
                 let bam_files: Vec<&str> = m.values_of("bam-files").unwrap().collect();
                 if filter_params.doing_filtering() {
                     let bam_readers =
@@ -729,44 +1242,75 @@ fn main() {
                             filter_params.min_aligned_length_pair,
                             filter_params.min_percent_identity_pair,
                             filter_params.min_aligned_percent_pair,
+                            &MateGenomes::SameReference,
+                            &stoit_naming,
                         );
                     run_contig(
                         &mut estimators_and_taker,
                         bam_readers,
+                        m,
                         print_zeros,
                         filter_params.flag_filters,
                         threads,
                     );
                 } else if m.is_present("sharded") {
-                    external_command_checker::check_for_samtools();
-                    let sort_threads = m.value_of("threads").unwrap().parse::<i32>().unwrap();
-                    let bam_readers =
-                        coverm::shard_bam_reader::generate_sharded_bam_reader_from_bam_files(
-                            bam_files,
-                            sort_threads,
-                            &NoExclusionGenomeFilter {},
+                    if coverm::shard_bam_reader::shard_bam_files_are_coordinate_sorted(&bam_files) {
+                        info!("Sharded BAM files are coordinate sorted, so choosing the \
+                               best hit for each read in two passes");
+                        let bam_readers = coverm::shard_bam_reader::
+                            generate_coordinate_sorted_sharded_bam_reader_from_bam_files(
+                                bam_files,
+                                &NoExclusionGenomeFilter {},
+                                parse_shard_selection(m),
+                                &stoit_naming,
+                            );
+                        run_contig(
+                            &mut estimators_and_taker,
+                            bam_readers,
+                            m,
+                            print_zeros,
+                            filter_params.flag_filters,
+                            threads,
                         );
-                    run_contig(
-                        &mut estimators_and_taker,
-                        bam_readers,
-                        print_zeros,
-                        filter_params.flag_filters,
-                        threads,
-                    );
+                    } else {
+                        external_command_checker::check_for_samtools();
+                        let sort_threads = m.value_of("threads").unwrap().parse::<i32>().unwrap();
+                        let bam_readers =
+                            coverm::shard_bam_reader::generate_sharded_bam_reader_from_bam_files(
+                                bam_files,
+                                sort_threads,
+                                &NoExclusionGenomeFilter {},
+                                parse_shard_selection(m),
+                                &stoit_naming,
+                            );
+                        run_contig(
+                            &mut estimators_and_taker,
+                            bam_readers,
+                            m,
+                            print_zeros,
+                            filter_params.flag_filters,
+                            threads,
+                        );
+                    }
                 } else {
                     let bam_readers =
-                        coverm::bam_generator::generate_named_bam_readers_from_bam_files(bam_files);
+                        coverm::bam_generator::generate_named_bam_readers_from_bam_files(
+                            bam_files, &stoit_naming);
                     run_contig(
                         &mut estimators_and_taker,
                         bam_readers,
+                        m,
                         print_zeros,
                         filter_params.flag_filters,
                         threads,
                     );
                 }
+            } else if m.is_present("depth-files") {
+                run_contig_from_depth_files(&mut estimators_and_taker, m, print_zeros, &stoit_naming);
             } else {
                 let mapping_program = parse_mapping_program(&m);
                 external_command_checker::check_for_samtools();
+                let host_depletion = setup_host_depletion(m, mapping_program);
 
                 if filter_params.doing_filtering() {
                     debug!("Filtering..");
@@ -774,7 +1318,11 @@ fn main() {
                         m,
                         mapping_program,
                         &None,
+                        &sample_sheet,
                         &filter_params,
+                        &MateGenomes::SameReference,
+                        host_depletion.as_ref(),
+                        &stoit_naming,
                     );
                     let mut all_generators = vec![];
                     let mut indices = vec![]; // Prevent indices from being dropped
@@ -788,6 +1336,7 @@ fn main() {
                     run_contig(
                         &mut estimators_and_taker,
                         all_generators,
+                        m,
                         print_zeros,
                         filter_params.flag_filters,
                         threads,
@@ -797,18 +1346,24 @@ fn main() {
                         m,
                         mapping_program,
                         &None,
+                        &sample_sheet,
                         &NoExclusionGenomeFilter {},
+                        &stoit_naming,
                     );
                     run_contig(
                         &mut estimators_and_taker,
                         generator_sets,
+                        m,
                         print_zeros,
                         filter_params.flag_filters,
                         threads,
                     );
                 } else {
                     debug!("Not filtering..");
-                    let generator_sets = get_streamed_bam_readers(m, mapping_program, &None);
+                    let generator_sets =
+                        get_streamed_bam_readers(
+                            m, mapping_program, &None, &sample_sheet, host_depletion.as_ref(),
+                            &stoit_naming);
                     let mut all_generators = vec![];
                     let mut indices = vec![]; // Prevent indices from being dropped
                     for set in generator_sets {
@@ -820,6 +1375,7 @@ fn main() {
                     run_contig(
                         &mut estimators_and_taker,
                         all_generators,
+                        m,
                         print_zeros,
                         filter_params.flag_filters.clone(),
                         threads,
@@ -836,51 +1392,237 @@ fn main() {
 
             let output_directory = m.value_of("output-directory").unwrap();
             setup_bam_cache_directory(output_directory);
-            let params = MappingParameters::generate_from_clap(&m, mapping_program, &None);
-            let mut generator_sets = vec![];
+            let sample_sheet = parse_sample_sheet(m);
+            // Samples cannot be renamed in make
+            let stoit_naming = StoitNaming::default();
+            let params = MappingParameters::generate_from_clap(
+                &m, mapping_program, &None, &sample_sheet, &stoit_naming);
+            let filter_params = FilterParameters::generate_from_clap(m);
+            let threads = value_t!(m.value_of("threads"), u16).unwrap();
+            let parallel_samples = value_t!(m.value_of("parallel-samples"), u16).unwrap();
+            if parallel_samples == 0 {
+                error!("--parallel-samples must be at least 1");
+                process::exit(1);
+            }
+            // Threads are divided between the samples mapped at once
+            let sample_threads = std::cmp::max(1, threads / parallel_samples);
+            let sample_index: Option<usize> = match m.is_present("sample-index") {
+                true => Some(value_t!(m.value_of("sample-index"), usize).unwrap()),
+                false => None,
+            };
+            let dry_run = m.is_present("dry-run");
+            if dry_run && filter_params.doing_filtering() {
+                error!("--dry-run cannot be used with alignment filtering, since filtering \
+                        is not carried out by an external command");
+                process::exit(1);
+            }
             let discard_unmapped_reads = m.is_present("discard-unmapped");
 
+            let mut indices = vec![]; // Prevent indices from being dropped
+            let mut bam_makers = vec![];
+            let mut filtered_bam_readers = vec![];
+            let mut output_paths = vec![];
+            let mut mapping_number = 0;
+
             for reference_wise_params in params {
-                let mut bam_readers = vec![];
-                let index = setup_mapping_index(&reference_wise_params, &m, mapping_program);
+                let num_samples = reference_wise_params.len();
+                if let Some(i) = sample_index {
+                    if i <= mapping_number || i > mapping_number + num_samples {
+                        mapping_number += num_samples;
+                        continue;
+                    }
+                }
+                // A single sample from --sample-index does not need a minimap2
+                // index to be generated first, and a dry run uses the
+                // reference (or BWA index stem) as it is, since a temporary
+                // index would be removed before the printed commands are run.
+                let index = match (dry_run, sample_index, mapping_program) {
+                    (true, _, MappingProgram::BWA_MEM) => {
+                        if !coverm::mapping_index_maintenance::bwa_index_exists(
+                            reference_wise_params.reference) {
+                            error!("No BWA index of the reference {} was found, which \
+                                    --dry-run requires. Create one with 'bwa index'.",
+                                   reference_wise_params.reference);
+                            process::exit(1);
+                        }
+                        None
+                    },
+                    (true, _, _) => None,
+                    (false, Some(_), MappingProgram::BWA_MEM) | (false, None, _) =>
+                        setup_mapping_index(&reference_wise_params, &m, mapping_program),
+                    (false, Some(_), _) => None,
+                };
                 let ref_string = reference_wise_params.reference;
 
                 for p in reference_wise_params {
-                    bam_readers.push(
-                        coverm::bam_generator::generate_bam_maker_generator_from_reads(
-                            mapping_program,
-                            match index {
-                                Some(ref index) => index.index_path(),
-                                None => ref_string,
-                            },
-                            p.read1,
-                            p.read2,
-                            p.read_format.clone(),
-                            p.threads,
-                            &generate_cached_bam_file_name(output_directory, p.reference, p.read1),
-                            discard_unmapped_reads,
-                            p.mapping_options,
-                        ),
+                    mapping_number += 1;
+                    if sample_index.is_some() && sample_index != Some(mapping_number) {
+                        continue;
+                    }
+                    let output_path = generate_cached_bam_file_name(
+                        output_directory,
+                        p.reference,
+                        p.naming_readset(),
+                        None,
                     );
+                    let index_path = match index {
+                        Some(ref index) => index.index_path(),
+                        None => ref_string,
+                    };
+                    if filter_params.doing_filtering() {
+                        // A filtered BAM file must not be reused as a cache of
+                        // all the reads.
+                        BamCacheManifest::invalidate(&output_path);
+                        filtered_bam_readers.push((
+                            mapping_number,
+                            coverm::bam_generator::generate_filtered_named_bam_readers_from_reads(
+                                mapping_program,
+                                index_path,
+                                &p.read1,
+                                &p.read2,
+                                p.sample_name,
+                                &stoit_naming,
+                                p.read_format.clone(),
+                                sample_threads,
+                                None,
+                                filter_params.flag_filters.clone(),
+                                filter_params.min_aligned_length_single,
+                                filter_params.min_percent_identity_single,
+                                filter_params.min_aligned_percent_single,
+                                filter_params.min_aligned_length_pair,
+                                filter_params.min_percent_identity_pair,
+                                filter_params.min_aligned_percent_pair,
+                                MateGenomes::SameReference,
+                                p.mapping_options,
+                                discard_unmapped_reads,
+                                true,
+                                None,
+                            ),
+                            output_path.clone(),
+                        ));
+                    } else {
+                        bam_makers.push((
+                            mapping_number,
+                            coverm::bam_generator::generate_bam_maker_generator_from_reads(
+                                mapping_program,
+                                index_path,
+                                &p.read1,
+                                &p.read2,
+                                p.sample_name,
+                                &stoit_naming,
+                                p.read_format.clone(),
+                                sample_threads,
+                                CachedBamFile {
+                                    path: output_path.clone(),
+                                    manifest: generate_bam_cache_manifest(
+                                        m,
+                                        mapping_program,
+                                        &None,
+                                        p.reference,
+                                        &p,
+                                        discard_unmapped_reads,
+                                    ),
+                                },
+                                discard_unmapped_reads,
+                                p.mapping_options,
+                            ),
+                        ));
+                    }
+                    output_paths.push(output_path);
                 }
-
+                indices.push(index);
                 debug!("Finished BAM setup");
-                let to_return = BamGeneratorSet {
-                    generators: bam_readers,
-                    index: index,
-                };
-                generator_sets.push(to_return);
+            }
+            if let Some(i) = sample_index {
+                if i == 0 || i > mapping_number {
+                    error!("--sample-index must be between 1 and the number of mappings ({})",
+                           mapping_number);
+                    process::exit(1);
+                }
+            }
+
+            if dry_run {
+                for ((i, generator), output_path) in bam_makers.iter().zip(output_paths.iter()) {
+                    println!("# Mapping number {}, generating {}", i, output_path);
+                    for command in generator.command_strings() {
+                        println!("{}", command);
+                    }
+                }
+                return;
             }
 
-            let mut i = 1;
-            for generator_set in generator_sets {
-                for generator in generator_set.generators {
-                    info!("Running mapping number {} ..", i);
-                    generator.start().finish();
-                    i += 1;
+            // Mapping runs in external processes, so several samples can run
+            // at once, finishing the oldest before starting another.
+            let mut running = std::collections::VecDeque::new();
+            for (i, generator) in bam_makers {
+                if running.len() == parallel_samples as usize {
+                    let maker: coverm::bam_generator::NamedBamMaker =
+                        running.pop_front().unwrap();
+                    maker.finish();
                 }
+                info!("Running mapping number {} ..", i);
+                running.push_back(generator.start());
+            }
+            for maker in running {
+                maker.finish();
+            }
+
+            if filtered_bam_readers.len() > 1 && parallel_samples > 1 {
+                warn!("Samples are mapped one at a time when filtering alignments, since \
+                       filtering is carried out by CoverM itself");
+            }
+            for (i, generator, output_path) in filtered_bam_readers {
+                info!("Running mapping and filtering number {} ..", i);
+                // Filtered records are not in position order, so are sorted
+                // afterwards.
+                let unsorted = tempfile::Builder::new()
+                    .prefix("coverm-make-unsorted")
+                    .suffix(".bam")
+                    .tempfile_in(output_directory)
+                    .expect("Failed to create unsorted BAM tempfile");
+                let unsorted_path = unsorted.path().to_str()
+                    .expect("Failed to convert tempfile path to str");
+                let mut reader = generator.start();
+                reader.set_threads(sample_threads as usize);
+                let header = reader.header().clone();
+                write_filtered_records(
+                    &mut |record: &mut bam::Record| reader
+                        .read(record)
+                        .expect("Failure to read filtered BAM record"),
+                    &header,
+                    &FilterOutputSpec {
+                        fastq: false,
+                        output: unsorted_path,
+                        output2: None,
+                        restricted_contigs: None,
+                        threads: sample_threads as usize,
+                    },
+                );
+                reader.finish();
+                sort_bam_file(unsorted_path, &output_path, sample_threads);
+            }
+
+            for path in output_paths {
+                info!("Indexing {} ..", path);
+                bam::index::build(&path, None, bam::index::Type::BAI, threads as u32)
+                    .expect(&format!("Failed to index BAM file {}", path));
             }
         }
+        Some("merge") => {
+            let m = matches.subcommand_matches("merge").unwrap();
+            set_log_level(m, true);
+
+            let tables = m
+                .values_of("input-files")
+                .unwrap()
+                .map(|path| coverm::coverage_merger::read_coverage_table(path))
+                .collect();
+            coverm::coverage_merger::merge_coverage_tables(
+                tables,
+                m.value_of("output-format").unwrap(),
+                &mut std::io::stdout(),
+            );
+        }
         Some("shell-completion") => {
             let m = matches.subcommand_matches("shell-completion").unwrap();
             set_log_level(m, true);
@@ -935,6 +1677,36 @@ fn setup_mapping_index(
     }
 }
 
+/// Index the --host-reference if specified, so that reads can be mapped to it
+/// before the main reference.
+fn setup_host_depletion(
+    m: &clap::ArgMatches,
+    mapping_program: MappingProgram,
+) -> Option<HostDepletion> {
+    m.value_of("host-reference").map(|host_reference| {
+        info!("Removing reads which map to the host reference {}", host_reference);
+        HostDepletion {
+            mapping_program: mapping_program,
+            index: match mapping_program {
+                MappingProgram::BWA_MEM => {
+                    coverm::mapping_index_maintenance::generate_bwa_index(host_reference, None)
+                }
+                MappingProgram::MINIMAP2_SR |
+                MappingProgram::MINIMAP2_ONT |
+                MappingProgram::MINIMAP2_PB |
+                MappingProgram::MINIMAP2_NO_PRESET => {
+                    coverm::mapping_index_maintenance::generate_minimap2_index(
+                        host_reference,
+                        Some(m.value_of("threads").unwrap().parse::<usize>().unwrap()),
+                        None,
+                        mapping_program,
+                    )
+                }
+            },
+        }
+    })
+}
+
 fn parse_mapping_program(m: &clap::ArgMatches) -> MappingProgram {
     let mapping_program = match m.value_of("mapper") {
         Some("bwa-mem") => MappingProgram::BWA_MEM,
@@ -1004,21 +1776,84 @@ impl<'a> EstimatorsAndTaker<'a> {
         let printer;
         let mut rpkm_column = None;
 
+        if m.is_present("no-intra-depth-variance") && !doing_metabat(&m) {
+            error!("--no-intra-depth-variance can only be used with MetaBAT adjusted coverage");
+            process::exit(1);
+        }
+
+        if !doing_pileup(&m) && m.is_present("pileup-reference") {
+            error!("--pileup-reference can only be used with --output-format pileup");
+            process::exit(1);
+        }
+
+        let snv_reference = parse_snv_reference(&m, &methods);
+
         if doing_metabat(&m) {
+            let include_variance = !m.is_present("no-intra-depth-variance");
+            // Calculated in the order the printer expects, see
+            // METABAT_LENGTH_INDEX etc.
             estimators.push(CoverageEstimator::new_estimator_length());
             estimators.push(CoverageEstimator::new_estimator_mean(
                 min_fraction_covered,
                 contig_end_exclusion,
                 false,
             ));
-            estimators.push(CoverageEstimator::new_estimator_variance(
+            if include_variance {
+                estimators.push(CoverageEstimator::new_estimator_variance(
+                    min_fraction_covered,
+                    contig_end_exclusion,
+                ));
+            }
+
+            debug!("Cached regular coverage taker for metabat mode being used");
+            taker = CoverageTakerType::new_cached_single_float_coverage_taker(estimators.len());
+            printer = CoveragePrinter::MetabatAdjustedCoveragePrinter {
+                include_variance: include_variance,
+            };
+        } else if let Some(format) = BinnerFormat::from_name(output_format) {
+            if m.occurrences_of("methods") > 0 && methods != vec!["mean"] {
+                error!(
+                    "The {} output format reports mean coverage, so cannot be \
+                     used with other coverage methods",
+                    output_format
+                );
+                process::exit(1);
+            }
+            estimators.push(CoverageEstimator::new_estimator_mean(
                 min_fraction_covered,
                 contig_end_exclusion,
+                false,
             ));
 
-            debug!("Cached regular coverage taker for metabat mode being used");
+            debug!("Cached regular coverage taker for {} output being used", output_format);
             taker = CoverageTakerType::new_cached_single_float_coverage_taker(estimators.len());
-            printer = CoveragePrinter::MetabatAdjustedCoveragePrinter;
+            printer = CoveragePrinter::BinnerCoveragePrinter {
+                format: format,
+                output_directory: parse_binner_output_directory(m, format),
+            };
+        } else if doing_pileup(&m) {
+            if m.occurrences_of("methods") > 0 {
+                error!("The pileup output format reports base counts, so cannot be \
+                        used with coverage methods");
+                process::exit(1);
+            }
+            if !m.is_present("pileup-reference") {
+                error!("--pileup-reference must be specified for --output-format pileup");
+                process::exit(1);
+            }
+            if m.is_present("depth-files") {
+                error!("The pileup output format requires the bases of aligned reads, \
+                        so cannot be used with depth files");
+                process::exit(1);
+            }
+            if m.is_present("checkpoint-directory") {
+                error!("The pileup output format cannot be used with --checkpoint-directory");
+                process::exit(1);
+            }
+            // Base counts are printed as each sample is read, so no
+            // coverage is taken, see run_pileup.
+            taker = CoverageTakerType::new_cached_single_float_coverage_taker(0);
+            printer = CoveragePrinter::StreamedCoveragePrinter;
         } else {
             for (i, method) in methods.iter().enumerate() {
                 match method {
@@ -1065,13 +1900,39 @@ impl<'a> EstimatorsAndTaker<'a> {
                     }
                     &"rpkm" => {
                         if rpkm_column.is_some() {
-                            error!("The RPKM column cannot be specified more than once");
+                            error!("The RPKM column cannot be specified more than once, \
+                                    or together with BPKM");
+                            process::exit(1);
+                        }
+                        if long_read_mode(&m) {
+                            error!("RPKM assumes reads of comparable length, so cannot be \
+                                    used in long-read mode (--long-read, or mapping with \
+                                    -p minimap2-ont or minimap2-pb). Consider using BPKM \
+                                    instead");
                             process::exit(1);
                         }
                         rpkm_column = Some(i);
                         estimators.push(CoverageEstimator::new_estimator_rpkm(
                             min_fraction_covered))
                     }
+                    &"bpkm" => {
+                        if rpkm_column.is_some() {
+                            error!("The BPKM column cannot be specified more than once, \
+                                    or together with RPKM");
+                            process::exit(1);
+                        }
+                        if !long_read_mode(&m) && !m.is_present("depth-files") {
+                            error!("BPKM counts aligned bases, so requires long-read mode \
+                                    (--long-read, or mapping with -p minimap2-ont or \
+                                    minimap2-pb) or depth files (--depth-files)");
+                            process::exit(1);
+                        }
+                        // BPKM is divided by the number of aligned bases when
+                        // printing, in the same way RPKM is divided by reads.
+                        rpkm_column = Some(i);
+                        estimators.push(CoverageEstimator::new_estimator_bpkm(
+                            min_fraction_covered))
+                    }
                     &"variance" => {
                         estimators.push(CoverageEstimator::new_estimator_variance(
                             min_fraction_covered,
@@ -1096,6 +1957,27 @@ impl<'a> EstimatorsAndTaker<'a> {
                     &"reads_per_base" => {
                         estimators.push(CoverageEstimator::new_estimator_reads_per_base());
                     }
+                    &"snv_count" | &"snv_density" | &"nucleotide_diversity"
+                    | &"major_allele_frequency" => {
+                        let statistic = match method {
+                            &"snv_count" => AlleleStatistic::SNVCount,
+                            &"snv_density" => AlleleStatistic::SNVDensity,
+                            &"nucleotide_diversity" => AlleleStatistic::NucleotideDiversity,
+                            _ => AlleleStatistic::MajorAlleleFrequency,
+                        };
+                        estimators.push(CoverageEstimator::new_estimator_allele_variation(
+                            statistic,
+                            value_t!(m.value_of("allele-min-depth"), u32).unwrap(),
+                            parse_percentage(&m, "snv-min-allele-frequency"),
+                            min_fraction_covered,
+                            contig_end_exclusion,
+                            match statistic {
+                                AlleleStatistic::SNVCount | AlleleStatistic::SNVDensity =>
+                                    snv_reference.clone(),
+                                _ => None,
+                            },
+                        ));
+                    }
                     _ => unreachable!(),
                 };
             }
@@ -1183,7 +2065,7 @@ impl<'a> EstimatorsAndTaker<'a> {
     }
 }
 
-fn parse_separator(m: &clap::ArgMatches) -> Option<u8> {
+fn parse_separator(m: &clap::ArgMatches, sample_sheet: &Option<SampleSheet>) -> Option<u8> {
     let single_genome = m.is_present("single-genome");
     if single_genome {
         Some("0".as_bytes()[0])
@@ -1198,7 +2080,11 @@ fn parse_separator(m: &clap::ArgMatches) -> Option<u8> {
             process::exit(1);
         }
         Some(separator_str[0])
-    } else if m.is_present("bam-files") || m.is_present("reference") {
+    } else if m.is_present("bam-files")
+        || m.is_present("reference")
+        || m.is_present("depth-files")
+        || sample_sheet_bam_samples(sample_sheet).is_some()
+    {
         // Argument parsing enforces that genomes have been specified as FASTA
         // files.
         None
@@ -1209,23 +2095,137 @@ fn parse_separator(m: &clap::ArgMatches) -> Option<u8> {
     }
 }
 
-fn parse_list_of_genome_fasta_files(m: &clap::ArgMatches) -> Vec<String> {
-    match m.is_present("genome-fasta-files") {
-        true => m
-            .values_of("genome-fasta-files")
-            .unwrap()
-            .map(|s| s.to_string())
-            .collect(),
-        false => {
-            if m.is_present("genome-fasta-directory") {
-                let dir = m.value_of("genome-fasta-directory").unwrap();
-                let paths = std::fs::read_dir(dir).unwrap();
-                let mut genome_fasta_files: Vec<String> = vec![];
-                let extension = m.value_of("genome-fasta-extension").unwrap();
-                for path in paths {
-                    let file = path.unwrap().path();
-                    match file.extension() {
-                        Some(ext) => {
+/// Genome of each contig, so that mates mapped to different contigs of the
+/// same genome can be filtered as a pair.
+fn parse_mate_genomes(
+    single_genome: bool,
+    separator: Option<u8>,
+    genomes_and_contigs_option: &Option<GenomesAndContigs>,
+) -> MateGenomes {
+    if single_genome {
+        MateGenomes::SingleGenome
+    } else if let Some(s) = separator {
+        MateGenomes::Separator(s)
+    } else {
+        match genomes_and_contigs_option {
+            Some(gc) => MateGenomes::ContigToGenome(Rc::new(gc.contig_to_genome.clone())),
+            None => MateGenomes::SameReference,
+        }
+    }
+}
+
+struct FilterOutputSpec<'a> {
+    fastq: bool,
+    output: &'a str,
+    output2: Option<&'a str>,
+    restricted_contigs: Option<&'a HashSet<String>>,
+    threads: usize,
+}
+
+enum FilterOutput {
+    Bam(bam::Writer),
+    Fastq(coverm::fastq_writer::FastqWriter),
+}
+
+/// Write the records returned by read_record to BAM or FASTQ, returning the
+/// number of reads not written to FASTQ because their mate was not seen.
+fn write_filtered_records(
+    read_record: &mut dyn FnMut(&mut bam::Record) -> bool,
+    header: &bam::HeaderView,
+    spec: &FilterOutputSpec,
+) -> u64 {
+    let allowed_tids: Option<HashSet<i32>> = spec.restricted_contigs.map(|contigs| {
+        header.target_names().iter().enumerate()
+            .filter(|&(_, name)| contigs.contains(str::from_utf8(name).unwrap()))
+            .map(|(tid, _)| tid as i32)
+            .collect()
+    });
+    let mut output_writer = match spec.fastq {
+        true => FilterOutput::Fastq(
+            coverm::fastq_writer::FastqWriter::new(spec.output, spec.output2)),
+        false => {
+            let mut writer = bam::Writer::from_path(
+                spec.output,
+                &bam::header::Header::from_template(header),
+                rust_htslib::bam::Format::BAM)
+                .expect(&format!("Failed to write BAM file {}", spec.output));
+            writer
+                .set_threads(spec.threads)
+                .expect("Failed to set num threads in writer");
+            FilterOutput::Bam(writer)
+        }
+    };
+
+    let mut record = bam::record::Record::new();
+    while read_record(&mut record) {
+        if let Some(ref tids) = allowed_tids {
+            if !tids.contains(&record.tid()) {
+                continue;
+            }
+        }
+        debug!("Writing.. {:?}", record.qname());
+        match output_writer {
+            FilterOutput::Bam(ref mut writer) =>
+                writer.write(&record).expect("Failed to write BAM record"),
+            FilterOutput::Fastq(ref mut writer) => writer.add_record(&record),
+        }
+    }
+    match output_writer {
+        FilterOutput::Fastq(writer) => writer.finish(),
+        FilterOutput::Bam(_) => 0,
+    }
+}
+
+fn sort_bam_file(input_path: &str, output_path: &str, threads: u16) {
+    let mut cmd = std::process::Command::new("samtools");
+    cmd.arg("sort")
+        .arg("-@")
+        .arg(&format!("{}", threads - 1))
+        .arg("-o")
+        .arg(output_path)
+        .arg(input_path);
+    debug!("Running {:?}", cmd);
+    let status = cmd.status().expect("Unable to execute samtools sort");
+    if !status.success() {
+        error!("samtools sort of {} into {} failed", input_path, output_path);
+        process::exit(1);
+    }
+}
+
+/// Names of the contigs that filter output is restricted to, if any.
+fn parse_filter_contig_restriction(m: &clap::ArgMatches) -> Option<HashSet<String>> {
+    let genomes_and_contigs = if m.is_present("genome-definition") {
+        coverm::genome_parsing::read_genome_definition_file(
+            m.value_of("genome-definition").unwrap())
+    } else if m.is_present("genome-fasta-files") {
+        coverm::genome_parsing::read_genome_fasta_files(
+            &m.values_of("genome-fasta-files").unwrap().collect())
+    } else {
+        return None;
+    };
+    let contigs: HashSet<String> = genomes_and_contigs.contig_to_genome.keys().cloned().collect();
+    info!("Restricting output to {} contigs from {} genomes",
+          contigs.len(), genomes_and_contigs.genomes.len());
+    Some(contigs)
+}
+
+fn parse_list_of_genome_fasta_files(m: &clap::ArgMatches) -> Vec<String> {
+    match m.is_present("genome-fasta-files") {
+        true => m
+            .values_of("genome-fasta-files")
+            .unwrap()
+            .map(|s| s.to_string())
+            .collect(),
+        false => {
+            if m.is_present("genome-fasta-directory") {
+                let dir = m.value_of("genome-fasta-directory").unwrap();
+                let paths = std::fs::read_dir(dir).unwrap();
+                let mut genome_fasta_files: Vec<String> = vec![];
+                let extension = m.value_of("genome-fasta-extension").unwrap();
+                for path in paths {
+                    let file = path.unwrap().path();
+                    match file.extension() {
+                        Some(ext) => {
                             if ext == extension {
                                 let s = String::from(file.to_string_lossy());
                                 genome_fasta_files.push(s);
@@ -1276,28 +2276,200 @@ fn run_genome<
     let print_zeros = !m.is_present("no-zeros");
     let proper_pairs_only = m.is_present("proper-pairs-only");
     let single_genome = m.is_present("single-genome");
+    let long_read = long_read_mode(m);
     let threads = m.value_of("threads").unwrap().parse().unwrap();
+    let checkpoints = setup_checkpoint_directory(m, "genome");
+    let (name_exclusion, excluded_read_treatment) = parse_name_exclusion(
+        m,
+        match single_genome {
+            true => None,
+            false => separator,
+        },
+        genomes_and_contigs_option.as_ref(),
+    );
+    let estimators = &mut estimators_and_taker.estimators;
+    let reads_mapped = calculate_coverage_with_checkpoints(
+        generate_excluding_bam_readers(bam_generators, &name_exclusion, excluded_read_treatment),
+        checkpoints.as_ref(),
+        &mut estimators_and_taker.taker,
+        |generators, taker| {
+            let taker = &mut ExcludingCoverageTaker::new(taker, &name_exclusion);
+            match separator.is_some() || single_genome {
+                true => coverm::genome::mosdepth_genome_coverage(
+                    generators,
+                    separator.unwrap(),
+                    taker,
+                    print_zeros,
+                    estimators,
+                    proper_pairs_only,
+                    single_genome,
+                    long_read,
+                    threads,
+                ),
+
+                false => match genomes_and_contigs_option {
+                    Some(gc) => coverm::genome::mosdepth_genome_coverage_with_contig_names(
+                        generators,
+                        gc,
+                        taker,
+                        print_zeros,
+                        proper_pairs_only,
+                        long_read,
+                        estimators,
+                        threads,
+                    ),
+                    None => unreachable!(),
+                },
+            }
+        },
+    );
+
+    debug!("Finalising printing ..");
+    estimators_and_taker.printer.finalise_printing(
+        &estimators_and_taker.taker,
+        &mut std::io::stdout(),
+        Some(&reads_mapped),
+        &estimators_and_taker.columns_to_normalise,
+        estimators_and_taker.rpkm_column,
+    );
+}
+
+/// Parse --exclude-list and --exclude-regex, which exclude contigs by their
+/// own name or the name of the genome they belong to.
+fn parse_name_exclusion<'a>(
+    m: &clap::ArgMatches,
+    separator: Option<u8>,
+    genomes_and_contigs: Option<&'a GenomesAndContigs>,
+) -> (NameExclusionFilter<'a>, ExcludedReadTreatment) {
+    let mut excluded_names: HashSet<Vec<u8>> = HashSet::new();
+    if let Some(filename) = m.value_of("exclude-list") {
+        let content = std::fs::read(filename).expect(&format!(
+            "Failed to open file '{}' containing list of excluded genomes and contigs",
+            filename
+        ));
+        for line in content.split(|c| *c == b'\n') {
+            let name = str::from_utf8(line)
+                .expect("UTF8 error in list of excluded genomes and contigs")
+                .trim();
+            if name != "" {
+                excluded_names.insert(name.as_bytes().to_vec());
+            }
+        }
+        if excluded_names.is_empty() {
+            warn!("No genomes or contigs read in that are to be excluded");
+        } else {
+            info!("Read in {} distinct genomes or contigs to exclude",
+                  excluded_names.len());
+        }
+    }
+    let regex = match m.value_of("exclude-regex") {
+        Some(r) => match Regex::new(r) {
+            Ok(regex) => Some(regex),
+            Err(e) => {
+                error!("Failed to parse --exclude-regex '{}': {}", r, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let treatment = match m.value_of("excluded-reads") {
+        Some("unmapped") | None => ExcludedReadTreatment::CountAsUnmapped,
+        Some("remove") => ExcludedReadTreatment::Remove,
+        _ => unreachable!(),
+    };
+    (
+        NameExclusionFilter {
+            excluded_names: excluded_names,
+            regex: regex,
+            split_char: separator,
+            genomes_and_contigs: genomes_and_contigs,
+        },
+        treatment,
+    )
+}
+
+fn parse_shard_selection(m: &clap::ArgMatches) -> ShardSelection {
+    let shard_selection = ShardSelection {
+        policy: match m.value_of("deshard-by") {
+            Some("alignment-score") | None => ShardSelectionPolicy::AlignmentScore,
+            Some("edit-distance") => ShardSelectionPolicy::EditDistance,
+            Some("percent-identity") => ShardSelectionPolicy::PercentIdentity,
+            _ => unreachable!(),
+        },
+        discard_ties: m.is_present("discard-deshard-ties"),
+    };
+    debug!("Using shard selection {:?}", shard_selection);
+    shard_selection
+}
+
+fn run_genome_from_sharded_bam_files<'a, E: GenomeExclusion>(
+    bam_files: Vec<&str>,
+    genome_exclusion: &E,
+    m: &clap::ArgMatches,
+    estimators_and_taker: &'a mut EstimatorsAndTaker<'a>,
+    separator: Option<u8>,
+    genomes_and_contigs_option: &Option<GenomesAndContigs>,
+    stoit_naming: &StoitNaming,
+) {
+    if coverm::shard_bam_reader::shard_bam_files_are_coordinate_sorted(&bam_files) {
+        info!("Sharded BAM files are coordinate sorted, so choosing the \
+               best hit for each read in two passes");
+        run_genome(
+            coverm::shard_bam_reader::generate_coordinate_sorted_sharded_bam_reader_from_bam_files(
+                bam_files,
+                genome_exclusion,
+                parse_shard_selection(m),
+                stoit_naming),
+            m,
+            estimators_and_taker,
+            separator,
+            genomes_and_contigs_option);
+    } else {
+        external_command_checker::check_for_samtools();
+        let sort_threads = m.value_of("threads").unwrap().parse::<i32>().unwrap();
+        run_genome(
+            coverm::shard_bam_reader::generate_sharded_bam_reader_from_bam_files(
+                bam_files,
+                sort_threads,
+                genome_exclusion,
+                parse_shard_selection(m),
+                stoit_naming),
+            m,
+            estimators_and_taker,
+            separator,
+            genomes_and_contigs_option);
+    }
+}
+
+fn run_genome_from_depth_files<'a>(
+    m: &clap::ArgMatches,
+    estimators_and_taker: &'a mut EstimatorsAndTaker<'a>,
+    separator: Option<u8>,
+    genomes_and_contigs_option: &Option<GenomesAndContigs>,
+    stoit_naming: &StoitNaming,
+) {
+    check_depth_file_compatibility(m, &estimators_and_taker.estimators);
+    let depth_files: Vec<&str> = m.values_of("depth-files").unwrap().collect();
+    let print_zeros = !m.is_present("no-zeros");
+    let single_genome = m.is_present("single-genome");
     let reads_mapped = match separator.is_some() || single_genome {
-        true => coverm::genome::mosdepth_genome_coverage(
-            bam_generators,
+        true => coverm::depth_file::depth_file_genome_coverage(
+            &depth_files,
             separator.unwrap(),
             &mut estimators_and_taker.taker,
             print_zeros,
             &mut estimators_and_taker.estimators,
-            proper_pairs_only,
             single_genome,
-            threads,
+            stoit_naming,
         ),
-
         false => match genomes_and_contigs_option {
-            Some(gc) => coverm::genome::mosdepth_genome_coverage_with_contig_names(
-                bam_generators,
+            Some(gc) => coverm::depth_file::depth_file_genome_coverage_with_contig_names(
+                &depth_files,
                 gc,
                 &mut estimators_and_taker.taker,
                 print_zeros,
-                proper_pairs_only,
                 &mut estimators_and_taker.estimators,
-                threads,
+                stoit_naming,
             ),
             None => unreachable!(),
         },
@@ -1313,22 +2485,124 @@ fn run_genome<
     );
 }
 
+fn run_contig_from_depth_files<'a>(
+    estimators_and_taker: &'a mut EstimatorsAndTaker<'a>,
+    m: &clap::ArgMatches,
+    print_zeros: bool,
+    stoit_naming: &StoitNaming,
+) {
+    check_depth_file_compatibility(m, &estimators_and_taker.estimators);
+    let depth_files: Vec<&str> = m.values_of("depth-files").unwrap().collect();
+    let reads_mapped = coverm::depth_file::contig_coverage_from_depth_files(
+        &depth_files,
+        &mut estimators_and_taker.taker,
+        &mut estimators_and_taker.estimators,
+        print_zeros,
+        stoit_naming,
+    );
+
+    debug!("Finalising printing ..");
+    estimators_and_taker.printer.finalise_printing(
+        &estimators_and_taker.taker,
+        &mut std::io::stdout(),
+        Some(&reads_mapped),
+        &estimators_and_taker.columns_to_normalise,
+        estimators_and_taker.rpkm_column,
+    );
+}
+
+/// Depth files record neither individual reads nor their alignments, so
+/// methods which count reads and alignment filtering cannot be used.
+fn check_depth_file_compatibility(m: &clap::ArgMatches, estimators: &Vec<CoverageEstimator>) {
+    for e in estimators {
+        if e.requires_allele_counts() {
+            error!(
+                "'{}' cannot be calculated from depth files, as it requires \
+                 the bases of aligned reads",
+                e.column_headers()[0]
+            );
+            process::exit(1);
+        }
+        let method = match e {
+            CoverageEstimator::ReadCountCalculator { .. } => Some("count"),
+            CoverageEstimator::ReadsPerBaseCalculator { .. } => Some("reads_per_base"),
+            CoverageEstimator::RPKMCoverageEstimator { .. } => Some("rpkm"),
+            _ => None,
+        };
+        if let Some(method) = method {
+            error!(
+                "The '{}' coverage method requires read counts, so cannot be \
+                 calculated from depth files",
+                method
+            );
+            process::exit(1);
+        }
+    }
+    for parameter in &[
+        "proper-pairs-only",
+        "min-read-aligned-length",
+        "min-read-percent-identity",
+        "min-read-aligned-percent",
+        "min-read-aligned-length-pair",
+        "min-read-percent-identity-pair",
+        "min-read-aligned-percent-pair",
+    ] {
+        if m.is_present(parameter) {
+            error!(
+                "Alignment filtering with --{} cannot be applied to depth files",
+                parameter
+            );
+            process::exit(1);
+        }
+    }
+    if doing_metabat(m) {
+        warn!(
+            "Depth files do not contain alignments, so the 97% identity \
+             threshold of MetaBAT-style coverage is not applied"
+        );
+    }
+}
+
+/// Long-read mode is used with --long-read, and when reads are mapped with
+/// minimap2 presets for ONT or PacBio reads.
+fn long_read_mode(m: &clap::ArgMatches) -> bool {
+    m.is_present("long-read")
+        || match m.value_of("mapper") {
+            Some("minimap2-ont") | Some("minimap2-pb") => true,
+            _ => false,
+        }
+}
+
 fn doing_metabat(m: &clap::ArgMatches) -> bool {
     match m.subcommand_name() {
         Some("contig") | None => {
+            let metabat_format = m.value_of("output-format") == Some("metabat");
             if !m.is_present("methods") {
-                return false;
+                return metabat_format;
             }
             let methods: Vec<&str> = m.values_of("methods").unwrap().collect();
             if methods.contains(&"metabat") {
                 if methods.len() > 1 {
                     error!("Cannot specify the metabat method with any other coverage methods");
                     process::exit(1);
+                } else if m.occurrences_of("output-format") > 0 && !metabat_format {
+                    error!(
+                        "The metabat method cannot be used with --output-format {}",
+                        m.value_of("output-format").unwrap()
+                    );
+                    process::exit(1);
                 } else {
                     return true;
                 }
             }
-            return false;
+            if metabat_format && m.occurrences_of("methods") > 0 {
+                error!(
+                    "The metabat output format calculates its own coverage, so \
+                     cannot be used with --methods"
+                );
+                process::exit(1);
+            }
+            return metabat_format;
         }
         _ => {
             debug!("Not running in contig mode so cannot be in metabat mode");
@@ -1337,6 +2611,73 @@ fn doing_metabat(m: &clap::ArgMatches) -> bool {
     }
 }
 
+/// Read the --snv-reference sequences, which are required by the methods
+/// that count positions differing from the reference.
+fn parse_snv_reference(
+    m: &clap::ArgMatches,
+    methods: &[&str],
+) -> Option<Rc<HashMap<String, Vec<u8>>>> {
+    let counting_snvs = methods
+        .iter()
+        .any(|method| *method == "snv_count" || *method == "snv_density");
+    match m.values_of("snv-reference") {
+        Some(paths) => {
+            if !counting_snvs {
+                error!("--snv-reference can only be used with the snv_count or snv_density methods");
+                process::exit(1);
+            }
+            let mut sequences = HashMap::new();
+            for path in paths {
+                sequences.extend(coverm::pileup::read_reference_sequences(path));
+            }
+            Some(Rc::new(sequences))
+        }
+        None => {
+            if counting_snvs {
+                error!(
+                    "--snv-reference must be specified for the snv_count and snv_density \
+                     methods, which count positions differing from the reference"
+                );
+                process::exit(1);
+            }
+            None
+        }
+    }
+}
+
+fn doing_pileup(m: &clap::ArgMatches) -> bool {
+    m.value_of("output-format") == Some("pileup")
+}
+
+/// The directory that binner formats writing one abundance file per sample
+/// write to, which is created if it does not exist.
+fn parse_binner_output_directory(m: &clap::ArgMatches, format: BinnerFormat) -> Option<String> {
+    if !format.writes_per_sample_files() {
+        if m.is_present("output-directory") {
+            warn!(
+                "The {} output format is printed to STDOUT, so --output-directory is ignored",
+                m.value_of("output-format").unwrap()
+            );
+        }
+        return None;
+    }
+    match m.value_of("output-directory") {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .expect(&format!("Unable to create output directory {}", dir));
+            Some(dir.to_string())
+        }
+        None => {
+            error!(
+                "The {} output format writes one abundance file per sample, \
+                 so requires --output-directory",
+                m.value_of("output-format").unwrap()
+            );
+            process::exit(1);
+        }
+    }
+}
+
 #[derive(Debug)]
 struct FilterParameters {
     flag_filters: FlagFilter,
@@ -1354,6 +2695,7 @@ impl FilterParameters {
                 include_improper_pairs: !m.is_present("proper-pairs-only"),
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             min_aligned_length_single: match m.is_present("min-read-aligned-length") {
                 true => value_t!(m.value_of("min-read-aligned-length"), u32).unwrap(),
@@ -1369,16 +2711,44 @@ impl FilterParameters {
             min_aligned_percent_pair: parse_percentage(&m, "min-read-aligned-percent-pair"),
         };
         if doing_metabat(&m) {
+            // As for jgi_summarize_bam_contig_depths --percentIdentity, the
+            // threshold defaults to 97%.
+            let percent_identity = match m.is_present("min-read-percent-identity") {
+                true => f.min_percent_identity_single,
+                false => 0.97,
+            };
             debug!(
-                "Setting single read percent identity threshold at 0.97 for \
-                 MetaBAT adjusted coverage."
+                "Setting single read percent identity threshold at {} for \
+                 MetaBAT adjusted coverage.",
+                percent_identity
             );
             // we use >= where metabat uses >. Gah.
-            f.min_percent_identity_single = 0.97001;
+            if percent_identity > 0.0 {
+                f.min_percent_identity_single = percent_identity + 0.00001;
+            }
             f.flag_filters.include_improper_pairs = true;
             f.flag_filters.include_supplementary = true;
             f.flag_filters.include_secondary = true;
         }
+        if long_read_mode(&m) {
+            for parameter in &[
+                "proper-pairs-only",
+                "min-read-aligned-length-pair",
+                "min-read-percent-identity-pair",
+                "min-read-aligned-percent-pair",
+            ] {
+                if m.is_present(parameter) {
+                    error!("--{} cannot be used in long-read mode, since long reads are \
+                            not paired", parameter);
+                    process::exit(1);
+                }
+            }
+            debug!("Using gap-compressed identity and including supplementary \
+                    alignments for long reads");
+            f.flag_filters.include_improper_pairs = true;
+            f.flag_filters.gap_compressed_identity = true;
+            f.flag_filters.include_supplementary = true;
+        }
         debug!("Filter parameters set as {:?}", f);
         return f;
     }
@@ -1391,13 +2761,35 @@ impl FilterParameters {
             || self.min_aligned_length_single > 0
             || self.min_aligned_length_pair > 0;
     }
+
+    fn provenance_description(&self) -> String {
+        format!(
+            "min-read-aligned-length={} min-read-percent-identity={} \
+             min-read-aligned-percent={} min-read-aligned-length-pair={} \
+             min-read-percent-identity-pair={} min-read-aligned-percent-pair={} \
+             include-improper-pairs={} include-secondary={} include-supplementary={} \
+             gap-compressed-identity={}",
+            self.min_aligned_length_single,
+            self.min_percent_identity_single,
+            self.min_aligned_percent_single,
+            self.min_aligned_length_pair,
+            self.min_percent_identity_pair,
+            self.min_aligned_percent_pair,
+            self.flag_filters.include_improper_pairs,
+            self.flag_filters.include_secondary,
+            self.flag_filters.include_supplementary,
+            self.flag_filters.gap_compressed_identity,
+        )
+    }
 }
 
 fn get_sharded_bam_readers<'a, 'b, T>(
     m: &'a clap::ArgMatches,
     mapping_program: MappingProgram,
     reference_tempfile: &'a Option<NamedTempFile>,
+    sample_sheet: &'a Option<SampleSheet>,
     genome_exclusion: &'b T,
+    stoit_naming: &'a StoitNaming,
 ) -> Vec<ShardedBamReaderGenerator<'b, T>>
 where
     T: GenomeExclusion,
@@ -1408,10 +2800,17 @@ where
     }
     let discard_unmapped = m.is_present("discard-unmapped");
     let sort_threads = m.value_of("threads").unwrap().parse::<i32>().unwrap();
-    let params = MappingParameters::generate_from_clap(&m, mapping_program, &reference_tempfile);
+    let params = MappingParameters::generate_from_clap(
+        &m, mapping_program, &reference_tempfile, sample_sheet, stoit_naming);
     let mut bam_readers = vec![];
+    let mut shard_mappings = vec![];
+    let mut cached_bam_paths = vec![];
     let mut concatenated_reference_name: Option<String> = None;
     let mut concatenated_read_names: Option<String> = None;
+    // Whether each read set has a sample name, rather than being named after
+    // its read1 file
+    let mut all_samples_named = true;
+    let mut all_read_paths: Vec<&str> = vec![];
 
     for reference_wise_params in params {
         let index = setup_mapping_index(&reference_wise_params, &m, mapping_program);
@@ -1427,73 +2826,171 @@ where
             Some(prev) => Some(format!("{}|{}", prev, reference_name)),
             None => Some(reference_name),
         };
-        let bam_file_cache = |naming_readset| -> Option<String> {
-            let bam_file_cache_path;
+        let bam_file_cache = |p: &OneSampleMappingParameters| -> Option<CachedBamFile> {
             match m.is_present("bam-file-cache-directory") {
                 false => None,
-                true => {
-                    bam_file_cache_path = generate_cached_bam_file_name(
-                        m.value_of("bam-file-cache-directory").unwrap(),
-                        match reference_tempfile {
-                            Some(_) => CONCATENATED_REFERENCE_CACHE_STEM,
-                            None => reference,
-                        },
-                        naming_readset,
-                    );
-                    info!("Caching BAM file to {}", bam_file_cache_path);
-                    Some(bam_file_cache_path)
-                }
+                true => Some(generate_cached_bam_file(
+                    m.value_of("bam-file-cache-directory").unwrap(),
+                    m,
+                    mapping_program,
+                    reference_tempfile,
+                    reference,
+                    p,
+                    discard_unmapped,
+                    true,
+                )),
             }
         };
 
         for p in reference_wise_params {
-            bam_readers.push(
+            let cached_bam_file = bam_file_cache(&p);
+            if let Some(ref cache) = cached_bam_file {
+                cached_bam_paths.push(cache.path.clone());
+            }
+            let (bam_reader, shard_mapping) =
                 coverm::shard_bam_reader::generate_named_sharded_bam_readers_from_reads(
                     mapping_program,
                     match index {
                         Some(ref index) => index.index_path(),
                         None => reference,
                     },
-                    p.read1,
-                    p.read2,
+                    &p.read1,
+                    &p.read2,
                     p.read_format.clone(),
                     p.threads,
-                    bam_file_cache(p.read1).as_ref().map(String::as_ref),
+                    cached_bam_file,
                     discard_unmapped,
                     p.mapping_options,
-                ),
-            );
-            let name = &std::path::Path::new(p.read1)
-                .file_name()
-                .expect("Unable to convert read1 name to file name")
-                .to_str()
-                .expect("Unable to covert file name into str")
-                .to_string();
+                );
+            bam_readers.push(bam_reader);
+            shard_mappings.push(shard_mapping);
+            let name = match p.sample_name {
+                Some(sample_name) => sample_name.to_string(),
+                None => {
+                    all_samples_named = false;
+                    std::path::Path::new(p.read1[0])
+                        .file_name()
+                        .expect("Unable to convert read1 name to file name")
+                        .to_str()
+                        .expect("Unable to covert file name into str")
+                        .to_string()
+                }
+            };
             concatenated_read_names = match concatenated_read_names {
                 Some(prev) => Some(format!("{}|{}", prev, name)),
                 None => Some(name.to_string()),
             };
+            all_read_paths.extend(p.read1.iter().chain(p.read2.iter()).cloned());
         }
 
         debug!("Finished BAM setup");
     }
+    let concatenated_read_names = concatenated_read_names.unwrap();
+    let stoit_name = stoit_naming.stoit_name(
+        Some(concatenated_reference_name.unwrap().as_str()),
+        &concatenated_read_names,
+        match all_samples_named {
+            true => Some(concatenated_read_names.as_str()),
+            false => None,
+        },
+    );
+    remove_stale_cached_bam_files(&cached_bam_paths);
+    all_read_paths.sort();
+    all_read_paths.dedup();
     let gen = ShardedBamReaderGenerator {
-        stoit_name: format!(
-            "{}/{}",
-            concatenated_reference_name.unwrap(),
-            concatenated_read_names.unwrap()
-        ),
+        stoit_name: stoit_name,
+        input_paths: all_read_paths.iter().map(|p| p.to_string()).collect(),
         read_sorted_bam_readers: bam_readers,
+        shard_mappings: shard_mappings,
         sort_threads: sort_threads,
         genome_exclusion: genome_exclusion,
+        shard_selection: parse_shard_selection(m),
     };
     return vec![gen];
 }
 
+/// Read the sample sheet, if one was given.
+fn parse_sample_sheet(m: &clap::ArgMatches) -> Option<SampleSheet> {
+    m.value_of("sample-sheet").map(|path| {
+        let sample_sheet = read_sample_sheet_file(path);
+        if let SampleSheet::BamFiles(_) = sample_sheet {
+            if m.is_present("sharded") {
+                error!("Sharded BAM files cannot be given in a sample sheet");
+                process::exit(1);
+            }
+        }
+        sample_sheet
+    })
+}
+
+/// BAM files of each sample, if the sample sheet gives BAM files rather
+/// than reads.
+fn sample_sheet_bam_samples(sample_sheet: &Option<SampleSheet>) -> Option<&[BamSampleSheetEntry]> {
+    match sample_sheet {
+        Some(SampleSheet::BamFiles(entries)) => Some(entries.as_slice()),
+        _ => None,
+    }
+}
+
+fn get_merged_bam_readers(
+    m: &clap::ArgMatches,
+    bam_samples: &[BamSampleSheetEntry],
+    stoit_naming: &StoitNaming,
+) -> Vec<StreamingNamedBamReaderGenerator> {
+    external_command_checker::check_for_samtools();
+    let threads = value_t!(m.value_of("threads"), u16).unwrap();
+    bam_samples
+        .iter()
+        .map(|sample| {
+            let bam_files: Vec<&str> = sample.bam_files.iter().map(|f| f.as_str()).collect();
+            coverm::bam_generator::generate_named_bam_readers_from_merged_bam_files(
+                &sample.name,
+                &bam_files,
+                threads,
+                stoit_naming,
+            )
+        })
+        .collect()
+}
+
+fn get_merged_filtered_bam_readers(
+    m: &clap::ArgMatches,
+    bam_samples: &[BamSampleSheetEntry],
+    filter_params: &FilterParameters,
+    mate_genomes: &MateGenomes,
+    stoit_naming: &StoitNaming,
+) -> Vec<StreamingFilteredNamedBamReaderGenerator> {
+    external_command_checker::check_for_samtools();
+    let threads = value_t!(m.value_of("threads"), u16).unwrap();
+    bam_samples
+        .iter()
+        .map(|sample| {
+            let bam_files: Vec<&str> = sample.bam_files.iter().map(|f| f.as_str()).collect();
+            coverm::bam_generator::generate_filtered_named_bam_readers_from_merged_bam_files(
+                &sample.name,
+                &bam_files,
+                threads,
+                stoit_naming,
+                filter_params.flag_filters.clone(),
+                filter_params.min_aligned_length_single,
+                filter_params.min_percent_identity_single,
+                filter_params.min_aligned_percent_single,
+                filter_params.min_aligned_length_pair,
+                filter_params.min_percent_identity_pair,
+                filter_params.min_aligned_percent_pair,
+                mate_genomes.clone(),
+            )
+        })
+        .collect()
+}
+
 fn get_streamed_bam_readers<'a>(
     m: &'a clap::ArgMatches,
     mapping_program: MappingProgram,
     reference_tempfile: &'a Option<NamedTempFile>,
+    sample_sheet: &'a Option<SampleSheet>,
+    host_depletion: Option<&HostDepletion>,
+    stoit_naming: &'a StoitNaming,
 ) -> Vec<BamGeneratorSet<StreamingNamedBamReaderGenerator>> {
     // Check the output BAM directory actually exists and is writeable
     if m.is_present("bam-file-cache-directory") {
@@ -1501,33 +2998,36 @@ fn get_streamed_bam_readers<'a>(
     }
     let discard_unmapped = m.is_present("discard-unmapped");
 
-    let params = MappingParameters::generate_from_clap(&m, mapping_program, &reference_tempfile);
+    let params = MappingParameters::generate_from_clap(
+        &m, mapping_program, &reference_tempfile, sample_sheet, stoit_naming);
     let mut generator_set = vec![];
+    let mut cached_bam_paths = vec![];
     for reference_wise_params in params {
         let mut bam_readers = vec![];
         let index = setup_mapping_index(&reference_wise_params, &m, mapping_program);
 
         let reference = reference_wise_params.reference;
-        let bam_file_cache = |naming_readset| -> Option<String> {
-            let bam_file_cache_path;
+        let bam_file_cache = |p: &OneSampleMappingParameters| -> Option<CachedBamFile> {
             match m.is_present("bam-file-cache-directory") {
                 false => None,
-                true => {
-                    bam_file_cache_path = generate_cached_bam_file_name(
-                        m.value_of("bam-file-cache-directory").unwrap(),
-                        match reference_tempfile {
-                            Some(_) => CONCATENATED_REFERENCE_CACHE_STEM,
-                            None => reference,
-                        },
-                        naming_readset,
-                    );
-                    info!("Caching BAM file to {}", bam_file_cache_path);
-                    Some(bam_file_cache_path)
-                }
+                true => Some(generate_cached_bam_file(
+                    m.value_of("bam-file-cache-directory").unwrap(),
+                    m,
+                    mapping_program,
+                    reference_tempfile,
+                    reference,
+                    p,
+                    discard_unmapped,
+                    false,
+                )),
             }
         };
 
         for p in reference_wise_params {
+            let cached_bam_file = bam_file_cache(&p);
+            if let Some(ref cache) = cached_bam_file {
+                cached_bam_paths.push(cache.path.clone());
+            }
             bam_readers.push(
                 coverm::bam_generator::generate_named_bam_readers_from_reads(
                     mapping_program,
@@ -1535,14 +3035,17 @@ fn get_streamed_bam_readers<'a>(
                         Some(ref index) => index.index_path(),
                         None => reference,
                     },
-                    p.read1,
-                    p.read2,
+                    &p.read1,
+                    &p.read2,
+                    p.sample_name,
+                    stoit_naming,
                     p.read_format.clone(),
                     p.threads,
-                    bam_file_cache(p.read1).as_ref().map(String::as_ref),
+                    cached_bam_file,
                     discard_unmapped,
                     p.mapping_options,
                     reference_tempfile.is_none(),
+                    host_depletion,
                 ),
             );
         }
@@ -1554,10 +3057,16 @@ fn get_streamed_bam_readers<'a>(
         };
         generator_set.push(to_return);
     }
+    remove_stale_cached_bam_files(&cached_bam_paths);
     return generator_set;
 }
 
-fn generate_cached_bam_file_name(directory: &str, reference: &str, read1_path: &str) -> String {
+fn generate_cached_bam_file_name(
+    directory: &str,
+    reference: &str,
+    read1_path: &str,
+    name_hash: Option<&str>,
+) -> String {
     debug!(
         "Constructing BAM file cache name in directory {}, reference {}, read1_path {}",
         directory, reference, read1_path
@@ -1580,9 +3089,84 @@ fn generate_cached_bam_file_name(directory: &str, reference: &str, read1_path: &
             .to_str()
             .expect("Unable to covert file name into str")
             .to_string()
+        + &match name_hash {
+            Some(hash) => format!(".{}", hash),
+            None => "".to_string(),
+        }
         + ".bam"
 }
 
+/// A BAM file in the cache directory, named by a hash of its manifest as well
+/// as the reference and reads, so that mappings of the same reads with
+/// different read2 files, mappers or parameters do not replace each other.
+fn generate_cached_bam_file(
+    directory: &str,
+    m: &clap::ArgMatches,
+    mapping_program: MappingProgram,
+    reference_tempfile: &Option<NamedTempFile>,
+    reference: &str,
+    p: &OneSampleMappingParameters,
+    discard_unmapped: bool,
+    name_sorted: bool,
+) -> CachedBamFile {
+    let mut manifest = generate_bam_cache_manifest(
+        m,
+        mapping_program,
+        reference_tempfile,
+        reference,
+        p,
+        discard_unmapped,
+    );
+    if name_sorted {
+        manifest.set_name_sorted();
+    }
+    let path = generate_cached_bam_file_name(
+        directory,
+        match reference_tempfile {
+            Some(_) => CONCATENATED_REFERENCE_CACHE_STEM,
+            None => reference,
+        },
+        p.naming_readset(),
+        Some(&manifest.name_hash()),
+    );
+    info!("Caching BAM file to {}", path);
+    CachedBamFile {
+        manifest: manifest,
+        path: path,
+    }
+}
+
+fn generate_bam_cache_manifest(
+    m: &clap::ArgMatches,
+    mapping_program: MappingProgram,
+    reference_tempfile: &Option<NamedTempFile>,
+    reference: &str,
+    p: &OneSampleMappingParameters,
+    discard_unmapped: bool,
+) -> BamCacheManifest {
+    // Concatenated genome references are regenerated each run, so describe
+    // the reference by the genome FASTA files it was made from instead.
+    let mut genome_fasta_files: Vec<String> = match reference_tempfile {
+        Some(_) => parse_list_of_genome_fasta_files(m),
+        None => vec![reference.to_string()],
+    };
+    genome_fasta_files.sort();
+    let references: Vec<&str> = genome_fasta_files.iter().map(|s| s.as_str()).collect();
+    let mut manifest = BamCacheManifest::new(
+        mapping_program,
+        &references,
+        &p.read1,
+        &p.read2,
+        &p.read_format,
+        p.mapping_options,
+        discard_unmapped,
+    );
+    if let Some(host_reference) = m.value_of("host-reference") {
+        manifest.add_host_reference(host_reference);
+    }
+    manifest
+}
+
 fn setup_bam_cache_directory(cache_directory: &str) {
     let path = std::path::Path::new(cache_directory);
     if path.is_dir() {
@@ -1668,7 +3252,11 @@ fn get_streamed_filtered_bam_readers(
     m: &clap::ArgMatches,
     mapping_program: MappingProgram,
     reference_tempfile: &Option<NamedTempFile>,
+    sample_sheet: &Option<SampleSheet>,
     filter_params: &FilterParameters,
+    mate_genomes: &MateGenomes,
+    host_depletion: Option<&HostDepletion>,
+    stoit_naming: &StoitNaming,
 ) -> Vec<BamGeneratorSet<StreamingFilteredNamedBamReaderGenerator>> {
     // Check the output BAM directory actually exists and is writeable
     if m.is_present("bam-file-cache-directory") {
@@ -1676,33 +3264,36 @@ fn get_streamed_filtered_bam_readers(
     }
     let discard_unmapped = m.is_present("discard-unmapped");
 
-    let params = MappingParameters::generate_from_clap(&m, mapping_program, &reference_tempfile);
+    let params = MappingParameters::generate_from_clap(
+        &m, mapping_program, &reference_tempfile, sample_sheet, stoit_naming);
     let mut generator_set = vec![];
+    let mut cached_bam_paths = vec![];
     for reference_wise_params in params {
         let mut bam_readers = vec![];
         let index = setup_mapping_index(&reference_wise_params, &m, mapping_program);
 
         let reference = reference_wise_params.reference;
-        let bam_file_cache = |naming_readset| -> Option<String> {
-            let bam_file_cache_path;
+        let bam_file_cache = |p: &OneSampleMappingParameters| -> Option<CachedBamFile> {
             match m.is_present("bam-file-cache-directory") {
                 false => None,
-                true => {
-                    bam_file_cache_path = generate_cached_bam_file_name(
-                        m.value_of("bam-file-cache-directory").unwrap(),
-                        match reference_tempfile {
-                            Some(_) => CONCATENATED_REFERENCE_CACHE_STEM,
-                            None => reference,
-                        },
-                        naming_readset,
-                    );
-                    info!("Caching BAM file to {}", bam_file_cache_path);
-                    Some(bam_file_cache_path)
-                }
+                true => Some(generate_cached_bam_file(
+                    m.value_of("bam-file-cache-directory").unwrap(),
+                    m,
+                    mapping_program,
+                    reference_tempfile,
+                    reference,
+                    p,
+                    discard_unmapped,
+                    false,
+                )),
             }
         };
 
         for p in reference_wise_params {
+            let cached_bam_file = bam_file_cache(&p);
+            if let Some(ref cache) = cached_bam_file {
+                cached_bam_paths.push(cache.path.clone());
+            }
             bam_readers.push(
                 coverm::bam_generator::generate_filtered_named_bam_readers_from_reads(
                     mapping_program,
@@ -1710,11 +3301,13 @@ fn get_streamed_filtered_bam_readers(
                         Some(ref index) => index.index_path(),
                         None => reference,
                     },
-                    p.read1,
-                    p.read2,
+                    &p.read1,
+                    &p.read2,
+                    p.sample_name,
+                    stoit_naming,
                     p.read_format.clone(),
                     p.threads,
-                    bam_file_cache(p.read1).as_ref().map(String::as_ref),
+                    cached_bam_file,
                     filter_params.flag_filters.clone(),
                     filter_params.min_aligned_length_single,
                     filter_params.min_percent_identity_single,
@@ -1722,9 +3315,11 @@ fn get_streamed_filtered_bam_readers(
                     filter_params.min_aligned_length_pair,
                     filter_params.min_percent_identity_pair,
                     filter_params.min_aligned_percent_pair,
+                    mate_genomes.clone(),
                     p.mapping_options,
                     discard_unmapped,
                     reference_tempfile.is_none(),
+                    host_depletion,
                 ),
             );
         }
@@ -1736,6 +3331,7 @@ fn get_streamed_filtered_bam_readers(
         };
         generator_set.push(to_return);
     }
+    remove_stale_cached_bam_files(&cached_bam_paths);
     return generator_set;
 }
 
@@ -1746,17 +3342,34 @@ fn run_contig<
 >(
     estimators_and_taker: &'a mut EstimatorsAndTaker<'a>,
     bam_readers: Vec<T>,
+    m: &clap::ArgMatches,
     print_zeros: bool,
     flag_filters: FlagFilter,
     threads: usize
 ) {
-    let reads_mapped = coverm::contig::contig_coverage(
+    let (name_exclusion, excluded_read_treatment) = parse_name_exclusion(m, None, None);
+    let bam_readers =
+        generate_excluding_bam_readers(bam_readers, &name_exclusion, excluded_read_treatment);
+    if doing_pileup(m) {
+        run_pileup(bam_readers, m, flag_filters, threads);
+        return;
+    }
+    let checkpoints = setup_checkpoint_directory(m, "contig");
+    let long_read = long_read_mode(m);
+    let estimators = &mut estimators_and_taker.estimators;
+    let reads_mapped = calculate_coverage_with_checkpoints(
         bam_readers,
+        checkpoints.as_ref(),
         &mut estimators_and_taker.taker,
-        &mut estimators_and_taker.estimators,
-        print_zeros,
-        flag_filters,
-        threads
+        |generators, taker| coverm::contig::contig_coverage(
+            generators,
+            &mut ExcludingCoverageTaker::new(taker, &name_exclusion),
+            estimators,
+            print_zeros,
+            flag_filters.clone(),
+            long_read,
+            threads
+        ),
     );
 
     debug!("Finalising printing ..");
@@ -1770,6 +3383,202 @@ fn run_contig<
     );
 }
 
+fn run_pileup<
+    R: coverm::bam_generator::NamedBamReader,
+    T: coverm::bam_generator::NamedBamReaderGenerator<R>,
+>(
+    bam_readers: Vec<T>,
+    m: &clap::ArgMatches,
+    flag_filters: FlagFilter,
+    threads: usize
+) {
+    let reference_sequences = coverm::pileup::read_reference_sequences(
+        m.value_of("pileup-reference").unwrap());
+    coverm::pileup::pileup(
+        bam_readers,
+        &reference_sequences,
+        value_t!(m.value_of("pileup-min-coverage"), f32).unwrap(),
+        flag_filters,
+        threads,
+        &mut std::io::stdout(),
+    );
+}
+
+/// Print a commented block recording how the output table was generated, if
+/// --provenance-header was specified.
+fn print_provenance_header(
+    m: &clap::ArgMatches,
+    mode: &str,
+    filter_params: &FilterParameters,
+    seed: u64,
+    sample_sheet: &Option<SampleSheet>,
+) {
+    if !m.is_present("provenance-header") {
+        return;
+    }
+    let mut provenance = Provenance::new();
+    provenance.add("CoverM version", crate_version!());
+    provenance.add("Command", env::args().collect::<Vec<String>>().join(" "));
+    provenance.add("Mode", mode);
+    if m.is_present("bam-files") || sample_sheet_bam_samples(sample_sheet).is_some() {
+        provenance.add("Mapper", "none (BAM files given)");
+    } else if m.is_present("depth-files") {
+        provenance.add("Mapper", "none (depth files given)");
+    } else {
+        let mapper = m.value_of("mapper").unwrap();
+        provenance.add(
+            "Mapper",
+            match mapper {
+                "bwa-mem" => format!(
+                    "{} (BWA version {})",
+                    mapper,
+                    external_command_checker::bwa_version()
+                ),
+                _ => format!(
+                    "{} (minimap2 version {})",
+                    mapper,
+                    external_command_checker::executable_version("minimap2")
+                ),
+            },
+        );
+    }
+    provenance.add("Filters", filter_params.provenance_description());
+    if mode == "genome" {
+        provenance.add("Genome definition", genome_definition_source(m));
+    }
+    provenance.add("Seed", seed.to_string());
+    if let Some(references) = m.values_of("reference") {
+        for reference in references {
+            provenance.add("Reference", reference);
+        }
+    }
+    if let Some(host_reference) = m.value_of("host-reference") {
+        provenance.add("Host reference", host_reference);
+    }
+    for sample_inputs in provenance_sample_inputs(m, sample_sheet) {
+        provenance.add("Sample", sample_inputs);
+    }
+    provenance.print(&mut std::io::stdout());
+}
+
+fn genome_definition_source(m: &clap::ArgMatches) -> String {
+    if m.is_present("single-genome") {
+        "single genome".to_string()
+    } else if m.is_present("separator") {
+        format!("separator '{}'", m.value_of("separator").unwrap())
+    } else if m.is_present("genome-definition") {
+        format!("genome definition file {}", m.value_of("genome-definition").unwrap())
+    } else if m.is_present("genome-fasta-files") {
+        format!(
+            "genome FASTA files {}",
+            m.values_of("genome-fasta-files")
+                .unwrap()
+                .collect::<Vec<&str>>()
+                .join(" ")
+        )
+    } else if m.is_present("genome-fasta-directory") {
+        format!(
+            "genome FASTA files in directory {} with extension {}",
+            m.value_of("genome-fasta-directory").unwrap(),
+            m.value_of("genome-fasta-extension").unwrap()
+        )
+    } else {
+        "none".to_string()
+    }
+}
+
+/// The input files of each sample, space separated.
+fn provenance_sample_inputs(m: &clap::ArgMatches, sample_sheet: &Option<SampleSheet>) -> Vec<String> {
+    fn values<'a>(m: &'a clap::ArgMatches, name: &str) -> Vec<&'a str> {
+        match m.values_of(name) {
+            Some(v) => v.collect(),
+            None => vec![],
+        }
+    }
+    let mut samples: Vec<String> = vec![];
+    for name in &["bam-files", "depth-files"] {
+        for file in values(m, name) {
+            samples.push(file.to_string());
+        }
+    }
+    for (read1, read2) in values(m, "read1").iter().zip(values(m, "read2").iter()) {
+        samples.push(format!("{} {}", read1, read2));
+    }
+    for pair in values(m, "coupled").chunks(2) {
+        samples.push(pair.join(" "));
+    }
+    for name in &["interleaved", "single"] {
+        for file in values(m, name) {
+            samples.push(file.to_string());
+        }
+    }
+    match sample_sheet {
+        Some(SampleSheet::Reads(entries)) => {
+            for entry in entries {
+                let mut files = entry.read1.clone();
+                files.extend(entry.read2.iter().cloned());
+                samples.push(format!("{}: {}", entry.name, files.join(" ")));
+            }
+        }
+        Some(SampleSheet::BamFiles(entries)) => {
+            for entry in entries {
+                samples.push(format!("{}: {}", entry.name, entry.bam_files.join(" ")));
+            }
+        }
+        None => {}
+    }
+    samples
+}
+
+// Arguments which do not affect the results calculated for each sample. All
+// other arguments are recorded in checkpoints, so that results are only
+// reused when they are unchanged.
+const NON_CHECKPOINT_PARAMETERS: &[&str] = &[
+    "threads",
+    "parallel-samples",
+    "verbose",
+    "quiet",
+    "full-help",
+    "output-file",
+    "output-directory",
+    "provenance-header",
+    "checkpoint-directory",
+    "resume",
+    "bam-file-cache-directory",
+    "discard-unmapped",
+];
+
+fn setup_checkpoint_directory(m: &clap::ArgMatches, mode: &str) -> Option<CheckpointDirectory> {
+    match m.value_of("checkpoint-directory") {
+        Some(directory) => {
+            let mut parameters = vec![
+                format!("version={}", crate_version!()),
+                format!("mode={}", mode),
+            ];
+            let mut arg_names: Vec<&str> = m
+                .args
+                .keys()
+                .cloned()
+                .filter(|name| !NON_CHECKPOINT_PARAMETERS.contains(name))
+                .collect();
+            arg_names.sort();
+            for name in arg_names {
+                let values: Vec<&str> = m.values_of(name).map(|v| v.collect()).unwrap_or(vec![]);
+                match values.is_empty() {
+                    true => parameters.push(name.to_string()),
+                    false => parameters.push(format!("{}={}", name, values.join(","))),
+                }
+            }
+            Some(CheckpointDirectory::new(
+                directory,
+                parameters.join(" "),
+                m.is_present("resume"),
+            ))
+        }
+        None => None,
+    }
+}
+
 fn set_log_level(matches: &clap::ArgMatches, is_last: bool) {
     let mut log_level = LevelFilter::Info;
     let mut specified = false;
@@ -1795,3 +3604,44 @@ fn set_log_level(matches: &clap::ArgMatches, is_last: bool) {
         info!("CoverM version {}", crate_version!());
     }
 }
+
+/// How stoits are named, from the template given and the names given with
+/// --sample-names, which are matched to samples by their position on the
+/// command line.
+fn setup_stoit_naming(m: &clap::ArgMatches) -> StoitNaming {
+    let mut num_samples = 0;
+    if let Some(bam_files) = m.values_of("bam-files") {
+        num_samples += match m.is_present("sharded") {
+            true => 1,
+            false => bam_files.count(),
+        };
+    }
+    for name in &["depth-files", "read1", "interleaved", "single"] {
+        if let Some(files) = m.values_of(name) {
+            num_samples += files.count();
+        }
+    }
+    if let Some(coupled) = m.values_of("coupled") {
+        num_samples += coupled.count() / 2;
+    }
+    StoitNaming::new(
+        m.value_of("sample-name-template"),
+        m.values_of("sample-names").map(|names| names.collect()),
+        num_samples,
+    )
+}
+
+/// Seed the random number generator all random choices are derived from, and
+/// log the seed so that the run can be reproduced.
+fn set_random_seed(m: &clap::ArgMatches) -> u64 {
+    let seed = random::set_seed(match m.is_present("seed") {
+        true => Some(value_t!(m.value_of("seed"), u64).unwrap()),
+        false => None,
+    });
+    if m.is_present("seed") || m.is_present("sharded") {
+        info!("Using random seed {}", seed);
+    } else {
+        debug!("Using random seed {}", seed);
+    }
+    seed
+}
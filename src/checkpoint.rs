@@ -0,0 +1,516 @@
+use std;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process;
+
+use flate2::Crc;
+
+use bam_cache::file_size_and_mtime;
+use bam_generator::*;
+use coverage_takers::CoverageTaker;
+use ReadsMapped;
+
+fn absolute_path(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(p) => p.to_str().unwrap_or(path).to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// Path, size and modification time of each input file of a stoit, or None
+/// if reads are from STDIN and so cannot be checked to be unchanged.
+fn stoit_input_fingerprints(input_paths: &[String]) -> Option<Vec<String>> {
+    let mut fingerprints = vec![];
+    for path in input_paths {
+        if path == "-" {
+            return None;
+        }
+        let absolute = absolute_path(path);
+        fingerprints.push(match file_size_and_mtime(path) {
+            Ok((size, mtime)) => format!("{}\t{}\t{}", absolute, size, mtime),
+            Err(_) => absolute,
+        });
+    }
+    Some(fingerprints)
+}
+
+/// Calls made on a CoverageTaker while calculating coverage of one stoit,
+/// recorded so they can be replayed when resuming.
+#[derive(PartialEq, Debug)]
+pub enum CoverageEvent {
+    StartStoit(String),
+    StartEntry(usize, String),
+    SingleCoverage(f32),
+    CoverageEntry(usize, u32),
+    FinishEntry,
+}
+
+/// A CoverageTaker which passes all calls through to another taker, and
+/// optionally records them.
+pub struct RecordingCoverageTaker<'a, T: 'a + CoverageTaker> {
+    inner: &'a mut T,
+    events: Option<Vec<CoverageEvent>>,
+}
+
+impl<'a, T: CoverageTaker> RecordingCoverageTaker<'a, T> {
+    pub fn new(inner: &'a mut T, record: bool) -> RecordingCoverageTaker<'a, T> {
+        RecordingCoverageTaker {
+            inner: inner,
+            events: match record {
+                true => Some(vec![]),
+                false => None,
+            },
+        }
+    }
+
+    pub fn into_events(self) -> Vec<CoverageEvent> {
+        self.events.unwrap_or(vec![])
+    }
+
+    fn record(&mut self, event: CoverageEvent) {
+        if let Some(ref mut events) = self.events {
+            events.push(event)
+        }
+    }
+}
+
+impl<'a, T: CoverageTaker> CoverageTaker for RecordingCoverageTaker<'a, T> {
+    fn start_stoit(&mut self, stoit_name: &str) {
+        self.inner.start_stoit(stoit_name);
+        self.record(CoverageEvent::StartStoit(stoit_name.to_string()));
+    }
+    fn start_entry(&mut self, entry_order_id: usize, entry_name: &str) {
+        self.inner.start_entry(entry_order_id, entry_name);
+        self.record(CoverageEvent::StartEntry(entry_order_id, entry_name.to_string()));
+    }
+    fn add_single_coverage(&mut self, coverage: f32) {
+        self.inner.add_single_coverage(coverage);
+        self.record(CoverageEvent::SingleCoverage(coverage));
+    }
+    fn add_coverage_entry(&mut self, num_reads: usize, num_bases: u32) {
+        self.inner.add_coverage_entry(num_reads, num_bases);
+        self.record(CoverageEvent::CoverageEntry(num_reads, num_bases));
+    }
+    fn finish_entry(&mut self) {
+        self.inner.finish_entry();
+        self.record(CoverageEvent::FinishEntry);
+    }
+}
+
+/// A CoverageTaker which only records calls, so that they can be replayed
+/// on another taker later.
+pub struct CoverageEventRecorder {
+    pub events: Vec<CoverageEvent>,
+}
+
+impl CoverageEventRecorder {
+    pub fn new() -> CoverageEventRecorder {
+        CoverageEventRecorder { events: vec![] }
+    }
+}
+
+impl CoverageTaker for CoverageEventRecorder {
+    fn start_stoit(&mut self, stoit_name: &str) {
+        self.events.push(CoverageEvent::StartStoit(stoit_name.to_string()));
+    }
+    fn start_entry(&mut self, entry_order_id: usize, entry_name: &str) {
+        self.events.push(CoverageEvent::StartEntry(entry_order_id, entry_name.to_string()));
+    }
+    fn add_single_coverage(&mut self, coverage: f32) {
+        self.events.push(CoverageEvent::SingleCoverage(coverage));
+    }
+    fn add_coverage_entry(&mut self, num_reads: usize, num_bases: u32) {
+        self.events.push(CoverageEvent::CoverageEntry(num_reads, num_bases));
+    }
+    fn finish_entry(&mut self) {
+        self.events.push(CoverageEvent::FinishEntry);
+    }
+}
+
+/// Make the recorded calls on the given CoverageTaker.
+pub fn replay_events<T: CoverageTaker>(events: &[CoverageEvent], taker: &mut T) {
+    for event in events {
+        match event {
+            CoverageEvent::StartStoit(name) => taker.start_stoit(name),
+            CoverageEvent::StartEntry(id, name) => taker.start_entry(*id, name),
+            CoverageEvent::SingleCoverage(c) => taker.add_single_coverage(*c),
+            CoverageEvent::CoverageEntry(reads, bases) => {
+                taker.add_coverage_entry(*reads, *bases)
+            }
+            CoverageEvent::FinishEntry => taker.finish_entry(),
+        }
+    }
+}
+
+/// Results of one completed stoit.
+#[derive(PartialEq, Debug)]
+pub struct SampleCheckpoint {
+    pub events: Vec<CoverageEvent>,
+    pub reads_mapped: ReadsMapped,
+}
+
+impl SampleCheckpoint {
+    pub fn replay<T: CoverageTaker>(&self, taker: &mut T) {
+        replay_events(&self.events, taker)
+    }
+}
+
+/// A working directory where the results of each stoit are written as they
+/// are completed, so that an interrupted run can be resumed. Each checkpoint
+/// records the parameters and input files it was generated with, and is only
+/// reused when those are unchanged.
+pub struct CheckpointDirectory {
+    path: PathBuf,
+    parameters: String,
+    resume: bool,
+}
+
+impl CheckpointDirectory {
+    pub fn new(path: &str, parameters: String, resume: bool) -> CheckpointDirectory {
+        let dir = std::path::Path::new(path);
+        if !dir.exists() {
+            info!("Creating checkpoint directory {}", path);
+            std::fs::create_dir_all(dir)
+                .expect(&format!("Unable to create checkpoint directory {}", path));
+        } else if !dir.is_dir() {
+            error!("Checkpoint directory {} is not a directory", path);
+            process::exit(1);
+        }
+        CheckpointDirectory {
+            path: dir.to_path_buf(),
+            // Newlines and tabs would corrupt the file format
+            parameters: parameters.replace("\n", " ").replace("\t", " "),
+            resume: resume,
+        }
+    }
+
+    // Stoits of the same name generated from different input files, e.g. BAM
+    // files of the same name in different directories, are checkpointed
+    // separately. Only the paths of the input files are hashed, so that when
+    // an input file changes its checkpoint is replaced.
+    fn checkpoint_path(&self, stoit_name: &str, input_paths: &[String]) -> PathBuf {
+        let mut file_name = String::new();
+        for c in stoit_name.chars() {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                file_name.push(c);
+            } else {
+                for b in c.to_string().as_bytes() {
+                    file_name.push_str(&format!("%{:02X}", b));
+                }
+            }
+        }
+        let mut crc = Crc::new();
+        for path in input_paths {
+            crc.update(format!("{}\n", absolute_path(path)).as_bytes());
+        }
+        file_name.push_str(&format!(".{:08x}.coverm-checkpoint", crc.sum()));
+        self.path.join(file_name)
+    }
+
+    /// Return the checkpoint of a previously completed stoit, if resuming
+    /// and it was calculated with the same parameters and input files.
+    pub fn load(&self, stoit_name: &str, input_paths: &[String]) -> Option<SampleCheckpoint> {
+        if !self.resume {
+            return None;
+        }
+        let path = self.checkpoint_path(stoit_name, input_paths);
+        let file = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(_) => {
+                debug!("No checkpoint found for {} at {:?}", stoit_name, path);
+                return None;
+            }
+        };
+        let mut parameters = None;
+        let mut inputs = vec![];
+        let mut num_mapped_reads = None;
+        let mut num_reads = None;
+        let mut num_mapped_bases = None;
+        let mut events = vec![];
+        for line_result in BufReader::new(file).lines() {
+            let line = line_result.expect(&format!("Failed to read checkpoint file {:?}", path));
+            let fields: Vec<&str> = line.splitn(3, '\t').collect();
+            match fields[0] {
+                "parameters" if fields.len() >= 2 => {
+                    parameters = Some(fields[1..].join("\t"))
+                }
+                "input" if fields.len() >= 2 => inputs.push(fields[1..].join("\t")),
+                "num_mapped_reads" if fields.len() == 2 => {
+                    num_mapped_reads = Some(parse_field::<u64>(fields[1], &line, &path))
+                }
+                "num_reads" if fields.len() == 2 => {
+                    num_reads = Some(parse_field::<u64>(fields[1], &line, &path))
+                }
+                "num_mapped_bases" if fields.len() == 2 => {
+                    num_mapped_bases = Some(parse_field::<u64>(fields[1], &line, &path))
+                }
+                "start_stoit" if fields.len() >= 2 => events.push(
+                    CoverageEvent::StartStoit(fields[1..].join("\t"))),
+                "start_entry" if fields.len() == 3 => events.push(
+                    CoverageEvent::StartEntry(
+                        parse_field::<usize>(fields[1], &line, &path),
+                        fields[2].to_string())),
+                "coverage" if fields.len() == 2 => events.push(
+                    CoverageEvent::SingleCoverage(
+                        parse_field::<f32>(fields[1], &line, &path))),
+                "coverage_entry" if fields.len() == 3 => events.push(
+                    CoverageEvent::CoverageEntry(
+                        parse_field::<usize>(fields[1], &line, &path),
+                        parse_field::<u32>(fields[2], &line, &path))),
+                "finish_entry" => events.push(CoverageEvent::FinishEntry),
+                _ => checkpoint_parse_failure(&line, &path),
+            }
+        }
+        if parameters.as_ref() != Some(&self.parameters) {
+            info!("Not reusing checkpoint for {} since it was generated with \
+                   different parameters", stoit_name);
+            return None;
+        }
+        match stoit_input_fingerprints(input_paths) {
+            None => {
+                info!("Not reusing checkpoint for {} since its reads are from STDIN",
+                      stoit_name);
+                return None;
+            }
+            Some(ref fingerprints) if *fingerprints != inputs => {
+                info!("Not reusing checkpoint for {} since its input files have \
+                       changed", stoit_name);
+                debug!("Old inputs: {:?}, new inputs: {:?}", inputs, fingerprints);
+                return None;
+            }
+            Some(_) => {}
+        }
+        match (num_mapped_reads, num_reads) {
+            (Some(num_mapped_reads), Some(num_reads)) => Some(SampleCheckpoint {
+                events: events,
+                reads_mapped: ReadsMapped {
+                    num_mapped_reads: num_mapped_reads,
+                    num_reads: num_reads,
+                    num_mapped_bases: num_mapped_bases,
+                },
+            }),
+            _ => {
+                warn!("Checkpoint file {:?} is incomplete, not reusing it", path);
+                None
+            }
+        }
+    }
+
+    /// Write the checkpoint for a completed stoit. The file is written to a
+    /// temporary name first, so that a partially written checkpoint is never
+    /// mistaken for a complete one.
+    pub fn save(
+        &self,
+        stoit_name: &str,
+        input_paths: &[String],
+        events: &[CoverageEvent],
+        reads_mapped: &ReadsMapped,
+    ) {
+        let path = self.checkpoint_path(stoit_name, input_paths);
+        let tmp_path = path.with_extension("coverm-checkpoint.tmp");
+        {
+            let mut f = std::io::BufWriter::new(
+                std::fs::File::create(&tmp_path)
+                    .expect(&format!("Failed to create checkpoint file {:?}", tmp_path)));
+            let mut write = || -> std::io::Result<()> {
+                writeln!(f, "parameters\t{}", self.parameters)?;
+                for input in stoit_input_fingerprints(input_paths).unwrap_or(vec![]) {
+                    writeln!(f, "input\t{}", input)?;
+                }
+                writeln!(f, "num_mapped_reads\t{}", reads_mapped.num_mapped_reads)?;
+                writeln!(f, "num_reads\t{}", reads_mapped.num_reads)?;
+                if let Some(num_mapped_bases) = reads_mapped.num_mapped_bases {
+                    writeln!(f, "num_mapped_bases\t{}", num_mapped_bases)?;
+                }
+                for event in events {
+                    match event {
+                        CoverageEvent::StartStoit(name) =>
+                            writeln!(f, "start_stoit\t{}", name)?,
+                        CoverageEvent::StartEntry(id, name) =>
+                            writeln!(f, "start_entry\t{}\t{}", id, name)?,
+                        CoverageEvent::SingleCoverage(c) =>
+                            writeln!(f, "coverage\t{}", c)?,
+                        CoverageEvent::CoverageEntry(reads, bases) =>
+                            writeln!(f, "coverage_entry\t{}\t{}", reads, bases)?,
+                        CoverageEvent::FinishEntry =>
+                            writeln!(f, "finish_entry")?,
+                    }
+                }
+                f.flush()
+            };
+            write().expect(&format!("Failed to write checkpoint file {:?}", tmp_path));
+        }
+        std::fs::rename(&tmp_path, &path)
+            .expect(&format!("Failed to move checkpoint file into place at {:?}", path));
+        debug!("Wrote checkpoint for {} to {:?}", stoit_name, path);
+    }
+}
+
+fn checkpoint_parse_failure(line: &str, path: &PathBuf) -> ! {
+    error!("Failed to parse line '{}' in checkpoint file {:?}", line, path);
+    process::exit(1);
+}
+
+fn parse_field<F: std::str::FromStr>(field: &str, line: &str, path: &PathBuf) -> F {
+    match field.parse::<F>() {
+        Ok(f) => f,
+        Err(_) => checkpoint_parse_failure(line, path),
+    }
+}
+
+/// Calculate coverage of each stoit in turn, reusing the results of stoits
+/// already completed in a previous run when resuming, and saving the results
+/// of newly completed stoits to the checkpoint directory. Without a
+/// checkpoint directory, all stoits are calculated in a single call.
+pub fn calculate_coverage_with_checkpoints<R, G, T, F>(
+    bam_generators: Vec<G>,
+    checkpoints: Option<&CheckpointDirectory>,
+    coverage_taker: &mut T,
+    mut calculate: F,
+) -> Vec<ReadsMapped>
+where
+    R: NamedBamReader,
+    G: NamedBamReaderGenerator<R>,
+    T: CoverageTaker,
+    F: FnMut(Vec<G>, &mut RecordingCoverageTaker<T>) -> Vec<ReadsMapped>,
+{
+    let checkpoints = match checkpoints {
+        Some(c) => c,
+        None => {
+            return calculate(
+                bam_generators,
+                &mut RecordingCoverageTaker::new(coverage_taker, false),
+            )
+        }
+    };
+
+    let mut reads_mapped = vec![];
+    for bam_generator in bam_generators {
+        let stoit_name = bam_generator.stoit_name().to_string();
+        let input_paths = bam_generator.input_paths().to_vec();
+        match checkpoints.load(&stoit_name, &input_paths) {
+            Some(checkpoint) => {
+                info!("Reusing checkpointed results for {}", stoit_name);
+                checkpoint.replay(&mut *coverage_taker);
+                reads_mapped.push(checkpoint.reads_mapped);
+            }
+            None => {
+                let (events, mut stoit_reads_mapped) = {
+                    let mut recorder = RecordingCoverageTaker::new(&mut *coverage_taker, true);
+                    let stoit_reads_mapped = calculate(vec![bam_generator], &mut recorder);
+                    (recorder.into_events(), stoit_reads_mapped)
+                };
+                assert_eq!(1, stoit_reads_mapped.len());
+                let stoit_reads_mapped = stoit_reads_mapped.pop().unwrap();
+                checkpoints.save(&stoit_name, &input_paths, &events, &stoit_reads_mapped);
+                reads_mapped.push(stoit_reads_mapped);
+            }
+        }
+    }
+    reads_mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coverage_takers::*;
+
+    #[test]
+    fn test_checkpoint_save_and_resume() {
+        let td = tempfile::TempDir::new().unwrap();
+        let dir = td.path().join("checkpoints");
+        let checkpoints = CheckpointDirectory::new(
+            dir.to_str().unwrap(), "methods=mean".to_string(), true);
+
+        let mut stream: Vec<u8> = vec![];
+        let events = {
+            let mut taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(&mut stream);
+            let mut recorder = RecordingCoverageTaker::new(&mut taker, true);
+            recorder.start_stoit("ref.fna/sample 1");
+            recorder.start_entry(0, "contig1");
+            recorder.add_single_coverage(1.5);
+            recorder.finish_entry();
+            recorder.into_events()
+        };
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: 3,
+            num_reads: 10,
+            num_mapped_bases: None,
+        };
+        let inputs = vec!["tests/data/2seqs.bad_read.1.bam".to_string()];
+        checkpoints.save("ref.fna/sample 1", &inputs, &events, &reads_mapped);
+
+        let loaded = checkpoints.load("ref.fna/sample 1", &inputs).unwrap();
+        assert_eq!(events, loaded.events);
+        assert_eq!(reads_mapped, loaded.reads_mapped);
+        assert_eq!(None, checkpoints.load("ref.fna/sample 2", &inputs));
+
+        let mut replayed: Vec<u8> = vec![];
+        {
+            let mut taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(&mut replayed);
+            loaded.replay(&mut taker);
+        }
+        assert_eq!(stream, replayed);
+
+        let changed = CheckpointDirectory::new(
+            dir.to_str().unwrap(), "methods=trimmed_mean".to_string(), true);
+        assert_eq!(None, changed.load("ref.fna/sample 1", &inputs));
+
+        let not_resuming = CheckpointDirectory::new(
+            dir.to_str().unwrap(), "methods=mean".to_string(), false);
+        assert_eq!(None, not_resuming.load("ref.fna/sample 1", &inputs));
+    }
+
+    #[test]
+    fn test_checkpoint_not_reused_when_inputs_change() {
+        let td = tempfile::TempDir::new().unwrap();
+        let dir = td.path().join("checkpoints");
+        let checkpoints = CheckpointDirectory::new(
+            dir.to_str().unwrap(), "methods=mean".to_string(), true);
+        let bam = td.path().join("sample.bam");
+        std::fs::write(&bam, "original").unwrap();
+        let inputs = vec![bam.to_str().unwrap().to_string()];
+
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: 3,
+            num_reads: 10,
+            num_mapped_bases: None,
+        };
+        checkpoints.save("changing_sample", &inputs, &[], &reads_mapped);
+        assert!(checkpoints.load("changing_sample", &inputs).is_some());
+
+        // A different file of the same name
+        std::fs::write(&bam, "a different sample").unwrap();
+        assert_eq!(None, checkpoints.load("changing_sample", &inputs));
+
+        let stdin = vec!["-".to_string()];
+        checkpoints.save("stdin_sample", &stdin, &[], &reads_mapped);
+        assert_eq!(None, checkpoints.load("stdin_sample", &stdin));
+    }
+
+    #[test]
+    fn test_checkpoints_of_same_named_stoits_are_separate() {
+        let td = tempfile::TempDir::new().unwrap();
+        let checkpoints = CheckpointDirectory::new(
+            td.path().join("checkpoints").to_str().unwrap(), "methods=mean".to_string(), true);
+        for directory in &["a", "b"] {
+            std::fs::create_dir(td.path().join(directory)).unwrap();
+            std::fs::write(td.path().join(directory).join("s.bam"), directory).unwrap();
+        }
+        let inputs_a = vec![td.path().join("a/s.bam").to_str().unwrap().to_string()];
+        let inputs_b = vec![td.path().join("b/s.bam").to_str().unwrap().to_string()];
+        let reads_mapped = |num_reads| ReadsMapped {
+            num_mapped_reads: 3,
+            num_reads: num_reads,
+            num_mapped_bases: None,
+        };
+        checkpoints.save("s", &inputs_a, &[], &reads_mapped(10));
+        assert_eq!(None, checkpoints.load("s", &inputs_b));
+        checkpoints.save("s", &inputs_b, &[], &reads_mapped(20));
+
+        assert_eq!(10, checkpoints.load("s", &inputs_a).unwrap().reads_mapped.num_reads);
+        assert_eq!(20, checkpoints.load("s", &inputs_b).unwrap().reads_mapped.num_reads);
+    }
+}
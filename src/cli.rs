@@ -13,14 +13,78 @@ const MAPPER_HELP: &'static str =
                                          [default: \"minimap2-sr\"]";
 
 pub fn filter_full_help() -> &'static str {
-    "coverm filter: Remove alignments with insufficient identity.
+    lazy_static! {
+        static ref FILTER_FULL_HELP: String = format!(
+        "coverm filter: Remove alignments with insufficient identity.
 
-Only primary, non-supplementary alignments are considered, and output files
+Only primary, non-supplementary alignments are considered (supplementary
+alignments are kept with --long-read), and output files
 are grouped by reference, but not sorted by position.
 
-Files (both required):
+Define mapping(s) (required):
+  Either define BAM:
    -b, --bam-files <PATH> ..             Path to reference-sorted BAM file(s)
-   -o, --output-bam-files <PATH> ..      Path to corresponding output file(s)
+
+  Or do mapping, without writing unfiltered BAM files:
+   -r, --reference <PATH> ..             FASTA file of contigs e.g. concatenated
+                                         genomes or assembly, or minimap2 index
+                                         (with --minimap2-reference-is-index),
+                                         or BWA index stem (with -p bwa-mem).
+   -1 <PATH> ..                          Forward FASTA/Q file(s) for mapping
+   -2 <PATH> ..                          Reverse FASTA/Q file(s) for mapping
+   -c, --coupled <PATH> <PATH> ..        One or more pairs of forward and reverse
+                                         FASTA/Q files for mapping in order
+                                         <sample1_R1.fq.gz> <sample1_R2.fq.gz>
+                                         <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
+   --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
+   --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
+{}
+   --minimap2-params PARAMS              Extra parameters to provide to minimap2,
+                                         both indexing command (if used) and for
+                                         mapping. Note that usage of this parameter
+                                         has security implications if untrusted input
+                                         is specified. '-a' is always specified.
+                                         [default \"\"]
+   --minimap2-reference-is-index         Treat reference as a minimap2 database, not
+                                         as a FASTA file.
+   --bwa-params PARAMS                   Extra parameters to provide to BWA. Note
+                                         that usage of this parameter has security
+                                         implications if untrusted input is specified.
+                                         [default \"\"]
+
+Output (one type required):
+   -o, --output-bam-files <PATH> ..      Path to corresponding output file(s).
+                                         When mapping, one file is needed for
+                                         each set of reads against each reference,
+                                         with sets of reads in the order -1/-2,
+                                         -c, --interleaved, --single then
+                                         --sample-sheet samples.
+   --output-fastq <PATH> ..              Write the reads of alignments passing
+                                         the thresholds to corresponding
+                                         gzip-compressed FASTQ file(s) instead.
+                                         Each read is written once, and a pair
+                                         only when both of its reads pass. Pairs
+                                         are interleaved unless
+                                         --output-fastq-2 is specified.
+   --output-fastq-2 <PATH> ..            Write the second read of each pair to
+                                         these file(s), and the first read to
+                                         --output-fastq.
+
+Restricting to genomes (optional):
+   --genome-definition <FILE>            Only keep alignments to contigs in this
+                                         file of genome and contig names, one
+                                         pair per line separated by a tab.
+   -f, --genome-fasta-files <PATH> ..    Only keep alignments to contigs of these
+                                         genome FASTA files.
 
 Thresholds:
    --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
@@ -32,24 +96,31 @@ Thresholds:
                                          bases must be aligned. [default 0.0]
    --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
                                          aligned bases.
-                                         Implies --proper-pairs-only. [default: 0]
+                                         Mates must map to the same contig.
+                                         [default: 0]
    --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
                                          identity e.g. 0.95 for 95%.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
                                          bases e.g. 0.95 means 95% of the read's
                                          bases must be aligned.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are kept and percent identity
+                                         is gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
 
 Other:
-   -t, --threads <INT>                   Number of threads for output compression
-                                         [default 1]
+   -t, --threads <INT>                   Number of threads for mapping and output
+                                         compression [default 1]
    --inverse                             Only keep reads which are unmapped or
                                          align below thresholds. Note that output
                                          records may still be marked as mapped
                                          if they do not meet the thresholds.
-                                         [default false]
+                                         Requires --bam-files. [default false]
    --verbose                             Print extra debugging information
    -q, --quiet                           Unless there is an error, do not print
                                          log messages
@@ -58,7 +129,16 @@ Example usage:
 
   coverm filter -b in.bam -o out.bam --min-read-aligned-length 75
 
-Ben J. Woodcroft <benjwoodcroft near gmail.com>"
+  coverm filter -b in.bam --output-fastq out.1.fq.gz --output-fastq-2
+    out.2.fq.gz --min-read-percent-identity 0.95 -f genome1.fna genome2.fna
+
+  coverm filter -r assembly.fna -1 read1.fq.gz -2 read2.fq.gz -o out.bam
+    --min-read-percent-identity 0.95 --threads 16
+
+Ben J. Woodcroft <benjwoodcroft near gmail.com>
+", MAPPER_HELP);
+    }
+    &FILTER_FULL_HELP
 }
 
 pub fn contig_full_help() -> &'static str {
@@ -72,7 +152,24 @@ Define mapping(s) (required):
                                          reference sorted (e.g. with samtools sort)
                                          unless --sharded is specified, in which
                                          case they must be read name sorted (e.g.
-                                         with samtools sort -n).
+                                         with samtools sort -n) or all coordinate
+                                         sorted.
+   --sample-sheet <PATH>                 Sample sheet as described below, with
+                                         read format 'bam' and a coordinate
+                                         sorted BAM file as read1. BAM files on
+                                         lines with the same sample name (e.g.
+                                         sequencing runs) are merged into one
+                                         sample.
+
+  Or define per-base depth:
+   --depth-files <PATH> ..               Path to per-base depth file(s) from
+                                         'samtools depth -aa' (of a single BAM
+                                         file each) or mosdepth (.per-base.bed.gz),
+                                         instead of BAM files. Methods requiring
+                                         read counts (count, rpkm, reads_per_base)
+                                         and alignment filtering are unavailable,
+                                         and relative_abundance does not account
+                                         for unmapped reads.
 
   Or do mapping:
    -r, --reference <PATH> ..             FASTA file of contigs e.g. concatenated 
@@ -93,6 +190,15 @@ Define mapping(s) (required):
                                          <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
    --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
    --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
 {}
    --minimap2-params PARAMS              Extra parameters to provide to minimap2,
                                          both indexing command (if used) and for
@@ -106,17 +212,47 @@ Define mapping(s) (required):
                                          that usage of this parameter has security
                                          implications if untrusted input is specified.
                                          [default \"\"]
+   --host-reference <PATH>               FASTA file of a host or contaminant
+                                         genome (or BWA index stem with -p
+                                         bwa-mem). Reads are mapped to it first,
+                                         and reads (or pairs) mapping to it are
+                                         removed before mapping to the reference,
+                                         so they are not counted as mapped or
+                                         unmapped. The number of reads removed is
+                                         logged for each sample.
 
 Sharding i.e. multiple reference sets (optional):
    --sharded (experimental)              If -b/--bam-files was used:
-                                           Input BAM files are read-sorted alignments
-                                           of a set of reads mapped to multiple
-                                           reference contig sets. Choose the best
-                                           hit for each read pair.
+                                           Input BAM files are alignments of a set
+                                           of reads mapped to multiple reference
+                                           contig sets, all sorted by read name or
+                                           all coordinate sorted (as declared in
+                                           their headers). Choose the best hit for
+                                           each read pair.
 
                                          Otherwise if mapping was carried out:
                                            Map reads to each reference, choosing the
                                            best hit for each pair.
+   --deshard-by <METHOD>                 How to choose the best hit for each read
+                                         (pair): 'alignment-score' (highest AS
+                                         tag), 'edit-distance' (lowest NM tag) or
+                                         'percent-identity'.
+                                         [default: alignment-score]
+   --discard-deshard-ties                Discard reads whose best hit is tied
+                                         between shards, rather than choosing one
+                                         of the tied shards at random.
+
+Excluding contigs (optional):
+   --exclude-list <FILE>                 Exclude contigs whose name appears in
+                                         this newline-separated file e.g. host
+                                         or PhiX contigs.
+   --exclude-regex <REGEX>               Exclude contigs whose name matches this
+                                         regular expression.
+   --excluded-reads <TREATMENT>          What happens to reads mapped to excluded
+                                         contigs: 'unmapped' to count them as
+                                         unmapped, or 'remove' to remove them
+                                         from all read counts.
+                                         [default: unmapped]
 
 Alignment filtering (optional):
    --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
@@ -128,15 +264,33 @@ Alignment filtering (optional):
                                          bases must be aligned. [default 0.0]
    --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
                                          aligned bases.
-                                         Implies --proper-pairs-only. [default: 0]
+                                         Mates must map to the same contig.
+                                         [default: 0]
    --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
                                          identity e.g. 0.95 for 95%.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
                                          bases e.g. 0.95 means 95% of the read's
                                          bases must be aligned.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same contig.
+                                         [default 0.0]
    --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are included, each read is
+                                         counted once, and bpkm is available in
+                                         place of rpkm. Percent identity is
+                                         gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
+
+MetaBAT adjusted coverage (optional, with -m metabat):
+   Options of MetaBAT's jgi_summarize_bam_contig_depths correspond to:
+     --percentIdentity <INT>             --min-read-percent-identity
+                                         [default in metabat mode: 97]
+     --includeEdgeBases                  --contig-end-exclusion 0
+     --maxEdgeBases <INT>                --contig-end-exclusion <INT>
+     --noIntraDepthVariance              --no-intra-depth-variance
+   --no-intra-depth-variance             Omit the variance column of each sample.
 
 Other arguments (optional):
    -m, --methods <METHOD> [METHOD ..]    Method(s) for calculating coverage.
@@ -152,13 +306,41 @@ Other arguments (optional):
                                            metabat (\"MetaBAT adjusted coverage\")
                                            reads_per_base
                                            rpkm
+                                           bpkm (long reads, bases per kilobase
+                                             per million aligned bases)
+                                           snv_count (positions differing from
+                                             the reference, see --snv-reference)
+                                           snv_density (SNVs per kb)
+                                           nucleotide_diversity (mean pi)
+                                           major_allele_frequency
                                          A more thorough description of the different
                                          methods is available at
                                          https://github.com/wwood/CoverM
 
    --output-format FORMAT                Shape of output: 'sparse' for long format,
-                                         'dense' for species-by-site.
+                                         'dense' for species-by-site, or the
+                                         coverage input of a binner:
+                                           metabat (same as -m metabat)
+                                           concoct
+                                           vamb (for --abundance_tsv)
+                                           maxbin2 (for -abund)
+                                           semibin (for --abundance)
+                                         Binner formats other than metabat
+                                         report mean coverage. The maxbin2 and
+                                         semibin formats write one file per
+                                         sample to --output-directory.
+                                         Alternatively, 'pileup' for counts of
+                                         each base, deletions and insertions at
+                                         each position of each contig.
                                          [default: dense]
+   --output-directory <DIR>              Directory for per-sample abundance files
+                                         of the maxbin2 and semibin formats.
+   --pileup-reference <FASTA>            Reference sequences of the contigs, to
+                                         report reference bases in pileup output.
+                                         Required for --output-format pileup.
+   --pileup-min-coverage <FLOAT>         Only output the pileup of contigs with
+                                         at least this mean depth in a sample
+                                         [default: 0]
    --min-covered-fraction FRACTION       Contigs with less coverage than this
                                          reported as having zero coverage.
                                          [default: 0]
@@ -169,11 +351,59 @@ Other arguments (optional):
                                          [default: 0.05]
    --trim-max FRACTION                   Maximum fraction for trimmed_mean
                                          calculations [default: 0.95]
+   --allele-min-depth <INT>              Only use positions with at least this
+                                         many aligned bases for snv_count,
+                                         snv_density, nucleotide_diversity and
+                                         major_allele_frequency. [default: 5]
+   --snv-min-allele-frequency <FLOAT>    Count positions as SNVs when an allele
+                                         other than the reference base has at
+                                         least this frequency, and is seen in
+                                         at least 2 reads. [default: 0.05]
+   --snv-reference <FASTA> ..            Reference sequences of the contigs,
+                                         which SNVs are called against.
+                                         Required for snv_count and
+                                         snv_density.
    --no-zeros                            Omit printing of genomes that have zero
                                          coverage
+   --sample-names <NAME> ..              Name each sample, in the order of -b,
+                                         --depth-files, or -1/-2, -c,
+                                         --interleaved then --single read sets,
+                                         instead of by its file name.
+   --sample-name-template <TEMPLATE>     Name samples by this template, where
+                                         {{reference}} is the reference file name
+                                         (empty when no reference is used),
+                                         {{file}} is the read1, BAM or depth file
+                                         name, and {{sample}} is the sample name,
+                                         or the file name without read direction
+                                         (e.g. _R1) or extensions. For instance
+                                         '{{sample}}' drops the reference prefix.
+                                         [default: {{reference}}/{{file}} when
+                                         mapping, otherwise {{file}}]
    --bam-file-cache-directory            Output BAM files generated during
-                                         alignment to this directory
+                                         alignment to this directory. A manifest
+                                         of the inputs and mapping parameters
+                                         is stored alongside each BAM file, so
+                                         that it is reused by later runs with
+                                         unchanged inputs. BAM files cached from
+                                         the same reference and reads with other
+                                         inputs or parameters are removed.
    --discard-unmapped                    Exclude unmapped reads from cached BAM files.
+   --checkpoint-directory <DIR>          Write the results of each sample to this
+                                         directory as it is completed.
+   --resume                              Reuse results in the checkpoint directory
+                                         from samples already completed with the
+                                         same parameters and unchanged input
+                                         files, rather than calculating them
+                                         again.
+   --seed <INT>                          Seed for all random choices (e.g. between
+                                         tied shards), so that reruns give
+                                         identical results. The seed used is
+                                         logged. [default: random]
+   --provenance-header                   Print lines starting with '#' before the
+                                         output table recording the CoverM
+                                         version, command line, mapper version,
+                                         read filters, genome definition and the
+                                         input files of each sample.
    -v, --verbose                         Print extra debugging information
    -q, --quiet                           Unless there is an error, do not print
                                          log messages
@@ -210,7 +440,24 @@ Define mapping(s) (required):
                                          reference sorted (e.g. with samtools sort)
                                          unless --sharded is specified, in which
                                          case they must be read name sorted (e.g.
-                                         with samtools sort -n).
+                                         with samtools sort -n) or all coordinate
+                                         sorted.
+   --sample-sheet <PATH>                 Sample sheet as described below, with
+                                         read format 'bam' and a coordinate
+                                         sorted BAM file as read1. BAM files on
+                                         lines with the same sample name (e.g.
+                                         sequencing runs) are merged into one
+                                         sample.
+
+  Or define per-base depth:
+   --depth-files <PATH> ..               Path to per-base depth file(s) from
+                                         'samtools depth -aa' (of a single BAM
+                                         file each) or mosdepth (.per-base.bed.gz),
+                                         instead of BAM files. Methods requiring
+                                         read counts (count, rpkm, reads_per_base)
+                                         and alignment filtering are unavailable,
+                                         and relative_abundance does not account
+                                         for unmapped reads.
 
   Or do mapping:
 {}
@@ -232,6 +479,15 @@ Define mapping(s) (required):
                                          <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
    --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
    --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
    --minimap2-params PARAMS              Extra parameters to provide to minimap2,
                                          both indexing command (if used) and for
                                          mapping. Note that usage of this parameter
@@ -244,21 +500,53 @@ Define mapping(s) (required):
                                          that usage of this parameter has security
                                          implications if untrusted input is specified.
                                          [default \"\"]
+   --host-reference <PATH>               FASTA file of a host or contaminant
+                                         genome (or BWA index stem with -p
+                                         bwa-mem). Reads are mapped to it first,
+                                         and reads (or pairs) mapping to it are
+                                         removed before mapping to the reference,
+                                         so they are not counted as mapped or
+                                         unmapped. The number of reads removed is
+                                         logged for each sample.
 
 Sharding i.e. multiple reference sets (optional):
    --sharded (experimental)              If -b/--bam-files was used:
-                                           Input BAM files are read-sorted alignments
-                                           of a set of reads mapped to multiple
-                                           reference contig sets. Choose the best
-                                           hit for each read pair.
+                                           Input BAM files are alignments of a set
+                                           of reads mapped to multiple reference
+                                           contig sets, all sorted by read name or
+                                           all coordinate sorted (as declared in
+                                           their headers). Choose the best hit for
+                                           each read pair.
 
                                          Otherwise if mapping was carried out:
                                            Map reads to each reference, choosing the
                                            best hit for each pair.
+   --deshard-by <METHOD>                 How to choose the best hit for each read
+                                         (pair): 'alignment-score' (highest AS
+                                         tag), 'edit-distance' (lowest NM tag) or
+                                         'percent-identity'.
+                                         [default: alignment-score]
+   --discard-deshard-ties                Discard reads whose best hit is tied
+                                         between shards, rather than choosing one
+                                         of the tied shards at random.
    --exclude-genomes-from-deshard <FILE> Ignore genomes whose name appears in this
                                          newline-separated file when combining shards.
 
 
+Excluding genomes and contigs (optional):
+   --exclude-list <FILE>                 Exclude genomes or contigs whose name
+                                         appears in this newline-separated file
+                                         e.g. host or PhiX genomes.
+   --exclude-regex <REGEX>               Exclude genomes or contigs whose name
+                                         matches this regular expression.
+   --excluded-reads <TREATMENT>          What happens to reads mapped to excluded
+                                         genomes or contigs: 'unmapped' to count
+                                         them as unmapped e.g. as part of the
+                                         unmapped fraction in relative
+                                         abundance, or 'remove' to remove them
+                                         from all read counts.
+                                         [default: unmapped]
+
 Alignment filtering (optional):
    --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
                                          aligned bases [default: 0]
@@ -269,15 +557,24 @@ Alignment filtering (optional):
                                          bases must be aligned. [default 0.0]
    --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
                                          aligned bases.
-                                         Implies --proper-pairs-only. [default: 0]
+                                         Mates must map to the same genome.
+                                         [default: 0]
    --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
                                          identity e.g. 0.95 for 95%.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same genome.
+                                         [default 0.0]
    --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
                                          bases e.g. 0.95 means 95% of the read's
                                          bases must be aligned.
-                                         Implies --proper-pairs-only. [default 0.0]
+                                         Mates must map to the same genome.
+                                         [default 0.0]
    --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are included, each read is
+                                         counted once, and bpkm is available in
+                                         place of rpkm. Percent identity is
+                                         gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
 
 Other arguments (optional):
    -m, --methods <METHOD> [METHOD ..]    Method(s) for calculating coverage.
@@ -293,6 +590,13 @@ Other arguments (optional):
                                               count
                                               reads_per_base
                                               rpkm
+                                              bpkm (long reads, bases per kilobase
+                                                per million aligned bases)
+                                              snv_count (positions differing from
+                                                the reference, see --snv-reference)
+                                              snv_density (SNVs per kb)
+                                              nucleotide_diversity (mean pi)
+                                              major_allele_frequency
                                          A more thorough description of the different
                                          methods is available at
                                          https://github.com/wwood/CoverM
@@ -310,11 +614,59 @@ Other arguments (optional):
                                          [default: 0.05]
    --trim-max FRACTION                   Maximum fraction for trimmed_mean
                                          calculations [default: 0.95]
+   --allele-min-depth <INT>              Only use positions with at least this
+                                         many aligned bases for snv_count,
+                                         snv_density, nucleotide_diversity and
+                                         major_allele_frequency. [default: 5]
+   --snv-min-allele-frequency <FLOAT>    Count positions as SNVs when an allele
+                                         other than the reference base has at
+                                         least this frequency, and is seen in
+                                         at least 2 reads. [default: 0.05]
+   --snv-reference <FASTA> ..            Reference sequences of the contigs,
+                                         which SNVs are called against.
+                                         Required for snv_count and
+                                         snv_density.
    --no-zeros                            Omit printing of genomes that have zero
                                          coverage
+   --sample-names <NAME> ..              Name each sample, in the order of -b,
+                                         --depth-files, or -1/-2, -c,
+                                         --interleaved then --single read sets,
+                                         instead of by its file name.
+   --sample-name-template <TEMPLATE>     Name samples by this template, where
+                                         {{reference}} is the reference file name
+                                         (empty when no reference is used),
+                                         {{file}} is the read1, BAM or depth file
+                                         name, and {{sample}} is the sample name,
+                                         or the file name without read direction
+                                         (e.g. _R1) or extensions. For instance
+                                         '{{sample}}' drops the reference prefix.
+                                         [default: {{reference}}/{{file}} when
+                                         mapping, otherwise {{file}}]
    --bam-file-cache-directory            Output BAM files generated during
-                                         alignment to this directory
+                                         alignment to this directory. A manifest
+                                         of the inputs and mapping parameters
+                                         is stored alongside each BAM file, so
+                                         that it is reused by later runs with
+                                         unchanged inputs. BAM files cached from
+                                         the same reference and reads with other
+                                         inputs or parameters are removed.
    --discard-unmapped                    Exclude unmapped reads from cached BAM files.
+   --checkpoint-directory <DIR>          Write the results of each sample to this
+                                         directory as it is completed.
+   --resume                              Reuse results in the checkpoint directory
+                                         from samples already completed with the
+                                         same parameters and unchanged input
+                                         files, rather than calculating them
+                                         again.
+   --seed <INT>                          Seed for all random choices (e.g. between
+                                         tied shards), so that reruns give
+                                         identical results. The seed used is
+                                         logged. [default: random]
+   --provenance-header                   Print lines starting with '#' before the
+                                         output table recording the CoverM
+                                         version, command line, mapper version,
+                                         read filters, genome definition and the
+                                         input files of each sample.
    -v, --verbose                         Print extra debugging information
    -q, --quiet                           Unless there is an error, do not print
                                          log messages
@@ -397,6 +749,11 @@ genomes_directory/ from a sorted BAM file:"
   coverm filter -b input.bam -o inverse_filtered.bam --inverse
     --min-read-percent-identity 0.95 --threads 16
 
+{}
+
+  coverm filter -r assembly.fna -1 read1.fq.gz -2 read2.fq.gz -o filtered.bam
+    --min-read-percent-identity 0.95
+
 See coverm filter --full-help for further options and further detail.
 ",
             ansi_term::Colour::Green.paint("coverm filter"),
@@ -409,6 +766,10 @@ See coverm filter --full-help for further options and further detail.
                  records that are still mapped, but align with < 95% identity. Use 16\n\
                  threads for output compression:"
             ),
+            ansi_term::Colour::Purple.paint(
+                "Example: Map reads and write only alignments with >=95% identity, without\n\
+                 writing an unfiltered BAM file:"
+            ),
         )
         .to_string();
     }
@@ -418,7 +779,9 @@ See coverm filter --full-help for further options and further detail.
             "coverm make: Generate BAM files through mapping.
 
 Output (required):
-   -o, --output-directory <DIR>          Where generated BAM files will go
+   -o, --output-directory <DIR>          Where generated BAM files will go. Each
+                                         BAM file is sorted by reference and
+                                         indexed (.bai).
 
 Mapping parameters:
    -r, --reference <PATH> ..             FASTA file of contigs e.g. concatenated 
@@ -438,6 +801,15 @@ Mapping parameters:
                                          <sample2_R1.fq.gz> <sample2_R2.fq.gz> ..
    --interleaved <PATH> ..               Interleaved FASTA/Q files(s) for mapping.
    --single <PATH> ..                    Unpaired FASTA/Q files(s) for mapping.
+   --sample-sheet <PATH>                 Tab-separated file of samples with
+                                         columns sample name, read1, and
+                                         optionally read2, read format (coupled,
+                                         interleaved or single) and reference.
+                                         Reads on lines with the same sample name
+                                         (e.g. sequencing lanes) are mapped
+                                         together, named by the sample name.
+   Read files may be FASTA or FASTQ, uncompressed or compressed with gzip,
+   bzip2 or zstd. '-' reads from STDIN, as a sample named 'stdin'.
 
 {}
    --minimap2-params PARAMS              Extra parameters to provide to minimap2,
@@ -454,14 +826,96 @@ Mapping parameters:
                                          [default \"\"]
    --discard-unmapped                    Exclude unmapped reads from generated BAM files.
 
+Running samples:
+   --parallel-samples <INT>              Number of samples to map at once, each
+                                         using an equal share of --threads.
+                                         Samples are mapped one at a time when
+                                         filtering alignments. [default 1]
+   --sample-index <INT>                  Only generate the BAM file of this
+                                         mapping, numbered from 1 in the order
+                                         of references, then -1/-2, -c,
+                                         --interleaved, --single and
+                                         --sample-sheet reads. Useful
+                                         for job arrays of cluster schedulers.
+   --dry-run                             Print the commands which would generate
+                                         each BAM file instead of running them.
+                                         The reference is used as given, so BWA
+                                         references must already be indexed.
+                                         Cannot be used with alignment filtering.
+
+Alignment filtering (optional):
+   --min-read-aligned-length <INT>            Exclude reads with smaller numbers of
+                                         aligned bases [default: 0]
+   --min-read-percent-identity <FLOAT>        Exclude reads by overall percent
+                                         identity e.g. 0.95 for 95%. [default 0.0]
+   --min-read-aligned-percent <FLOAT>         Exclude reads by percent aligned
+                                         bases e.g. 0.95 means 95% of the read's
+                                         bases must be aligned. [default 0.0]
+   --min-read-aligned-length-pair <INT>       Exclude pairs with smaller numbers of
+                                         aligned bases.
+                                         Mates must map to the same contig.
+                                         [default: 0]
+   --min-read-percent-identity-pair <FLOAT>   Exclude pairs by overall percent
+                                         identity e.g. 0.95 for 95%.
+                                         Mates must map to the same contig.
+                                         [default 0.0]
+   --min-read-aligned-percent-pair <FLOAT>    Exclude reads by percent aligned
+                                         bases e.g. 0.95 means 95% of the read's
+                                         bases must be aligned.
+                                         Mates must map to the same contig.
+                                         [default 0.0]
+   --proper-pairs-only                   Require reads to be mapped as proper pairs
+   --long-read                           Treat reads as long reads. Supplementary
+                                         alignments are kept and percent identity
+                                         is gap-compressed. Implied when mapping
+                                         with -p minimap2-ont or minimap2-pb.
+
 Example usage:
 
   coverm make -r combined_genomes.fna -1 read1.fq -2 read2.fq
 
+  coverm make -r combined_genomes.fna -1 read1.fq -2 read2.fq
+    --min-read-percent-identity 0.95 --min-read-aligned-percent 0.75
+
+  coverm make -r combined_genomes.fna -1 sample*_R1.fq.gz -2 sample*_R2.fq.gz
+    --sample-index $SLURM_ARRAY_TASK_ID -t 8
+
 Ben J. Woodcroft <benjwoodcroft near gmail.com>
 ", MAPPER_HELP);
     };
 
+    lazy_static! {
+        static ref MERGE_HELP: String = "coverm merge: Combine coverage tables generated by separate
+coverm genome or coverm contig runs into a single table.
+
+Input (required):
+   -i, --input-files <PATH> ..           Sparse or dense output files of coverm
+                                         genome or coverm contig. Each must have
+                                         been generated with the same methods,
+                                         contain the same genomes or contigs,
+                                         and contain different samples.
+
+Other arguments (optional):
+   --output-format FORMAT                Shape of output: 'sparse' for long format,
+                                         'dense' for species-by-site.
+                                         [default: dense]
+   -v, --verbose                         Print extra debugging information
+   -q, --quiet                           Unless there is an error, do not print
+                                         log messages
+
+Relative abundances are recalculated from the merged values, so that they
+remain consistent with the fraction of reads mapped in each sample. Output of
+the coverage_histogram method, metabat output, or output generated with
+--no-zeros cannot be merged.
+
+Example usage:
+
+  coverm merge -i sample1.tsv sample2.tsv > combined.tsv
+
+Ben J. Woodcroft <benjwoodcroft near gmail.com>
+".to_string();
+    }
+
     return App::new("coverm")
         .version(crate_version!())
         .author("Ben J. Woodcroft <benjwoodcroft near gmail.com>")
@@ -483,6 +937,7 @@ Main subcommands:
 Less used utility subcommands:
 \tmake\tGenerate BAM files through alignment
 \tfilter\tRemove (or only keep) alignments with insufficient identity
+\tmerge\tCombine coverage tables generated by separate runs
 \tshell-completion
 \t\tGenerate shell completion scripts
 
@@ -505,7 +960,33 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .multiple(true)
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("depth-files")
+                        .long("depth-files")
+                        .multiple(true)
+                        .takes_value(true)
+                        .conflicts_with("bam-files")
+                        .conflicts_with("sharded")
+                        .conflicts_with("checkpoint-directory"),
+                )
                 .arg(Arg::with_name("sharded").long("sharded").required(false))
+                .arg(
+                    Arg::with_name("deshard-by")
+                        .long("deshard-by")
+                        .requires("sharded")
+                        .takes_value(true)
+                        .possible_values(&["alignment-score", "edit-distance", "percent-identity"]),
+                )
+                .arg(
+                    Arg::with_name("discard-deshard-ties")
+                        .long("discard-deshard-ties")
+                        .requires("sharded"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("exclude-genomes-from-deshard")
                         .long("exclude-genomes-from-deshard")
@@ -520,12 +1001,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("read2")
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("read2")
@@ -535,12 +1019,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("read1")
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("coupled")
@@ -550,12 +1037,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true)
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "read1",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("interleaved")
@@ -564,12 +1054,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true)
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "read1",
                             "coupled",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("single")
@@ -578,12 +1071,22 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true)
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "read1",
                             "coupled",
                             "interleaved",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("sample-sheet")
+                        .long("sample-sheet")
+                        .takes_value(true)
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("reference")
@@ -591,13 +1094,54 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("reference")
                         .takes_value(true)
                         .multiple(true)
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("bam-file-cache-directory")
                         .long("bam-file-cache-directory")
                         .takes_value(true)
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("checkpoint-directory")
+                        .long("checkpoint-directory")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .requires("checkpoint-directory"),
+                )
+                .arg(
+                    Arg::with_name("host-reference")
+                        .long("host-reference")
+                        .takes_value(true)
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files")
+                        .conflicts_with("sharded"),
+                )
+                .arg(Arg::with_name("provenance-header").long("provenance-header"))
+                .arg(
+                    Arg::with_name("exclude-list")
+                        .long("exclude-list")
+                        .takes_value(true)
+                        .conflicts_with("sharded")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("exclude-regex")
+                        .long("exclude-regex")
+                        .takes_value(true)
+                        .conflicts_with("sharded")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("excluded-reads")
+                        .long("excluded-reads")
+                        .takes_value(true)
+                        .possible_values(&["unmapped", "remove"]),
                 )
                 .arg(
                     Arg::with_name("threads")
@@ -773,6 +1317,11 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "count",
                             "reads_per_base",
                             "rpkm",
+                            "bpkm",
+                            "snv_count",
+                            "snv_density",
+                            "nucleotide_diversity",
+                            "major_allele_frequency",
                         ])
                         .default_value("relative_abundance"),
                 )
@@ -786,6 +1335,22 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("trim-max")
                         .default_value("0.95"),
                 )
+                .arg(
+                    Arg::with_name("allele-min-depth")
+                        .long("allele-min-depth")
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("snv-min-allele-frequency")
+                        .long("snv-min-allele-frequency")
+                        .default_value("0.05"),
+                )
+                .arg(
+                    Arg::with_name("snv-reference")
+                        .long("snv-reference")
+                        .multiple(true)
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("min-covered-fraction")
                         .long("min-covered-fraction")
@@ -797,7 +1362,24 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .default_value("75"),
                 )
                 .arg(Arg::with_name("no-zeros").long("no-zeros"))
+                .arg(
+                    Arg::with_name("sample-names")
+                        .long("sample-names")
+                        .multiple(true)
+                        .takes_value(true)
+                        .conflicts_with("sample-sheet"),
+                )
+                .arg(
+                    Arg::with_name("sample-name-template")
+                        .long("sample-name-template")
+                        .takes_value(true),
+                )
                 .arg(Arg::with_name("proper-pairs-only").long("proper-pairs-only"))
+                .arg(
+                    Arg::with_name("long-read")
+                        .long("long-read")
+                        .conflicts_with("proper-pairs-only"),
+                )
                 .arg(
                     Arg::with_name("output-format")
                         .long("output-format")
@@ -819,7 +1401,33 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .multiple(true)
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("depth-files")
+                        .long("depth-files")
+                        .multiple(true)
+                        .takes_value(true)
+                        .conflicts_with("bam-files")
+                        .conflicts_with("sharded")
+                        .conflicts_with("checkpoint-directory"),
+                )
                 .arg(Arg::with_name("sharded").long("sharded").required(false))
+                .arg(
+                    Arg::with_name("deshard-by")
+                        .long("deshard-by")
+                        .requires("sharded")
+                        .takes_value(true)
+                        .possible_values(&["alignment-score", "edit-distance", "percent-identity"]),
+                )
+                .arg(
+                    Arg::with_name("discard-deshard-ties")
+                        .long("discard-deshard-ties")
+                        .requires("sharded"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("read1")
                         .short("-1")
@@ -828,12 +1436,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("read2")
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("read2")
@@ -843,12 +1454,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("read1")
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("coupled")
@@ -858,12 +1472,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true)
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "read1",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("interleaved")
@@ -872,12 +1489,15 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true)
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "read1",
                             "coupled",
                             "single",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("single")
@@ -886,12 +1506,22 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true)
                         .required_unless_one(&[
                             "bam-files",
+                            "depth-files",
                             "read1",
                             "coupled",
                             "interleaved",
+                            "sample-sheet",
                             "full-help",
                         ])
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("sample-sheet")
+                        .long("sample-sheet")
+                        .takes_value(true)
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("reference")
@@ -899,14 +1529,55 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("reference")
                         .takes_value(true)
                         .multiple(true)
-                        .required_unless_one(&["bam-files", "full-help"])
-                        .conflicts_with("bam-files"),
+                        .required_unless_one(&["bam-files", "depth-files", "sample-sheet", "full-help"])
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
                 )
                 .arg(
                     Arg::with_name("bam-file-cache-directory")
                         .long("bam-file-cache-directory")
                         .takes_value(true)
-                        .conflicts_with("bam-files"),
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("checkpoint-directory")
+                        .long("checkpoint-directory")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .requires("checkpoint-directory"),
+                )
+                .arg(
+                    Arg::with_name("host-reference")
+                        .long("host-reference")
+                        .takes_value(true)
+                        .conflicts_with("bam-files")
+                        .conflicts_with("depth-files")
+                        .conflicts_with("sharded"),
+                )
+                .arg(Arg::with_name("provenance-header").long("provenance-header"))
+                .arg(
+                    Arg::with_name("exclude-list")
+                        .long("exclude-list")
+                        .takes_value(true)
+                        .conflicts_with("sharded")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("exclude-regex")
+                        .long("exclude-regex")
+                        .takes_value(true)
+                        .conflicts_with("sharded")
+                        .conflicts_with("depth-files"),
+                )
+                .arg(
+                    Arg::with_name("excluded-reads")
+                        .long("excluded-reads")
+                        .takes_value(true)
+                        .possible_values(&["unmapped", "remove"]),
                 )
                 .arg(
                     Arg::with_name("threads")
@@ -999,6 +1670,11 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "metabat",
                             "reads_per_base",
                             "rpkm",
+                            "bpkm",
+                            "snv_count",
+                            "snv_density",
+                            "nucleotide_diversity",
+                            "major_allele_frequency",
                         ])
                         .default_value("mean"),
                 )
@@ -1022,14 +1698,67 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("trim-max")
                         .default_value("0.95"),
                 )
+                .arg(
+                    Arg::with_name("allele-min-depth")
+                        .long("allele-min-depth")
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("snv-min-allele-frequency")
+                        .long("snv-min-allele-frequency")
+                        .default_value("0.05"),
+                )
+                .arg(
+                    Arg::with_name("snv-reference")
+                        .long("snv-reference")
+                        .multiple(true)
+                        .takes_value(true),
+                )
                 .arg(Arg::with_name("no-zeros").long("no-zeros"))
+                .arg(
+                    Arg::with_name("sample-names")
+                        .long("sample-names")
+                        .multiple(true)
+                        .takes_value(true)
+                        .conflicts_with("sample-sheet"),
+                )
+                .arg(
+                    Arg::with_name("sample-name-template")
+                        .long("sample-name-template")
+                        .takes_value(true),
+                )
                 .arg(Arg::with_name("proper-pairs-only").long("proper-pairs-only"))
+                .arg(
+                    Arg::with_name("long-read")
+                        .long("long-read")
+                        .conflicts_with("proper-pairs-only"),
+                )
                 .arg(
                     Arg::with_name("output-format")
                         .long("output-format")
-                        .possible_values(&["sparse", "dense"])
+                        .possible_values(&[
+                            "sparse", "dense", "metabat", "concoct", "vamb", "maxbin2", "semibin",
+                            "pileup",
+                        ])
                         .default_value("dense"),
                 )
+                .arg(
+                    Arg::with_name("output-directory")
+                        .long("output-directory")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pileup-reference")
+                        .long("pileup-reference")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pileup-min-coverage")
+                        .long("pileup-min-coverage")
+                        .takes_value(true)
+                        .default_value("0"),
+                )
+                .arg(Arg::with_name("no-intra-depth-variance").long("no-intra-depth-variance"))
                 .arg(Arg::with_name("verbose").short("v").long("verbose"))
                 .arg(Arg::with_name("quiet").short("q").long("quiet")),
         )
@@ -1044,7 +1773,134 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("bam-files")
                         .multiple(true)
                         .takes_value(true)
-                        .required_unless_one(&["full-help"]),
+                        .required_unless_one(&[
+                            "full-help",
+                            "read1",
+                            "coupled",
+                            "interleaved",
+                            "single",
+                            "sample-sheet",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("read1")
+                        .short("-1")
+                        .multiple(true)
+                        .takes_value(true)
+                        .requires("read2")
+                        .required_unless_one(&[
+                            "bam-files",
+                            "coupled",
+                            "interleaved",
+                            "single",
+                            "sample-sheet",
+                            "full-help",
+                        ])
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("read2")
+                        .short("-2")
+                        .multiple(true)
+                        .takes_value(true)
+                        .requires("read1")
+                        .required_unless_one(&[
+                            "bam-files",
+                            "coupled",
+                            "interleaved",
+                            "single",
+                            "sample-sheet",
+                            "full-help",
+                        ])
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("coupled")
+                        .short("-c")
+                        .long("coupled")
+                        .multiple(true)
+                        .takes_value(true)
+                        .required_unless_one(&[
+                            "bam-files",
+                            "read1",
+                            "interleaved",
+                            "single",
+                            "sample-sheet",
+                            "full-help",
+                        ])
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("interleaved")
+                        .long("interleaved")
+                        .multiple(true)
+                        .takes_value(true)
+                        .required_unless_one(&[
+                            "bam-files",
+                            "read1",
+                            "coupled",
+                            "single",
+                            "sample-sheet",
+                            "full-help",
+                        ])
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("single")
+                        .long("single")
+                        .multiple(true)
+                        .takes_value(true)
+                        .required_unless_one(&[
+                            "bam-files",
+                            "read1",
+                            "coupled",
+                            "interleaved",
+                            "sample-sheet",
+                            "full-help",
+                        ])
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("sample-sheet")
+                        .long("sample-sheet")
+                        .takes_value(true)
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("reference")
+                        .short("-r")
+                        .long("reference")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required_unless_one(&["bam-files", "sample-sheet", "full-help"])
+                        .conflicts_with("bam-files"),
+                )
+                .arg(
+                    Arg::with_name("mapper")
+                        .short("p")
+                        .long("mapper")
+                        .possible_values(MAPPING_SOFTWARE_LIST)
+                        .default_value(DEFAULT_MAPPING_SOFTWARE),
+                )
+                .arg(
+                    Arg::with_name("minimap2-params")
+                        .long("minimap2-params")
+                        .long("minimap2-parameters")
+                        .takes_value(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::with_name("minimap2-reference-is-index")
+                        .long("minimap2-reference-is-index")
+                        .requires("reference"),
+                )
+                .arg(
+                    Arg::with_name("bwa-params")
+                        .long("bwa-params")
+                        .long("bwa-parameters")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .requires("reference"),
                 )
                 .arg(
                     Arg::with_name("output-bam-files")
@@ -1052,9 +1908,40 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("output-bam-files")
                         .multiple(true)
                         .takes_value(true)
-                        .required_unless_one(&["full-help"]),
+                        .required_unless_one(&["full-help", "output-fastq"]),
+                )
+                .arg(
+                    Arg::with_name("output-fastq")
+                        .long("output-fastq")
+                        .multiple(true)
+                        .takes_value(true)
+                        .conflicts_with("output-bam-files"),
+                )
+                .arg(
+                    Arg::with_name("output-fastq-2")
+                        .long("output-fastq-2")
+                        .multiple(true)
+                        .takes_value(true)
+                        .requires("output-fastq"),
+                )
+                .arg(
+                    Arg::with_name("genome-definition")
+                        .long("genome-definition")
+                        .takes_value(true)
+                        .conflicts_with("genome-fasta-files"),
+                )
+                .arg(
+                    Arg::with_name("genome-fasta-files")
+                        .short("f")
+                        .long("genome-fasta-files")
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("inverse")
+                        .long("inverse")
+                        .requires("bam-files"),
                 )
-                .arg(Arg::with_name("inverse").long("inverse"))
                 .arg(
                     Arg::with_name("min-read-aligned-length")
                         .long("min-read-aligned-length")
@@ -1089,6 +1976,11 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("proper-pairs-only"),
                 )
                 .arg(Arg::with_name("proper-pairs-only").long("proper-pairs-only"))
+                .arg(
+                    Arg::with_name("long-read")
+                        .long("long-read")
+                        .conflicts_with("proper-pairs-only"),
+                )
                 .arg(
                     Arg::with_name("threads")
                         .long("threads")
@@ -1120,7 +2012,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .multiple(true)
                         .takes_value(true)
                         .requires("read2")
-                        .required_unless_one(&["coupled", "interleaved", "single"]),
+                        .required_unless_one(&["coupled", "interleaved", "single", "sample-sheet"]),
                 )
                 .arg(
                     Arg::with_name("read2")
@@ -1128,7 +2020,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .multiple(true)
                         .takes_value(true)
                         .requires("read1")
-                        .required_unless_one(&["coupled", "interleaved", "single"]),
+                        .required_unless_one(&["coupled", "interleaved", "single", "sample-sheet"]),
                 )
                 .arg(
                     Arg::with_name("coupled")
@@ -1136,21 +2028,26 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("coupled")
                         .multiple(true)
                         .takes_value(true)
-                        .required_unless_one(&["read1", "interleaved", "single"]),
+                        .required_unless_one(&["read1", "interleaved", "single", "sample-sheet"]),
                 )
                 .arg(
                     Arg::with_name("interleaved")
                         .long("interleaved")
                         .multiple(true)
                         .takes_value(true)
-                        .required_unless_one(&["read1", "coupled", "single"]),
+                        .required_unless_one(&["read1", "coupled", "single", "sample-sheet"]),
                 )
                 .arg(
                     Arg::with_name("single")
                         .long("single")
                         .multiple(true)
                         .takes_value(true)
-                        .required_unless_one(&["read1", "coupled", "interleaved"]),
+                        .required_unless_one(&["read1", "coupled", "interleaved", "sample-sheet"]),
+                )
+                .arg(
+                    Arg::with_name("sample-sheet")
+                        .long("sample-sheet")
+                        .takes_value(true),
                 )
                 .arg(
                     Arg::with_name("reference")
@@ -1158,7 +2055,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("reference")
                         .multiple(true)
                         .takes_value(true)
-                        .required(true),
+                        .required_unless("sample-sheet"),
                 )
                 .arg(
                     Arg::with_name("threads")
@@ -1168,6 +2065,57 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .takes_value(true),
                 )
                 .arg(Arg::with_name("discard-unmapped").long("discard-unmapped"))
+                .arg(
+                    Arg::with_name("parallel-samples")
+                        .long("parallel-samples")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sample-index")
+                        .long("sample-index")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("dry-run").long("dry-run"))
+                .arg(
+                    Arg::with_name("min-read-aligned-length")
+                        .long("min-read-aligned-length")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-read-percent-identity")
+                        .long("min-read-percent-identity")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-read-aligned-percent")
+                        .long("min-read-aligned-percent")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-read-aligned-length-pair")
+                        .long("min-read-aligned-length-pair")
+                        .takes_value(true)
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::with_name("min-read-percent-identity-pair")
+                        .long("min-read-percent-identity-pair")
+                        .takes_value(true)
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::with_name("min-read-aligned-percent-pair")
+                        .long("min-read-aligned-percent-pair")
+                        .takes_value(true)
+                        .requires("proper-pairs-only"),
+                )
+                .arg(Arg::with_name("proper-pairs-only").long("proper-pairs-only"))
+                .arg(
+                    Arg::with_name("long-read")
+                        .long("long-read")
+                        .conflicts_with("proper-pairs-only"),
+                )
                 .arg(
                     Arg::with_name("mapper")
                         .short("p")
@@ -1204,6 +2152,32 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                 )
                 .arg(Arg::with_name("quiet").short("q").long("quiet")),
         )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Combine coverage tables generated by separate runs")
+                .help(MERGE_HELP.as_str())
+                .arg(
+                    Arg::with_name("input-files")
+                        .short("-i")
+                        .long("input-files")
+                        .multiple(true)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output-format")
+                        .long("output-format")
+                        .possible_values(&["sparse", "dense"])
+                        .default_value("dense"),
+                )
+                .arg(
+                    Arg::with_name("verbose")
+                        // .short("v") // Do not use since could be confused with
+                        // inverse (a la grep -v)
+                        .long("verbose"),
+                )
+                .arg(Arg::with_name("quiet").short("q").long("quiet")),
+        )
         .subcommand(
             SubCommand::with_name("shell-completion")
                 .about("Generate a shell completion script for coverm")
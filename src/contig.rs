@@ -5,6 +5,7 @@ use rust_htslib::bam;
 use rust_htslib::bam::record::Cigar;
 
 use mosdepth_genome_coverage_estimators::*;
+use allele_counts::AlleleCounts;
 use bam_generator::*;
 use coverage_takers::*;
 use FlagFilter;
@@ -18,10 +19,12 @@ pub fn contig_coverage<R: NamedBamReader,
     coverage_estimators: &mut Vec<CoverageEstimator>,
     print_zero_coverage_contigs: bool,
     flag_filters: FlagFilter,
+    long_read: bool,
     threads: usize)
     -> Vec<ReadsMapped> {
 
     let mut reads_mapped_vector = vec!();
+    let count_alleles = coverage_estimators.iter().any(|e| e.requires_allele_counts());
     for bam_generator in bam_readers {
         let mut bam_generated = bam_generator.start();
         bam_generated.set_threads(threads);
@@ -31,21 +34,27 @@ pub fn contig_coverage<R: NamedBamReader,
         let mut record: bam::record::Record = bam::record::Record::new();
         let mut last_tid: i32 = -2; // no such tid in a real BAM file
         let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut allele_counts: Option<AlleleCounts> = None;
         let header = bam_generated.header().clone();
         let target_names = header.target_names();
 
         let mut num_mapped_reads_total: u64 = 0;
         let mut num_mapped_reads_in_current_contig: u64 = 0;
+        let mut num_mapped_bases_total: u64 = 0;
+        let mut num_mapped_bases_in_current_contig: u64 = 0;
         let mut total_indels_in_current_contig: u32 = 0;
         let mut total_edit_distance_in_current_contig: u32 = 0;
 
         let mut process_previous_contigs = |last_tid, tid,
         coverage_estimators: &mut Vec<CoverageEstimator>,
         ups_and_downs,
+        allele_counts: Option<AlleleCounts>,
         num_mapped_reads_in_current_contig,
+        num_mapped_bases_in_current_contig,
         total_edit_distance_in_current_contig,
         total_indels_in_current_contig,
-        num_mapped_reads_total: &mut u64| {
+        num_mapped_reads_total: &mut u64,
+        num_mapped_bases_total: &mut u64| {
             if last_tid != -2 {
                 debug!("Found {} reads mapped to tid {}, with total edit \
                         distance {} and {} indels",
@@ -57,7 +66,10 @@ pub fn contig_coverage<R: NamedBamReader,
                         &ups_and_downs,
                         num_mapped_reads_in_current_contig,
                         total_edit_distance_in_current_contig -
-                            total_indels_in_current_contig)
+                            total_indels_in_current_contig);
+                    if let Some(ref counts) = allele_counts {
+                        estimator.add_allele_counts(counts);
+                    }
                 }
                 let coverages: Vec<f32> = coverage_estimators.iter_mut()
                     .map(|estimator| estimator.calculate_coverage(&vec![0])).collect();
@@ -66,6 +78,7 @@ pub fn contig_coverage<R: NamedBamReader,
                 debug!("Found nonzero coverage?: {}", has_nonzero_coverage);
                 if has_nonzero_coverage {
                     *num_mapped_reads_total += num_mapped_reads_in_current_contig;
+                    *num_mapped_bases_total += num_mapped_bases_in_current_contig;
                 }
                 if print_zero_coverage_contigs || has_nonzero_coverage {
                     coverage_taker.start_entry(
@@ -111,20 +124,34 @@ pub fn contig_coverage<R: NamedBamReader,
                         tid,
                         coverage_estimators,
                         ups_and_downs,
+                        allele_counts,
                         num_mapped_reads_in_current_contig,
+                        num_mapped_bases_in_current_contig,
                         total_edit_distance_in_current_contig,
                         total_indels_in_current_contig,
-                        &mut num_mapped_reads_total);
+                        &mut num_mapped_reads_total,
+                        &mut num_mapped_bases_total);
                     ups_and_downs = vec![0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                    allele_counts = match count_alleles {
+                        true => Some(AlleleCounts::new(
+                            std::str::from_utf8(target_names[tid as usize]).unwrap(),
+                            ups_and_downs.len())),
+                        false => None
+                    };
                     debug!("Working on new reference {}",
                            std::str::from_utf8(target_names[tid as usize]).unwrap());
                     last_tid = tid;
                     num_mapped_reads_in_current_contig = 0;
+                    num_mapped_bases_in_current_contig = 0;
                     total_edit_distance_in_current_contig = 0;
                     total_indels_in_current_contig = 0;
                 }
 
-                num_mapped_reads_in_current_contig += 1;
+                // In long-read mode supplementary alignments are pieces of
+                // a read whose primary alignment is counted already.
+                if !(long_read && record.is_supplementary()) {
+                    num_mapped_reads_in_current_contig += 1;
+                }
 
                 // for each chunk of the cigar string
                 trace!("read name {:?}", std::str::from_utf8(record.qname()).unwrap());
@@ -141,6 +168,7 @@ pub fn contig_coverage<R: NamedBamReader,
                                 ups_and_downs[final_pos] -= 1;
                             }
                             cursor += cig.len() as usize;
+                            num_mapped_bases_in_current_contig += cig.len() as u64;
                         },
                         Cigar::Del(_) => {
                             cursor += cig.len() as usize;
@@ -157,6 +185,10 @@ pub fn contig_coverage<R: NamedBamReader,
                     }
                 }
 
+                if let Some(ref mut counts) = allele_counts {
+                    counts.add_record(&record);
+                }
+
                 // Determine the number of mismatching bases in this read by
                 // looking at the NM tag.
                 total_edit_distance_in_current_contig += match
@@ -181,14 +213,21 @@ pub fn contig_coverage<R: NamedBamReader,
             target_names.len() as i32,
             coverage_estimators,
             ups_and_downs,
+            allele_counts,
             num_mapped_reads_in_current_contig,
+            num_mapped_bases_in_current_contig,
             total_edit_distance_in_current_contig,
             total_indels_in_current_contig,
-            &mut num_mapped_reads_total);
+            &mut num_mapped_reads_total,
+            &mut num_mapped_bases_total);
 
         let reads_mapped = ReadsMapped {
             num_mapped_reads: num_mapped_reads_total,
-            num_reads: bam_generated.num_detected_primary_alignments()
+            num_reads: bam_generated.num_detected_primary_alignments(),
+            num_mapped_bases: match long_read {
+                true => Some(num_mapped_bases_total),
+                false => None
+            }
         };
         info!("In sample '{}', found {} reads mapped out of {} total ({:.*}%)",
               stoit_name, reads_mapped.num_mapped_reads,
@@ -240,6 +279,12 @@ mod tests {
     use mapping_parameters::*;
     use shard_bam_reader::*;
     use genome_exclusion::*;
+    use filter::MateGenomes;
+    use test_bam_writer::*;
+    use tempfile;
+    use pileup;
+    use std::rc::Rc;
+    use stoit_naming::StoitNaming;
 
     fn test_with_stream<R: NamedBamReader,
                         G: NamedBamReaderGenerator<R>>(
@@ -253,6 +298,7 @@ mod tests {
             include_improper_pairs: !proper_pairs_only,
             include_secondary: false,
             include_supplementary: false,
+            gap_compressed_identity: false,
         };
         let reads_mapped_vec;
         {
@@ -264,6 +310,7 @@ mod tests {
                 coverage_estimators,
                 print_zero_coverage_contigs,
                 flag_filters,
+                false,
                 1);
         }
         assert_eq!(expected, str::from_utf8(stream.get_ref()).unwrap());
@@ -275,7 +322,8 @@ mod tests {
         test_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2~seq1\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome5~seq2\t1.2\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+                vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+                &StoitNaming::default()),
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
             false,
             false);
@@ -286,7 +334,8 @@ mod tests {
         test_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome1~random_sequence_length_11000\t0\n7seqs.reads_for_seq1_and_seq2\tgenome1~random_sequence_length_11010\t0\n7seqs.reads_for_seq1_and_seq2\tgenome2~seq1\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome3~random_sequence_length_11001\t0\n7seqs.reads_for_seq1_and_seq2\tgenome4~random_sequence_length_11002\t0\n7seqs.reads_for_seq1_and_seq2\tgenome5~seq2\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome6~random_sequence_length_11003\t0\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+                vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+                &StoitNaming::default()),
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
             true,
             false);
@@ -304,7 +353,9 @@ mod tests {
             \nshard1|shard2\tgenome2~seq1\t0\n",
             generate_sharded_bam_reader_from_bam_files(
                 vec!["tests/data/shard1.bam", "tests/data/shard2.bam"], 4,
-                &NoExclusionGenomeFilter{}),
+                &NoExclusionGenomeFilter{},
+                ShardSelection::default(),
+                &StoitNaming::default()),
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
             true,
             false);
@@ -315,7 +366,8 @@ mod tests {
         test_with_stream(
             "",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/1.bam"]),
+                vec!["tests/data/1.bam"],
+                &StoitNaming::default()),
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
             false,
             true);
@@ -327,7 +379,8 @@ mod tests {
             &("2seqs.reads_for_seq1\tseq1\t0.9489489\n".to_owned()+
                 "2seqs.reads_for_seq1\tseq2\t0\n"),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             &mut vec!(CoverageEstimator::new_estimator_variance(0.0,0)),
             true,
             false);
@@ -342,14 +395,17 @@ mod tests {
                 generate_named_bam_readers_from_reads(
                     MappingProgram::BWA_MEM,
                     "tests/data/7seqs.fna",
-                    "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
-                    Some("tests/data/reads_for_seq1_and_seq2.2.fq.gz"),
+                    &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+                    &["tests/data/reads_for_seq1_and_seq2.2.fq.gz"],
+                    None,
+                    &StoitNaming::default(),
                     ReadFormat::Coupled,
                     4,
                     None,
                     false,
                     None,
-                    true)],
+                    true,
+                    None)],
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
             false,
             false);
@@ -361,7 +417,8 @@ mod tests {
             &("2seqs.reads_for_seq1\tseq1\t1.2\t0.9489489\n".to_owned()+
                 "2seqs.reads_for_seq1\tseq2\t0\t0\n"),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_mean(0.0,0,false),
                 CoverageEstimator::new_estimator_variance(0.0,0)
@@ -377,7 +434,8 @@ mod tests {
              2seqs.reads_for_seq1.with_unmapped\tseq2\t1.5\n",
             // has unmapped reads, which caused problems with --proper-pairs-only.
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.with_unmapped.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.with_unmapped.bam"],
+                &StoitNaming::default()),
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,true)),
             true,
             false);
@@ -389,7 +447,8 @@ mod tests {
             &("2seqs.reads_for_seq1\tseq1\t0\n".to_owned()+
                 "2seqs.reads_for_seq1\tseq2\t0\n"),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_trimmed_mean(0.0,0.05,0.0,0)
             ),
@@ -402,7 +461,8 @@ mod tests {
         test_with_stream(
             "2seqs.reads_for_seq1\tseq1\t1.2\t0\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_mean(0.0,0,false),
                 // covered fraction is 0.727, so go lower so trimmed mean is 0,
@@ -418,7 +478,8 @@ mod tests {
         test_with_stream(
             "2seqs.reads_for_seq1\tseq1\t0\t1.2\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 // covered fraction is 0.727, so go lower so trimmed mean is 0,
                 // mean > 0.
@@ -436,7 +497,8 @@ mod tests {
             &("7seqs.reads_for_seq1_and_seq2\tgenome2~seq1\t1.4117647\t1.3049262\n".to_owned()+
               "7seqs.reads_for_seq1_and_seq2\tgenome5~seq2\t1.2435294\t0.6862065\n"),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+                vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_mean(0.0,75,false),
                 // covered fraction is 0.727, so go lower so trimmed mean is 0,
@@ -453,7 +515,8 @@ mod tests {
         test_with_stream(
             &("1read_of_pair_mapped\t73.20100900_E1D.16_contig_9606\t0.011293635\n".to_owned()),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/1read_of_pair_mapped.bam"]),
+                vec!["tests/data/1read_of_pair_mapped.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_mean(0.0,75,true),
             ),
@@ -467,7 +530,8 @@ mod tests {
         test_with_stream(
             &("k141_2005182	k141_2005182	5.107387\n".to_owned()),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/k141_2005182.bam"]),
+                vec!["tests/data/k141_2005182.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_variance(0.0,75),
             ),
@@ -475,6 +539,47 @@ mod tests {
             false);
     }
 
+    #[test]
+    fn test_allele_variation_estimators(){
+        for (statistic, expected) in vec![
+            (AlleleStatistic::NucleotideDiversity, "0.07383146"),
+            (AlleleStatistic::MajorAlleleFrequency, "0.9579817")] {
+            test_with_stream(
+                &format!("k141_2005182\tk141_2005182\t{}\n", expected),
+                generate_named_bam_readers_from_bam_files(
+                    vec!["tests/data/k141_2005182.bam"],
+                    &StoitNaming::default()),
+                &mut vec!(
+                    CoverageEstimator::new_estimator_allele_variation(
+                        statistic, 1, 0.05, 0.0, 0, None),
+                ),
+                false,
+                false);
+        }
+    }
+
+    #[test]
+    fn test_snv_estimators_count_differences_from_reference(){
+        // All reads differ from the reference at one position, and 2 of 6
+        // reads differ at another.
+        let reference = Rc::new(pileup::read_reference_sequences("tests/data/snv.fna"));
+        for (statistic, expected) in vec![
+            (AlleleStatistic::SNVCount, "2"),
+            (AlleleStatistic::SNVDensity, "100")] {
+            test_with_stream(
+                &format!("snv\tcontig1\t{}\n", expected),
+                generate_named_bam_readers_from_bam_files(
+                    vec!["tests/data/snv.bam"],
+                    &StoitNaming::default()),
+                &mut vec!(
+                    CoverageEstimator::new_estimator_allele_variation(
+                        statistic, 5, 0.05, 0.0, 0, Some(reference.clone())),
+                ),
+                false,
+                false);
+        }
+    }
+
     #[test]
     fn test_reads_not_counting_when_sufficient_min_covered(){
         // In the past this threw up a underflow error
@@ -482,7 +587,8 @@ mod tests {
             &("2seqs.reads_for_seq1_and_seq2	seq1	1.3049262\n\
                2seqs.reads_for_seq1_and_seq2	seq2	0.6862065\n".to_owned()),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_variance(0.0,75),
             ),
@@ -490,7 +596,8 @@ mod tests {
             false);
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 24,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
     }
 
@@ -501,7 +608,8 @@ mod tests {
         let reads_mapped = test_with_stream(
             &("".to_owned()),
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"],
+                &StoitNaming::default()),
             &mut vec!(
                 CoverageEstimator::new_estimator_variance(0.99,75),
             ),
@@ -509,7 +617,8 @@ mod tests {
             false);
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 0,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
     }
 
@@ -527,16 +636,117 @@ mod tests {
                 generate_named_bam_readers_from_reads(
                     MappingProgram::BWA_MEM,
                     "tests/data/7seqs.fna",
-                    "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
-                    Some("tests/data/reads_for_seq1_and_seq2.2.fq.gz"),
+                    &["tests/data/reads_for_seq1_and_seq2.1.fq.gz"],
+                    &["tests/data/reads_for_seq1_and_seq2.2.fq.gz"],
+                    None,
+                    &StoitNaming::default(),
                     ReadFormat::Coupled,
                     4,
                     None,
                     false,
                     None,
-                    true)],
+                    true,
+                    None)],
             &mut vec!(CoverageEstimator::new_estimator_reads_per_base()),
             true,
             false);
     }
+
+    fn contig_coverage_of_stream<R: NamedBamReader,
+                                 G: NamedBamReaderGenerator<R>>(
+        bam_readers: Vec<G>,
+        flag_filters: FlagFilter,
+        long_read: bool) -> (String, Vec<ReadsMapped>) {
+        let mut stream = Cursor::new(Vec::new());
+        let reads_mapped;
+        {
+            let mut coverage_taker = CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                &mut stream);
+            reads_mapped = contig_coverage(
+                bam_readers,
+                &mut coverage_taker,
+                &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
+                true,
+                flag_filters,
+                long_read,
+                1);
+        }
+        (str::from_utf8(stream.get_ref()).unwrap().to_string(), reads_mapped)
+    }
+
+    #[test]
+    fn test_long_read_supplementary_bases(){
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("long.bam");
+        write_test_bam(&bam, &[("c1", 100)], &[
+            TestAlignment::single("read1", 0, 0, 0, vec![Cigar::Match(50), Cigar::SoftClip(30)]),
+            // The rest of read1, aligned elsewhere
+            TestAlignment::single("read1", 2048, 0, 60, vec![Cigar::SoftClip(50), Cigar::Match(30)]),
+        ]);
+        let flag_filters = FlagFilter {
+            include_improper_pairs: true,
+            include_secondary: false,
+            include_supplementary: true,
+            gap_compressed_identity: true,
+        };
+
+        // Supplementary bases count towards coverage and aligned bases, but
+        // the read is only counted once.
+        let (output, reads_mapped) = contig_coverage_of_stream(
+            generate_named_bam_readers_from_bam_files(vec![bam.to_str().unwrap()], &StoitNaming::default()),
+            flag_filters.clone(),
+            true);
+        assert_eq!("long\tc1\t0.8\n", output);
+        assert_eq!(vec!(ReadsMapped{
+            num_mapped_reads: 1,
+            num_reads: 1,
+            num_mapped_bases: Some(80)
+        }), reads_mapped);
+
+        let (output, reads_mapped) = contig_coverage_of_stream(
+            generate_named_bam_readers_from_bam_files(vec![bam.to_str().unwrap()], &StoitNaming::default()),
+            FlagFilter {
+                include_supplementary: false,
+                gap_compressed_identity: false,
+                ..flag_filters
+            },
+            false);
+        assert_eq!("long\tc1\t0.5\n", output);
+        assert_eq!(vec!(ReadsMapped{
+            num_mapped_reads: 1,
+            num_reads: 1,
+            num_mapped_bases: None
+        }), reads_mapped);
+    }
+
+    #[test]
+    fn test_gap_compressed_identity_filtering(){
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("long.bam");
+        // A 10bp deletion, which is 95% identity, or 99.5% gap-compressed.
+        let mut alignment = TestAlignment::single(
+            "read1", 0, 0, 0, vec![Cigar::Match(100), Cigar::Del(10), Cigar::Match(100)]);
+        alignment.edit_distance = 10;
+        write_test_bam(&bam, &[("c1", 400)], &[alignment]);
+
+        for (gap_compressed_identity, expected) in vec![(true, "long\tc1\t0.5\n"), (false, "long\tc1\t0\n")] {
+            let flag_filters = FlagFilter {
+                include_improper_pairs: true,
+                include_secondary: false,
+                include_supplementary: true,
+                gap_compressed_identity: gap_compressed_identity,
+            };
+            let (output, _) = contig_coverage_of_stream(
+                generate_filtered_bam_readers_from_bam_files(
+                    vec![bam.to_str().unwrap()],
+                    flag_filters.clone(),
+                    0, 0.99, 0.0,
+                    0, 0.0, 0.0,
+                    &MateGenomes::SameReference,
+                    &StoitNaming::default()),
+                flag_filters,
+                true);
+            assert_eq!(expected, output);
+        }
+    }
 }
@@ -0,0 +1,517 @@
+use std;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::process;
+
+use coverage_printer::*;
+use coverage_takers::*;
+use mosdepth_genome_coverage_estimators::CoverageEstimator;
+use provenance::PROVENANCE_LINE_PREFIX;
+use ReadsMapped;
+
+const RELATIVE_ABUNDANCE_HEADER: &str = "Relative Abundance (%)";
+
+// Column headers which may be output by coverm. Headers in dense output are
+// prefixed by the sample name and a space, so these are used to separate the
+// two.
+fn known_estimator_headers() -> Vec<&'static str> {
+    let mut headers = CoverageEstimator::all_column_headers();
+    headers.push(RELATIVE_ABUNDANCE_HEADER);
+    headers
+}
+
+// Used to express the fraction of reads mapped as a ReadsMapped, so that the
+// relative abundance can be recalculated by the regular printers.
+const NOMINAL_NUM_READS: u64 = 1_000_000_000;
+
+/// Coverage values of one stoit as read from a previous coverm output
+#[derive(Debug, PartialEq)]
+pub struct StoitCoverages {
+    pub stoit_name: String,
+    pub entries: Vec<(String, Vec<f32>)>,
+    // Percentage of reads unmapped, only known if relative abundance was
+    // calculated.
+    pub unmapped_percentage: Option<f32>,
+}
+
+/// Contents of a sparse or dense table output by 'coverm genome' or 'coverm
+/// contig'.
+#[derive(Debug, PartialEq)]
+pub struct CoverageTable {
+    pub entry_type: String,
+    pub estimator_headers: Vec<String>,
+    pub stoits: Vec<StoitCoverages>,
+}
+
+fn parse_coverage_value(value: &str, path: &str) -> f32 {
+    match value.parse::<f32>() {
+        Ok(f) => f,
+        Err(_) => {
+            error!("Unable to parse coverage value '{}' in {}", value, path);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_unmapped_percentage(values: &[&str], headers: &[String], path: &str) -> Option<f32> {
+    let mut unmapped = None;
+    for (value, header) in values.iter().zip(headers.iter()) {
+        if header == RELATIVE_ABUNDANCE_HEADER {
+            unmapped = Some(parse_coverage_value(value, path));
+        }
+    }
+    unmapped
+}
+
+fn split_dense_header<'a>(column: &'a str, path: &str) -> (&'a str, &'a str) {
+    let mut best: Option<&str> = None;
+    for header in known_estimator_headers() {
+        if column.ends_with(&format!(" {}", header))
+            && (best.is_none() || header.len() > best.unwrap().len())
+        {
+            best = Some(header);
+        }
+    }
+    match best {
+        Some(header) => (&column[..(column.len() - header.len() - 1)], header),
+        None => {
+            error!(
+                "Unable to determine the sample name and coverage method of \
+                 column '{}' in {}",
+                column, path
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Read a table previously output by coverm, in either sparse or dense format.
+pub fn read_coverage_table(path: &str) -> CoverageTable {
+    let file =
+        std::fs::File::open(path).expect(&format!("Unable to open coverage file {}", path));
+    let mut lines = BufReader::new(file)
+        .lines()
+        .map(|l| l.expect(&format!("Failed to read line from coverage file {}", path)))
+        // Skip any provenance header
+        .skip_while(|l| l.starts_with(PROVENANCE_LINE_PREFIX));
+    let header_line = match lines.next() {
+        Some(l) => l,
+        None => {
+            error!("Coverage file {} is empty", path);
+            process::exit(1);
+        }
+    };
+    let header: Vec<&str> = header_line.split('\t').collect();
+    if header.len() < 2 {
+        error!("Unexpected header line in coverage file {}", path);
+        process::exit(1);
+    }
+    if header.contains(&"Coverage") && header.contains(&"Bases") && header[0] == "Sample" {
+        error!(
+            "Merging output of the coverage_histogram method is not supported, in {}",
+            path
+        );
+        process::exit(1);
+    }
+
+    if header[0] == "Sample" {
+        // Sparse format
+        let estimator_headers: Vec<String> = header[2..].iter().map(|s| s.to_string()).collect();
+        let mut stoits: Vec<StoitCoverages> = vec![];
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != header.len() {
+                error!("Unexpected number of fields in line '{}' of {}", line, path);
+                process::exit(1);
+            }
+            if stoits.last().map(|s| s.stoit_name != fields[0]).unwrap_or(true) {
+                stoits.push(StoitCoverages {
+                    stoit_name: fields[0].to_string(),
+                    entries: vec![],
+                    unmapped_percentage: None,
+                });
+            }
+            let stoit = stoits.last_mut().unwrap();
+            if fields[1] == "unmapped" && estimator_headers.iter().any(
+                |h| h == RELATIVE_ABUNDANCE_HEADER) {
+                stoit.unmapped_percentage =
+                    parse_unmapped_percentage(&fields[2..], &estimator_headers, path);
+            } else {
+                stoit.entries.push((
+                    fields[1].to_string(),
+                    fields[2..].iter().map(|v| parse_coverage_value(v, path)).collect(),
+                ));
+            }
+        }
+        CoverageTable {
+            entry_type: header[1].to_string(),
+            estimator_headers: estimator_headers,
+            stoits: stoits,
+        }
+    } else {
+        // Dense format
+        let mut stoits: Vec<StoitCoverages> = vec![];
+        let mut estimator_headers: Vec<String> = vec![];
+        for column in header[1..].iter() {
+            let (stoit_name, estimator_header) = split_dense_header(column, path);
+            if stoits.last().map(|s| s.stoit_name != stoit_name).unwrap_or(true) {
+                stoits.push(StoitCoverages {
+                    stoit_name: stoit_name.to_string(),
+                    entries: vec![],
+                    unmapped_percentage: None,
+                });
+            }
+            if stoits.len() == 1 {
+                estimator_headers.push(estimator_header.to_string());
+            }
+        }
+        let num_estimators = estimator_headers.len();
+        if num_estimators * stoits.len() != header.len() - 1 {
+            error!(
+                "The same coverage methods must be given for each sample in {}",
+                path
+            );
+            process::exit(1);
+        }
+        for (i, column) in header[1..].iter().enumerate() {
+            let (_, estimator_header) = split_dense_header(column, path);
+            if estimator_header != estimator_headers[i % num_estimators] {
+                error!(
+                    "The same coverage methods must be given for each sample in {}",
+                    path
+                );
+                process::exit(1);
+            }
+        }
+
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != header.len() {
+                error!("Unexpected number of fields in line '{}' of {}", line, path);
+                process::exit(1);
+            }
+            for (stoit_i, stoit) in stoits.iter_mut().enumerate() {
+                let values = &fields[(1 + stoit_i * num_estimators)
+                    ..(1 + (stoit_i + 1) * num_estimators)];
+                if fields[0] == "unmapped" && estimator_headers.iter().any(
+                    |h| h == RELATIVE_ABUNDANCE_HEADER) {
+                    stoit.unmapped_percentage =
+                        parse_unmapped_percentage(values, &estimator_headers, path);
+                } else {
+                    stoit.entries.push((
+                        fields[0].to_string(),
+                        values.iter().map(|v| parse_coverage_value(v, path)).collect(),
+                    ));
+                }
+            }
+        }
+        CoverageTable {
+            entry_type: header[0].to_string(),
+            estimator_headers: estimator_headers,
+            stoits: stoits,
+        }
+    }
+}
+
+/// Combine tables previously output by coverm into a single table, checking
+/// that they were generated with the same coverage methods and contain the
+/// same entries. Relative abundances are renormalised from the merged values.
+pub fn merge_coverage_tables(
+    tables: Vec<CoverageTable>,
+    output_format: &str,
+    print_stream: &mut dyn std::io::Write,
+) {
+    if tables.is_empty() {
+        error!("No coverage files to merge");
+        process::exit(1);
+    }
+    let entry_type = tables[0].entry_type.clone();
+    let estimator_headers = tables[0].estimator_headers.clone();
+    let columns_to_normalise: Vec<usize> = estimator_headers
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| *h == RELATIVE_ABUNDANCE_HEADER)
+        .map(|(i, _)| i)
+        .collect();
+
+    // Entries are ordered as in the first sample
+    let mut entry_order: Vec<String> = vec![];
+    let mut entry_indices: HashMap<String, usize> = HashMap::new();
+    match tables[0].stoits.first() {
+        Some(stoit) => {
+            for (entry, _) in stoit.entries.iter() {
+                entry_indices.insert(entry.clone(), entry_order.len());
+                entry_order.push(entry.clone());
+            }
+        }
+        None => {
+            error!("No samples found in the first coverage file to be merged");
+            process::exit(1);
+        }
+    }
+
+    let mut seen_stoits = HashSet::new();
+    let mut reads_mapped = vec![];
+    let mut taker = CoverageTakerType::new_cached_single_float_coverage_taker(
+        estimator_headers.len(),
+    );
+    for table in tables.iter() {
+        if table.entry_type != entry_type {
+            error!(
+                "Cannot merge coverage of different entry types, found '{}' and '{}'",
+                entry_type, table.entry_type
+            );
+            process::exit(1);
+        }
+        if table.estimator_headers != estimator_headers {
+            error!(
+                "Cannot merge coverage files generated with different methods, \
+                 found {:?} and {:?}",
+                estimator_headers, table.estimator_headers
+            );
+            process::exit(1);
+        }
+        for stoit in table.stoits.iter() {
+            if !seen_stoits.insert(stoit.stoit_name.clone()) {
+                error!(
+                    "Sample '{}' was found more than once in the files to be merged",
+                    stoit.stoit_name
+                );
+                process::exit(1);
+            }
+            if stoit.entries.len() != entry_order.len() {
+                error!(
+                    "Sample '{}' has {} {} entries, but '{}' has {}. Note that \
+                     output generated with --no-zeros cannot be merged.",
+                    stoit.stoit_name,
+                    stoit.entries.len(),
+                    entry_type.to_lowercase(),
+                    tables[0].stoits[0].stoit_name,
+                    entry_order.len()
+                );
+                process::exit(1);
+            }
+
+            // Sort entries into the common order
+            let mut sorted_entries: Vec<Option<&Vec<f32>>> = vec![None; entry_order.len()];
+            for (entry, coverages) in stoit.entries.iter() {
+                match entry_indices.get(entry) {
+                    Some(i) => sorted_entries[*i] = Some(coverages),
+                    None => {
+                        error!(
+                            "{} '{}' in sample '{}' was not found in sample '{}'",
+                            entry_type, entry, stoit.stoit_name, tables[0].stoits[0].stoit_name
+                        );
+                        process::exit(1);
+                    }
+                }
+            }
+
+            taker.start_stoit(&stoit.stoit_name);
+            for (i, coverages) in sorted_entries.iter().enumerate() {
+                taker.start_entry(i, &entry_order[i]);
+                for coverage in coverages.unwrap() {
+                    taker.add_single_coverage(*coverage);
+                }
+                taker.finish_entry();
+            }
+
+            if !columns_to_normalise.is_empty() {
+                let unmapped = match stoit.unmapped_percentage {
+                    Some(u) => u,
+                    None => {
+                        error!(
+                            "No unmapped line found for sample '{}', cannot recalculate \
+                             relative abundance",
+                            stoit.stoit_name
+                        );
+                        process::exit(1);
+                    }
+                };
+                let fraction_mapped = (100.0 - unmapped as f64) / 100.0;
+                reads_mapped.push(ReadsMapped {
+                    num_mapped_reads: (fraction_mapped * NOMINAL_NUM_READS as f64).round() as u64,
+                    num_reads: NOMINAL_NUM_READS,
+                    num_mapped_bases: None,
+                });
+            }
+        }
+    }
+    info!(
+        "Merging coverage of {} {} entries across {} samples",
+        entry_order.len(),
+        entry_type.to_lowercase(),
+        seen_stoits.len()
+    );
+
+    let mut printer = match output_format {
+        "sparse" => CoveragePrinter::SparseCachedCoveragePrinter,
+        "dense" => CoveragePrinter::DenseCachedCoveragePrinter {
+            entry_type: None,
+            estimator_headers: None,
+        },
+        _ => unreachable!(),
+    };
+    printer.print_headers(&entry_type, estimator_headers, print_stream);
+    printer.finalise_printing(
+        &taker,
+        print_stream,
+        match columns_to_normalise.is_empty() {
+            true => None,
+            false => Some(&reads_mapped),
+        },
+        &columns_to_normalise,
+        None,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::str;
+
+    fn write_table(contents: &str) -> tempfile::NamedTempFile {
+        let mut tf = tempfile::NamedTempFile::new().unwrap();
+        tf.write_all(contents.as_bytes()).unwrap();
+        tf.flush().unwrap();
+        tf
+    }
+
+    #[test]
+    fn test_read_sparse_and_dense_tables() {
+        let sparse = write_table(
+            "Sample\tGenome\tRelative Abundance (%)\tMean\n\
+             s1\tunmapped\t20\tNA\n\
+             s1\tg1\t80\t1.5\n\
+             s1\tg2\t0\t0\n",
+        );
+        let dense = write_table(
+            "Genome\ts1 Relative Abundance (%)\ts1 Mean\n\
+             unmapped\t20\tNA\n\
+             g1\t80\t1.5\n\
+             g2\t0\t0\n",
+        );
+        let expected = CoverageTable {
+            entry_type: "Genome".to_string(),
+            estimator_headers: vec![
+                "Relative Abundance (%)".to_string(),
+                "Mean".to_string(),
+            ],
+            stoits: vec![StoitCoverages {
+                stoit_name: "s1".to_string(),
+                entries: vec![
+                    ("g1".to_string(), vec![80.0, 1.5]),
+                    ("g2".to_string(), vec![0.0, 0.0]),
+                ],
+                unmapped_percentage: Some(20.0),
+            }],
+        };
+        assert_eq!(
+            expected,
+            read_coverage_table(sparse.path().to_str().unwrap())
+        );
+        assert_eq!(
+            expected,
+            read_coverage_table(dense.path().to_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_read_table_with_provenance_header() {
+        let table = write_table(
+            "# CoverM version: 0.3.2\n\
+             # Command: coverm contig -b s1.bam\n\
+             Contig\ts1 Mean\n\
+             c1\t1.5\n",
+        );
+        let expected = CoverageTable {
+            entry_type: "Contig".to_string(),
+            estimator_headers: vec!["Mean".to_string()],
+            stoits: vec![StoitCoverages {
+                stoit_name: "s1".to_string(),
+                entries: vec![("c1".to_string(), vec![1.5])],
+                unmapped_percentage: None,
+            }],
+        };
+        assert_eq!(
+            expected,
+            read_coverage_table(table.path().to_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_split_dense_header() {
+        assert_eq!(("s1", "SNVs per kb"), split_dense_header("s1 SNVs per kb", "t"));
+        assert_eq!(("s1 SNVs", "Mean"), split_dense_header("s1 SNVs Mean", "t"));
+        assert_eq!(("s 1", "BPKM"), split_dense_header("s 1 BPKM", "t"));
+        assert_eq!(("s1", "Major Allele Frequency"),
+                   split_dense_header("s1 Major Allele Frequency", "t"));
+        assert_eq!(("s1", "Nucleotide Diversity"),
+                   split_dense_header("s1 Nucleotide Diversity", "t"));
+    }
+
+    #[test]
+    fn test_merge_dense() {
+        let t1 = write_table(
+            "Contig\ts1 Mean\ts1 Covered Fraction\n\
+             c1\t1.5\t0.5\n\
+             c2\t0\t0\n",
+        );
+        let t2 = write_table(
+            "Sample\tContig\tMean\tCovered Fraction\n\
+             s2\tc2\t2\t1\n\
+             s2\tc1\t0.5\t0.25\n",
+        );
+        let mut stream = std::io::Cursor::new(Vec::new());
+        merge_coverage_tables(
+            vec![
+                read_coverage_table(t1.path().to_str().unwrap()),
+                read_coverage_table(t2.path().to_str().unwrap()),
+            ],
+            "dense",
+            &mut stream,
+        );
+        assert_eq!(
+            "Contig\ts1 Mean\ts1 Covered Fraction\ts2 Mean\ts2 Covered Fraction\n\
+             c1\t1.5\t0.5\t0.5\t0.25\n\
+             c2\t0\t0\t2\t1\n",
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_relative_abundance_sparse() {
+        let t1 = write_table(
+            "Sample\tGenome\tRelative Abundance (%)\n\
+             s1\tunmapped\t50\n\
+             s1\tg1\t25\n\
+             s1\tg2\t25\n",
+        );
+        let t2 = write_table(
+            "Sample\tGenome\tRelative Abundance (%)\n\
+             s2\tunmapped\t0\n\
+             s2\tg1\t100\n\
+             s2\tg2\t0\n",
+        );
+        let mut stream = std::io::Cursor::new(Vec::new());
+        merge_coverage_tables(
+            vec![
+                read_coverage_table(t1.path().to_str().unwrap()),
+                read_coverage_table(t2.path().to_str().unwrap()),
+            ],
+            "sparse",
+            &mut stream,
+        );
+        assert_eq!(
+            "Sample\tGenome\tRelative Abundance (%)\n\
+             s1\tunmapped\t50\n\
+             s1\tg1\t25\n\
+             s1\tg2\t25\n\
+             s2\tunmapped\t0\n\
+             s2\tg1\t100\n\
+             s2\tg2\t0\n",
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+}
@@ -1,4 +1,7 @@
 use std;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 use std::process;
 
 use coverage_takers::*;
@@ -11,7 +14,59 @@ pub enum CoveragePrinter {
         entry_type: Option<String>,
         estimator_headers: Option<Vec<String>>,
     },
-    MetabatAdjustedCoveragePrinter,
+    MetabatAdjustedCoveragePrinter {
+        include_variance: bool,
+    },
+    BinnerCoveragePrinter {
+        format: BinnerFormat,
+        output_directory: Option<String>,
+    },
+}
+
+/// Indices of the coverages calculated for the MetaBAT printer, which must be
+/// calculated in this order. The variance is only calculated when it is
+/// printed.
+pub const METABAT_LENGTH_INDEX: usize = 0;
+pub const METABAT_MEAN_INDEX: usize = 1;
+pub const METABAT_VARIANCE_INDEX: usize = 2;
+
+/// Coverage file formats expected by genome binning tools, other than
+/// MetaBAT's which has its own printer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinnerFormat {
+    Concoct,
+    Vamb,
+    Maxbin2,
+    Semibin,
+}
+
+impl BinnerFormat {
+    pub fn from_name(name: &str) -> Option<BinnerFormat> {
+        match name {
+            "concoct" => Some(BinnerFormat::Concoct),
+            "vamb" => Some(BinnerFormat::Vamb),
+            "maxbin2" => Some(BinnerFormat::Maxbin2),
+            "semibin" => Some(BinnerFormat::Semibin),
+            _ => None,
+        }
+    }
+
+    /// Whether one abundance file is written per sample, rather than a
+    /// single table to the output stream.
+    pub fn writes_per_sample_files(&self) -> bool {
+        match self {
+            BinnerFormat::Maxbin2 | BinnerFormat::Semibin => true,
+            BinnerFormat::Concoct | BinnerFormat::Vamb => false,
+        }
+    }
+
+    fn per_sample_file_suffix(&self) -> &str {
+        match self {
+            BinnerFormat::Maxbin2 => "maxbin2.abund",
+            BinnerFormat::Semibin => "semibin.abundance.tsv",
+            _ => unreachable!(),
+        }
+    }
 }
 
 impl CoveragePrinter {
@@ -39,55 +94,17 @@ impl CoveragePrinter {
                     cached_coverage_taker, print_stream, reads_mapped_per_sample,
                     &columns_to_normalise, rpkm_column);
             },
-            CoveragePrinter::MetabatAdjustedCoveragePrinter => {
-                // Print header e.g.
-                // contigName      contigLen       totalAvgDepth   2seqs.bad_read.1.bam    2seqs.bad_read.1.bam-var
-                write!(print_stream, "contigName\tcontigLen\ttotalAvgDepth").unwrap();
-                match &cached_coverage_taker {
-                    CoverageTakerType::CachedSingleFloatCoverageTaker {
-                        stoit_names,
-                        ref entry_names,
-                        ref coverages, ..
-                    } => {
-                        for stoit in stoit_names.iter() {
-                            write!(print_stream, "\t{}.bam\t{}.bam-var", &stoit, &stoit).unwrap();
-                        }
-                        writeln!(print_stream).unwrap();
-
-                        // Collect data into an easier to index shape
-                        let mut stoit_by_entry_by_coverage: Vec<Vec<EntryAndCoverages>> = vec!();
-                        let iterator = cached_coverage_taker.generate_iterator();
-                        for ecs in iterator {
-                            // If first entry in stoit, make room
-                            if stoit_by_entry_by_coverage.len() <= ecs.stoit_index {
-                                stoit_by_entry_by_coverage.push(vec![]);
-                            }
-                            stoit_by_entry_by_coverage[ecs.stoit_index].push(ecs);
-                        }
-
-                        // Assumes that all entries are given some coverage.
-                        // Assumes length, mean, variance have been calculated
-                        // in that order, and no other modes have been
-                        // calculated.
-                        for (entry_i, _) in stoit_by_entry_by_coverage[0].iter().enumerate() {
-                            // Calculate the total average across each sample.
-                            let mut total_depth = 0.0;
-                            for stoit_i in 0..stoit_by_entry_by_coverage.len() {
-                                total_depth += stoit_by_entry_by_coverage[stoit_i][entry_i].coverages[1];
-                            }
-                            write!(print_stream, "{}\t{}\t{}",
-                                   entry_names[entry_i].as_ref().unwrap(),
-                                   stoit_by_entry_by_coverage[0][entry_i].coverages[0],
-                                   total_depth / coverages.len() as f32).unwrap();
-                            for stoit_i in 0..coverages.len() {
-                                let c = &stoit_by_entry_by_coverage[stoit_i][entry_i].coverages;
-                                write!(print_stream, "\t{}\t{}", c[1], c[2]).unwrap();
-                            }
-                            writeln!(print_stream).unwrap();
-                        }
-                    },
-                    _ => unreachable!()
-                }
+            CoveragePrinter::MetabatAdjustedCoveragePrinter { include_variance } => {
+                print_metabat_cached_coverage_taker(
+                    *include_variance, cached_coverage_taker, print_stream);
+            },
+            CoveragePrinter::BinnerCoveragePrinter {
+                format,
+                output_directory
+            } => {
+                print_binner_cached_coverage_taker(
+                    *format, output_directory.as_ref().map(|s| s.as_str()),
+                    cached_coverage_taker, print_stream);
             }
         }
     }
@@ -115,7 +132,8 @@ impl CoveragePrinter {
                 *estimator_headers = Some(estimator_headers_vec.iter().map(
                     |s| s.to_string()).collect());
             },
-            CoveragePrinter::MetabatAdjustedCoveragePrinter => {},
+            CoveragePrinter::MetabatAdjustedCoveragePrinter { .. } |
+            CoveragePrinter::BinnerCoveragePrinter { .. } => {},
         }
     }
 }
@@ -123,6 +141,137 @@ impl CoveragePrinter {
 
 
 
+/// Print coverage in the format of MetaBAT's jgi_summarize_bam_contig_depths
+/// e.g.
+/// contigName      contigLen       totalAvgDepth   2seqs.bad_read.1.bam    2seqs.bad_read.1.bam-var
+/// where the variance columns are omitted unless include_variance.
+pub fn print_metabat_cached_coverage_taker<'a>(
+    include_variance: bool,
+    cached_coverage_taker: &'a CoverageTakerType<'a>,
+    print_stream: &mut dyn std::io::Write) {
+
+    write!(print_stream, "contigName\tcontigLen\ttotalAvgDepth").unwrap();
+    match &cached_coverage_taker {
+        CoverageTakerType::CachedSingleFloatCoverageTaker {
+            stoit_names,
+            entry_names, ..
+        } => {
+            for stoit in stoit_names.iter() {
+                write!(print_stream, "\t{}.bam", &stoit).unwrap();
+                if include_variance {
+                    write!(print_stream, "\t{}.bam-var", &stoit).unwrap();
+                }
+            }
+            writeln!(print_stream).unwrap();
+
+            // Collect data into an entry by stoit shape. Entries without
+            // coverage in a stoit are given zero depth and variance there.
+            let mut lengths: Vec<Option<f32>> = vec![None; entry_names.len()];
+            let mut means: Vec<Vec<f32>> = vec![vec![0.0; entry_names.len()]; stoit_names.len()];
+            let mut variances: Vec<Vec<f32>> = vec![vec![0.0; entry_names.len()]; stoit_names.len()];
+            for ecs in cached_coverage_taker.generate_iterator() {
+                lengths[ecs.entry_index] = Some(ecs.coverages[METABAT_LENGTH_INDEX]);
+                means[ecs.stoit_index][ecs.entry_index] = ecs.coverages[METABAT_MEAN_INDEX];
+                if include_variance {
+                    variances[ecs.stoit_index][ecs.entry_index] =
+                        ecs.coverages[METABAT_VARIANCE_INDEX];
+                }
+            }
+
+            for (entry_i, length) in lengths.iter().enumerate() {
+                let length = match length {
+                    Some(l) => l,
+                    None => continue,
+                };
+                // Calculate the total average across each sample.
+                let total_depth: f32 = means.iter().map(|m| m[entry_i]).sum();
+                write!(print_stream, "{}\t{}\t{}",
+                       entry_names[entry_i].as_ref().unwrap(),
+                       length,
+                       total_depth / stoit_names.len() as f32).unwrap();
+                for stoit_i in 0..stoit_names.len() {
+                    write!(print_stream, "\t{}", means[stoit_i][entry_i]).unwrap();
+                    if include_variance {
+                        write!(print_stream, "\t{}", variances[stoit_i][entry_i]).unwrap();
+                    }
+                }
+                writeln!(print_stream).unwrap();
+            }
+        },
+        _ => unreachable!()
+    }
+}
+
+/// Print the mean coverage of each entry in the format expected by a binner.
+/// CONCOCT and VAMB take a single contig by sample table, which is written to
+/// print_stream. MaxBin2 and SemiBin take a headerless contig/abundance file
+/// for each sample, which are written to output_directory. Assumes that the
+/// mean is the only coverage calculated.
+pub fn print_binner_cached_coverage_taker<'a>(
+    format: BinnerFormat,
+    output_directory: Option<&str>,
+    cached_coverage_taker: &'a CoverageTakerType<'a>,
+    print_stream: &mut dyn std::io::Write) {
+
+    match &cached_coverage_taker {
+        CoverageTakerType::CachedSingleFloatCoverageTaker {
+            stoit_names,
+            entry_names, ..
+        } => {
+            // Collect data into an entry by stoit shape. Entries without
+            // coverage in a stoit are given zero coverage there, so that each
+            // sample covers the same set of contigs.
+            let mut means: Vec<Vec<f32>> = vec![vec![0.0; entry_names.len()]; stoit_names.len()];
+            let mut observed = vec![false; entry_names.len()];
+            for ecs in cached_coverage_taker.generate_iterator() {
+                means[ecs.stoit_index][ecs.entry_index] = ecs.coverages[0];
+                observed[ecs.entry_index] = true;
+            }
+            let entry_indices: Vec<usize> = (0..entry_names.len())
+                .filter(|i| observed[*i])
+                .collect();
+
+            if format.writes_per_sample_files() {
+                let directory = output_directory
+                    .expect("Output directory required for per-sample binner output");
+                for (stoit_i, stoit) in stoit_names.iter().enumerate() {
+                    // Stoit names may contain the reference path.
+                    let path = Path::new(directory).join(format!(
+                        "{}.{}", stoit.replace("/", "_"), format.per_sample_file_suffix()));
+                    let mut writer = BufWriter::new(File::create(&path).expect(
+                        &format!("Unable to create abundance file {}", path.display())));
+                    for entry_i in entry_indices.iter() {
+                        writeln!(writer, "{}\t{}",
+                                 entry_names[*entry_i].as_ref().unwrap(),
+                                 means[stoit_i][*entry_i])
+                            .expect("Failed to write abundance file");
+                    }
+                    info!("Wrote abundance of sample '{}' to {}", stoit, path.display());
+                }
+            } else {
+                let (first_header, sample_prefix) = match format {
+                    BinnerFormat::Concoct => ("contig", "cov_mean_sample_"),
+                    BinnerFormat::Vamb => ("contigname", ""),
+                    _ => unreachable!()
+                };
+                write!(print_stream, "{}", first_header).unwrap();
+                for stoit in stoit_names.iter() {
+                    write!(print_stream, "\t{}{}", sample_prefix, stoit).unwrap();
+                }
+                writeln!(print_stream).unwrap();
+                for entry_i in entry_indices.iter() {
+                    write!(print_stream, "{}", entry_names[*entry_i].as_ref().unwrap()).unwrap();
+                    for stoit_means in means.iter() {
+                        write!(print_stream, "\t{}", stoit_means[*entry_i]).unwrap();
+                    }
+                    writeln!(print_stream).unwrap();
+                }
+            }
+        },
+        _ => unreachable!()
+    }
+}
+
 pub fn print_sparse_cached_coverage_taker<'a>(
     cached_coverage_taker: &'a CoverageTakerType<'a>,
     print_stream: &mut dyn std::io::Write,
@@ -225,7 +374,7 @@ pub fn print_sparse_cached_coverage_taker<'a>(
                                 debug!("Writing RPKM with coverage {} and reads_mapped_per_sample {:?}",
                                     coverages[i], reads_mapped_per_sample);
                                 let num_mapped_reads = reads_mapped_per_sample
-                                    .unwrap()[current_stoit_index].num_mapped_reads;
+                                    .unwrap()[current_stoit_index].rpkm_denominator();
                                 write!(
                                     print_stream, "\t{}",
                                     match num_mapped_reads == 0 {
@@ -388,7 +537,7 @@ pub fn print_dense_cached_coverage_taker<'a>(
                             debug!("Writing RPKM with coverage {} and reads_mapped_per_sample {:?}",
                                 coverages[i], reads_mapped_per_sample);
                             let num_mapped_reads = reads_mapped_per_sample
-                                .unwrap()[stoit_i].num_mapped_reads;
+                                .unwrap()[stoit_i].rpkm_denominator();
                             write!(
                                 print_stream, "\t{}",
                                 match num_mapped_reads == 0 {
@@ -452,7 +601,8 @@ mod tests {
             &mut stream,
             Some(&vec!(ReadsMapped {
                 num_mapped_reads: 1,
-                num_reads: 2
+                num_reads: 2,
+                num_mapped_bases: None
             })),
             &vec!(0),
             None);
@@ -487,7 +637,9 @@ mod tests {
         c.add_single_coverage(22.2);
 
         let mut stream = Cursor::new(Vec::new());
-        let mut metabat = CoveragePrinter::MetabatAdjustedCoveragePrinter;
+        let mut metabat = CoveragePrinter::MetabatAdjustedCoveragePrinter {
+            include_variance: true,
+        };
         metabat.finalise_printing(
             &c,
             &mut stream,
@@ -500,4 +652,102 @@ mod tests {
              contig2\t1025\t12.1\t2.1\t2.2\t22.1\t22.2\n",
             str::from_utf8(stream.get_ref()).unwrap());
     }
+
+    #[test]
+    fn test_metabat_mode_printer_no_variance_missing_entry(){
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+
+        c.start_stoit("stoit1");
+        c.start_entry(0, "contig1");
+        c.add_single_coverage(1024.0);
+        c.add_single_coverage(1.1);
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(1025.0);
+        c.add_single_coverage(2.0);
+
+        c.start_stoit("stoit2");
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(1025.0);
+        c.add_single_coverage(22.0);
+
+        let mut stream = Cursor::new(Vec::new());
+        let mut metabat = CoveragePrinter::MetabatAdjustedCoveragePrinter {
+            include_variance: false,
+        };
+        metabat.finalise_printing(
+            &c,
+            &mut stream,
+            None,
+            &vec!(),
+            None);
+        assert_eq!(
+            "contigName\tcontigLen\ttotalAvgDepth\tstoit1.bam\tstoit2.bam\n\
+             contig1\t1024\t0.55\t1.1\t0\n\
+             contig2\t1025\t12\t2\t22\n",
+            str::from_utf8(stream.get_ref()).unwrap());
+    }
+
+    fn two_stoit_mean_coverage_taker<'a>() -> CoverageTakerType<'a> {
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(1);
+        c.start_stoit("stoit1");
+        c.start_entry(0, "contig1");
+        c.add_single_coverage(1.1);
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(2.1);
+        c.start_stoit("stoit2");
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(22.1);
+        c
+    }
+
+    #[test]
+    fn test_concoct_printer(){
+        let c = two_stoit_mean_coverage_taker();
+        let mut stream = Cursor::new(Vec::new());
+        let mut printer = CoveragePrinter::BinnerCoveragePrinter {
+            format: BinnerFormat::Concoct,
+            output_directory: None,
+        };
+        printer.finalise_printing(&c, &mut stream, None, &vec!(), None);
+        assert_eq!(
+            "contig\tcov_mean_sample_stoit1\tcov_mean_sample_stoit2\n\
+             contig1\t1.1\t0\n\
+             contig2\t2.1\t22.1\n",
+            str::from_utf8(stream.get_ref()).unwrap());
+    }
+
+    #[test]
+    fn test_vamb_printer(){
+        let c = two_stoit_mean_coverage_taker();
+        let mut stream = Cursor::new(Vec::new());
+        let mut printer = CoveragePrinter::BinnerCoveragePrinter {
+            format: BinnerFormat::Vamb,
+            output_directory: None,
+        };
+        printer.finalise_printing(&c, &mut stream, None, &vec!(), None);
+        assert_eq!(
+            "contigname\tstoit1\tstoit2\n\
+             contig1\t1.1\t0\n\
+             contig2\t2.1\t22.1\n",
+            str::from_utf8(stream.get_ref()).unwrap());
+    }
+
+    #[test]
+    fn test_maxbin2_printer(){
+        let c = two_stoit_mean_coverage_taker();
+        let td = tempfile::TempDir::new().unwrap();
+        let mut stream = Cursor::new(Vec::new());
+        let mut printer = CoveragePrinter::BinnerCoveragePrinter {
+            format: BinnerFormat::Maxbin2,
+            output_directory: Some(td.path().to_str().unwrap().to_string()),
+        };
+        printer.finalise_printing(&c, &mut stream, None, &vec!(), None);
+        assert!(stream.get_ref().is_empty());
+        assert_eq!(
+            "contig1\t1.1\ncontig2\t2.1\n",
+            std::fs::read_to_string(td.path().join("stoit1.maxbin2.abund")).unwrap());
+        assert_eq!(
+            "contig1\t0\ncontig2\t22.1\n",
+            std::fs::read_to_string(td.path().join("stoit2.maxbin2.abund")).unwrap());
+    }
 }
@@ -0,0 +1,627 @@
+use std;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::process;
+
+use flate2::read::MultiGzDecoder;
+
+use checkpoint::{replay_events, CoverageEvent, CoverageEventRecorder};
+use coverage_takers::*;
+use genomes_and_contigs::find_first;
+use genomes_and_contigs::GenomesAndContigs;
+use mosdepth_genome_coverage_estimators::*;
+use stoit_naming::StoitNaming;
+use ReadsMapped;
+
+/// File name suffixes removed from depth file names to give sample names.
+const DEPTH_FILE_SUFFIXES: &[&str] = &[".per-base.bed", ".bed", ".depth", ".tsv", ".txt"];
+
+/// The per-base read depth along one contig, as read from a depth file.
+#[derive(Debug, PartialEq)]
+pub struct DepthFileContig {
+    pub name: String,
+    /// Changes in depth along the contig, in the same form as is accumulated
+    /// from BAM records, so the depth at position i is the sum of the first
+    /// i+1 values.
+    pub ups_and_downs: Vec<i32>,
+}
+
+/// Reads per-base depth files, either from `samtools depth` (contig,
+/// 1-based position, depth) or mosdepth's per-base BED output (contig,
+/// 0-based start, end, depth), optionally gzip/bgzip compressed.
+///
+/// Depth files do not record the lengths of contigs, so each contig is
+/// assumed to end at the last position reported for it. Positions with zero
+/// depth are only reported by `samtools depth` when it is run with `-a`, and
+/// contigs with no coverage at all only with `-aa`.
+pub struct DepthFileReader {
+    path: String,
+    lines: std::io::Lines<Box<dyn BufRead>>,
+    line_number: usize,
+    current_contig: Option<String>,
+    current_depths: Vec<i32>,
+    seen_contigs: HashSet<String>,
+}
+
+impl DepthFileReader {
+    pub fn from_path(path: &str) -> DepthFileReader {
+        let file = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Unable to open depth file {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        let reader: Box<dyn BufRead> = match path.ends_with(".gz") {
+            true => Box::new(std::io::BufReader::new(MultiGzDecoder::new(file))),
+            false => Box::new(std::io::BufReader::new(file)),
+        };
+        DepthFileReader {
+            path: path.to_string(),
+            lines: reader.lines(),
+            line_number: 0,
+            current_contig: None,
+            current_depths: vec![],
+            seen_contigs: HashSet::new(),
+        }
+    }
+
+    /// Returns the next contig in the file, or None once all contigs have
+    /// been read.
+    pub fn next_contig(&mut self) -> Option<DepthFileContig> {
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(l)) => l,
+                Some(Err(e)) => {
+                    error!("Error reading depth file {}: {}", self.path, e);
+                    process::exit(1);
+                }
+                None => return self.take_current_contig(),
+            };
+            self.line_number += 1;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let contig = fields[0];
+            let mut finished = None;
+            if self.current_contig.as_ref().map(|c| c.as_str()) != Some(contig) {
+                finished = self.take_current_contig();
+                if !self.seen_contigs.insert(contig.to_string()) {
+                    error!(
+                        "Depth file {} is not grouped by contig, since contig '{}' \
+                         was seen again at line {}",
+                        self.path, contig, self.line_number
+                    );
+                    process::exit(1);
+                }
+                self.current_contig = Some(contig.to_string());
+            }
+            match fields.len() {
+                3 => {
+                    // samtools depth
+                    let position: usize = self.parse_field(fields[1]);
+                    let depth: i32 = self.parse_field(fields[2]);
+                    if position == 0 {
+                        self.parse_failure("positions are 1-based, but found position 0");
+                    }
+                    if self.current_depths.len() < position {
+                        self.current_depths.resize(position, 0);
+                    }
+                    self.current_depths[position - 1] = depth;
+                }
+                4 => {
+                    // mosdepth per-base BED
+                    let start: usize = self.parse_field(fields[1]);
+                    let end: usize = self.parse_field(fields[2]);
+                    let depth: i32 = self.parse_field(fields[3]);
+                    if end < start {
+                        self.parse_failure("interval end is before its start");
+                    }
+                    if self.current_depths.len() < end {
+                        self.current_depths.resize(end, 0);
+                    }
+                    for d in self.current_depths[start..end].iter_mut() {
+                        *d = depth;
+                    }
+                }
+                _ => self.parse_failure(
+                    "expected 3 columns (samtools depth of a single BAM file) or 4 \
+                     columns (mosdepth per-base BED)",
+                ),
+            }
+            if finished.is_some() {
+                return finished;
+            }
+        }
+    }
+
+    fn take_current_contig(&mut self) -> Option<DepthFileContig> {
+        match self.current_contig.take() {
+            Some(name) => {
+                let depths = std::mem::replace(&mut self.current_depths, vec![]);
+                Some(DepthFileContig {
+                    name: name,
+                    ups_and_downs: depths_to_ups_and_downs(&depths),
+                })
+            }
+            None => None,
+        }
+    }
+
+    fn parse_field<F: std::str::FromStr>(&self, field: &str) -> F {
+        match field.parse() {
+            Ok(v) => v,
+            Err(_) => self.parse_failure(&format!("unable to parse '{}'", field)),
+        }
+    }
+
+    fn parse_failure(&self, reason: &str) -> ! {
+        error!(
+            "Failed to parse line {} of depth file {}: {}",
+            self.line_number, self.path, reason
+        );
+        process::exit(1);
+    }
+}
+
+/// Stoit name of a depth file, which is its file name without the extensions
+/// unless another name was given.
+pub fn depth_file_stoit_name(path: &str, stoit_naming: &StoitNaming, sample_name: Option<&str>) -> String {
+    let mut name = std::path::Path::new(path)
+        .file_name()
+        .unwrap()
+        .to_str()
+        .expect("failure to convert depth file name to stoit name - UTF8 error maybe?");
+    if name.ends_with(".gz") {
+        name = &name[..name.len() - 3];
+    }
+    for suffix in DEPTH_FILE_SUFFIXES {
+        if name.ends_with(suffix) {
+            name = &name[..name.len() - suffix.len()];
+            break;
+        }
+    }
+    stoit_naming.stoit_name(None, name, sample_name)
+}
+
+fn depths_to_ups_and_downs(depths: &[i32]) -> Vec<i32> {
+    let mut previous = 0;
+    depths
+        .iter()
+        .map(|depth| {
+            let change = depth - previous;
+            previous = *depth;
+            change
+        })
+        .collect()
+}
+
+fn aligned_bases(ups_and_downs: &[i32]) -> u64 {
+    let mut depth: i32 = 0;
+    let mut total: u64 = 0;
+    for change in ups_and_downs {
+        depth += change;
+        total += depth as u64;
+    }
+    total
+}
+
+/// Depth files do not record how many reads were mapped, so all reads are
+/// considered mapped. Relative abundances are therefore relative to the
+/// total coverage of the references, without an unmapped fraction.
+fn reads_mapped_unknown(num_mapped_bases: u64) -> ReadsMapped {
+    ReadsMapped {
+        num_mapped_reads: 1,
+        num_reads: 1,
+        num_mapped_bases: Some(num_mapped_bases),
+    }
+}
+
+pub fn contig_coverage_from_depth_files<T: CoverageTaker>(
+    depth_files: &[&str],
+    coverage_taker: &mut T,
+    coverage_estimators: &mut Vec<CoverageEstimator>,
+    print_zero_coverage_contigs: bool,
+    stoit_naming: &StoitNaming,
+) -> Vec<ReadsMapped> {
+    let mut reads_mapped_vector = vec![];
+    // Contigs are numbered in the order they are first seen in any depth
+    // file, so that entries are consistent across samples.
+    let mut contig_indices: HashMap<String, usize> = HashMap::new();
+    for (i, path) in depth_files.iter().enumerate() {
+        let mut reader = DepthFileReader::from_path(path);
+        let stoit_name = depth_file_stoit_name(path, stoit_naming, stoit_naming.sample_name(i));
+        debug!("Working on depth file {} as stoit {}", path, stoit_name);
+        coverage_taker.start_stoit(&stoit_name);
+
+        // Contigs without coverage may be missing from some depth files (e.g.
+        // from samtools depth without -aa), so a contig first seen in this
+        // file can be numbered after contigs that follow it. Entries are
+        // therefore passed on in order of their numbers once the whole file
+        // is read.
+        let mut entries: Vec<(usize, Vec<CoverageEvent>)> = vec![];
+        let mut num_mapped_bases: u64 = 0;
+        while let Some(contig) = reader.next_contig() {
+            let next_index = contig_indices.len();
+            let index = *contig_indices
+                .entry(contig.name.clone())
+                .or_insert(next_index);
+
+            for estimator in coverage_estimators.iter_mut() {
+                estimator.add_contig(&contig.ups_and_downs, 0, 0)
+            }
+            let coverages: Vec<f32> = coverage_estimators
+                .iter_mut()
+                .map(|estimator| estimator.calculate_coverage(&vec![0]))
+                .collect();
+            let has_nonzero_coverage = coverages.iter().any(|&coverage| coverage > 0.0);
+            if has_nonzero_coverage {
+                num_mapped_bases += aligned_bases(&contig.ups_and_downs);
+            }
+            if print_zero_coverage_contigs || has_nonzero_coverage {
+                let mut recorder = CoverageEventRecorder::new();
+                recorder.start_entry(index, &contig.name);
+                for (coverage, estimator) in coverages.iter().zip(coverage_estimators.iter()) {
+                    estimator.print_coverage(coverage, &mut recorder);
+                }
+                recorder.finish_entry();
+                entries.push((index, recorder.events));
+            }
+            for estimator in coverage_estimators.iter_mut() {
+                estimator.setup();
+            }
+        }
+        entries.sort_by_key(|&(index, _)| index);
+        for (_, events) in entries {
+            replay_events(&events, coverage_taker);
+        }
+        reads_mapped_vector.push(reads_mapped_unknown(num_mapped_bases));
+    }
+    reads_mapped_vector
+}
+
+/// Assigns contigs in depth files to genomes.
+enum GenomeAssigner<'a> {
+    Separator {
+        split_char: u8,
+        genomes: Vec<String>,
+        genome_indices: HashMap<String, usize>,
+    },
+    SingleGenome {
+        genomes: Vec<String>,
+    },
+    ContigNames(&'a GenomesAndContigs),
+}
+
+impl<'a> GenomeAssigner<'a> {
+    fn genome_index(&mut self, contig_name: &str) -> Option<usize> {
+        match self {
+            GenomeAssigner::Separator {
+                split_char,
+                ref mut genomes,
+                ref mut genome_indices,
+            } => {
+                let offset = find_first(contig_name.as_bytes(), *split_char).expect(&format!(
+                    "Contig name {} does not contain split symbol, so cannot determine \
+                     which genome it belongs to",
+                    contig_name
+                ));
+                let genome = &contig_name[0..offset];
+                if !genome_indices.contains_key(genome) {
+                    genome_indices.insert(genome.to_string(), genomes.len());
+                    genomes.push(genome.to_string());
+                }
+                Some(genome_indices[genome])
+            }
+            GenomeAssigner::SingleGenome { .. } => Some(0),
+            GenomeAssigner::ContigNames(contigs_and_genomes) => {
+                contigs_and_genomes.genome_index_of_contig(&contig_name.to_string())
+            }
+        }
+    }
+
+    fn genomes(&self) -> &[String] {
+        match self {
+            GenomeAssigner::Separator { genomes, .. } | GenomeAssigner::SingleGenome { genomes } => {
+                genomes
+            }
+            GenomeAssigner::ContigNames(contigs_and_genomes) => &contigs_and_genomes.genomes,
+        }
+    }
+}
+
+pub fn depth_file_genome_coverage<T: CoverageTaker>(
+    depth_files: &[&str],
+    split_char: u8,
+    coverage_taker: &mut T,
+    print_zero_coverage_genomes: bool,
+    coverage_estimators: &mut Vec<CoverageEstimator>,
+    single_genome: bool,
+    stoit_naming: &StoitNaming,
+) -> Vec<ReadsMapped> {
+    let mut assigner = match single_genome {
+        // Give the single genome the same dummy name as when reading BAM files
+        true => GenomeAssigner::SingleGenome {
+            genomes: vec!["genome1".to_string()],
+        },
+        false => GenomeAssigner::Separator {
+            split_char: split_char,
+            genomes: vec![],
+            genome_indices: HashMap::new(),
+        },
+    };
+    genome_coverage_from_depth_files(
+        depth_files,
+        &mut assigner,
+        coverage_taker,
+        print_zero_coverage_genomes,
+        coverage_estimators,
+        stoit_naming,
+    )
+}
+
+pub fn depth_file_genome_coverage_with_contig_names<T: CoverageTaker>(
+    depth_files: &[&str],
+    contigs_and_genomes: &GenomesAndContigs,
+    coverage_taker: &mut T,
+    print_zero_coverage_genomes: bool,
+    coverage_estimators: &mut Vec<CoverageEstimator>,
+    stoit_naming: &StoitNaming,
+) -> Vec<ReadsMapped> {
+    genome_coverage_from_depth_files(
+        depth_files,
+        &mut GenomeAssigner::ContigNames(contigs_and_genomes),
+        coverage_taker,
+        print_zero_coverage_genomes,
+        coverage_estimators,
+        stoit_naming,
+    )
+}
+
+fn genome_coverage_from_depth_files<T: CoverageTaker>(
+    depth_files: &[&str],
+    assigner: &mut GenomeAssigner,
+    coverage_taker: &mut T,
+    print_zero_coverage_genomes: bool,
+    coverage_estimators: &mut Vec<CoverageEstimator>,
+    stoit_naming: &StoitNaming,
+) -> Vec<ReadsMapped> {
+    let mut reads_mapped_vector = vec![];
+    for (i, path) in depth_files.iter().enumerate() {
+        let mut reader = DepthFileReader::from_path(path);
+        let stoit_name = depth_file_stoit_name(path, stoit_naming, stoit_naming.sample_name(i));
+        debug!("Working on depth file {} as stoit {}", path, stoit_name);
+        coverage_taker.start_stoit(&stoit_name);
+
+        // Contigs of a genome need not be adjacent in the depth file, so
+        // accumulate each genome's coverage before printing any.
+        let mut per_genome_coverage_estimators: Vec<Vec<CoverageEstimator>> = vec![];
+        let mut genome_lengths: Vec<u32> = vec![];
+        let mut genome_aligned_bases: Vec<u64> = vec![];
+        let mut num_contigs_in_genomes: usize = 0;
+        let mut num_contigs_not_in_genomes: usize = 0;
+        let mut num_mapped_bases: u64 = 0;
+        while let Some(contig) = reader.next_contig() {
+            match assigner.genome_index(&contig.name) {
+                Some(genome_index) => {
+                    num_contigs_in_genomes += 1;
+                    while per_genome_coverage_estimators.len() <= genome_index {
+                        per_genome_coverage_estimators.push(coverage_estimators.clone());
+                        genome_lengths.push(0);
+                        genome_aligned_bases.push(0);
+                    }
+                    for estimator in per_genome_coverage_estimators[genome_index].iter_mut() {
+                        estimator.add_contig(&contig.ups_and_downs, 0, 0);
+                    }
+                    genome_lengths[genome_index] += contig.ups_and_downs.len() as u32;
+                    genome_aligned_bases[genome_index] += aligned_bases(&contig.ups_and_downs);
+                }
+                None => num_contigs_not_in_genomes += 1,
+            }
+        }
+        info!(
+            "Of {} contigs in depth file {}, {} were assigned to a genome and {} were not",
+            num_contigs_in_genomes + num_contigs_not_in_genomes,
+            path,
+            num_contigs_in_genomes,
+            num_contigs_not_in_genomes
+        );
+        if num_contigs_in_genomes == 0 {
+            error!(
+                "Error: There are no contigs in depth file {} that are a part of a genome",
+                path
+            );
+            process::exit(1);
+        }
+        if let GenomeAssigner::ContigNames(contigs_and_genomes) = assigner {
+            let num_missing = contigs_and_genomes.contig_to_genome.len() - num_contigs_in_genomes;
+            if num_missing > 0 {
+                warn!(
+                    "Found {} contig(s) that were defined as being part of a genome, \
+                     but were not in depth file {}, so their lengths are not known.",
+                    num_missing, path
+                )
+            }
+        }
+
+        for (i, genome) in assigner.genomes().iter().enumerate() {
+            while per_genome_coverage_estimators.len() <= i {
+                per_genome_coverage_estimators.push(coverage_estimators.clone());
+                genome_lengths.push(0);
+                genome_aligned_bases.push(0);
+            }
+            let estimators = &mut per_genome_coverage_estimators[i];
+            let coverages: Vec<f32> = estimators
+                .iter_mut()
+                .map(|estimator| estimator.calculate_coverage(&vec![]))
+                .collect();
+            let any_nonzero_coverage = coverages.iter().any(|c| *c > 0.0);
+            if any_nonzero_coverage {
+                num_mapped_bases += genome_aligned_bases[i];
+            }
+            if print_zero_coverage_genomes || any_nonzero_coverage {
+                coverage_taker.start_entry(i, genome);
+                for (coverage, estimator) in coverages.iter().zip(estimators.iter()) {
+                    debug!("Found coverage {} for genome {}", coverage, genome);
+                    if *coverage > 0.0 {
+                        estimator.print_coverage(coverage, coverage_taker);
+                    } else {
+                        estimator.print_zero_coverage(coverage_taker, genome_lengths[i]);
+                    }
+                }
+                coverage_taker.finish_entry();
+            }
+        }
+        reads_mapped_vector.push(reads_mapped_unknown(num_mapped_bases));
+    }
+    reads_mapped_vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    fn write_depth_file(td: &tempfile::TempDir, name: &str, contents: &str) -> String {
+        let path = td.path().join(name);
+        let mut f = std::fs::File::create(&path).unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_depth_file_stoit_name() {
+        let naming = StoitNaming::default();
+        assert_eq!("s1", depth_file_stoit_name("/tmp/s1.per-base.bed.gz", &naming, None));
+        assert_eq!("s1", depth_file_stoit_name("s1.depth", &naming, None));
+        assert_eq!("s1.sorted", depth_file_stoit_name("dir/s1.sorted.bed", &naming, None));
+        assert_eq!("given", depth_file_stoit_name("s1.depth", &naming, Some("given")));
+    }
+
+    #[test]
+    fn test_samtools_and_mosdepth_formats_agree() {
+        let td = tempfile::TempDir::new().unwrap();
+        let samtools = write_depth_file(
+            &td,
+            "a.depth",
+            "#CHROM\tPOS\ta.bam\nc1\t1\t0\nc1\t2\t2\nc1\t3\t2\nc1\t4\t1\nc2\t1\t5\n",
+        );
+        let mosdepth = write_depth_file(&td, "b.bed", "c1\t0\t1\t0\nc1\t1\t3\t2\nc1\t3\t4\t1\nc2\t0\t1\t5\n");
+        for path in &[samtools, mosdepth] {
+            let mut reader = DepthFileReader::from_path(path);
+            assert_eq!(
+                Some(DepthFileContig {
+                    name: "c1".to_string(),
+                    ups_and_downs: vec![0, 2, 0, -1]
+                }),
+                reader.next_contig()
+            );
+            assert_eq!(
+                Some(DepthFileContig {
+                    name: "c2".to_string(),
+                    ups_and_downs: vec![5]
+                }),
+                reader.next_contig()
+            );
+            assert_eq!(None, reader.next_contig());
+        }
+    }
+
+    #[test]
+    fn test_contig_coverage_from_depth_files() {
+        let td = tempfile::TempDir::new().unwrap();
+        let path = write_depth_file(&td, "s1.depth", "c1\t1\t2\nc1\t2\t2\nc1\t3\t1\nc1\t4\t1\nc2\t1\t0\nc2\t2\t0\n");
+        let mut stream = Cursor::new(Vec::new());
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(&mut stream);
+            contig_coverage_from_depth_files(
+                &[&path],
+                &mut coverage_taker,
+                &mut vec![
+                    CoverageEstimator::new_estimator_mean(0.0, 0, false),
+                    CoverageEstimator::new_estimator_covered_fraction(0.0),
+                ],
+                true,
+                &StoitNaming::default(),
+            );
+        }
+        assert_eq!(
+            "s1\tc1\t1.5\t1\ns1\tc2\t0\t0\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_contig_coverage_from_depth_files_missing_contigs() {
+        let td = tempfile::TempDir::new().unwrap();
+        // Without -aa, samtools depth leaves out contigs without coverage
+        let path1 = write_depth_file(&td, "s1.depth", "A\t1\t2\nC\t1\t4\n");
+        let path2 = write_depth_file(&td, "s2.depth", "A\t1\t1\nB\t1\t3\nC\t1\t5\n");
+        let mut stream = Cursor::new(Vec::new());
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(&mut stream);
+            contig_coverage_from_depth_files(
+                &[&path1, &path2],
+                &mut coverage_taker,
+                &mut vec![CoverageEstimator::new_estimator_mean(0.0, 0, false)],
+                true,
+                &StoitNaming::default(),
+            );
+        }
+        assert_eq!(
+            "s1\tA\t2\ns1\tC\t4\ns2\tA\t1\ns2\tC\t5\ns2\tB\t3\n",
+            std::str::from_utf8(stream.get_ref()).unwrap()
+        );
+
+        let mut cached = CoverageTakerType::new_cached_single_float_coverage_taker(1);
+        contig_coverage_from_depth_files(
+            &[&path1, &path2],
+            &mut cached,
+            &mut vec![CoverageEstimator::new_estimator_mean(0.0, 0, false)],
+            true,
+            &StoitNaming::default(),
+        );
+        let dense: Vec<(usize, usize, Vec<f32>)> = cached
+            .generate_iterator()
+            .map(|e| (e.stoit_index, e.entry_index, e.coverages))
+            .collect();
+        assert_eq!(
+            vec![
+                (0, 0, vec![2.0]),
+                (0, 1, vec![4.0]),
+                (0, 2, vec![0.0]),
+                (1, 0, vec![1.0]),
+                (1, 1, vec![5.0]),
+                (1, 2, vec![3.0]),
+            ],
+            dense
+        );
+    }
+
+    #[test]
+    fn test_genome_coverage_from_depth_files_with_separator() {
+        let td = tempfile::TempDir::new().unwrap();
+        let path = write_depth_file(&td, "s1.depth", "g1~c1\t1\t2\ng1~c1\t2\t2\ng2~c1\t1\t0\ng1~c2\t1\t0\ng1~c2\t2\t0\n");
+        let mut stream = Cursor::new(Vec::new());
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(&mut stream);
+            depth_file_genome_coverage(
+                &[&path],
+                b'~',
+                &mut coverage_taker,
+                false,
+                &mut vec![
+                    CoverageEstimator::new_estimator_mean(0.0, 0, false),
+                    CoverageEstimator::new_estimator_covered_bases(0.0),
+                ],
+                false,
+                &StoitNaming::default(),
+            );
+        }
+        assert_eq!("s1\tg1\t1\t2\n", std::str::from_utf8(stream.get_ref()).unwrap());
+    }
+}
@@ -17,6 +17,14 @@ pub fn check_for_samtools() {
     self::default_version_check("samtools","1.9");
 }
 
+pub fn check_for_zstd() {
+    self::check_for_external_command_presence("zstd", "which zstd");
+}
+
+pub fn check_for_bzip2() {
+    self::check_for_external_command_presence("bzip2", "which bzip2");
+}
+
 fn check_for_external_command_presence(
     executable_name: &str, testing_cmd: &str) {
     debug!("Checking for {} ..", executable_name);
@@ -44,6 +52,38 @@ fn check_for_external_command_presence(
 }
 
 fn default_version_check(executable_name: &str, min_version: &str) {
+    let version = executable_version(executable_name);
+    let expected_version = Version::from(min_version)
+        .expect("Programming error: failed to parse code-specified version");
+    let found_version = Version::from(&version)
+        .expect(&format!("Unable to parse version number '{}' from executable {}",
+            version, executable_name));
+
+    info!("Found {} version {} ", executable_name, found_version);
+    if found_version < expected_version {
+        error!("It appears the available version of {} is too old \
+            (found version {}, required is {})",
+            executable_name, found_version, expected_version);
+        process::exit(11);
+    }
+}
+
+/// Find the version of BWA, which does not have a --version flag but reports
+/// its version in the usage message it prints when run without arguments.
+pub fn bwa_version() -> String {
+    let output = std::process::Command::new("bwa")
+        .output()
+        .expect("Unable to execute bwa to find its version");
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .find(|line| line.starts_with("Version:"))
+        .map(|line| line["Version:".len()..].trim().to_string())
+        .expect("Unable to parse version for bwa")
+}
+
+/// Find the version of an executable from the last word of the first line
+/// output by `<executable> --version`.
+pub fn executable_version(executable_name: &str) -> String {
     let version_command = format!("{} --version", executable_name);
     let mut cmd = std::process::Command::new("bash");
     cmd
@@ -69,28 +109,15 @@ fn default_version_check(executable_name: &str, min_version: &str) {
     let mut version = String::new();
     process.stdout.expect("Failed to grab stdout from failed command version finding process")
         .read_to_string(&mut version).expect("Failed to read stdout into string");
-    version = version.trim().to_string();
-
-    let expected_version = Version::from(min_version)
-        .expect("Programming error: failed to parse code-specified version");
-    let found_version = Version::from(
-        version
-            .lines()
-            .next()
-            .expect(&format!("Unable to parse version for {} (error 1)", &executable_name))
-            .trim()
-            .rsplit(' ')
-            .next()
-            .expect(&format!("Unable to parse version for {} (error 2)", &executable_name)))
-
-        .expect(&format!("Unable to parse version number '{}' from executable {}",
-            version, executable_name));
 
-    info!("Found {} version {} ", executable_name, found_version);
-    if found_version < expected_version {
-        error!("It appears the available version of {} is too old \
-            (found version {}, required is {})",
-            executable_name, found_version, expected_version);
-        process::exit(11);
-    }
+    version
+        .trim()
+        .lines()
+        .next()
+        .expect(&format!("Unable to parse version for {} (error 1)", &executable_name))
+        .trim()
+        .rsplit(' ')
+        .next()
+        .expect(&format!("Unable to parse version for {} (error 2)", &executable_name))
+        .to_string()
 }
@@ -0,0 +1,223 @@
+use std;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use bio::alphabets::dna;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rust_htslib::bam;
+
+type GzFastqWriter = GzEncoder<BufWriter<File>>;
+
+/// Writes the reads of BAM records as gzip-compressed FASTQ, either
+/// interleaved or as separate files of first and second reads of each pair.
+/// Each read is written once, from its primary alignment, and pairs are only
+/// written once both mates have been seen. Records are expected in reference
+/// order, so that reads can be forgotten once the position of their mate has
+/// been passed.
+pub struct FastqWriter {
+    output_path: String,
+    writer1: GzFastqWriter,
+    writer2: Option<GzFastqWriter>,
+    pending_mates: HashMap<Vec<u8>, bam::Record>,
+    // Names of reads in pending_mates by the (tid, pos) of their mate, as in
+    // ReferenceSortedBamFilter.
+    mate_positions: BTreeMap<(i32, i32), Vec<Vec<u8>>>,
+    num_unpaired: u64,
+    pub num_reads_written: u64,
+}
+
+fn gz_fastq_writer(path: &str) -> GzFastqWriter {
+    let file = File::create(path).expect(&format!("Failed to create FASTQ file {}", path));
+    GzEncoder::new(BufWriter::new(file), Compression::default())
+}
+
+impl FastqWriter {
+    /// Paired reads are written to output2 if it is specified, otherwise
+    /// interleaved in output1. Unpaired reads are written to output1.
+    pub fn new(output1: &str, output2: Option<&str>) -> FastqWriter {
+        FastqWriter {
+            output_path: output1.to_string(),
+            writer1: gz_fastq_writer(output1),
+            writer2: output2.map(gz_fastq_writer),
+            pending_mates: HashMap::new(),
+            mate_positions: BTreeMap::new(),
+            num_unpaired: 0,
+            num_reads_written: 0,
+        }
+    }
+
+    pub fn add_record(&mut self, record: &bam::Record) {
+        if record.is_secondary() || record.is_supplementary() {
+            return;
+        }
+        if !record.is_paired() {
+            write_fastq_record(&mut self.writer1, record);
+            self.num_reads_written += 1;
+            return;
+        }
+        self.forget_reads_with_passed_mates(record.tid(), record.pos());
+        match self.pending_mates.remove(record.qname()) {
+            None => {
+                self.mate_positions.entry((record.mtid(), record.mpos()))
+                    .or_insert_with(Vec::new)
+                    .push(record.qname().to_vec());
+                self.pending_mates.insert(record.qname().to_vec(), record.clone());
+            }
+            Some(mate) => {
+                let (first, second) = match record.is_first_in_template() {
+                    true => (record, &mate),
+                    false => (&mate, record),
+                };
+                write_fastq_record(&mut self.writer1, first);
+                match self.writer2 {
+                    Some(ref mut writer2) => write_fastq_record(writer2, second),
+                    None => write_fastq_record(&mut self.writer1, second),
+                }
+                self.num_reads_written += 2;
+            }
+        }
+    }
+
+    /// Forget reads whose mate is positioned before the given position,
+    /// since the mate would have been seen already if it was going to be.
+    fn forget_reads_with_passed_mates(&mut self, tid: i32, pos: i32) {
+        loop {
+            let mate_position = match self.mate_positions.keys().next() {
+                Some(mate_position) if *mate_position < (tid, pos) => *mate_position,
+                _ => break
+            };
+            for qname in self.mate_positions.remove(&mate_position).unwrap() {
+                if self.pending_mates.remove(&qname).is_some() {
+                    self.num_unpaired += 1;
+                }
+            }
+        }
+    }
+
+    /// Flush the output, returning the number of reads not written because
+    /// their mate was not seen.
+    pub fn finish(self) -> u64 {
+        let num_unpaired = self.num_unpaired + self.pending_mates.len() as u64;
+        let output_path = self.output_path;
+        let finish_writer = |writer: GzFastqWriter| {
+            writer
+                .finish()
+                .and_then(|mut w| w.flush())
+                .expect(&format!("Failed to finish writing FASTQ output {}", output_path));
+        };
+        finish_writer(self.writer1);
+        if let Some(writer2) = self.writer2 {
+            finish_writer(writer2);
+        }
+        num_unpaired
+    }
+}
+
+/// Write the read of a record in its original orientation.
+fn write_fastq_record(writer: &mut dyn Write, record: &bam::Record) {
+    let mut seq = record.seq().as_bytes();
+    let mut qual: Vec<u8> = match record.qual().first() {
+        // Quality is not stored e.g. when reads were mapped from FASTA, so
+        // write uninformative high qualities.
+        Some(&255) | None => vec![b'I'; seq.len()],
+        _ => record.qual().iter().map(|q| q + 33).collect(),
+    };
+    if record.is_reverse() {
+        seq = dna::revcomp(&seq);
+        qual.reverse();
+    }
+    writer
+        .write_all(b"@")
+        .and_then(|_| writer.write_all(record.qname()))
+        .and_then(|_| writer.write_all(b"\n"))
+        .and_then(|_| writer.write_all(&seq))
+        .and_then(|_| writer.write_all(b"\n+\n"))
+        .and_then(|_| writer.write_all(&qual))
+        .and_then(|_| writer.write_all(b"\n"))
+        .expect("Failed to write FASTQ record");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    fn record(qname: &[u8], flags: u16, seq: &[u8], qual: &[u8]) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(
+            qname,
+            Some(&CigarString(vec![Cigar::Match(seq.len() as u32)])),
+            seq,
+            qual,
+        );
+        record.set_flags(flags);
+        record
+    }
+
+    fn read_gz(path: &std::path::Path) -> String {
+        let mut contents = String::new();
+        MultiGzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_fastq_writer_paired() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path1 = tmp.path().join("reads.1.fq.gz");
+        let path2 = tmp.path().join("reads.2.fq.gz");
+        let mut writer = FastqWriter::new(
+            path1.to_str().unwrap(), Some(path2.to_str().unwrap()));
+        // read2 is reverse complemented, and seen before read1.
+        writer.add_record(&record(b"pair", 1 + 16 + 128, b"AACG", &[30, 31, 32, 33]));
+        writer.add_record(&record(b"orphan", 1 + 64, b"TTTT", &[30; 4]));
+        writer.add_record(&record(b"pair", 1 + 32 + 64, b"GGGA", &[20, 21, 22, 23]));
+        assert_eq!(2, writer.num_reads_written);
+        assert_eq!(1, writer.finish());
+        assert_eq!("@pair\nGGGA\n+\n5678\n", read_gz(&path1));
+        assert_eq!("@pair\nCGTT\n+\nBA@?\n", read_gz(&path2));
+    }
+
+    #[test]
+    fn test_fastq_writer_forgets_reads_with_passed_mates() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("reads.fq.gz");
+        let mut writer = FastqWriter::new(path.to_str().unwrap(), None);
+        let placed = |qname: &[u8], flags, pos, mpos| {
+            let mut r = record(qname, flags, b"AC", &[30; 2]);
+            r.set_tid(0);
+            r.set_pos(pos);
+            r.set_mtid(0);
+            r.set_mpos(mpos);
+            r
+        };
+        writer.add_record(&placed(b"orphan", 1 + 64, 0, 10));
+        writer.add_record(&placed(b"pair", 1 + 64, 5, 20));
+        assert_eq!(2, writer.pending_mates.len());
+        // The orphan's mate would have been seen by now
+        writer.add_record(&placed(b"pair", 1 + 128, 20, 5));
+        assert!(writer.pending_mates.is_empty());
+        assert_eq!(2, writer.num_reads_written);
+        assert_eq!(1, writer.finish());
+    }
+
+    #[test]
+    fn test_fastq_writer_interleaved() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("reads.fq.gz");
+        let mut writer = FastqWriter::new(path.to_str().unwrap(), None);
+        writer.add_record(&record(b"single", 0, b"ACGT", &[255; 4]));
+        writer.add_record(&record(b"pair", 1 + 128, b"CC", &[30; 2]));
+        writer.add_record(&record(b"pair", 1 + 64, b"GG", &[30; 2]));
+        writer.add_record(&record(b"pair", 1 + 64 + 256, b"GG", &[30; 2]));
+        assert_eq!(0, writer.finish());
+        assert_eq!(
+            "@single\nACGT\n+\nIIII\n@pair\nGG\n+\n??\n@pair\nCC\n+\n??\n",
+            read_gz(&path));
+    }
+}
@@ -1,6 +1,6 @@
 use std::rc::Rc;
 use std::str;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::process;
 
 use FlagFilter;
@@ -10,10 +10,44 @@ use rust_htslib::bam::Read;
 use rust_htslib::bam::record::Cigar;
 use rust_htslib::bam::errors::Result as HtslibResult;
 
+/// Which genome each reference belongs to, so that mates mapped to different
+/// contigs of the same genome can be filtered as a pair.
+#[derive(Clone, Debug)]
+pub enum MateGenomes {
+    /// Mates are only paired when mapped to the same reference.
+    SameReference,
+    /// All references are contigs of a single genome.
+    SingleGenome,
+    /// Reference names are the genome name, this separator, then the contig
+    /// name.
+    Separator(u8),
+    /// Genome index of each contig, as in GenomesAndContigs. Mates of contigs
+    /// not listed are only paired on the same reference.
+    ContigToGenome(Rc<HashMap<String, usize>>),
+}
+
 pub struct ReferenceSortedBamFilter {
     first_set: BTreeMap<Rc<String>, Rc<bam::Record>>,
-    current_reference: i32,
-    known_next_read: Option<bam::Record>,
+    // Names of reads in first_set by the (tid, pos) of their mate, so reads
+    // can be forgotten once the position of their mate has been passed
+    // without it being found. This bounds memory to reads whose mate is yet
+    // to come, rather than all reads of a reference.
+    mate_positions: BTreeMap<(i32, i32), Vec<Rc<String>>>,
+    // Genome of each tid, or None if mates must map to the same tid. Empty
+    // unless set_mate_genomes has been called.
+    reference_genomes: Vec<Option<usize>>,
+    // Records yet to be returned, in the order they are to be returned, each
+    // with whether it passed filtering, or None if that depends on a mate
+    // not yet seen. Reads whose mate is mapped to a later reference are
+    // queued when they are read, so that they are returned alongside the
+    // other reads of their reference. Records read after such a read are
+    // held here until its mate is found or forgotten.
+    output_queue: VecDeque<(Rc<bam::Record>, Option<bool>)>,
+    // Number of records removed from the front of output_queue so far.
+    num_dequeued: usize,
+    // Index in output_queue (counting dequeued records) of each read queued
+    // while waiting for its mate.
+    queued_mates: HashMap<Rc<String>, usize>,
     pub reader: bam::Reader,
     filter_single_reads: bool,
     min_aligned_length_single: u32,
@@ -24,6 +58,9 @@ pub struct ReferenceSortedBamFilter {
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
     pub num_detected_primary_alignments: u64,
+    // Reads excluded from pair filtering because their mate was not found
+    // where expected.
+    pub num_unresolved_pairs: u64,
     flag_filters: FlagFilter,
     filter_out: bool, // true if we are filtering out reads
 }
@@ -51,8 +88,11 @@ impl ReferenceSortedBamFilter {
 
         ReferenceSortedBamFilter {
             first_set: BTreeMap::new(),
-            current_reference: -1,
-            known_next_read: None,
+            mate_positions: BTreeMap::new(),
+            reference_genomes: vec![],
+            output_queue: VecDeque::new(),
+            num_dequeued: 0,
+            queued_mates: HashMap::new(),
             reader: reader,
             filter_single_reads: filtering_single,
             min_aligned_length_single: min_aligned_length_single,
@@ -63,6 +103,7 @@ impl ReferenceSortedBamFilter {
             min_percent_identity_pair: min_percent_identity_pair,
             min_aligned_percent_pair: min_aligned_percent_pair,
             num_detected_primary_alignments: 0,
+            num_unresolved_pairs: 0,
             flag_filters: flag_filters,
             filter_out: filter_out,
         }
@@ -70,6 +111,110 @@ impl ReferenceSortedBamFilter {
 }
 
 impl ReferenceSortedBamFilter {
+    /// Allow mates mapped to different references of the same genome to be
+    /// filtered as a pair. By default, mates must map to the same reference.
+    pub fn set_mate_genomes(&mut self, mate_genomes: &MateGenomes) {
+        let target_names = self.reader.header().target_names();
+        self.reference_genomes = match mate_genomes {
+            MateGenomes::SameReference => vec![None; target_names.len()],
+            MateGenomes::SingleGenome => vec![Some(0); target_names.len()],
+            MateGenomes::Separator(separator) => {
+                let mut genome_indices: HashMap<&[u8], usize> = HashMap::new();
+                target_names.iter().map(|name| {
+                    match name.iter().position(|c| c == separator) {
+                        Some(offset) => {
+                            let num_genomes = genome_indices.len();
+                            Some(*genome_indices.entry(&name[..offset]).or_insert(num_genomes))
+                        },
+                        None => None
+                    }
+                }).collect()
+            },
+            MateGenomes::ContigToGenome(contig_to_genome) => {
+                target_names.iter().map(|name| {
+                    str::from_utf8(name).ok()
+                        .and_then(|contig| contig_to_genome.get(contig))
+                        .cloned()
+                }).collect()
+            }
+        };
+    }
+
+    /// True if the mate of a mapped record can be matched with it, so that
+    /// the two are filtered as a pair.
+    fn mate_is_pairable(&self, record: &bam::Record) -> bool {
+        if record.is_unmapped() || !record.is_paired() || record.is_mate_unmapped() {
+            return false
+        }
+        let tid = record.tid();
+        let mtid = record.mtid();
+        if tid == mtid {
+            return true
+        }
+        match (self.reference_genomes.get(tid as usize),
+               self.reference_genomes.get(mtid as usize)) {
+            (Some(Some(genome)), Some(Some(mate_genome))) => genome == mate_genome,
+            _ => false
+        }
+    }
+
+    /// Forget reads whose mate is positioned before the given position,
+    /// since the mate would have been seen already if it was going to be.
+    fn forget_reads_with_passed_mates(&mut self, tid: i32, pos: i32) {
+        loop {
+            let mate_position = match self.mate_positions.keys().next() {
+                Some(mate_position) if *mate_position < (tid, pos) => *mate_position,
+                _ => break
+            };
+            for qname in self.mate_positions.remove(&mate_position).unwrap() {
+                if self.first_set.remove(&qname).is_some() {
+                    debug!("Mate of read {} not found", qname);
+                    self.num_unresolved_pairs += 1;
+                    self.resolve_queued_mate(&qname, false);
+                }
+            }
+        }
+    }
+
+    /// Record whether a read queued while waiting for its mate passed
+    /// filtering. Returns false if the read was not queued.
+    fn resolve_queued_mate(&mut self, qname: &String, passed: bool) -> bool {
+        match self.queued_mates.remove(qname) {
+            Some(index) => {
+                self.output_queue[index - self.num_dequeued].1 = Some(passed);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Remove and return the next record to be returned, unless the record at
+    /// the front of the queue is still waiting for its mate.
+    fn next_queued_record(&mut self) -> Option<Rc<bam::Record>> {
+        loop {
+            match self.output_queue.front() {
+                Some((_, Some(_))) => {},
+                _ => return None
+            }
+            let (record, passed) = self.output_queue.pop_front().unwrap();
+            self.num_dequeued += 1;
+            if passed == Some(true) {
+                return Some(record)
+            }
+        }
+    }
+
+    /// Queue a record which passed filtering if records before it are yet to
+    /// be returned. Returns true if it can be returned straight away instead.
+    fn return_or_queue(&mut self, record: &bam::Record) -> bool {
+        if self.output_queue.is_empty() {
+            true
+        } else {
+            self.output_queue.push_back((Rc::new(record.clone()), Some(true)));
+            false
+        }
+    }
+
     pub fn read(&mut self, mut record: &mut bam::record::Record) -> HtslibResult<bool> {
         // if doing only singles, remove filter them and return
         if self.filter_single_reads && !self.filter_pairs {
@@ -92,7 +237,8 @@ impl ReferenceSortedBamFilter {
                         &record,
                         self.min_aligned_length_single,
                         self.min_percent_identity_single,
-                        self.min_aligned_percent_single);
+                        self.min_aligned_percent_single,
+                        self.flag_filters.gap_compressed_identity);
                     if (passes_filter2 && self.filter_out) ||
                         (!passes_filter2 && !self.filter_out) {
                             return Ok(true)
@@ -104,116 +250,132 @@ impl ReferenceSortedBamFilter {
 
         // else doing pairs, so do as before except maybe filter out single reads too
         else {
-            if self.known_next_read.is_none() {
-                while self.reader
+            loop {
+                if let Some(next_record) = self.next_queued_record() {
+                    record.clone_from(&next_record);
+                    return Ok(true)
+                }
+                if self.reader
                     .read(&mut record)
-                    .expect("Failure to read BAM record") == true {
-                    debug!("record: {:?}", record);
+                    .expect("Failure to read BAM record") == false {
+                    // No more records, we are finished. Reads still waiting
+                    // for their mate are unresolved.
+                    self.num_unresolved_pairs += self.first_set.len() as u64;
+                    self.first_set = BTreeMap::new();
+                    self.mate_positions = BTreeMap::new();
+                    for (_, index) in self.queued_mates.drain() {
+                        self.output_queue[index - self.num_dequeued].1 = Some(false);
+                    }
+                    return match self.next_queued_record() {
+                        Some(next_record) => {
+                            record.clone_from(&next_record);
+                            Ok(true)
+                        },
+                        None => Ok(false)
+                    }
+                }
+                debug!("record: {:?}", record);
 
-                    debug!("passed flags, {} {} {}",
-                           record.is_secondary(),
-                           record.is_supplementary(),
-                           !record.is_proper_pair());
+                debug!("passed flags, {} {} {}",
+                       record.is_secondary(),
+                       record.is_supplementary(),
+                       !record.is_proper_pair());
 
-                    if !record.is_supplementary() && !record.is_secondary() {
-                        self.num_detected_primary_alignments += 1;
-                    }
+                if !record.is_supplementary() && !record.is_secondary() {
+                    self.num_detected_primary_alignments += 1;
+                }
 
-                    if record.is_unmapped() && !self.filter_out {
+                if record.is_unmapped() && !self.filter_out {
+                    if self.return_or_queue(&record) {
                         return Ok(true)
                     }
+                    continue
+                }
 
-                    // TODO: make usage ensure flag_filtering when mapping
-                    if record.is_secondary() ||
-                        record.is_supplementary() {
-                            continue
-                        }
-                    if !record.is_proper_pair() {
-                        if self.filter_out {
-                            continue
-                        } else {
-                            return Ok(true)
-                        }
+                // TODO: make usage ensure flag_filtering when mapping
+                if record.is_secondary() ||
+                    record.is_supplementary() {
+                        continue
                     }
-
-                    // if a new reference ID is encountered, instantiate a new first read set
-                    if record.tid() != self.current_reference {
-                        if self.first_set.len() > 0 {
-                            warn!("After processing reference tid '{}', there were pairs \
-                                   of reads marked as proper, but appear to be improperly \
-                                   mapped since only one read was found mapping to this \
-                                   reference", self.current_reference);
-                        }
-                        self.current_reference = record.tid();
-                        self.first_set = BTreeMap::new();
+                // Improper pairs are filtered as pairs where possible,
+                // unless only proper pairs are wanted.
+                if (!record.is_proper_pair() && !self.flag_filters.include_improper_pairs) ||
+                    !self.mate_is_pairable(&record) {
+                    if !self.filter_out && self.return_or_queue(&record) {
+                        return Ok(true)
                     }
+                    continue
+                }
 
-                    let qname = String::from(str::from_utf8(record.qname())
-                                             .expect("UTF8 error in conversion of read name"));
-
-                    match self.first_set.remove(&qname) {
-                        None => {
-                            debug!("Processing qname1 {}", qname);
-                            if record.mtid() == self.current_reference {
-                                // if tlen is +ve and < threshold
-                                // add to first read set
-                                self.first_set.insert(Rc::new(qname), Rc::new(record.clone()));
-                                // continue the loop without returning as we need to see the second record
-                            }
-                            // pairs from different contigs are ignored.
-                            else {
-                                warn!(
-                                    "Found a mapping record marked as being a proper pair, \
-                                     but mtid != tid, indicating it was an improper pair. Record was {:?}",
-                                    record);
+                self.forget_reads_with_passed_mates(record.tid(), record.pos());
+
+                let qname = String::from(str::from_utf8(record.qname())
+                                         .expect("UTF8 error in conversion of read name"));
+
+                match self.first_set.remove(&qname) {
+                    None => {
+                        debug!("Processing qname1 {}", qname);
+                        let mate_position = (record.mtid(), record.mpos());
+                        if mate_position < (record.tid(), record.pos()) {
+                            debug!("Mate of read {} not found", qname);
+                            self.num_unresolved_pairs += 1;
+                        } else {
+                            // add to first read set, continuing the loop
+                            // without returning as we need to see the
+                            // second record
+                            let qname = Rc::new(qname);
+                            let record1 = Rc::new(record.clone());
+                            self.mate_positions.entry(mate_position)
+                                .or_insert_with(Vec::new)
+                                .push(qname.clone());
+                            if record.mtid() != record.tid() {
+                                // The mate is on a later reference, so this
+                                // read must be returned before reads of that
+                                // reference.
+                                self.queued_mates.insert(
+                                    qname.clone(), self.num_dequeued + self.output_queue.len());
+                                self.output_queue.push_back((record1.clone(), None));
                             }
-                        },
-                        Some(record1) => {
-                            debug!("Testing qname2 {}", qname);
-                            // if filtering single and paired reads then
-                            // both must pass QC, as well as the pair
-                            // together.
-                            let passes_filter = (!self.filter_single_reads ||
-                                                 (single_read_passes_filter(
-                                                     &record1,
-                                                     self.min_aligned_length_single,
-                                                     self.min_percent_identity_single,
-                                                     self.min_aligned_percent_single) &&
-                                                  single_read_passes_filter(
-                                                      &record,
-                                                      self.min_aligned_length_single,
-                                                      self.min_percent_identity_single,
-                                                      self.min_aligned_percent_single))) &&
-                                read_pair_passes_filter(
-                                    &record,
-                                    &record1,
-                                    self.min_aligned_length_pair,
-                                    self.min_percent_identity_pair,
-                                    self.min_aligned_percent_pair);
-                            if (passes_filter && self.filter_out) ||
-                                (!passes_filter && !self.filter_out) {
-                                    debug!("Read pair passed QC");
-                                    self.known_next_read = Some(record.clone());
-                                    record.clone_from(
-                                        &Rc::try_unwrap(record1).expect("Cannot get strong RC pointer"));
-                                    debug!("Returning..");
-                                    return Ok(true)
-                                } else {
-                                    debug!("Read pair did not pass QC");
-                                }
+                            self.first_set.insert(qname, record1);
+                        }
+                    },
+                    Some(record1) => {
+                        debug!("Testing qname2 {}", qname);
+                        // if filtering single and paired reads then
+                        // both must pass QC, as well as the pair
+                        // together.
+                        let passes_filter = (!self.filter_single_reads ||
+                                             (single_read_passes_filter(
+                                                 &record1,
+                                                 self.min_aligned_length_single,
+                                                 self.min_percent_identity_single,
+                                                 self.min_aligned_percent_single,
+                                                 self.flag_filters.gap_compressed_identity) &&
+                                              single_read_passes_filter(
+                                                  &record,
+                                                  self.min_aligned_length_single,
+                                                  self.min_percent_identity_single,
+                                                  self.min_aligned_percent_single,
+                                                  self.flag_filters.gap_compressed_identity))) &&
+                            read_pair_passes_filter(
+                                &record,
+                                &record1,
+                                self.min_aligned_length_pair,
+                                self.min_percent_identity_pair,
+                                self.min_aligned_percent_pair);
+                        let passes = (passes_filter && self.filter_out) ||
+                            (!passes_filter && !self.filter_out);
+                        if !self.resolve_queued_mate(&qname, passes) && passes {
+                            self.output_queue.push_back((record1, Some(true)));
+                        }
+                        if passes {
+                            debug!("Read pair passed QC");
+                            self.output_queue.push_back((Rc::new(record.clone()), Some(true)));
+                        } else {
+                            debug!("Read pair did not pass QC");
                         }
                     }
                 }
-
-                // No more records, we are finished.
-                return Ok(false)
-            }
-
-
-            else {
-                record.clone_from(self.known_next_read.as_ref().unwrap());
-                self.known_next_read = None;
-                return Ok(true)
             }
         }
     }
@@ -230,7 +392,8 @@ fn single_read_passes_filter(
     record: &bam::Record,
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
-    min_aligned_percent_single: f32) -> bool {
+    min_aligned_percent_single: f32,
+    gap_compressed_identity: bool) -> bool {
 
     let edit_distance1 = match record.aux(b"NM") {
         Some(i) => i.integer(),
@@ -241,27 +404,45 @@ fn single_read_passes_filter(
     };
 
     let mut aligned: u32 = 0;
+    let mut matched_columns: u32 = 0;
+    let mut indel_bases: u32 = 0;
+    let mut num_gaps: u32 = 0;
     for cig in record.cigar().iter() {
         match cig {
             Cigar::Match(i) |
-            Cigar::Ins(i) |
-            Cigar::Del(i) |
             Cigar::Diff(i) |
             Cigar::Equal(i) => {
                 aligned += i;
+                matched_columns += i;
+            },
+            Cigar::Ins(i) |
+            Cigar::Del(i) => {
+                aligned += i;
+                indel_bases += i;
+                num_gaps += 1;
             },
             _ => {}
         }
     }
 
+    let percent_identity = match gap_compressed_identity {
+        // Each gap counts as one difference, as for minimap2's 'de' tag.
+        true => {
+            let mismatches = edit_distance1 as u32 - std::cmp::min(
+                indel_bases, edit_distance1 as u32);
+            1.0 - (mismatches + num_gaps) as f32 / (matched_columns + num_gaps) as f32
+        },
+        false => 1.0 - edit_distance1 as f32 / aligned as f32
+    };
+
     debug!("num_bases {}, distance {}, perc id {}, percent aligned {}",
            aligned, edit_distance1,
-           1.0 - edit_distance1 as f32 / aligned as f32,
+           percent_identity,
            aligned as f32 / record.seq().len() as f32);
 
     return aligned >= min_aligned_length_single &&
         aligned as f32 / record.seq().len() as f32 >= min_aligned_percent_single &&
-        1.0 - edit_distance1 as f32 / aligned as f32 >= min_percent_identity_single
+        percent_identity >= min_percent_identity_single
 }
 
 fn read_pair_passes_filter(
@@ -339,6 +520,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 90, 0.99, 0.0, true);
         let queries = vec![
@@ -385,6 +567,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 90, 0.99, 0.0, false);
         let queries: Vec<&str> = vec![];
@@ -407,6 +590,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 250, 0.99, 0.0, true); // perc too high
         let queries = vec![
@@ -429,6 +613,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 300, 0.98, 0.0, true); // aligned length too high
         let queries = vec![
@@ -450,6 +635,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 0, 0.98, 0.94, true); // aligned percent too high
         let queries = vec![
@@ -471,6 +657,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 299, 0.98, 0.0, true);
         let queries = vec![
@@ -495,6 +682,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 250, 0.99, 0.0, false); // perc too high
         let queries = vec![
@@ -515,6 +703,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 300, 0.98, 0.0, false); // aligned length too high
         let queries = vec![
@@ -534,6 +723,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 0, 0.98, 0.94, false); // aligned percent too high
         let queries = vec![
@@ -553,6 +743,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0,0.0,0.0, 299, 0.98, 0.0, false);
         let queries: Vec<&str> = vec![];
@@ -573,6 +764,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0, 0.99, 0.0, 0,0.0,0.0, true); // perc too high
         assert_eq!(true, sorted.filter_single_reads);
@@ -599,6 +791,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0, 0.99, 0.0, 0,0.0,0.0, false); // perc too high
         assert_eq!(true, sorted.filter_single_reads);
@@ -622,6 +815,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0, 0.95, 0.0, 300,0.0,0.0, true); // perc OK, but pair fails on length
         assert_eq!(true, sorted.filter_single_reads);
@@ -650,6 +844,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             0, 0.95, 0.0, 300,0.0,0.0, false); // perc OK, but pair fails on length
         assert_eq!(true, sorted.filter_single_reads);
@@ -675,6 +870,7 @@ mod tests {
                 include_improper_pairs: false,
                 include_secondary: false,
                 include_supplementary: false,
+                gap_compressed_identity: false,
             },
             // 1 base required from reads mapped in proper pair, all pass.
             0, 0.0, 0.0, 1,0.0,0.0, true);
@@ -687,4 +883,91 @@ mod tests {
         }
         assert_eq!(11192, num_passing);
     }
+
+    #[test]
+    fn test_mate_genomes(){
+        // References are genome1~random_sequence_length_11000,
+        // genome1~random_sequence_length_11010, genome2~seq1, ...
+        let reader = bam::Reader::from_path(
+            &"tests/data/7seqs.reads_for_seq1_and_seq2.bam").unwrap();
+        let mut sorted = ReferenceSortedBamFilter::new(
+            reader,
+            FlagFilter {
+                include_improper_pairs: true,
+                include_secondary: false,
+                include_supplementary: false,
+                gap_compressed_identity: false,
+            },
+            0,0.0,0.0, 90, 0.99, 0.0, true);
+        let mut record = bam::record::Record::new();
+        record.set_flags(1); // paired, mate mapped
+        record.set_tid(0);
+        record.set_mtid(1);
+        assert_eq!(false, sorted.mate_is_pairable(&record));
+
+        sorted.set_mate_genomes(&MateGenomes::Separator(b'~'));
+        assert_eq!(true, sorted.mate_is_pairable(&record));
+        record.set_mtid(2);
+        assert_eq!(false, sorted.mate_is_pairable(&record));
+
+        sorted.set_mate_genomes(&MateGenomes::SingleGenome);
+        assert_eq!(true, sorted.mate_is_pairable(&record));
+        record.set_flags(1 + 8); // mate unmapped
+        assert_eq!(false, sorted.mate_is_pairable(&record));
+        record.set_flags(1 + 4); // unmapped, placed at the mate's position
+        record.set_mtid(0);
+        assert_eq!(false, sorted.mate_is_pairable(&record));
+        record.set_mtid(2);
+
+        let mut contig_to_genome = HashMap::new();
+        contig_to_genome.insert("genome1~random_sequence_length_11000".to_string(), 0);
+        contig_to_genome.insert("genome2~seq1".to_string(), 0);
+        sorted.set_mate_genomes(&MateGenomes::ContigToGenome(Rc::new(contig_to_genome)));
+        record.set_flags(1);
+        assert_eq!(true, sorted.mate_is_pairable(&record));
+        record.set_mtid(1);
+        assert_eq!(false, sorted.mate_is_pairable(&record));
+    }
+
+    #[test]
+    fn test_unresolved_pairs(){
+        // The first 11 records of a BAM file, so 5 reads whose mate is mapped
+        // to the same contig have no mate in the file.
+        let reader = bam::Reader::from_path(
+            &"tests/data/k141_2005182.head11.bam").unwrap();
+        let mut sorted = ReferenceSortedBamFilter::new(
+            reader,
+            FlagFilter {
+                include_improper_pairs: true,
+                include_secondary: false,
+                include_supplementary: false,
+                gap_compressed_identity: false,
+            },
+            0,0.0,0.0, 0, 0.9, 0.0, true);
+        let mut record = bam::record::Record::new();
+        assert!(sorted.read(&mut record) == Ok(false));
+        assert_eq!(5, sorted.num_unresolved_pairs);
+        assert!(sorted.first_set.is_empty());
+        assert!(sorted.mate_positions.is_empty());
+    }
+
+    #[test]
+    fn test_gap_compressed_identity(){
+        // 200 aligned bases with a single 10bp deletion and no mismatches
+        let mut record = bam::record::Record::new();
+        record.set(
+            b"read1",
+            Some(&bam::record::CigarString(vec![
+                Cigar::Match(100), Cigar::Del(10), Cigar::Match(100)])),
+            &[b'A'; 200],
+            &[30; 200]);
+        record.push_aux(b"NM", &bam::record::Aux::Integer(10));
+
+        // 1 - 10/210 = 0.952
+        assert_eq!(false, single_read_passes_filter(&record, 0, 0.99, 0.0, false));
+        assert_eq!(true, single_read_passes_filter(&record, 0, 0.95, 0.0, false));
+        // 1 - 1/201 = 0.995
+        assert_eq!(true, single_read_passes_filter(&record, 0, 0.99, 0.0, true));
+        assert_eq!(false, single_read_passes_filter(&record, 0, 0.996, 0.0, true));
+    }
 }
@@ -7,6 +7,7 @@ use std::str;
 use std::collections::BTreeSet;
 
 use mosdepth_genome_coverage_estimators::*;
+use allele_counts::AlleleCounts;
 use genomes_and_contigs::GenomesAndContigs;
 use bam_generator::*;
 use coverage_takers::*;
@@ -21,11 +22,13 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
     coverage_taker: &mut T,
     print_zero_coverage_genomes: bool,
     proper_pairs_only: bool,
+    long_read: bool,
     coverage_estimators: &mut Vec<CoverageEstimator>,
     threads: usize)
     -> Vec<ReadsMapped> {
 
     let mut reads_mapped_vector = vec!();
+    let count_alleles = coverage_estimators.iter().any(|e| e.requires_allele_counts());
     let mut is_first_bam = true;
     for bam_generator in bam_readers {
         let mut bam_generated = bam_generator.start();
@@ -47,6 +50,8 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
         // Reads mapped are only counted when the genome has non-zero coverage.
         let mut reads_mapped_in_each_genome: Vec<u64> = vec!(
             0; contigs_and_genomes.genomes.len());
+        let mut bases_mapped_in_each_genome: Vec<u64> = vec!(
+            0; contigs_and_genomes.genomes.len());
         for (tid, name) in target_names.iter().enumerate() {
             let genome_index = contigs_and_genomes.genome_index_of_contig(
                 &String::from(std::str::from_utf8(name)
@@ -94,6 +99,7 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
         let mut last_tid: u32 = 0;
         let mut doing_first = true;
         let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut allele_counts: Option<AlleleCounts> = None;
         let mut record: bam::record::Record = bam::record::Record::new();
         let mut seen_ref_ids = BTreeSet::new();
         let mut num_mapped_reads_in_current_contig: u64 = 0;
@@ -103,7 +109,7 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
             .read(&mut record)
             .expect("Failure to read BAM record") == true {
 
-            if record.is_secondary() || record.is_supplementary() {
+            if record.is_secondary() || (!long_read && record.is_supplementary()) {
                 continue;
             }
             if proper_pairs_only && !record.is_proper_pair() {
@@ -127,6 +133,9 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                                             &ups_and_downs, num_mapped_reads_in_current_contig,
                                             total_edit_distance_in_current_contig -
                                                 total_indels_in_current_contig);
+                                        if let Some(ref counts) = allele_counts {
+                                            coverage_estimator.add_allele_counts(counts);
+                                        }
                                     }
                             },
                             None => {}
@@ -134,6 +143,12 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                     }
 
                     ups_and_downs = vec![0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                    allele_counts = match count_alleles {
+                        true => Some(AlleleCounts::new(
+                            str::from_utf8(target_names[tid as usize]).unwrap(),
+                            ups_and_downs.len())),
+                        false => None
+                    };
                     num_mapped_reads_in_current_contig = 0;
                     total_edit_distance_in_current_contig = 0;
                     total_indels_in_current_contig = 0;
@@ -147,8 +162,13 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                 match reference_number_to_genome_index[tid as usize] {
                     None => {},
                     Some(genome_index) => {
-                        reads_mapped_in_each_genome[genome_index] += 1;
-                        num_mapped_reads_in_current_contig += 1;
+                        // In long-read mode supplementary alignments are
+                        // pieces of a read whose primary alignment is counted
+                        // already.
+                        if !record.is_supplementary() {
+                            reads_mapped_in_each_genome[genome_index] += 1;
+                            num_mapped_reads_in_current_contig += 1;
+                        }
                         trace!("read name {:?}", std::str::from_utf8(record.qname()).unwrap());
                         let mut cursor: usize = record.pos() as usize;
                         for cig in record.cigar().iter() {
@@ -163,6 +183,7 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                                         ups_and_downs[final_pos] -= 1;
                                     }
                                     cursor += cig.len() as usize;
+                                    bases_mapped_in_each_genome[genome_index] += cig.len() as u64;
                                 },
                                 Cigar::Del(_) => {
                                     cursor += cig.len() as usize;
@@ -177,6 +198,9 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                                 Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
                             }
                         }
+                        if let Some(ref mut counts) = allele_counts {
+                            counts.add_record(&record);
+                        }
 
                         // Determine the number of mismatching bases in this read by
                         // looking at the NM tag.
@@ -198,6 +222,7 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
         }
 
         let mut num_mapped_reads_total: u64 = 0;
+        let mut num_mapped_bases_total: u64 = 0;
         if doing_first && bam_generated.num_detected_primary_alignments() == 0 {
             warn!("No primary alignments were observed for sample {} \
                    - perhaps something went wrong in the mapping?",
@@ -210,7 +235,10 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                         coverage_estimator.add_contig(
                             &ups_and_downs, num_mapped_reads_in_current_contig,
                             total_edit_distance_in_current_contig -
-                                total_indels_in_current_contig)
+                                total_indels_in_current_contig);
+                        if let Some(ref counts) = allele_counts {
+                            coverage_estimator.add_allele_counts(counts);
+                        }
                     }
                 },
                 None => {}
@@ -244,6 +272,7 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
                 let any_nonzero_coverage = coverages.iter().any(|c| *c > 0.0);
                 if any_nonzero_coverage {
                     num_mapped_reads_total += reads_mapped_in_each_genome[i];
+                    num_mapped_bases_total += bases_mapped_in_each_genome[i];
                 }
                 if print_zero_coverage_genomes || any_nonzero_coverage {
                     coverage_taker.start_entry(i, &genome);
@@ -271,7 +300,11 @@ pub fn mosdepth_genome_coverage_with_contig_names<R: NamedBamReader,
 
         let reads_mapped = ReadsMapped {
             num_mapped_reads: num_mapped_reads_total,
-            num_reads: bam_generated.num_detected_primary_alignments()
+            num_reads: bam_generated.num_detected_primary_alignments(),
+            num_mapped_bases: match long_read {
+                true => Some(num_mapped_bases_total),
+                false => None
+            }
         };
         info!("In sample '{}', found {} reads mapped out of {} total ({:.*}%)",
               stoit_name, reads_mapped.num_mapped_reads,
@@ -296,6 +329,7 @@ fn print_last_genomes<T: CoverageTaker>(
     last_genome: Option<&[u8]>,
     unobserved_contig_length_and_first_tid: &mut UnobservedLengthAndFirstTid,
     ups_and_downs: &Vec<i32>,
+    allele_counts: &Option<AlleleCounts>,
     total_edit_distance_in_current_contig: u32,
     total_indels_in_current_contig: u32,
     current_genome: &[u8],
@@ -314,6 +348,9 @@ fn print_last_genomes<T: CoverageTaker>(
             &ups_and_downs, num_mapped_reads_in_current_contig,
             total_edit_distance_in_current_contig -
                 total_indels_in_current_contig);
+        if let Some(ref counts) = allele_counts {
+            coverage_estimator.add_allele_counts(counts);
+        }
     }
 
     // Determine coverage of previous genome
@@ -368,9 +405,11 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
     coverage_estimators: &mut Vec<CoverageEstimator>,
     proper_pairs_only: bool,
     single_genome: bool,
+    long_read: bool,
     threads: usize)
     -> Vec<ReadsMapped> {
     let mut reads_mapped_vector = vec!();
+    let count_alleles = coverage_estimators.iter().any(|e| e.requires_allele_counts());
     for bam_generator in bam_readers {
         let mut bam_generated = bam_generator.start();
         bam_generated.set_threads(threads);
@@ -436,17 +475,20 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
             first_tid: 0
         };
         let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut allele_counts: Option<AlleleCounts> = None;
         let mut record: bam::record::Record = bam::record::Record::new();
         let mut num_mapped_reads_total: u64 = 0;
         let mut num_mapped_reads_in_current_contig: u64 = 0;
         let mut num_mapped_reads_in_current_genome: u64 = 0;
+        let mut num_mapped_bases_total: u64 = 0;
+        let mut num_mapped_bases_in_current_genome: u64 = 0;
         let mut total_edit_distance_in_current_contig: u32 = 0;
         let mut total_indels_in_current_contig: u32 = 0;
         while bam_generated
             .read(&mut record)
             .expect("Failure to read BAM record") == true {
 
-            if record.is_secondary() || record.is_supplementary() {
+            if record.is_secondary() || (!long_read && record.is_supplementary()) {
                 continue;
             }
             if proper_pairs_only && !record.is_proper_pair() {
@@ -497,6 +539,9 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                                 &ups_and_downs, num_mapped_reads_in_current_contig,
                                 total_edit_distance_in_current_contig -
                                     total_indels_in_current_contig);
+                            if let Some(ref counts) = allele_counts {
+                                coverage_estimator.add_allele_counts(counts);
+                            }
                         }
                         // Collect the length of reference sequences from this
                         // genome that had no hits that were just skipped over.
@@ -519,6 +564,7 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                             last_genome,
                             &mut unobserved_contig_length_and_first_tid,
                             &ups_and_downs,
+                            &allele_counts,
                             total_edit_distance_in_current_contig,
                             total_indels_in_current_contig,
                             current_genome,
@@ -533,8 +579,10 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                         );
                         if positive_coverage {
                             num_mapped_reads_total += num_mapped_reads_in_current_genome;
+                            num_mapped_bases_total += num_mapped_bases_in_current_genome;
                         }
                         num_mapped_reads_in_current_genome = 0;
+                        num_mapped_bases_in_current_genome = 0;
                         last_genome = Some(current_genome);
 
                         unobserved_contig_length_and_first_tid = fill_genome_length_backwards(
@@ -550,6 +598,12 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                     }
 
                     ups_and_downs = vec![0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                    allele_counts = match count_alleles {
+                        true => Some(AlleleCounts::new(
+                            str::from_utf8(target_names[tid as usize]).unwrap(),
+                            ups_and_downs.len())),
+                        false => None
+                    };
                     num_mapped_reads_in_current_contig = 0;
                     total_edit_distance_in_current_contig = 0;
                     total_indels_in_current_contig = 0;
@@ -559,8 +613,12 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                 // Add coverage info for the current record
                 // for each chunk of the cigar string
                 trace!("read name {:?}", std::str::from_utf8(record.qname()).unwrap());
-                num_mapped_reads_in_current_contig += 1;
-                num_mapped_reads_in_current_genome += 1;
+                // In long-read mode supplementary alignments are pieces of
+                // a read whose primary alignment is counted already.
+                if !record.is_supplementary() {
+                    num_mapped_reads_in_current_contig += 1;
+                    num_mapped_reads_in_current_genome += 1;
+                }
                 let mut cursor: usize = record.pos() as usize;
                 for cig in record.cigar().iter() {
                     trace!("Found cigar {:} from {}", cig, cursor);
@@ -574,6 +632,7 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                                 ups_and_downs[final_pos] -= 1;
                             }
                             cursor += cig.len() as usize;
+                            num_mapped_bases_in_current_genome += cig.len() as u64;
                         },
                         Cigar::Del(_) => {
                             cursor += cig.len() as usize;
@@ -588,6 +647,9 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                         Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
                     }
                 }
+                if let Some(ref mut counts) = allele_counts {
+                    counts.add_record(&record);
+                }
 
                 // Determine the number of mismatching bases in this read by
                 // looking at the NM tag.
@@ -634,6 +696,7 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
                 last_genome,
                 &mut unobserved_contig_length_and_first_tid,
                 &ups_and_downs,
+                &allele_counts,
                 total_edit_distance_in_current_contig,
                 total_indels_in_current_contig,
                 b"",
@@ -648,12 +711,17 @@ pub fn mosdepth_genome_coverage<R: NamedBamReader,
             );
             if positive_coverage {
                 num_mapped_reads_total += num_mapped_reads_in_current_genome;
+                num_mapped_bases_total += num_mapped_bases_in_current_genome;
             }
         }
 
         let reads_mapped = ReadsMapped {
             num_mapped_reads: num_mapped_reads_total,
-            num_reads: bam_generated.num_detected_primary_alignments()
+            num_reads: bam_generated.num_detected_primary_alignments(),
+            num_mapped_bases: match long_read {
+                true => Some(num_mapped_bases_total),
+                false => None
+            }
         };
         info!("In sample '{}', found {} reads mapped out of {} total ({:.*}%)",
               stoit_name, reads_mapped.num_mapped_reads,
@@ -805,6 +873,13 @@ mod tests {
     use genome_exclusion::*;
     use std::collections::HashSet;
     use rust_htslib::bam::Read;
+    use test_bam_writer::*;
+    use tempfile;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use filter::MateGenomes;
+    use FlagFilter;
+    use stoit_naming::StoitNaming;
 
     fn test_streaming_with_stream<R: NamedBamReader,
                                   G: NamedBamReaderGenerator<R>>(
@@ -828,6 +903,7 @@ mod tests {
                 coverage_estimators,
                 proper_pairs_only,
                 single_genome,
+                false,
                 1);
         }
         assert_eq!(expected, str::from_utf8(stream.get_ref()).unwrap());
@@ -856,6 +932,7 @@ mod tests {
                 coverage_estimators,
                 proper_pairs_only,
                 single_genome,
+                false,
                 1);
         }
         assert_eq!(expected, str::from_utf8(stream.get_ref()).unwrap());
@@ -882,6 +959,7 @@ mod tests {
                 &mut coverage_taker,
                 print_zero_coverage_contigs,
                 proper_pairs_only,
+                false,
                 coverage_estimators,
                 1);
         }
@@ -909,6 +987,7 @@ mod tests {
                 &mut coverage_taker,
                 print_zero_coverage_contigs,
                 proper_pairs_only,
+                false,
                 coverage_estimators,
                 1);
         }
@@ -921,7 +1000,7 @@ mod tests {
     fn test_one_genome_two_contigs_first_covered(){
         test_streaming_with_stream(
             "2seqs.reads_for_seq1\tse\t0.6\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"], &StoitNaming::default()),
             'q' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
@@ -937,7 +1016,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream(
             "2seqs.reads_for_seq1\tse\t0.6\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -948,7 +1027,7 @@ mod tests {
     fn test_one_genome_two_contigs_second_covered(){
         test_streaming_with_stream(
             "2seqs.reads_for_seq2\tse\t0.6\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq2.bam"], &StoitNaming::default()),
             'q' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
@@ -964,7 +1043,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream(
             "2seqs.reads_for_seq2\tse\t0.6\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -975,7 +1054,7 @@ mod tests {
     fn test_one_genome_two_contigs_both_covered(){
         test_streaming_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t1.2\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             'e' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
@@ -991,7 +1070,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t1.2\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1002,7 +1081,7 @@ mod tests {
     fn test_one_genome_min_fraction_covered_under_min(){
         test_streaming_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t0\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             'e' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.76,0,false)),
@@ -1018,7 +1097,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream(
             "",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             false,
             false,
@@ -1029,7 +1108,7 @@ mod tests {
     fn test_one_genome_min_fraction_covered_just_ok(){
         test_streaming_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t1.2\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             'e' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.759,0,false)),
@@ -1046,7 +1125,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t1.2\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1057,7 +1136,7 @@ mod tests {
     fn test_two_contigs_trimmed_mean(){
         test_streaming_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t1.08875\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             'e' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_trimmed_mean(
@@ -1074,7 +1153,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream(
             "2seqs.reads_for_seq1_and_seq2\ts\t1.08875\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1086,7 +1165,7 @@ mod tests {
     fn test_two_contigs_pileup_counts_estimator(){
         test_streaming_with_stream_pileup_counts(
             "2seqs.reads_for_seq1_and_seq2\ts\t0\t482\n2seqs.reads_for_seq1_and_seq2\ts\t1\t922\n2seqs.reads_for_seq1_and_seq2\ts\t2\t371\n2seqs.reads_for_seq1_and_seq2\ts\t3\t164\n2seqs.reads_for_seq1_and_seq2\ts\t4\t61\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             'e' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_pileup_counts(0.0,0)),
@@ -1102,7 +1181,7 @@ mod tests {
         geco.insert("seq2".to_string(),genome1);
         test_contig_names_with_stream_pileup_counts(
             "2seqs.reads_for_seq1_and_seq2\ts\t0\t482\n2seqs.reads_for_seq1_and_seq2\ts\t1\t922\n2seqs.reads_for_seq1_and_seq2\ts\t2\t371\n2seqs.reads_for_seq1_and_seq2\ts\t3\t164\n2seqs.reads_for_seq1_and_seq2\ts\t4\t61\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1113,7 +1192,7 @@ mod tests {
     fn test_zero_coverage_genomes(){
         test_streaming_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome1\t0\n7seqs.reads_for_seq1_and_seq2\tgenome2\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome3\t0\n7seqs.reads_for_seq1_and_seq2\tgenome4\t0\n7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome6\t0\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.1,0,false)),
@@ -1122,7 +1201,7 @@ mod tests {
 
         test_streaming_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             '~' as u8,
             false,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.1,0,false)),
@@ -1137,7 +1216,9 @@ mod tests {
             generate_sharded_bam_reader_from_bam_files(
                 vec!["tests/data/shard1.bam", "tests/data/shard2.bam"],
                 4,
-                &NoExclusionGenomeFilter{}),
+                &NoExclusionGenomeFilter{},
+                ShardSelection::default(),
+                &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.1,0,false)),
@@ -1163,7 +1244,9 @@ mod tests {
             generate_sharded_bam_reader_from_bam_files(
                 vec!["tests/data/shard1.bam", "tests/data/shard2.bam"],
                 4,
-                &ex),
+                &ex,
+                ShardSelection::default(),
+                &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.1,0,false)),
@@ -1176,7 +1259,7 @@ mod tests {
     fn test_zero_coverage_genomes_after_min_fraction(){
         test_streaming_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome1\t0\n7seqs.reads_for_seq1_and_seq2\tgenome2\t0\n7seqs.reads_for_seq1_and_seq2\tgenome3\t0\n7seqs.reads_for_seq1_and_seq2\tgenome4\t0\n7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome6\t0\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.759,0,false)),
@@ -1188,7 +1271,7 @@ mod tests {
     fn test_single_genome(){
         test_streaming_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome1\t0.04209345\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
@@ -1204,7 +1287,7 @@ mod tests {
         test_streaming_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t669\n\
              7seqs.reads_for_seq1_and_seq2\tgenome5\t849\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             '~' as u8,
             false,
             &mut vec!(CoverageEstimator::new_estimator_covered_bases(0.0)),
@@ -1220,7 +1303,7 @@ mod tests {
         test_streaming_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t669\n\
              7seqs.reads_for_seq1_and_seq2\tgenome5\t849\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             '~' as u8,
             false,
             &mut vec!(CoverageEstimator::new_estimator_covered_bases(0.0)),
@@ -1258,7 +1341,7 @@ mod tests {
             7seqs.reads_for_seq1_and_seq2\tgenome4\t0\n\
             7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\n\
             7seqs.reads_for_seq1_and_seq2\tgenome6\t0\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1266,7 +1349,7 @@ mod tests {
 
         test_contig_names_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t1.2\n7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             false,
             false,
@@ -1303,7 +1386,7 @@ mod tests {
             7seqs.reads_for_seq1_and_seq2\tgenome4\t0\t0\n\
             7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\t0.6166166\n\
             7seqs.reads_for_seq1_and_seq2\tgenome6\t0\t0\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1313,7 +1396,7 @@ mod tests {
 
         let reads_mapped = test_contig_names_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t1.2\t1.3633634\n7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\t0.6166166\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             false,
             false,
@@ -1322,7 +1405,8 @@ mod tests {
                 CoverageEstimator::new_estimator_variance(0.1,0)));
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 24,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
     }
 
@@ -1343,7 +1427,7 @@ mod tests {
         let reads_mapped = test_contig_names_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t1.2\t1.3633634\n\
             7seqs.reads_for_seq1_and_seq2\tgenome3\t0\t0\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             true,
             false,
@@ -1352,7 +1436,8 @@ mod tests {
                 CoverageEstimator::new_estimator_variance(0.1,0)));
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 12,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
     }
 
@@ -1362,7 +1447,8 @@ mod tests {
             "2seqs.reads_for_seq1.with_unmapped\tgenome1\t1.4985\n",
             // has unmapped reads, which caused problems with --proper-pairs-only
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.with_unmapped.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.with_unmapped.bam"],
+                &StoitNaming::default()),
             '\0' as u8,
             true,
             &mut vec!(CoverageEstimator::new_estimator_mean(0.1,0,true)),
@@ -1370,7 +1456,8 @@ mod tests {
             true);
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 20,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
     }
 
@@ -1379,7 +1466,8 @@ mod tests {
         test_streaming_with_stream(
             "2seqs.reads_for_seq1\tgenome1\t0.6\t0\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             'q' as u8,
             true,
             &mut vec!(
@@ -1397,7 +1485,8 @@ mod tests {
         test_streaming_with_stream(
             "2seqs.reads_for_seq1\tgenome1\t0\t0.6\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/2seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             'q' as u8,
             true,
             &mut vec!(
@@ -1415,7 +1504,8 @@ mod tests {
         test_streaming_with_stream(
             "7seqs.reads_for_seq1\tgenome1\t0\t0\n7seqs.reads_for_seq1\tgenome2\t1.2\t0\n7seqs.reads_for_seq1\tgenome3\t0\t0\n7seqs.reads_for_seq1\tgenome4\t0\t0\n7seqs.reads_for_seq1\tgenome5\t0\t0\n7seqs.reads_for_seq1\tgenome6\t0\t0\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/7seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/7seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(
@@ -1433,7 +1523,8 @@ mod tests {
         test_streaming_with_stream(
             "7seqs.reads_for_seq1\tgenome1\t0\t0\n7seqs.reads_for_seq1\tgenome2\t0\t1.2\n7seqs.reads_for_seq1\tgenome3\t0\t0\n7seqs.reads_for_seq1\tgenome4\t0\t0\n7seqs.reads_for_seq1\tgenome5\t0\t0\n7seqs.reads_for_seq1\tgenome6\t0\t0\n",
             generate_named_bam_readers_from_bam_files(
-                vec!["tests/data/7seqs.reads_for_seq1.bam"]),
+                vec!["tests/data/7seqs.reads_for_seq1.bam"],
+                &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(
@@ -1560,7 +1651,8 @@ mod tests {
             generate_named_bam_readers_from_bam_files(
                 vec![
                     "tests/data/7seqs.reads_for_seq1.bam",
-                    "tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+                    "tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+                    &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(
@@ -1574,11 +1666,13 @@ mod tests {
             vec!(
                 ReadsMapped {
                     num_mapped_reads: 12,
-                    num_reads: 12
+                    num_reads: 12,
+                    num_mapped_bases: None
                 },
                 ReadsMapped {
                     num_mapped_reads: 24,
-                    num_reads: 24
+                    num_reads: 24,
+                    num_mapped_bases: None
                 }),
             res);
     }
@@ -1602,7 +1696,8 @@ mod tests {
             generate_named_bam_readers_from_bam_files(
                 vec![
                     "tests/data/7seqs.reads_for_seq1.bam",
-                    "tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+                    "tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+                    &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(
@@ -1617,11 +1712,13 @@ mod tests {
             vec!(
                 ReadsMapped {
                     num_mapped_reads: 12,
-                    num_reads: 12
+                    num_reads: 12,
+                    num_mapped_bases: None
                 },
                 ReadsMapped {
                     num_mapped_reads: 24,
-                    num_reads: 24
+                    num_reads: 24,
+                    num_mapped_bases: None
                 }),
             res);
 
@@ -1642,7 +1739,8 @@ mod tests {
             generate_named_bam_readers_from_bam_files(
                 vec![
                     "tests/data/7seqs.reads_for_seq1.bam",
-                    "tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+                    "tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+                    &StoitNaming::default()),
             '~' as u8,
             true,
             &mut vec!(
@@ -1655,11 +1753,13 @@ mod tests {
             vec!(
                 ReadsMapped {
                     num_mapped_reads: 0,
-                    num_reads: 12
+                    num_reads: 12,
+                    num_mapped_bases: None
                 },
                 ReadsMapped {
                     num_mapped_reads: 0,
-                    num_reads: 24
+                    num_reads: 24,
+                    num_mapped_bases: None
                 }),
             res);
     }
@@ -1685,7 +1785,7 @@ mod tests {
         let mut reads_mapped = test_contig_names_with_stream(
             "7seqs.reads_for_seq1_and_seq2\tgenome2\t1.2\t1.3633634\n\
              7seqs.reads_for_seq1_and_seq2\tgenome5\t1.2\t0.6166166\n",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             false,
             false,
@@ -1694,13 +1794,14 @@ mod tests {
                 CoverageEstimator::new_estimator_variance(0.1,0)));
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 24,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
 
         // Then test when the reads do not make the threshold
         reads_mapped = test_contig_names_with_stream(
             "",
-            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"]),
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/7seqs.reads_for_seq1_and_seq2.bam"], &StoitNaming::default()),
             &geco,
             false,
             false,
@@ -1709,8 +1810,129 @@ mod tests {
                 CoverageEstimator::new_estimator_variance(0.99,0)));
         assert_eq!(vec!(ReadsMapped{
             num_mapped_reads: 0,
-            num_reads: 24
+            num_reads: 24,
+            num_mapped_bases: None
         }), reads_mapped);
     }
 
+
+    #[test]
+    fn test_long_read_supplementary_bases(){
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("long.bam");
+        write_test_bam(&bam, &[("g1~c1", 100)], &[
+            TestAlignment::single("read1", 0, 0, 0, vec![Cigar::Match(50), Cigar::SoftClip(30)]),
+            // The rest of read1, aligned elsewhere
+            TestAlignment::single("read1", 2048, 0, 60, vec![Cigar::SoftClip(50), Cigar::Match(30)]),
+        ]);
+
+        for (long_read, expected, num_mapped_bases) in vec![
+            (true, "long\tg1\t0.8\n", Some(80)),
+            (false, "long\tg1\t0.5\n", None)] {
+            let mut stream = Cursor::new(Vec::new());
+            let reads_mapped;
+            {
+                let mut coverage_taker = CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                    &mut stream);
+                reads_mapped = mosdepth_genome_coverage(
+                    generate_named_bam_readers_from_bam_files(vec![bam.to_str().unwrap()], &StoitNaming::default()),
+                    b'~',
+                    &mut coverage_taker,
+                    true,
+                    &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
+                    false,
+                    false,
+                    long_read,
+                    1);
+            }
+            assert_eq!(expected, str::from_utf8(stream.get_ref()).unwrap());
+            assert_eq!(vec!(ReadsMapped{
+                num_mapped_reads: 1,
+                num_reads: 1,
+                num_mapped_bases: num_mapped_bases
+            }), reads_mapped);
+        }
+    }
+
+    #[test]
+    fn test_filtered_pairs_across_contigs(){
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("pairs.bam");
+        let mate = |qname, flags, tid, pos, mtid, mpos| TestAlignment {
+            qname: qname,
+            flags: flags,
+            tid: tid,
+            pos: pos,
+            cigar: vec![Cigar::Match(50)],
+            mtid: mtid,
+            mpos: mpos,
+            edit_distance: 0,
+        };
+        // Pair x has one mate on each contig of g1, and the pair on g1~c2 is
+        // found before the second mate of x.
+        write_test_bam(&bam, &[("g1~c1", 100), ("g1~c2", 100)], &[
+            mate("a", 1+2+32+64, 0, 0, 0, 50),
+            mate("x", 1+2+32+64, 0, 50, 1, 0),
+            mate("a", 1+2+16+128, 0, 50, 0, 0),
+            mate("b", 1+2+32+64, 1, 0, 1, 0),
+            mate("b", 1+2+16+128, 1, 0, 1, 0),
+            mate("x", 1+2+16+128, 1, 0, 0, 50),
+        ]);
+
+        let flag_filters = FlagFilter {
+            include_improper_pairs: false,
+            include_secondary: false,
+            include_supplementary: false,
+            gap_compressed_identity: false,
+        };
+        let mut stream = Cursor::new(Vec::new());
+        {
+            let mut coverage_taker = CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                &mut stream);
+            mosdepth_genome_coverage(
+                generate_filtered_bam_readers_from_bam_files(
+                    vec![bam.to_str().unwrap()],
+                    flag_filters,
+                    0, 0.0, 0.0,
+                    1, 0.0, 0.0,
+                    &MateGenomes::Separator(b'~'),
+                    &StoitNaming::default()),
+                b'~',
+                &mut coverage_taker,
+                true,
+                &mut vec!(CoverageEstimator::new_estimator_mean(0.0,0,false)),
+                false,
+                false,
+                false,
+                1);
+        }
+        // 150 bases on each contig
+        assert_eq!("pairs\tg1\t1.5\n", str::from_utf8(stream.get_ref()).unwrap());
+    }
+
+    #[test]
+    fn test_snv_count_against_reference(){
+        let td = tempfile::TempDir::new().unwrap();
+        let bam = td.path().join("snv.bam");
+        // Reads are all A's, so differ from the reference at 2 positions of
+        // g1~c1.
+        let mut reference = HashMap::new();
+        reference.insert("g1~c1".to_string(), b"AAAAACAAAAAAAAGAAAAA".to_vec());
+        reference.insert("g1~c2".to_string(), vec![b'A'; 20]);
+        let alignments: Vec<TestAlignment> = (0..10).map(|i|
+            TestAlignment::single(
+                if i < 5 { "c1read" } else { "c2read" }, 0, i / 5, 0, vec![Cigar::Match(20)])
+        ).collect();
+        write_test_bam(&bam, &[("g1~c1", 20), ("g1~c2", 20)], &alignments);
+
+        test_streaming_with_stream(
+            "snv\tg1\t2\n",
+            generate_named_bam_readers_from_bam_files(vec![bam.to_str().unwrap()], &StoitNaming::default()),
+            b'~',
+            false,
+            &mut vec!(CoverageEstimator::new_estimator_allele_variation(
+                AlleleStatistic::SNVCount, 5, 0.05, 0.0, 0, Some(Rc::new(reference)))),
+            false,
+            false);
+    }
 }
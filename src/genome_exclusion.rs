@@ -1,6 +1,12 @@
 use std::collections::HashSet;
 use std::str;
 
+use regex::bytes::Regex;
+use rust_htslib::bam;
+use rust_htslib::bam::errors::Result as HtslibResult;
+
+use bam_generator::*;
+use coverage_takers::CoverageTaker;
 use genomes_and_contigs::GenomesAndContigs;
 use genomes_and_contigs::find_first;
 
@@ -60,9 +66,221 @@ impl GenomeExclusion for NoExclusionGenomeFilter {
     fn is_excluded(&self, _contig_name: &[u8]) -> bool { false }
 }
 
+/// Excludes contigs whose name, or the name of the genome they belong to, is
+/// in a list or matches a regular expression.
+pub struct NameExclusionFilter<'a> {
+    pub excluded_names: HashSet<Vec<u8>>,
+    pub regex: Option<Regex>,
+    // How to find the genome of each contig, if genomes are defined
+    pub split_char: Option<u8>,
+    pub genomes_and_contigs: Option<&'a GenomesAndContigs>,
+}
+
+impl<'a> NameExclusionFilter<'a> {
+    fn name_is_excluded(&self, name: &[u8]) -> bool {
+        self.excluded_names.contains(name) || match self.regex {
+            Some(ref r) => r.is_match(name),
+            None => false,
+        }
+    }
+}
+
+impl<'a> GenomeExclusion for NameExclusionFilter<'a> {
+    fn is_excluded(&self, contig_name: &[u8]) -> bool {
+        if self.name_is_excluded(contig_name) {
+            return true;
+        }
+        if let Some(split_char) = self.split_char {
+            if let Ok(offset) = find_first(contig_name, split_char) {
+                return self.name_is_excluded(&contig_name[0..offset]);
+            }
+        }
+        if let Some(gc) = self.genomes_and_contigs {
+            let contig_str = str::from_utf8(contig_name).unwrap().to_string();
+            if let Some(g) = gc.genome_of_contig(&contig_str) {
+                return self.name_is_excluded(g.as_bytes());
+            }
+        }
+        false
+    }
+}
+
+/// What happens to reads mapped to excluded contigs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExcludedReadTreatment {
+    /// Count reads as unmapped, e.g. so they are part of the unmapped
+    /// fraction when calculating relative abundance.
+    CountAsUnmapped,
+    /// Remove reads entirely, as if they were never sequenced.
+    Remove,
+}
+
+/// Wraps a BAM reader so that reads mapped to excluded contigs are either
+/// marked unmapped or removed.
+pub struct ExcludingNamedBamReader<R: NamedBamReader> {
+    reader: R,
+    excluded_tids: Vec<bool>,
+    treatment: ExcludedReadTreatment,
+    num_removed_primary_alignments: u64,
+}
+
+impl<R: NamedBamReader> NamedBamReader for ExcludingNamedBamReader<R> {
+    fn name(&self) -> &str {
+        self.reader.name()
+    }
+    fn read(&mut self, record: &mut bam::record::Record) -> HtslibResult<bool> {
+        loop {
+            let res = self.reader.read(record);
+            if res != Ok(true) || record.is_unmapped() || record.tid() < 0
+                || !self.excluded_tids[record.tid() as usize] {
+                return res;
+            }
+            match self.treatment {
+                ExcludedReadTreatment::CountAsUnmapped => {
+                    record.set_unmapped();
+                    return res;
+                }
+                ExcludedReadTreatment::Remove => {
+                    if !record.is_secondary() && !record.is_supplementary() {
+                        self.num_removed_primary_alignments += 1;
+                    }
+                }
+            }
+        }
+    }
+    fn header(&self) -> &bam::HeaderView {
+        self.reader.header()
+    }
+    fn finish(self) {
+        if self.num_removed_primary_alignments > 0 {
+            info!("In sample '{}', removed {} reads mapped to excluded contigs",
+                  self.reader.name(), self.num_removed_primary_alignments);
+        }
+        self.reader.finish()
+    }
+    fn set_threads(&mut self, n_threads: usize) {
+        self.reader.set_threads(n_threads)
+    }
+    fn num_detected_primary_alignments(&self) -> u64 {
+        self.reader.num_detected_primary_alignments() - self.num_removed_primary_alignments
+    }
+}
+
+pub struct ExcludingNamedBamReaderGenerator<'a, G, E>
+where E: GenomeExclusion {
+    generator: G,
+    exclusion: &'a E,
+    treatment: ExcludedReadTreatment,
+}
+
+impl<'a, R, G, E> NamedBamReaderGenerator<ExcludingNamedBamReader<R>>
+    for ExcludingNamedBamReaderGenerator<'a, G, E>
+where
+    R: NamedBamReader,
+    G: NamedBamReaderGenerator<R>,
+    E: GenomeExclusion,
+{
+    fn stoit_name(&self) -> &str {
+        self.generator.stoit_name()
+    }
+    fn input_paths(&self) -> &[String] {
+        self.generator.input_paths()
+    }
+    fn start(self) -> ExcludingNamedBamReader<R> {
+        let reader = self.generator.start();
+        let excluded_tids: Vec<bool> = reader
+            .header()
+            .target_names()
+            .iter()
+            .map(|name| self.exclusion.is_excluded(name))
+            .collect();
+        debug!("Excluding {} of {} reference sequences in sample '{}'",
+               excluded_tids.iter().filter(|e| **e).count(),
+               excluded_tids.len(),
+               reader.name());
+        ExcludingNamedBamReader {
+            reader: reader,
+            excluded_tids: excluded_tids,
+            treatment: self.treatment,
+            num_removed_primary_alignments: 0,
+        }
+    }
+}
+
+/// Wrap each generator so that reads mapped to contigs excluded by
+/// exclusion are treated as specified.
+pub fn generate_excluding_bam_readers<'a, R, G, E>(
+    generators: Vec<G>,
+    exclusion: &'a E,
+    treatment: ExcludedReadTreatment,
+) -> Vec<ExcludingNamedBamReaderGenerator<'a, G, E>>
+where
+    R: NamedBamReader,
+    G: NamedBamReaderGenerator<R>,
+    E: GenomeExclusion,
+{
+    generators
+        .into_iter()
+        .map(|generator| ExcludingNamedBamReaderGenerator {
+            generator: generator,
+            exclusion: exclusion,
+            treatment: treatment,
+        })
+        .collect()
+}
+
+/// Wraps a coverage taker so that excluded genomes and contigs are left out
+/// of the output entirely, rather than being reported with zero coverage.
+pub struct ExcludingCoverageTaker<'a, T: CoverageTaker + 'a, E: GenomeExclusion + 'a> {
+    taker: &'a mut T,
+    exclusion: &'a E,
+    in_excluded_entry: bool,
+}
+
+impl<'a, T: CoverageTaker + 'a, E: GenomeExclusion + 'a> ExcludingCoverageTaker<'a, T, E> {
+    pub fn new(taker: &'a mut T, exclusion: &'a E) -> ExcludingCoverageTaker<'a, T, E> {
+        ExcludingCoverageTaker {
+            taker: taker,
+            exclusion: exclusion,
+            in_excluded_entry: false,
+        }
+    }
+}
+
+impl<'a, T: CoverageTaker + 'a, E: GenomeExclusion + 'a> CoverageTaker
+    for ExcludingCoverageTaker<'a, T, E> {
+    fn start_stoit(&mut self, stoit_name: &str) {
+        self.taker.start_stoit(stoit_name)
+    }
+    fn start_entry(&mut self, entry_order_id: usize, entry_name: &str) {
+        self.in_excluded_entry = self.exclusion.is_excluded(entry_name.as_bytes());
+        if !self.in_excluded_entry {
+            self.taker.start_entry(entry_order_id, entry_name)
+        }
+    }
+    fn add_single_coverage(&mut self, coverage: f32) {
+        if !self.in_excluded_entry {
+            self.taker.add_single_coverage(coverage)
+        }
+    }
+    fn add_coverage_entry(&mut self, num_reads: usize, num_bases: u32) {
+        if !self.in_excluded_entry {
+            self.taker.add_coverage_entry(num_reads, num_bases)
+        }
+    }
+    fn finish_entry(&mut self) {
+        if !self.in_excluded_entry {
+            self.taker.finish_entry()
+        }
+        self.in_excluded_entry = false;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use coverage_takers::CoverageTakerType;
+    use stoit_naming::StoitNaming;
 
     #[test]
     fn test_genomes_and_contigs_exclusion_filter() {
@@ -96,4 +314,100 @@ mod tests {
         assert_eq!(true, ex.is_excluded(b"genomeYes=contig1"));
         assert_eq!(false, ex.is_excluded(b"genomeNo=contig1"));
     }
+
+    #[test]
+    fn test_name_exclusion_filter() {
+        let mut excluded_names: HashSet<Vec<u8>> = HashSet::new();
+        excluded_names.insert(b"host".to_vec());
+        excluded_names.insert(b"genome1~contig2".to_vec());
+        let ex = NameExclusionFilter {
+            excluded_names: excluded_names,
+            regex: Some(Regex::new("^phiX").unwrap()),
+            split_char: Some(b'~'),
+            genomes_and_contigs: None,
+        };
+        assert_eq!(true, ex.is_excluded(b"host~contig1"));
+        assert_eq!(true, ex.is_excluded(b"genome1~contig2"));
+        assert_eq!(false, ex.is_excluded(b"genome1~contig1"));
+        assert_eq!(true, ex.is_excluded(b"phiX174~contig1"));
+        assert_eq!(false, ex.is_excluded(b"genome2~phiX"));
+    }
+
+    #[test]
+    fn test_name_exclusion_filter_genomes_and_contigs() {
+        let mut contig_to_genome = GenomesAndContigs::new();
+        let index = contig_to_genome.establish_genome(String::from("host"));
+        contig_to_genome.insert(String::from("chr1"), index);
+        let mut excluded_names: HashSet<Vec<u8>> = HashSet::new();
+        excluded_names.insert(b"host".to_vec());
+        let ex = NameExclusionFilter {
+            excluded_names: excluded_names,
+            regex: None,
+            split_char: None,
+            genomes_and_contigs: Some(&contig_to_genome),
+        };
+        assert_eq!(true, ex.is_excluded(b"chr1"));
+        assert_eq!(false, ex.is_excluded(b"chr2"));
+    }
+
+    #[test]
+    fn test_excluding_bam_reader() {
+        let mut excluded_names: HashSet<Vec<u8>> = HashSet::new();
+        excluded_names.insert(b"seq1".to_vec());
+        let ex = NameExclusionFilter {
+            excluded_names: excluded_names,
+            regex: None,
+            split_char: None,
+            genomes_and_contigs: None,
+        };
+        let count = |treatment| {
+            let generators = generate_excluding_bam_readers(
+                generate_named_bam_readers_from_bam_files(
+                    vec!["tests/data/2seqs.reads_for_seq1_and_seq2.bam"],
+                    &StoitNaming::default()),
+                &ex,
+                treatment);
+            let mut reader = generators.into_iter().next().unwrap().start();
+            let mut record = bam::record::Record::new();
+            let mut num_mapped_to_seq1 = 0;
+            let mut num_records = 0;
+            while reader.read(&mut record) == Ok(true) {
+                num_records += 1;
+                if !record.is_unmapped() && record.tid() == 0 {
+                    num_mapped_to_seq1 += 1;
+                }
+            }
+            (num_mapped_to_seq1, num_records, reader.num_detected_primary_alignments())
+        };
+        // 12 reads are mapped to each of seq1 and seq2
+        assert_eq!((0, 24, 24), count(ExcludedReadTreatment::CountAsUnmapped));
+        assert_eq!((0, 12, 12), count(ExcludedReadTreatment::Remove));
+    }
+
+    #[test]
+    fn test_excluding_coverage_taker() {
+        let mut excluded_names: HashSet<Vec<u8>> = HashSet::new();
+        excluded_names.insert(b"genome2".to_vec());
+        let ex = NameExclusionFilter {
+            excluded_names: excluded_names,
+            regex: None,
+            split_char: None,
+            genomes_and_contigs: None,
+        };
+        let mut stream: Vec<u8> = vec![];
+        {
+            let mut taker = CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                &mut stream);
+            let mut excluding_taker = ExcludingCoverageTaker::new(&mut taker, &ex);
+            excluding_taker.start_stoit("sample");
+            for (i, (genome, coverage)) in vec![("genome1", 1.5), ("genome2", 0.0), ("genome3", 2.5)]
+                .into_iter().enumerate() {
+                excluding_taker.start_entry(i, genome);
+                excluding_taker.add_single_coverage(coverage);
+                excluding_taker.finish_entry();
+            }
+        }
+        assert_eq!("sample\tgenome1\t1.5\nsample\tgenome3\t2.5\n",
+                   str::from_utf8(&stream).unwrap());
+    }
 }
@@ -13,6 +13,19 @@ pub mod shard_bam_reader;
 pub mod genome_exclusion;
 pub mod cli;
 pub mod genome_parsing;
+pub mod bam_cache;
+pub mod checkpoint;
+pub mod coverage_merger;
+pub mod depth_file;
+pub mod random;
+pub mod provenance;
+pub mod allele_counts;
+pub mod pileup;
+pub mod fastq_writer;
+pub mod sample_sheet;
+pub mod stoit_naming;
+#[cfg(test)]
+mod test_bam_writer;
 
 extern crate bio;
 #[macro_use]
@@ -30,13 +43,30 @@ extern crate clap;
 #[macro_use]
 extern crate lazy_static;
 extern crate version_compare;
+extern crate flate2;
+extern crate regex;
 
 pub const CONCATENATED_FASTA_FILE_SEPARATOR: &str = "~";
 
 #[derive(PartialEq, Debug)]
 pub struct ReadsMapped {
     num_mapped_reads: u64,
-    num_reads: u64
+    num_reads: u64,
+    // Total aligned bases, only counted in long-read mode and when reading
+    // depth files.
+    num_mapped_bases: Option<u64>,
+}
+
+impl ReadsMapped {
+    /// The per-million denominator of RPKM-like columns. When aligned bases
+    /// are counted this is the number of bases (giving BPKM), otherwise the
+    /// number of mapped reads.
+    fn rpkm_denominator(&self) -> u64 {
+        match self.num_mapped_bases {
+            Some(bases) => bases,
+            None => self.num_mapped_reads,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -44,4 +74,8 @@ pub struct FlagFilter {
     pub include_improper_pairs: bool,
     pub include_supplementary: bool,
     pub include_secondary: bool,
+    // Calculate percent identity as gap-compressed identity, where each
+    // insertion or deletion counts as a single difference regardless of its
+    // length, as is more appropriate for long reads.
+    pub gap_compressed_identity: bool,
 }
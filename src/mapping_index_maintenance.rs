@@ -145,21 +145,28 @@ impl Drop for TemporaryIndexStruct {
     }
 }
 
+const BWA_INDEX_EXTENSIONS: &[&str] = &["amb","ann","bwt","pac","sa"];
+
+fn num_existing_bwa_index_files(reference_path: &str) -> usize {
+    BWA_INDEX_EXTENSIONS.iter().filter(|extension|
+        std::path::Path::new(&format!("{}.{}", reference_path, extension)).exists()
+    ).count()
+}
+
+/// True if all the files of a BWA index of the reference exist.
+pub fn bwa_index_exists(reference_path: &str) -> bool {
+    num_existing_bwa_index_files(reference_path) == BWA_INDEX_EXTENSIONS.len()
+}
+
 pub fn generate_bwa_index(
     reference_path: &str,
     index_creation_parameters: Option<&str>) -> Box<dyn MappingIndex> {
-    let bwa_extensions = vec!("amb","ann","bwt","pac","sa");
-    let num_extensions = bwa_extensions.len();
-    let mut num_existing: u8 = 0;
-    for extension in bwa_extensions {
-        if std::path::Path::new(&format!("{}.{}", reference_path, extension)).exists() {
-            num_existing += 1;
-        }
-    }
+    let num_extensions = BWA_INDEX_EXTENSIONS.len();
+    let num_existing = num_existing_bwa_index_files(reference_path);
     if num_existing == 0 {
         return Box::new(TemporaryIndexStruct::new(
             MappingProgram::BWA_MEM, reference_path, None, index_creation_parameters));
-    } else if num_existing as usize != num_extensions {
+    } else if num_existing != num_extensions {
         error!("BWA index appears to be incomplete, cannot continue.");
         process::exit(1);
     } else {
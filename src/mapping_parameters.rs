@@ -3,6 +3,9 @@ use std::process;
 use tempfile::NamedTempFile;
 
 use bam_generator::MappingProgram;
+use external_command_checker;
+use sample_sheet::*;
+use stoit_naming::StoitNaming;
 
 #[derive(Clone)]
 pub enum ReadFormat {
@@ -11,13 +14,22 @@ pub enum ReadFormat {
     Single,
 }
 
+/// The reads of one sample. There is more than one read file (or pair of
+/// files) when a sample in a sample sheet is spread over several lines.
+#[derive(Clone)]
+struct SampleReads<'a> {
+    name: Option<&'a str>,
+    read_format: ReadFormat,
+    read1: Vec<&'a str>,
+    read2: Vec<&'a str>,
+    // Only map to this reference, rather than all references
+    reference: Option<&'a str>,
+}
+
 pub struct MappingParameters<'a> {
     references: Vec<&'a str>,
     threads: u16,
-    read1: Vec<&'a str>,
-    read2: Vec<&'a str>,
-    interleaved: Vec<&'a str>,
-    unpaired: Vec<&'a str>,
+    samples: Vec<SampleReads<'a>>,
     iter_reference_index: usize,
     mapping_options: Option<&'a str>
 }
@@ -26,7 +38,9 @@ impl<'a> MappingParameters<'a> {
     pub fn generate_from_clap(
         m: &'a clap::ArgMatches,
         mapping_program: MappingProgram,
-        reference_tempfile: &'a Option<NamedTempFile>)
+        reference_tempfile: &'a Option<NamedTempFile>,
+        sample_sheet: &'a Option<SampleSheet>,
+        stoit_naming: &'a StoitNaming)
         -> MappingParameters<'a> {
 
         let mut read1: Vec<&str> = vec!();
@@ -71,9 +85,110 @@ impl<'a> MappingParameters<'a> {
             unpaired = m.values_of("single").unwrap().collect();
         }
 
+        // Samples given on the command line are named by their position
+        let command_line_sample = |sample_index: usize, read_format: ReadFormat, read1: &'a str,
+                                   read2: Option<&'a str>| {
+            SampleReads {
+                name: stoit_naming.sample_name(sample_index),
+                read_format: read_format,
+                read1: vec![read1],
+                read2: read2.into_iter().collect(),
+                reference: None,
+            }
+        };
+        let mut samples: Vec<SampleReads> = vec![];
+        for (r1, r2) in read1.iter().zip(read2.iter()) {
+            samples.push(command_line_sample(samples.len(), ReadFormat::Coupled, *r1, Some(*r2)));
+        }
+        for r in interleaved {
+            samples.push(command_line_sample(samples.len(), ReadFormat::Interleaved, r, None));
+        }
+        for r in unpaired {
+            samples.push(command_line_sample(samples.len(), ReadFormat::Single, r, None));
+        }
+
+        let mut references: Vec<&str> = match reference_tempfile {
+            Some(r) => vec!(r.path().to_str().unwrap()),
+            None => match m.values_of("reference") {
+                Some(refs) => refs.collect(),
+                None => vec![]
+            }
+        };
+        if let Some(sample_sheet) = sample_sheet {
+            let entries = match sample_sheet {
+                SampleSheet::Reads(entries) => entries,
+                SampleSheet::BamFiles(_) => {
+                    error!("BAM files given in a sample sheet cannot be used for mapping");
+                    process::exit(1);
+                }
+            };
+            for entry in entries {
+                if let Some(ref reference) = entry.reference {
+                    if reference_tempfile.is_some() {
+                        error!("A reference cannot be given for sample {} in the sample \
+                                sheet when mapping to genomes", entry.name);
+                        process::exit(1);
+                    }
+                    if !references.contains(&reference.as_str()) {
+                        references.push(reference);
+                    }
+                }
+                samples.push(SampleReads {
+                    name: Some(entry.name.as_str()),
+                    read_format: entry.read_format.clone(),
+                    read1: entry.read1.iter().map(|r| r.as_str()).collect(),
+                    read2: entry.read2.iter().map(|r| r.as_str()).collect(),
+                    reference: entry.reference.as_ref().map(|r| r.as_str()),
+                });
+            }
+        }
+        if references.is_empty() {
+            error!("A reference must be given with --reference, or for every sample in \
+                    the sample sheet");
+            process::exit(1);
+        }
+        if m.values_of("reference").is_none() && reference_tempfile.is_none() {
+            if let Some(sample) = samples.iter().find(|s| s.reference.is_none()) {
+                error!("No reference was given for sample {} of the sample sheet, and \
+                        --reference was not specified",
+                       sample.name.unwrap_or(sample.read1[0]));
+                process::exit(1);
+            }
+        }
+
+        let read_paths: Vec<&str> = samples.iter()
+            .flat_map(|s| s.read1.iter().chain(s.read2.iter()))
+            .cloned()
+            .collect();
+        if read_paths.iter().any(|p| p.ends_with(".bz2")) {
+            external_command_checker::check_for_bzip2();
+        }
+        if read_paths.iter().any(|p| p.ends_with(".zst") || p.ends_with(".zstd")) {
+            external_command_checker::check_for_zstd();
+        }
+        let num_stdin_mappings: usize = samples.iter()
+            .map(|s| {
+                let num_stdin = s.read1.iter().chain(s.read2.iter())
+                    .filter(|p| **p == "-")
+                    .count();
+                num_stdin * match s.reference {
+                    Some(_) => 1,
+                    None => references.len(),
+                }
+            })
+            .sum();
+        if num_stdin_mappings > 1 {
+            error!("Reads can only be read from STDIN once, so '-' can only be given \
+                    as one read file, mapped to a single reference");
+            process::exit(1);
+        }
+
         match mapping_program {
             MappingProgram::MINIMAP2_ONT | MappingProgram::MINIMAP2_PB => {
-                if !read1.is_empty() || !interleaved.is_empty(){
+                if samples.iter().any(|s| match s.read_format {
+                    ReadFormat::Single => false,
+                    ReadFormat::Coupled | ReadFormat::Interleaved => true,
+                }) {
                     error!("Paired-end read input specified to be mapped \
                         with minimap2-ont or minimap2-pb, which is presumably \
                         incorrect. Mapping paired reads can be run via \
@@ -103,56 +218,28 @@ impl<'a> MappingParameters<'a> {
             mapping_program, mapping_options);
 
         return MappingParameters {
-            references: match reference_tempfile {
-                Some(r) => vec!(r.path().to_str().unwrap()),
-                None => match m.values_of("reference") {
-                    Some(refs) => refs.collect(),
-                    None => vec![]
-                }
-            },
+            references: references,
             threads: m.value_of("threads").unwrap().parse::<u16>()
                 .expect("Failed to convert threads argument into integer"),
-            read1: read1,
-            read2: read2,
-            interleaved: interleaved,
-            unpaired: unpaired,
+            samples: samples,
             iter_reference_index: 0,
             mapping_options: mapping_options,
         }
     }
-
-    // Return a Vec of str + Option<str> where each entry is a read pair or
-    // single with None.
-    pub fn readsets(&self) -> Vec<(&str, Option<&str>)> {
-        let mut to_return: Vec<(&str, Option<&str>)> = vec!();
-
-        for (ref r1, ref r2) in self.read1.iter().zip(self.read2.iter()) {
-            to_return.push((r1, Some(r2)))
-        }
-        for ref s in self.unpaired.iter() {
-            to_return.push((&s, None))
-        }
-        return to_return
-    }
 }
 
 pub struct SingleReferenceMappingParameters<'a> {
     pub reference: &'a str,
     threads: u16,
-    read1: Vec<&'a str>,
-    read2: Vec<&'a str>,
-    interleaved: Vec<&'a str>,
-    unpaired: Vec<&'a str>,
+    samples: Vec<SampleReads<'a>>,
     mapping_options: Option<&'a str>,
 
-    iter_read_pair_index: usize,
-    iter_interleaved_index: usize,
-    iter_unpaired_index: usize,
+    iter_sample_index: usize,
 }
 
 impl<'a> SingleReferenceMappingParameters<'a> {
     pub fn len(&self) -> usize {
-        self.read1.len() + self.interleaved.len() + self.unpaired.len()
+        self.samples.len()
     }
 }
 
@@ -163,17 +250,19 @@ impl<'a> Iterator for MappingParameters<'a> {
         if self.iter_reference_index < self.references.len() {
             let i = self.iter_reference_index;
             self.iter_reference_index += 1;
+            let reference = self.references[i];
             return Some(SingleReferenceMappingParameters {
-                reference: self.references[i],
+                reference: reference,
                 threads: self.threads,
-                read1: self.read1.clone(),
-                read2: self.read2.clone(),
-                interleaved: self.interleaved.clone(),
-                unpaired: self.unpaired.clone(),
+                samples: self.samples.iter()
+                    .filter(|s| match s.reference {
+                        Some(r) => r == reference,
+                        None => true,
+                    })
+                    .cloned()
+                    .collect(),
                 mapping_options: self.mapping_options,
-                iter_read_pair_index: 0,
-                iter_interleaved_index: 0,
-                iter_unpaired_index: 0,
+                iter_sample_index: 0,
             })
         } else {
             return None
@@ -185,36 +274,15 @@ impl<'a> Iterator for SingleReferenceMappingParameters<'a> {
     type Item = OneSampleMappingParameters<'a>;
 
     fn next(&mut self) -> Option<OneSampleMappingParameters<'a>> {
-        if self.iter_read_pair_index < self.read1.len() {
-            let i = self.iter_read_pair_index;
-            self.iter_read_pair_index += 1;
+        if self.iter_sample_index < self.samples.len() {
+            let sample = self.samples[self.iter_sample_index].clone();
+            self.iter_sample_index += 1;
             return Some(OneSampleMappingParameters {
                 reference: self.reference,
-                read_format: ReadFormat::Coupled,
-                read1: self.read1[i],
-                read2: Some(self.read2[i]),
-                threads: self.threads,
-                mapping_options: self.mapping_options,
-            })
-        } else if self.iter_interleaved_index < self.interleaved.len() {
-            let i = self.iter_interleaved_index;
-            self.iter_interleaved_index += 1;
-            return Some(OneSampleMappingParameters {
-                reference: self.reference,
-                read_format: ReadFormat::Interleaved,
-                read1: self.interleaved[i],
-                read2: None,
-                threads: self.threads,
-                mapping_options: self.mapping_options,
-            })
-        } else if self.iter_unpaired_index < self.unpaired.len() {
-            let i = self.iter_unpaired_index;
-            self.iter_unpaired_index += 1;
-            return Some(OneSampleMappingParameters {
-                reference: self.reference,
-                read_format: ReadFormat::Single,
-                read1: self.unpaired[i],
-                read2: None,
+                read_format: sample.read_format,
+                read1: sample.read1,
+                read2: sample.read2,
+                sample_name: sample.name,
                 threads: self.threads,
                 mapping_options: self.mapping_options,
             })
@@ -227,8 +295,18 @@ impl<'a> Iterator for SingleReferenceMappingParameters<'a> {
 pub struct OneSampleMappingParameters<'a> {
     pub reference: &'a str,
     pub read_format: ReadFormat,
-    pub read1: &'a str,
-    pub read2: Option<&'a str>,
+    pub read1: Vec<&'a str>,
+    // Empty unless the reads are coupled
+    pub read2: Vec<&'a str>,
+    pub sample_name: Option<&'a str>,
     pub threads: u16,
     pub mapping_options: Option<&'a str>
 }
+
+impl<'a> OneSampleMappingParameters<'a> {
+    /// The name of the sample from the sample sheet, otherwise the first
+    /// read file, used to name the stoit and any cached BAM file.
+    pub fn naming_readset(&self) -> &'a str {
+        self.sample_name.unwrap_or(self.read1[0])
+    }
+}
@@ -1,5 +1,21 @@
+use std::collections::HashMap;
+use std::process;
+use std::rc::Rc;
+
+use allele_counts::AlleleCounts;
 use coverage_takers::CoverageTaker;
 
+/// Statistics of the alleles observed in aligned reads, for comparing
+/// strains across samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlleleStatistic {
+    SNVCount,
+    // SNVs per kilobase of positions with sufficient depth
+    SNVDensity,
+    NucleotideDiversity,
+    MajorAlleleFrequency,
+}
+
 #[derive(Clone, Debug)]
 pub enum CoverageEstimator {
     MeanGenomeCoverageEstimator{
@@ -48,6 +64,15 @@ pub enum CoverageEstimator {
         num_mapped_reads: u64,
         min_fraction_covered_bases: f32,
     },
+    // Like RPKM but counting aligned bases rather than reads, since long
+    // reads vary in length.
+    BPKMCoverageEstimator {
+        total_bases: u32,
+        num_covered_bases: u32,
+        num_mapped_reads: u64,
+        num_aligned_bases: u64,
+        min_fraction_covered_bases: f32,
+    },
     VarianceGenomeCoverageEstimator {
         counts: Vec<u32>,
         observed_contig_length: u32,
@@ -67,10 +92,29 @@ pub enum CoverageEstimator {
         observed_contig_length: u32,
         num_mapped_reads: u64
     },
+    // Calculated from the alleles at positions with at least min_depth
+    // aligned bases, rather than from coverage alone.
+    AlleleVariationEstimator {
+        statistic: AlleleStatistic,
+        total_bases: u32,
+        num_covered_bases: u32,
+        num_mapped_reads: u64,
+        num_callable_sites: u32,
+        num_snvs: u32,
+        total_nucleotide_diversity: f64,
+        total_major_allele_frequency: f64,
+        min_depth: u32,
+        min_allele_frequency: f32,
+        min_fraction_covered_bases: f32,
+        contig_end_exclusion: u32,
+        // Sequences of the contigs keyed by name, which SNVs are called
+        // against.
+        reference_sequences: Option<Rc<HashMap<String, Vec<u8>>>>,
+    },
 }
 
 impl CoverageEstimator {
-    pub fn column_headers(&self) -> Vec<&str> {
+    pub fn column_headers(&self) -> Vec<&'static str> {
         match self {
             CoverageEstimator::MeanGenomeCoverageEstimator{..} => {vec!("Mean")},
             CoverageEstimator::TrimmedMeanGenomeCoverageEstimator{..} => {vec!("Trimmed Mean")},
@@ -78,10 +122,54 @@ impl CoverageEstimator {
             CoverageEstimator::CoverageFractionGenomeCoverageEstimator{..} => {vec!("Covered Fraction")},
             CoverageEstimator::NumCoveredBasesCoverageEstimator{..} => {vec!("Covered Bases")},
             CoverageEstimator::RPKMCoverageEstimator{..} => {vec!("RPKM")},
+            CoverageEstimator::BPKMCoverageEstimator{..} => {vec!("BPKM")},
             CoverageEstimator::VarianceGenomeCoverageEstimator{..} => {vec!("Variance")},
             CoverageEstimator::ReferenceLengthCalculator{..} => vec!("Length"),
             CoverageEstimator::ReadCountCalculator{..} => vec!("Read Count"),
             CoverageEstimator::ReadsPerBaseCalculator{..} => vec!("Reads per base"),
+            CoverageEstimator::AlleleVariationEstimator{statistic, ..} => {
+                match statistic {
+                    AlleleStatistic::SNVCount => vec!("SNVs"),
+                    AlleleStatistic::SNVDensity => vec!("SNVs per kb"),
+                    AlleleStatistic::NucleotideDiversity => vec!("Nucleotide Diversity"),
+                    AlleleStatistic::MajorAlleleFrequency => vec!("Major Allele Frequency"),
+                }
+            },
+        }
+    }
+
+    /// Column headers of every kind of estimator.
+    pub fn all_column_headers() -> Vec<&'static str> {
+        let mut estimators = vec![
+            CoverageEstimator::new_estimator_mean(0.0, 0, false),
+            CoverageEstimator::new_estimator_trimmed_mean(0.0, 0.0, 0.0, 0),
+            CoverageEstimator::new_estimator_pileup_counts(0.0, 0),
+            CoverageEstimator::new_estimator_covered_fraction(0.0),
+            CoverageEstimator::new_estimator_covered_bases(0.0),
+            CoverageEstimator::new_estimator_rpkm(0.0),
+            CoverageEstimator::new_estimator_bpkm(0.0),
+            CoverageEstimator::new_estimator_variance(0.0, 0),
+            CoverageEstimator::new_estimator_length(),
+            CoverageEstimator::new_estimator_read_count(),
+            CoverageEstimator::new_estimator_reads_per_base(),
+        ];
+        for statistic in &[
+            AlleleStatistic::SNVCount,
+            AlleleStatistic::SNVDensity,
+            AlleleStatistic::NucleotideDiversity,
+            AlleleStatistic::MajorAlleleFrequency] {
+            estimators.push(CoverageEstimator::new_estimator_allele_variation(
+                *statistic, 0, 0.0, 0.0, 0, None));
+        }
+        estimators.iter().flat_map(|e| e.column_headers()).collect()
+    }
+
+    /// Whether the estimator requires the alleles at each position, which
+    /// are only counted when needed.
+    pub fn requires_allele_counts(&self) -> bool {
+        match self {
+            CoverageEstimator::AlleleVariationEstimator{..} => true,
+            _ => false,
         }
     }
 }
@@ -151,6 +239,17 @@ impl CoverageEstimator {
             min_fraction_covered_bases: min_fraction_covered_bases,
         }
     }
+    pub fn new_estimator_bpkm(
+        min_fraction_covered_bases: f32)
+        -> CoverageEstimator {
+        CoverageEstimator::BPKMCoverageEstimator {
+            total_bases: 0,
+            num_covered_bases: 0,
+            num_mapped_reads: 0,
+            num_aligned_bases: 0,
+            min_fraction_covered_bases: min_fraction_covered_bases,
+        }
+    }
     pub fn new_estimator_covered_bases(
         min_fraction_covered_bases: f32)
         -> CoverageEstimator {
@@ -191,6 +290,30 @@ impl CoverageEstimator {
             num_mapped_reads: 0
         }
     }
+    pub fn new_estimator_allele_variation(
+        statistic: AlleleStatistic,
+        min_depth: u32,
+        min_allele_frequency: f32,
+        min_fraction_covered_bases: f32,
+        contig_end_exclusion: u32,
+        reference_sequences: Option<Rc<HashMap<String, Vec<u8>>>>)
+        -> CoverageEstimator {
+        CoverageEstimator::AlleleVariationEstimator {
+            statistic: statistic,
+            total_bases: 0,
+            num_covered_bases: 0,
+            num_mapped_reads: 0,
+            num_callable_sites: 0,
+            num_snvs: 0,
+            total_nucleotide_diversity: 0.0,
+            total_major_allele_frequency: 0.0,
+            min_depth: min_depth,
+            min_allele_frequency: min_allele_frequency,
+            min_fraction_covered_bases: min_fraction_covered_bases,
+            contig_end_exclusion: contig_end_exclusion,
+            reference_sequences: reference_sequences,
+        }
+    }
 
     fn calculate_unobserved_bases(
         unobserved_contig_lengths: &Vec<u32>,
@@ -217,6 +340,10 @@ pub trait MosdepthGenomeCoverageEstimator {
         num_mapped_reads: u64,
         total_mismatches: u32);
 
+    // Called for each contig after add_contig, when allele counts are
+    // required by any estimator.
+    fn add_allele_counts(&mut self, allele_counts: &AlleleCounts);
+
     fn calculate_coverage(&mut self, unobserved_contig_lengths: &Vec<u32>) -> f32;
 
     fn print_coverage<T: CoverageTaker>(
@@ -288,6 +415,17 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 *num_covered_bases = 0;
                 *num_mapped_reads = 0;
             },
+            CoverageEstimator::BPKMCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ref mut num_aligned_bases, ..
+            } => {
+                *total_bases = 0;
+                *num_covered_bases = 0;
+                *num_mapped_reads = 0;
+                *num_aligned_bases = 0;
+            },
             CoverageEstimator::ReferenceLengthCalculator {
                 ref mut observed_contig_length,
                 ref mut num_mapped_reads,
@@ -302,6 +440,23 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut num_mapped_reads
             } => {
                 *num_mapped_reads = 0;
+            },
+            CoverageEstimator::AlleleVariationEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ref mut num_callable_sites,
+                ref mut num_snvs,
+                ref mut total_nucleotide_diversity,
+                ref mut total_major_allele_frequency, ..
+            } => {
+                *total_bases = 0;
+                *num_covered_bases = 0;
+                *num_mapped_reads = 0;
+                *num_callable_sites = 0;
+                *num_snvs = 0;
+                *total_nucleotide_diversity = 0.0;
+                *total_major_allele_frequency = 0.0;
             }
         }
     }
@@ -425,6 +580,25 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     }
                 }
             },
+            CoverageEstimator::BPKMCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ref mut num_aligned_bases, ..
+            } => {
+                *num_mapped_reads += num_mapped_reads_in_contig;
+                *total_bases += ups_and_downs.len() as u32;
+                // The sum of the depth at each position is the number of
+                // aligned bases.
+                let mut cumulative_sum: i32 = 0;
+                for current in ups_and_downs.iter() {
+                    cumulative_sum += current;
+                    if cumulative_sum > 0 {
+                        *num_covered_bases += 1;
+                        *num_aligned_bases += cumulative_sum as u64;
+                    }
+                }
+            },
             CoverageEstimator::ReferenceLengthCalculator {
                 ref mut observed_contig_length,
                 ref mut num_mapped_reads,
@@ -439,10 +613,88 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut num_mapped_reads
             } => {
                 *num_mapped_reads += num_mapped_reads_in_contig;
+            },
+            CoverageEstimator::AlleleVariationEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                contig_end_exclusion, ..
+            } => {
+                *num_mapped_reads += num_mapped_reads_in_contig;
+                let len = ups_and_downs.len();
+                match *contig_end_exclusion*2 < len as u32 {
+                    true => {
+                        *total_bases += len as u32 - 2* *contig_end_exclusion},
+                    false => {
+                        debug!("Contig too short - less than twice the contig-end-exclusion");
+                        return; //contig is all ends, too short
+                    }
+                }
+                let mut cumulative_sum: i32 = 0;
+                let start_from = *contig_end_exclusion as usize;
+                let end_at = len - *contig_end_exclusion as usize - 1;
+                for (i, current) in ups_and_downs.iter().enumerate() {
+                    cumulative_sum += current;
+                    if i >= start_from && i <= end_at && cumulative_sum > 0 {
+                        *num_covered_bases += 1
+                    }
+                }
             }
         }
     }
 
+    fn add_allele_counts(&mut self, allele_counts: &AlleleCounts) {
+        match self {
+            CoverageEstimator::AlleleVariationEstimator {
+                ref mut num_callable_sites,
+                ref mut num_snvs,
+                ref mut total_nucleotide_diversity,
+                ref mut total_major_allele_frequency,
+                min_depth,
+                min_allele_frequency,
+                contig_end_exclusion,
+                reference_sequences, ..
+            } => {
+                let len = allele_counts.len();
+                if *contig_end_exclusion as usize * 2 >= len {
+                    return; //contig is all ends, too short
+                }
+                let reference = match reference_sequences {
+                    Some(sequences) => match sequences.get(allele_counts.contig_name()) {
+                        Some(seq) if seq.len() == len => Some(seq),
+                        Some(seq) => {
+                            error!("Contig {} is {}bp in the SNV reference fasta file, but {}bp \
+                                    in the BAM header",
+                                   allele_counts.contig_name(), seq.len(), len);
+                            process::exit(1);
+                        },
+                        None => {
+                            error!("Contig {} was not found in the SNV reference fasta file",
+                                   allele_counts.contig_name());
+                            process::exit(1);
+                        }
+                    },
+                    None => None,
+                };
+                let start_from = *contig_end_exclusion as usize;
+                let end_at = len - *contig_end_exclusion as usize - 1;
+                for position in start_from..(end_at+1) {
+                    if let Some(site) = allele_counts.site_statistics(
+                        position, reference.map(|r| r[position]),
+                        *min_depth, *min_allele_frequency) {
+                        *num_callable_sites += 1;
+                        if site.is_snv {
+                            *num_snvs += 1;
+                        }
+                        *total_nucleotide_diversity += site.nucleotide_diversity;
+                        *total_major_allele_frequency += site.major_allele_frequency;
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
     fn calculate_coverage(&mut self, unobserved_contig_lengths: &Vec<u32>) -> f32 {
         match self {
             CoverageEstimator::MeanGenomeCoverageEstimator {
@@ -622,6 +874,23 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                         }
                     }
             },
+            CoverageEstimator::BPKMCoverageEstimator {
+                total_bases,
+                num_covered_bases,
+                num_mapped_reads: _,
+                num_aligned_bases,
+                min_fraction_covered_bases,
+            } => {
+                let final_total_bases: u32 = *total_bases + unobserved_contig_lengths.iter().sum::<u32>();
+                if final_total_bases == 0 ||
+                    (*num_covered_bases as f32 / final_total_bases as f32) < *min_fraction_covered_bases {
+                        return 0.0
+                    } else {
+                        // As for RPKM, this is divided by the total number
+                        // of aligned bases in the sample later.
+                        return (*num_aligned_bases as f64 * 1e9 / final_total_bases as f64) as f32
+                    }
+            },
             CoverageEstimator::VarianceGenomeCoverageEstimator {
                 observed_contig_length,
                 ref mut counts,
@@ -682,6 +951,36 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 *num_mapped_reads as f32 /
                     (*observed_contig_length + unobserved_contig_lengths.iter().sum::<u32>()) as f32
             },
+            CoverageEstimator::AlleleVariationEstimator {
+                statistic,
+                total_bases,
+                num_covered_bases,
+                num_callable_sites,
+                num_snvs,
+                total_nucleotide_diversity,
+                total_major_allele_frequency,
+                min_fraction_covered_bases,
+                contig_end_exclusion, ..
+            } => {
+                let final_total_bases = *total_bases +
+                    CoverageEstimator::calculate_unobserved_bases(
+                        unobserved_contig_lengths, *contig_end_exclusion);
+                if final_total_bases == 0 ||
+                    (*num_covered_bases as f32 / final_total_bases as f32) < *min_fraction_covered_bases ||
+                    *num_callable_sites == 0 {
+                    return 0.0
+                }
+                let num_callable_sites = *num_callable_sites as f64;
+                match statistic {
+                    AlleleStatistic::SNVCount => *num_snvs as f32,
+                    AlleleStatistic::SNVDensity =>
+                        (*num_snvs as f64 * 1000.0 / num_callable_sites) as f32,
+                    AlleleStatistic::NucleotideDiversity =>
+                        (*total_nucleotide_diversity / num_callable_sites) as f32,
+                    AlleleStatistic::MajorAlleleFrequency =>
+                        (*total_major_allele_frequency / num_callable_sites) as f32,
+                }
+            },
         }
     }
 
@@ -752,6 +1051,11 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             } => {
                 CoverageEstimator::new_estimator_rpkm(*min_fraction_covered_bases)
             },
+            CoverageEstimator::BPKMCoverageEstimator {
+                min_fraction_covered_bases, ..
+            } => {
+                CoverageEstimator::new_estimator_bpkm(*min_fraction_covered_bases)
+            },
             CoverageEstimator::VarianceGenomeCoverageEstimator {
                 observed_contig_length: _,
                 counts: _,
@@ -771,6 +1075,19 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             },
             CoverageEstimator::ReadsPerBaseCalculator {..} => {
                 CoverageEstimator::new_estimator_reads_per_base()
+            },
+            CoverageEstimator::AlleleVariationEstimator {
+                statistic,
+                min_depth,
+                min_allele_frequency,
+                min_fraction_covered_bases,
+                contig_end_exclusion,
+                reference_sequences, ..
+            } => {
+                CoverageEstimator::new_estimator_allele_variation(
+                    *statistic, *min_depth, *min_allele_frequency,
+                    *min_fraction_covered_bases, *contig_end_exclusion,
+                    reference_sequences.clone())
             }
         }
     }
@@ -786,10 +1103,12 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             CoverageEstimator::CoverageFractionGenomeCoverageEstimator{..} |
             CoverageEstimator::NumCoveredBasesCoverageEstimator{..} |
             CoverageEstimator::RPKMCoverageEstimator{..} |
+            CoverageEstimator::BPKMCoverageEstimator{..} |
             CoverageEstimator::VarianceGenomeCoverageEstimator{..} |
             CoverageEstimator::ReferenceLengthCalculator{..} |
             CoverageEstimator::ReadCountCalculator{..} |
-            CoverageEstimator::ReadsPerBaseCalculator{..} => {
+            CoverageEstimator::ReadsPerBaseCalculator{..} |
+            CoverageEstimator::AlleleVariationEstimator{..} => {
                 coverage_taker.add_single_coverage(*coverage);
             },
             CoverageEstimator::PileupCountsGenomeCoverageEstimator {
@@ -822,9 +1141,11 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             CoverageEstimator::CoverageFractionGenomeCoverageEstimator{..} |
             CoverageEstimator::NumCoveredBasesCoverageEstimator{..} |
             CoverageEstimator::RPKMCoverageEstimator{..} |
+            CoverageEstimator::BPKMCoverageEstimator{..} |
             CoverageEstimator::VarianceGenomeCoverageEstimator{..} |
             CoverageEstimator::ReadCountCalculator{..} |
-            CoverageEstimator::ReadsPerBaseCalculator{..} => {
+            CoverageEstimator::ReadsPerBaseCalculator{..} |
+            CoverageEstimator::AlleleVariationEstimator{..} => {
                 coverage_taker.add_single_coverage(0.0);
             },
             CoverageEstimator::PileupCountsGenomeCoverageEstimator{..} => {},
@@ -869,6 +1190,9 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 num_covered_bases: _,
                 num_mapped_reads, ..
             } |
+            CoverageEstimator::BPKMCoverageEstimator {
+                num_mapped_reads, ..
+            } |
             CoverageEstimator::VarianceGenomeCoverageEstimator {
                 observed_contig_length: _,
                 counts: _,
@@ -885,6 +1209,9 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             CoverageEstimator::ReadsPerBaseCalculator {
                 observed_contig_length: _,
                 num_mapped_reads,
+            } |
+            CoverageEstimator::AlleleVariationEstimator {
+                num_mapped_reads, ..
             } => {
                 *num_mapped_reads
             },
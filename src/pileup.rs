@@ -0,0 +1,186 @@
+use std;
+use std::collections::HashMap;
+use std::process;
+
+use rust_htslib::bam;
+
+use allele_counts::AlleleCounts;
+use bam_generator::*;
+use FlagFilter;
+
+/// Reference sequences keyed by contig name, used to report the reference
+/// base at each position.
+pub fn read_reference_sequences(fasta_path: &str) -> HashMap<String, Vec<u8>> {
+    let reader = bio::io::fasta::Reader::from_file(fasta_path)
+        .expect(&format!("Unable to read reference fasta file {}", fasta_path));
+    let mut sequences = HashMap::new();
+    for record in reader.records() {
+        let record = record.expect(&format!(
+            "Failed to parse reference fasta file {}", fasta_path));
+        sequences.insert(record.id().to_string(), record.seq().to_ascii_uppercase());
+    }
+    sequences
+}
+
+pub fn print_pileup_header(print_stream: &mut dyn std::io::Write) {
+    writeln!(
+        print_stream,
+        "Sample\tContig\tPosition\tReference\tDepth\tA\tC\tG\tT\tDeletions\tInsertions")
+        .expect("Failed to write pileup header");
+}
+
+/// Print per-position base counts of each contig with mean depth at least
+/// min_coverage, for each sample. Positions are 1-based. Reads are filtered in
+/// the same way as when calculating contig coverage.
+pub fn pileup<R: NamedBamReader,
+              G: NamedBamReaderGenerator<R>>(
+    bam_readers: Vec<G>,
+    reference_sequences: &HashMap<String, Vec<u8>>,
+    min_coverage: f32,
+    flag_filters: FlagFilter,
+    threads: usize,
+    print_stream: &mut dyn std::io::Write) {
+
+    for bam_generator in bam_readers {
+        let mut bam_generated = bam_generator.start();
+        bam_generated.set_threads(threads);
+
+        let stoit_name = bam_generated.name().to_string();
+        let mut record: bam::record::Record = bam::record::Record::new();
+        let mut last_tid: i32 = -2; // no such tid in a real BAM file
+        let mut allele_counts: Option<AlleleCounts> = None;
+        let header = bam_generated.header().clone();
+        let target_names = header.target_names();
+
+        let mut print_previous_contig = |last_tid: i32, allele_counts: Option<AlleleCounts>| {
+            if let Some(counts) = allele_counts {
+                let contig_name = std::str::from_utf8(target_names[last_tid as usize]).unwrap();
+                if counts.mean_depth() >= min_coverage as f64 {
+                    print_contig_pileup(
+                        &stoit_name, contig_name, &counts, reference_sequences, print_stream);
+                } else {
+                    debug!("Not printing pileup of {} in {} as its mean depth is {}",
+                           contig_name, stoit_name, counts.mean_depth());
+                }
+            }
+        };
+
+        while bam_generated
+            .read(&mut record)
+            .expect("Error while reading BAM record") == true {
+
+            if (!flag_filters.include_supplementary && record.is_supplementary()) ||
+                (!flag_filters.include_secondary && record.is_secondary()) ||
+                (!flag_filters.include_improper_pairs && !record.is_proper_pair()) ||
+                record.is_unmapped() {
+                    continue;
+                }
+            let tid = record.tid();
+            if tid != last_tid {
+                print_previous_contig(last_tid, allele_counts);
+                allele_counts = Some(AlleleCounts::new(
+                    std::str::from_utf8(target_names[tid as usize]).unwrap(),
+                    header.target_len(tid as u32).expect("Corrupt BAM file?") as usize));
+                last_tid = tid;
+            }
+            if let Some(ref mut counts) = allele_counts {
+                counts.add_record(&record);
+            }
+        }
+        print_previous_contig(last_tid, allele_counts);
+
+        if bam_generated.num_detected_primary_alignments() == 0 {
+            warn!("No primary alignments were observed for sample {} \
+                   - perhaps something went wrong in the mapping?",
+                  stoit_name);
+        }
+
+        bam_generated.finish();
+    }
+}
+
+fn print_contig_pileup(
+    stoit_name: &str,
+    contig_name: &str,
+    counts: &AlleleCounts,
+    reference_sequences: &HashMap<String, Vec<u8>>,
+    print_stream: &mut dyn std::io::Write) {
+
+    let reference = match reference_sequences.get(contig_name) {
+        Some(seq) => seq,
+        None => {
+            error!("Contig {} was not found in the pileup reference fasta file", contig_name);
+            process::exit(1);
+        }
+    };
+    if reference.len() != counts.len() {
+        error!("Contig {} is {}bp in the pileup reference fasta file, but {}bp in the \
+                BAM header", contig_name, reference.len(), counts.len());
+        process::exit(1);
+    }
+    for position in 0..counts.len() {
+        let bases = counts.base_counts(position);
+        writeln!(
+            print_stream,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            stoit_name,
+            contig_name,
+            position + 1,
+            reference[position] as char,
+            bases.iter().sum::<u32>(),
+            bases[0],
+            bases[1],
+            bases[2],
+            bases[3],
+            counts.deletions(position),
+            counts.insertions(position))
+            .expect("Failed to write pileup");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str;
+    use stoit_naming::StoitNaming;
+
+    fn pileup_of_bad_read(min_coverage: f32) -> String {
+        let mut stream = vec![];
+        pileup(
+            generate_named_bam_readers_from_bam_files(
+                vec!["tests/data/2seqs.bad_read.1.bam"],
+                &StoitNaming::default()),
+            &read_reference_sequences("tests/data/2seqs.fasta"),
+            min_coverage,
+            FlagFilter {
+                include_improper_pairs: true,
+                include_secondary: false,
+                include_supplementary: false,
+                gap_compressed_identity: false,
+            },
+            1,
+            &mut stream);
+        str::from_utf8(&stream).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_pileup_deletion() {
+        let output = pileup_of_bad_read(0.0);
+        assert_eq!(2000, output.lines().count());
+        assert!(output.starts_with(
+            "2seqs.bad_read.1\tseq1\t1\tG\t1\t0\t0\t1\t0\t0\t0\n"));
+        assert!(output.contains(
+            "2seqs.bad_read.1\tseq1\t4\tT\t1\t0\t0\t0\t1\t0\t0\n\
+             2seqs.bad_read.1\tseq1\t5\tC\t0\t0\t0\t0\t0\t1\t0\n\
+             2seqs.bad_read.1\tseq1\t6\tC\t1\t0\t1\t0\t0\t0\t0\n"));
+    }
+
+    #[test]
+    fn test_pileup_min_coverage() {
+        // seq1 has mean depth 1.499 because of the deletion, seq2 1.5.
+        let output = pileup_of_bad_read(1.5);
+        assert_eq!(1000, output.lines().count());
+        assert!(output.starts_with(
+            "2seqs.bad_read.1\tseq2\t1\tG\t1\t0\t0\t1\t0\t0\t0\n"));
+    }
+}
@@ -0,0 +1,49 @@
+use std;
+
+/// Prefix of each line in the provenance header. Readers of coverm output
+/// tables skip leading lines starting with this.
+pub const PROVENANCE_LINE_PREFIX: &str = "#";
+
+/// A record of how an output table was generated, printed as a block of
+/// commented lines before the table itself.
+#[derive(Debug, Default)]
+pub struct Provenance {
+    entries: Vec<(String, String)>,
+}
+
+impl Provenance {
+    pub fn new() -> Provenance {
+        Provenance::default()
+    }
+
+    pub fn add<S: Into<String>>(&mut self, key: &str, value: S) {
+        self.entries.push((key.to_string(), value.into()));
+    }
+
+    pub fn print(&self, print_stream: &mut dyn std::io::Write) {
+        for (key, value) in self.entries.iter() {
+            // Keep each entry on a single commented line.
+            let value = value.replace('\n', " ");
+            writeln!(print_stream, "{} {}: {}", PROVENANCE_LINE_PREFIX, key, value)
+                .expect("Failed to write provenance header");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_print_provenance() {
+        let mut provenance = Provenance::new();
+        provenance.add("CoverM version", "0.3.2");
+        provenance.add("Sample", "a.bam\nb.bam");
+        let mut stream = vec![];
+        provenance.print(&mut stream);
+        assert_eq!(
+            "# CoverM version: 0.3.2\n# Sample: a.bam b.bam\n",
+            std::str::from_utf8(&stream).unwrap()
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Mutex;
+
+use rand::prelude::*;
+
+lazy_static! {
+    // All random number generators used in coverm are derived from this one,
+    // so that a run is reproducible given its seed.
+    static ref MASTER_RNG: Mutex<Option<StdRng>> = Mutex::new(None);
+}
+
+/// Seed the generator that all random choices are derived from. If seed is
+/// None, a seed is chosen from entropy. The seed used is returned so that it
+/// can be recorded.
+pub fn set_seed(seed: Option<u64>) -> u64 {
+    let seed = match seed {
+        Some(s) => s,
+        None => StdRng::from_entropy().gen(),
+    };
+    *MASTER_RNG.lock().unwrap() = Some(StdRng::seed_from_u64(seed));
+    seed
+}
+
+/// Create a new random number generator derived from the seeded generator.
+/// Random choices are reproducible as long as generators are created in the
+/// same order. If set_seed has not been called, a random seed is used.
+pub fn derived_rng() -> StdRng {
+    let mut master = MASTER_RNG.lock().unwrap();
+    if master.is_none() {
+        *master = Some(StdRng::from_entropy());
+    }
+    StdRng::from_rng(master.as_mut().unwrap())
+        .expect("Failed to derive random number generator")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_seed() {
+        assert_eq!(42, set_seed(Some(42)));
+        // Other tests may derive generators concurrently, so only check that
+        // a generator can be derived, not the values it produces.
+        derived_rng().gen::<u32>();
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::BufRead;
+use std::process;
+
+use mapping_parameters::ReadFormat;
+
+/// The reads of one sample given in a sample sheet. Reads from each row of
+/// the same sample (e.g. from different lanes) are mapped together as a
+/// single stoit.
+#[derive(Clone)]
+pub struct SampleSheetEntry {
+    pub name: String,
+    pub read_format: ReadFormat,
+    pub read1: Vec<String>,
+    pub read2: Vec<String>,
+    pub reference: Option<String>,
+}
+
+/// The BAM files of one sample given in a sample sheet, which are merged
+/// into a single stoit.
+pub struct BamSampleSheetEntry {
+    pub name: String,
+    pub bam_files: Vec<String>,
+}
+
+/// Samples of a sample sheet, which are either all reads to be mapped, or
+/// all BAM files.
+pub enum SampleSheet {
+    Reads(Vec<SampleSheetEntry>),
+    BamFiles(Vec<BamSampleSheetEntry>),
+}
+
+fn parse_read_format(format: &str, has_read2: bool, line_number: usize) -> ReadFormat {
+    let read_format = match format {
+        "" => match has_read2 {
+            true => ReadFormat::Coupled,
+            false => ReadFormat::Single,
+        },
+        "coupled" | "paired" => ReadFormat::Coupled,
+        "interleaved" => ReadFormat::Interleaved,
+        "single" => ReadFormat::Single,
+        _ => {
+            error!("Unknown read format '{}' on line {} of the sample sheet. It must be \
+                    'coupled', 'interleaved', 'single' or 'bam'", format, line_number);
+            process::exit(1);
+        }
+    };
+    match (&read_format, has_read2) {
+        (ReadFormat::Coupled, false) | (ReadFormat::Interleaved, true) |
+        (ReadFormat::Single, true) => {
+            error!("Line {} of the sample sheet must have a read2 file if and only if \
+                    the reads are coupled", line_number);
+            process::exit(1);
+        }
+        _ => {}
+    }
+    read_format
+}
+
+fn same_read_format(format1: &ReadFormat, format2: &ReadFormat) -> bool {
+    match (format1, format2) {
+        (ReadFormat::Coupled, ReadFormat::Coupled) |
+        (ReadFormat::Interleaved, ReadFormat::Interleaved) |
+        (ReadFormat::Single, ReadFormat::Single) => true,
+        _ => false,
+    }
+}
+
+/// Parse a tab-separated sample sheet with columns sample name, read1, read2,
+/// format and reference, of which the last three are optional. Lines starting
+/// with '#' and an initial header line starting with 'sample' are ignored.
+/// With format 'bam', read1 is a BAM file instead. Samples are returned in
+/// the order they first appear.
+pub fn read_sample_sheet<R: BufRead>(reader: R) -> SampleSheet {
+    let mut entries: Vec<SampleSheetEntry> = vec![];
+    let mut bam_entries: Vec<BamSampleSheetEntry> = vec![];
+    for (i, line_res) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line_res.expect("Read error on sample sheet");
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+        if line_number == 1 && fields[0].eq_ignore_ascii_case("sample") {
+            continue;
+        }
+        if fields.len() < 2 || fields.len() > 5 || fields[0].is_empty() || fields[1].is_empty() {
+            error!("Line {} of the sample sheet should have a sample name, read1, and \
+                    optionally read2, format and reference separated by tabs, but \
+                    found '{}'", line_number, line);
+            process::exit(1);
+        }
+        let field = |i: usize| fields.get(i).map(|f| *f).unwrap_or("");
+        let read2 = field(2);
+        if field(3) == "bam" {
+            if !read2.is_empty() || !field(4).is_empty() {
+                error!("Line {} of the sample sheet gives a BAM file, so cannot also \
+                        have a read2 file or reference", line_number);
+                process::exit(1);
+            }
+            match bam_entries.iter().position(|e| e.name == fields[0]) {
+                Some(index) => bam_entries[index].bam_files.push(field(1).to_string()),
+                None => bam_entries.push(BamSampleSheetEntry {
+                    name: fields[0].to_string(),
+                    bam_files: vec![field(1).to_string()],
+                }),
+            }
+            continue;
+        }
+        let read_format = parse_read_format(field(3), !read2.is_empty(), line_number);
+        let reference = match field(4) {
+            "" => None,
+            r => Some(r.to_string()),
+        };
+
+        match entries.iter().position(|e| e.name == fields[0]) {
+            Some(index) => {
+                let entry = &mut entries[index];
+                if !same_read_format(&entry.read_format, &read_format) ||
+                    entry.reference != reference {
+                    error!("Sample {} is given on multiple lines of the sample sheet with \
+                            different read formats or references", entry.name);
+                    process::exit(1);
+                }
+                entry.read1.push(field(1).to_string());
+                if !read2.is_empty() {
+                    entry.read2.push(read2.to_string());
+                }
+            }
+            None => entries.push(SampleSheetEntry {
+                name: fields[0].to_string(),
+                read_format: read_format,
+                read1: vec![field(1).to_string()],
+                read2: match read2 {
+                    "" => vec![],
+                    r => vec![r.to_string()],
+                },
+                reference: reference,
+            }),
+        }
+    }
+    match (entries.is_empty(), bam_entries.is_empty()) {
+        (true, true) => {
+            error!("No samples were found in the sample sheet");
+            process::exit(1);
+        }
+        (false, false) => {
+            error!("A sample sheet cannot give both reads and BAM files");
+            process::exit(1);
+        }
+        (false, true) => SampleSheet::Reads(entries),
+        (true, false) => SampleSheet::BamFiles(bam_entries),
+    }
+}
+
+pub fn read_sample_sheet_file(path: &str) -> SampleSheet {
+    let f = std::fs::File::open(path)
+        .expect(&format!("Unable to find/read sample sheet {}", path));
+    read_sample_sheet(std::io::BufReader::new(f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_sheet_lanes() {
+        let sample_sheet = read_sample_sheet(
+            "sample\tread1\tread2\tformat\treference\n\
+             # a comment\n\
+             s1\tL1_R1.fq.gz\tL1_R2.fq.gz\n\
+             s2\ts2.fq\t\tinterleaved\tref.fna\n\
+             s1\tL2_R1.fq.gz\tL2_R2.fq.gz\tcoupled\n\
+             \n\
+             s3\ts3.fq\n"
+                .as_bytes());
+        let entries = match sample_sheet {
+            SampleSheet::Reads(entries) => entries,
+            SampleSheet::BamFiles(_) => panic!("Expected reads"),
+        };
+        assert_eq!(3, entries.len());
+        assert_eq!("s1", entries[0].name);
+        assert_eq!(vec!["L1_R1.fq.gz", "L2_R1.fq.gz"], entries[0].read1);
+        assert_eq!(vec!["L1_R2.fq.gz", "L2_R2.fq.gz"], entries[0].read2);
+        assert!(same_read_format(&ReadFormat::Coupled, &entries[0].read_format));
+        assert_eq!(None, entries[0].reference);
+        assert_eq!("s2", entries[1].name);
+        assert!(same_read_format(&ReadFormat::Interleaved, &entries[1].read_format));
+        assert_eq!(Some("ref.fna".to_string()), entries[1].reference);
+        assert!(entries[1].read2.is_empty());
+        assert!(same_read_format(&ReadFormat::Single, &entries[2].read_format));
+    }
+
+    #[test]
+    fn test_sample_sheet_bam_files() {
+        let sample_sheet = read_sample_sheet(
+            "s1\trun1.bam\t\tbam\n\
+             s2\ts2.bam\t\tbam\n\
+             s1\trun2.bam\t\tbam\n"
+                .as_bytes());
+        match sample_sheet {
+            SampleSheet::BamFiles(entries) => {
+                assert_eq!(2, entries.len());
+                assert_eq!("s1", entries[0].name);
+                assert_eq!(vec!["run1.bam", "run2.bam"], entries[0].bam_files);
+                assert_eq!("s2", entries[1].name);
+                assert_eq!(vec!["s2.bam"], entries[1].bam_files);
+            }
+            SampleSheet::Reads(_) => panic!("Expected BAM files"),
+        }
+    }
+}
@@ -11,6 +11,8 @@ use rust_htslib::bam::Read as BamRead;
 use rust_htslib::bam::errors::Result as HtslibResult;
 
 use mapping_parameters::ReadFormat;
+use random;
+use stoit_naming::StoitNaming;
 
 use tempdir::TempDir;
 use tempfile;
@@ -19,8 +21,11 @@ use nix::sys::stat;
 
 use bam_generator::*;
 use bam_generator::complete_processes;
+use bam_cache::CachedBamFile;
 use genome_exclusion::*;
 
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::slice;
 use std::ffi;
 
@@ -34,6 +39,118 @@ fn bam_header_target_name(header: &bam::HeaderView, tid: usize) -> &[u8] {
     }
 }
 
+/// How the best shard is chosen for each read (pair) when desharding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShardSelectionPolicy {
+    /// Highest total alignment score (AS tag)
+    AlignmentScore,
+    /// Most reads mapped, then lowest total edit distance (NM tag)
+    EditDistance,
+    /// Most reads mapped, then highest percent identity across aligned bases
+    PercentIdentity,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShardSelection {
+    pub policy: ShardSelectionPolicy,
+    // Discard reads whose best score is tied across shards, rather than
+    // choosing one of the tied shards at random.
+    pub discard_ties: bool,
+}
+
+impl ShardSelection {
+    // Random choices between tied shards follow the global seed.
+    fn rng(&self) -> StdRng {
+        random::derived_rng()
+    }
+}
+
+impl Default for ShardSelection {
+    fn default() -> ShardSelection {
+        ShardSelection {
+            policy: ShardSelectionPolicy::AlignmentScore,
+            discard_ties: false,
+        }
+    }
+}
+
+// Totals over the primary alignments of a read (pair) to one shard.
+#[derive(Clone, Copy, Debug, Default)]
+struct ShardAlignmentStats {
+    alignment_score: i64,
+    edit_distance: u64,
+    aligned_bases: u64,
+    num_mapped: u32,
+}
+
+impl ShardAlignmentStats {
+    fn add_record(&mut self, record: &Record, policy: ShardSelectionPolicy) {
+        // Unlike BWA-MEM, Minimap2 does not have AS tags when the read is
+        // unmapped.
+        if record.is_unmapped() {
+            return
+        }
+        self.num_mapped += 1;
+        match policy {
+            ShardSelectionPolicy::AlignmentScore => {
+                self.alignment_score += record.aux(b"AS")
+                    .expect(&format!(
+                        "Record {:#?} unexpectedly did not have AS tag, which is needed for \
+                         ranking alignments", str::from_utf8(record.qname()).unwrap()))
+                    .integer();
+            },
+            ShardSelectionPolicy::EditDistance | ShardSelectionPolicy::PercentIdentity => {
+                self.edit_distance += record.aux(b"NM")
+                    .expect(&format!(
+                        "Record {:#?} unexpectedly did not have NM tag, which is needed for \
+                         ranking alignments", str::from_utf8(record.qname()).unwrap()))
+                    .integer() as u64;
+                for cig in record.cigar().iter() {
+                    match cig {
+                        Cigar::Match(_) | Cigar::Ins(_) | Cigar::Del(_) |
+                        Cigar::Diff(_) | Cigar::Equal(_) => {
+                            self.aligned_bases += cig.len() as u64
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // Higher is better. Apart from when ranking by alignment score, reads
+    // which map are always preferred over those which do not.
+    fn score(&self, policy: ShardSelectionPolicy) -> (u32, f64) {
+        match policy {
+            ShardSelectionPolicy::AlignmentScore => (0, self.alignment_score as f64),
+            ShardSelectionPolicy::EditDistance => (self.num_mapped, -(self.edit_distance as f64)),
+            ShardSelectionPolicy::PercentIdentity => (
+                self.num_mapped,
+                match self.aligned_bases {
+                    0 => 0.0,
+                    _ => 1.0 - self.edit_distance as f64 / self.aligned_bases as f64
+                }),
+        }
+    }
+}
+
+// Counts of reads (pairs) whose best score was tied between shards, for
+// reporting once desharding is complete.
+#[derive(Debug, Default)]
+struct ShardTieSummary {
+    num_reads: u64,
+    num_tied: u64,
+    num_discarded: u64,
+}
+
+impl ShardTieSummary {
+    fn log(&self) {
+        info!("Of {} reads (or pairs) chosen between shards, {} had a best hit tied \
+               between more than one shard, of which {} were discarded",
+              self.num_reads, self.num_tied, self.num_discarded);
+    }
+}
+
 pub struct ReadSortedShardedBamReader<'a, T>
 where T: GenomeExclusion {
     shard_bam_readers: Vec<bam::Reader>,
@@ -42,6 +159,9 @@ where T: GenomeExclusion {
     winning_index: Option<usize>,
     tid_offsets: Vec<i32>,
     genome_exclusion: &'a T,
+    shard_selection: ShardSelection,
+    rng: StdRng,
+    tie_summary: ShardTieSummary,
 }
 
 impl<'a, T> ReadSortedShardedBamReader<'a, T>
@@ -167,72 +287,80 @@ where T: GenomeExclusion {
             to_return.set_tid(tid_now + self.tid_offsets[self.winning_index.unwrap()]);
             return Ok(true);
         } else {
-            if self.previous_read_records.is_none() {
-                self.previous_read_records = self.read_a_record_set();
+            // Choose the winning shard, reading further pairs when ties are
+            // discarded.
+            let (second_read_alignments, winning_index) = loop {
                 if self.previous_read_records.is_none() {
-                    // All finished all the input files.
-                    return Ok(false);
+                    self.previous_read_records = self.read_a_record_set();
+                    if self.previous_read_records.is_none() {
+                        // All finished all the input files.
+                        return Ok(false);
+                    }
                 }
-            }
-            // Read the second set
-            // If we get None, then croak
-            let second_read_alignments = self.read_a_record_set()
-                .expect("Unexpectedly was able to read a first read set, but not a second. Hmm.");
-
-            debug!("Previous records {:?}", self.previous_read_records);
-            debug!("Second read records {:?}", second_read_alignments);
-
-            // Decide which pair is the winner
-            // Cannot use max_by_key() here since we want a random winner
-            let mut max_score: Option<i64> = None;
-            let mut winning_indices: Vec<usize> = vec![];
-            match self.previous_read_records {
-                None => unreachable!(),
-                Some(ref previous_records) => {
-                    for (i, ref aln1) in previous_records.iter().enumerate() {
-                        let tid = aln1.tid();
-                        if tid < 0 || !self.genome_exclusion.is_excluded(
-                            bam_header_target_name(&self.shard_bam_readers[i].header(), tid as usize)) {
-                            let mut score: i64 = 0;
-                            // Unlike BWA-MEM, Minimap2 does not have AS tags
-                            // when the read is unmapped.
-                            if !aln1.is_unmapped() {
-                                score += aln1.aux(b"AS")
-                                    .expect(&format!(
-                                        "Record {:#?} (read1) unexpectedly did not have AS tag, which is needed for \
-                                        ranking pairs of alignments", aln1.qname())).integer();
-                            }
-                            if !second_read_alignments[i].is_unmapped() {
-                                score += second_read_alignments[i].aux(b"AS")
-                                    .expect(&format!(
-                                        "Record {:#?} (read2) unexpectedly did not have AS tag, which is needed for \
-                                        ranking pairs of alignments", aln1.qname())).integer();
-                            }
-                            if max_score.is_none() || score > max_score.unwrap() {
-                                max_score = Some(score);
-                                winning_indices = vec![i]
-                            } else if score == max_score.unwrap() {
-                                winning_indices.push(i)
+                // Read the second set
+                // If we get None, then croak
+                let second_read_alignments = self.read_a_record_set()
+                    .expect("Unexpectedly was able to read a first read set, but not a second. Hmm.");
+
+                debug!("Previous records {:?}", self.previous_read_records);
+                debug!("Second read records {:?}", second_read_alignments);
+
+                // Decide which pair is the winner
+                // Cannot use max_by_key() here since we want a random winner
+                let policy = self.shard_selection.policy;
+                let mut max_score: Option<(u32, f64)> = None;
+                let mut winning_indices: Vec<usize> = vec![];
+                let mut winner_mapped = false;
+                match self.previous_read_records {
+                    None => unreachable!(),
+                    Some(ref previous_records) => {
+                        for (i, ref aln1) in previous_records.iter().enumerate() {
+                            let tid = aln1.tid();
+                            if tid < 0 || !self.genome_exclusion.is_excluded(
+                                bam_header_target_name(&self.shard_bam_readers[i].header(), tid as usize)) {
+                                let mut stats = ShardAlignmentStats::default();
+                                stats.add_record(aln1, policy);
+                                stats.add_record(&second_read_alignments[i], policy);
+                                let score = stats.score(policy);
+                                if max_score.is_none() || score > max_score.unwrap() {
+                                    max_score = Some(score);
+                                    winning_indices = vec![i];
+                                    winner_mapped = stats.num_mapped > 0;
+                                } else if score == max_score.unwrap() {
+                                    winning_indices.push(i)
+                                }
+                                // Else a loser when there was a previous winner
                             }
-                            // Else a loser when there was a previous winner
                         }
                     }
+                };
+
+                self.tie_summary.num_reads += 1;
+                let winning_index: usize;
+                if winning_indices.len() > 1 {
+                    // Reads unmapped in every shard are not considered tied.
+                    if winner_mapped {
+                        self.tie_summary.num_tied += 1;
+                    }
+                    if winner_mapped && self.shard_selection.discard_ties {
+                        debug!("Discarding pair tied between shards {:?}", winning_indices);
+                        self.tie_summary.num_discarded += 1;
+                        self.previous_read_records = None;
+                        continue;
+                    }
+                    winning_index = *winning_indices
+                        .choose(&mut self.rng).unwrap();
+                } else if winning_indices.len() == 1 {
+                    winning_index = winning_indices[0];
+                } else {
+                    error!("CoverM cannot currently deal with reads that only map to excluded genomes");
+                    process::exit(1);
                 }
+                debug!("Choosing winning index {} from winner pool {:?}",
+                       winning_index, winning_indices);
+                break (second_read_alignments, winning_index);
             };
 
-            let winning_index: usize;
-            if winning_indices.len() > 1 {
-                winning_index = *winning_indices
-                    .choose(&mut thread_rng()).unwrap();
-            } else if winning_indices.len() == 1 {
-                winning_index = winning_indices[0];
-            } else {
-                error!("CoverM cannot currently deal with reads that only map to excluded genomes");
-                process::exit(1);
-            }
-            debug!("Choosing winning index {} from winner pool {:?}",
-                   winning_index, winning_indices);
-
             // Set the next read to return
             self.winning_index = Some(winning_index);
             self.next_record_to_return = Some(
@@ -262,43 +390,61 @@ where T: GenomeExclusion {
     }
 }
 
+// Read header info for each BAM file, and write to a new BAM header, returning
+// it along with the tid offset of each shard.
+fn concatenate_shard_headers(readers: &[bam::Reader]) -> (bam::header::Header, Vec<i32>) {
+    let mut new_header = bam::header::Header::new();
+    let mut tid_offsets: Vec<i32> = vec!();
+
+    let mut current_tid_offset: i32 = 0;
+    for reader in readers.iter() {
+        let header = reader.header();
+        let mut current_tid: u32 = 0; // I think TID counting should start at 0, 1 returns wrong lengths.
+        let names = header.target_names();
+        for name in names.iter() {
+            let length = header.target_len(current_tid)
+                .expect(&format!("Failed to get target length for TID {}", current_tid));
+            // e.g. @SQ	SN:a62_bin.100.fna=k141_20475	LN:15123
+            let mut current_record = bam::header::HeaderRecord::new(b"SQ");
+            current_record.push_tag(
+                b"SN",
+                &std::str::from_utf8(name).unwrap());
+            current_record.push_tag(b"LN", &length);
+            new_header.push_record(&current_record);
+            current_tid += 1; // increment current tid
+        }
+
+        tid_offsets.push(current_tid_offset);
+        current_tid_offset += header.target_count() as i32;
+    }
+    return (new_header, tid_offsets)
+}
+
 pub struct ShardedBamReaderGenerator<'a, T>
 where T: GenomeExclusion {
     pub stoit_name: String,
+    // Read or BAM files that the shards are generated from
+    pub input_paths: Vec<String>,
     pub read_sorted_bam_readers: Vec<bam::Reader>,
+    // Processes mapping reads to generate the read sorted BAM files, if they
+    // are not pre-existing BAM files.
+    pub shard_mappings: Vec<ShardMapping>,
     pub sort_threads: i32,
     pub genome_exclusion: &'a T,
+    pub shard_selection: ShardSelection,
 }
 
 impl<'a, T> NamedBamReaderGenerator<ShardedBamReader> for ShardedBamReaderGenerator<'a, T>
 where T: GenomeExclusion {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.input_paths
+    }
     fn start(self) -> ShardedBamReader {
-        let mut new_header = bam::header::Header::new();
-        let mut tid_offsets: Vec<i32> = vec!();
-
-        // Read header info for each BAM file, and write to new BAM header,
-        // adding tid offsets.
-        let mut current_tid_offset: i32 = 0;
-        for reader in self.read_sorted_bam_readers.iter() {
-            let header = reader.header();
-            let mut current_tid: u32 = 0; // I think TID counting should start at 0, 1 returns wrong lengths.
-            let names = header.target_names();
-            for name in names.iter() {
-                let length = header.target_len(current_tid)
-                    .expect(&format!("Failed to get target length for TID {}", current_tid));
-                // e.g. @SQ	SN:a62_bin.100.fna=k141_20475	LN:15123
-                let mut current_record = bam::header::HeaderRecord::new(b"SQ");
-                current_record.push_tag(
-                    b"SN",
-                    &std::str::from_utf8(name).unwrap());
-                current_record.push_tag(b"LN", &length);
-                new_header.push_record(&current_record);
-                current_tid += 1; // increment current tid
-            }
-
-            tid_offsets.push(current_tid_offset);
-            current_tid_offset += header.target_count() as i32;
-        }
+        let (new_header, tid_offsets) = concatenate_shard_headers(
+            &self.read_sorted_bam_readers);
 
         let tmp_dir = TempDir::new("coverm_fifo")
             .expect("Unable to create samtools sort temporary directory");
@@ -331,6 +477,9 @@ where T: GenomeExclusion {
             next_record_to_return: None,
             winning_index: None,
             genome_exclusion: self.genome_exclusion,
+            rng: self.shard_selection.rng(),
+            shard_selection: self.shard_selection,
+            tie_summary: ShardTieSummary::default(),
         };
 
 
@@ -398,6 +547,7 @@ where T: GenomeExclusion {
             }
             debug!("Finished writing records to samtools sort input FIFO.");
         }
+        demux.tie_summary.log();
 
         let reader = sorted_reader_join_handle.join()
             .expect("sorted reader thread failed");
@@ -410,6 +560,7 @@ where T: GenomeExclusion {
             sort_command_string: sort_command_string,
             sort_log_file_description: "samtools sort".to_string(),
             sort_log_file: sort_log_file,
+            shard_mappings: self.shard_mappings,
             num_detected_primary_alignments: 0,
         }
     }
@@ -423,6 +574,7 @@ pub struct ShardedBamReader {
     sort_command_string: String,
     sort_log_file_description: String,
     sort_log_file: tempfile::NamedTempFile,
+    shard_mappings: Vec<ShardMapping>,
     num_detected_primary_alignments: u64,
 }
 
@@ -447,6 +599,9 @@ impl NamedBamReader for ShardedBamReader {
             vec![self.sort_log_file_description],
             vec![self.sort_log_file],
             Some(self.tempdir));
+        for shard_mapping in self.shard_mappings {
+            shard_mapping.finish();
+        }
     }
 
     fn set_threads(&mut self, n_threads: usize) {
@@ -463,7 +618,8 @@ impl NamedBamReader for ShardedBamReader {
 // same read set to different references (all sorted by read name), generate a
 // BAM reader that chooses the best place for each read to map to.
 pub fn generate_sharded_bam_reader_from_bam_files<'a, T>(
-    bam_paths: Vec<&str>, sort_threads: i32, genome_exclusion: &'a T)
+    bam_paths: Vec<&str>, sort_threads: i32, genome_exclusion: &'a T,
+    shard_selection: ShardSelection, stoit_naming: &StoitNaming)
     -> Vec<ShardedBamReaderGenerator<'a, T>>
 where T: GenomeExclusion {
     // open an output BAM file that gets put to samtools sort without -n
@@ -479,7 +635,22 @@ where T: GenomeExclusion {
                 .expect(&format!("Unable to open bam file {}", f))
         }
     ).collect();
-    let stoit_name = bam_paths.iter().map(
+    debug!("Opened all input BAM files");
+    let gen = ShardedBamReaderGenerator {
+        stoit_name: sharded_stoit_name(&bam_paths, stoit_naming),
+        input_paths: bam_paths.iter().map(|s| s.to_string()).collect(),
+        read_sorted_bam_readers: bam_readers,
+        shard_mappings: vec![],
+        sort_threads: sort_threads,
+        genome_exclusion: genome_exclusion,
+        shard_selection: shard_selection,
+    };
+    return vec!(gen);
+
+}
+
+fn sharded_stoit_name(bam_paths: &[&str], stoit_naming: &StoitNaming) -> String {
+    let file_names = bam_paths.iter().map(
         |f| std::path::Path::new(f).file_stem().unwrap().to_str().expect(
             "failure to convert bam file name to stoit name - UTF8 error maybe?").to_string())
         .fold(
@@ -489,27 +660,314 @@ where T: GenomeExclusion {
                 Some(prev) => Some(format!("{}|{}",prev,s))
             }}
     ).unwrap();
-    debug!("Opened all input BAM files");
-    let gen = ShardedBamReaderGenerator {
-        stoit_name: stoit_name,
-        read_sorted_bam_readers: bam_readers,
-        sort_threads: sort_threads,
+    // Shards are of a single sample
+    stoit_naming.stoit_name(None, &file_names, stoit_naming.sample_name(0))
+}
+
+fn header_is_coordinate_sorted(header: &bam::HeaderView) -> bool {
+    header.as_bytes()
+        .split(|c| *c == b'\n')
+        .filter(|line| line.starts_with(b"@HD"))
+        .any(|line| line.split(|c| *c == b'\t').any(|field| field == b"SO:coordinate"))
+}
+
+// Whether each of the shard BAM files declares itself as coordinate sorted in
+// its header (@HD SO:coordinate). Mixtures of sort orders are not supported.
+pub fn shard_bam_files_are_coordinate_sorted(bam_paths: &[&str]) -> bool {
+    let num_coordinate_sorted = bam_paths.iter().filter(|path| {
+        let reader = bam::Reader::from_path(path)
+            .expect(&format!("Unable to open bam file {}", path));
+        header_is_coordinate_sorted(reader.header())
+    }).count();
+    match num_coordinate_sorted {
+        0 => false,
+        n if n == bam_paths.len() => true,
+        _ => {
+            error!("Some but not all of the sharded BAM files are coordinate sorted. \
+                    Shards must either all be sorted by read name, or all by coordinate.");
+            process::exit(1);
+        }
+    }
+}
+
+// The best scoring shard for a read (pair) seen so far, and the number of
+// shards tied at that score, so that ties can be broken uniformly at random
+// as the shards are read.
+struct BestShardHit {
+    score: (u32, f64),
+    shard_index: usize,
+    num_ties: u32,
+    mapped: bool,
+}
+
+impl BestShardHit {
+    // Reads unmapped in every shard are not considered tied.
+    fn is_tied(&self) -> bool {
+        self.num_ties > 1 && self.mapped
+    }
+}
+
+// Total the primary alignments of each read (pair) in a shard. Reads with an
+// alignment to an excluded genome are recorded as None.
+fn score_shard_alignments<T: GenomeExclusion>(
+    bam_path: &str, genome_exclusion: &T, policy: ShardSelectionPolicy)
+    -> HashMap<Vec<u8>, Option<ShardAlignmentStats>> {
+
+    let mut reader = bam::Reader::from_path(bam_path)
+        .expect(&format!("Unable to open bam file {}", bam_path));
+    let header = reader.header().clone();
+    let mut scores: HashMap<Vec<u8>, Option<ShardAlignmentStats>> = HashMap::new();
+    let mut record = bam::Record::new();
+    while reader.read(&mut record).expect("Failure to read from a shard BAM file") {
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        let stats = scores.entry(record.qname().to_vec())
+            .or_insert(Some(ShardAlignmentStats::default()));
+        if !record.is_unmapped() && genome_exclusion.is_excluded(
+            bam_header_target_name(&header, record.tid() as usize)) {
+            *stats = None;
+        }
+        if let Some(read_stats) = stats.as_mut() {
+            read_stats.add_record(&record, policy);
+        }
+    }
+    return scores
+}
+
+// Find the best shard for each read (pair) by reading through each shard in
+// turn. Reads within a shard are considered in qname order rather than hash
+// order, so that ties are broken the same way given the same random number
+// generator.
+fn find_best_shard_hits<T: GenomeExclusion, R: Rng>(
+    bam_paths: &[String], genome_exclusion: &T, policy: ShardSelectionPolicy,
+    rng: &mut R)
+    -> HashMap<Vec<u8>, BestShardHit> {
+
+    let mut best_hits: HashMap<Vec<u8>, BestShardHit> = HashMap::new();
+    for (i, path) in bam_paths.iter().enumerate() {
+        debug!("Scoring alignments in shard {}", path);
+        let mut shard_scores: Vec<(Vec<u8>, Option<ShardAlignmentStats>)> =
+            score_shard_alignments(path, genome_exclusion, policy)
+            .into_iter().collect();
+        shard_scores.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        for (qname, stats_option) in shard_scores {
+            let stats = match stats_option {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let score = stats.score(policy);
+            match best_hits.entry(qname) {
+                Entry::Vacant(entry) => {
+                    entry.insert(BestShardHit {
+                        score: score,
+                        shard_index: i,
+                        num_ties: 1,
+                        mapped: stats.num_mapped > 0,
+                    });
+                },
+                Entry::Occupied(mut entry) => {
+                    let hit = entry.get_mut();
+                    if score > hit.score {
+                        hit.score = score;
+                        hit.shard_index = i;
+                        hit.num_ties = 1;
+                        hit.mapped = stats.num_mapped > 0;
+                    } else if score == hit.score {
+                        hit.num_ties += 1;
+                        if rng.gen_range(0, hit.num_ties) == 0 {
+                            hit.shard_index = i;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    return best_hits
+}
+
+pub struct CoordinateSortedShardedBamReaderGenerator<'a, T>
+where T: GenomeExclusion {
+    pub stoit_name: String,
+    pub bam_paths: Vec<String>,
+    pub genome_exclusion: &'a T,
+    pub shard_selection: ShardSelection,
+}
+
+impl<'a, T> NamedBamReaderGenerator<CoordinateSortedShardedBamReader> for
+    CoordinateSortedShardedBamReaderGenerator<'a, T>
+where T: GenomeExclusion {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn input_paths(&self) -> &[String] {
+        &self.bam_paths
+    }
+    fn start(self) -> CoordinateSortedShardedBamReader {
+        let readers: Vec<bam::Reader> = self.bam_paths.iter().map(|f|
+            bam::Reader::from_path(f).expect(&format!("Unable to open bam file {}", f))
+        ).collect();
+        let (new_header, tid_offsets) = concatenate_shard_headers(&readers);
+
+        // First pass: find the best shard for each read.
+        let best_hits = find_best_shard_hits(
+            &self.bam_paths, self.genome_exclusion, self.shard_selection.policy,
+            &mut self.shard_selection.rng());
+        debug!("Found best shards for {} reads", best_hits.len());
+        let mut tie_summary = ShardTieSummary::default();
+        tie_summary.num_reads = best_hits.len() as u64;
+        tie_summary.num_tied = best_hits.values().filter(|hit| hit.is_tied()).count() as u64;
+        if self.shard_selection.discard_ties {
+            tie_summary.num_discarded = tie_summary.num_tied;
+        }
+        tie_summary.log();
+
+        return CoordinateSortedShardedBamReader {
+            stoit_name: self.stoit_name,
+            header: bam::HeaderView::from_header(&new_header),
+            bam_paths: self.bam_paths,
+            tid_offsets: tid_offsets,
+            best_hits: best_hits,
+            discard_ties: self.shard_selection.discard_ties,
+            current_shard_index: 0,
+            current_reader: None,
+            threads: 1,
+            num_detected_primary_alignments: 0,
+        }
+    }
+}
+
+// Second pass over coordinate sorted shards, returning only the alignments of
+// each read to its best shard. Since the tids of each shard are offset past
+// those of the previous shards, reading the shards one after another gives
+// coordinate sorted output without needing to sort again.
+pub struct CoordinateSortedShardedBamReader {
+    stoit_name: String,
+    header: bam::HeaderView,
+    bam_paths: Vec<String>,
+    tid_offsets: Vec<i32>,
+    best_hits: HashMap<Vec<u8>, BestShardHit>,
+    discard_ties: bool,
+    current_shard_index: usize,
+    current_reader: Option<bam::Reader>,
+    threads: usize,
+    num_detected_primary_alignments: u64,
+}
+
+impl NamedBamReader for CoordinateSortedShardedBamReader {
+    fn name(&self) -> &str {
+        &(self.stoit_name)
+    }
+    fn read(&mut self, record: &mut bam::record::Record) -> HtslibResult<bool> {
+        loop {
+            if self.current_reader.is_none() {
+                if self.current_shard_index >= self.bam_paths.len() {
+                    return Ok(false);
+                }
+                let path = &self.bam_paths[self.current_shard_index];
+                debug!("Reading best hits from shard {}", path);
+                let mut reader = bam::Reader::from_path(path)
+                    .expect(&format!("Unable to open bam file {}", path));
+                if self.threads > 1 {
+                    reader.set_threads(self.threads-1).unwrap();
+                }
+                self.current_reader = Some(reader);
+            }
+            if !self.current_reader.as_mut().unwrap().read(record)? {
+                self.current_reader = None;
+                self.current_shard_index += 1;
+                continue;
+            }
+            if record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+            match self.best_hits.get(record.qname()) {
+                Some(hit) => {
+                    if hit.shard_index != self.current_shard_index ||
+                        (self.discard_ties && hit.is_tied()) {
+                        continue;
+                    }
+                },
+                None => {
+                    error!("CoverM cannot currently deal with reads that only map to excluded genomes");
+                    process::exit(1);
+                }
+            }
+            let tid_now = record.tid();
+            if tid_now >= 0 {
+                record.set_tid(tid_now + self.tid_offsets[self.current_shard_index]);
+            }
+            self.num_detected_primary_alignments += 1;
+            return Ok(true);
+        }
+    }
+    fn header(&self) -> &bam::HeaderView {
+        &self.header
+    }
+    fn finish(self) {}
+
+    fn set_threads(&mut self, n_threads: usize) {
+        self.threads = n_threads;
+    }
+    fn num_detected_primary_alignments(&self) -> u64 {
+        return self.num_detected_primary_alignments
+    }
+}
+
+// Given a list of paths to different BAM files which are all mappings of the
+// same read set to different references (all sorted by coordinate), generate a
+// BAM reader that chooses the best place for each read to map to, without
+// sorting the BAM files by read name.
+pub fn generate_coordinate_sorted_sharded_bam_reader_from_bam_files<'a, T>(
+    bam_paths: Vec<&str>, genome_exclusion: &'a T, shard_selection: ShardSelection,
+    stoit_naming: &StoitNaming)
+    -> Vec<CoordinateSortedShardedBamReaderGenerator<'a, T>>
+where T: GenomeExclusion {
+    let gen = CoordinateSortedShardedBamReaderGenerator {
+        stoit_name: sharded_stoit_name(&bam_paths, stoit_naming),
+        bam_paths: bam_paths.iter().map(|s| s.to_string()).collect(),
         genome_exclusion: genome_exclusion,
+        shard_selection: shard_selection,
     };
     return vec!(gen);
+}
 
+/// Processes generating the read sorted BAM file of one shard, which are
+/// waited on once the shard has been read to completion.
+pub struct ShardMapping {
+    processes: Vec<std::process::Child>,
+    command_strings: Vec<String>,
+    log_file_descriptions: Vec<String>,
+    log_files: Vec<tempfile::NamedTempFile>,
+    tempdir: TempDir,
+    pending_cache_manifest: Option<CachedBamFile>,
+}
+
+impl ShardMapping {
+    fn finish(self) {
+        complete_processes(
+            self.processes,
+            self.command_strings,
+            self.log_file_descriptions,
+            self.log_files,
+            Some(self.tempdir));
+        // The manifest is only written once the cached BAM file is complete.
+        if let Some(cache) = self.pending_cache_manifest {
+            cache.write_manifest();
+        }
+    }
 }
 
 pub fn generate_named_sharded_bam_readers_from_reads(
     mapping_program: MappingProgram,
     reference: &str,
-    read1_path: &str,
-    read2_path: Option<&str>,
+    read1_paths: &[&str],
+    read2_paths: &[&str],
     read_format: ReadFormat,
     threads: u16,
-    cached_bam_file: Option<&str>,
+    cached_bam_file: Option<CachedBamFile>,
     discard_unmapped: bool,
-    mapping_options: Option<&str>) -> bam::Reader {
+    mapping_options: Option<&str>) -> (bam::Reader, ShardMapping) {
 
     let tmp_dir = TempDir::new("coverm_fifo")
         .expect("Unable to create temporary directory");
@@ -522,6 +980,102 @@ pub fn generate_named_sharded_bam_readers_from_reads(
     unistd::mkfifo(&fifo_path, stat::Mode::S_IRWXU)
         .expect(&format!("Error creating named pipe {:?}", fifo_path));
 
+    let reuse_cache = match cached_bam_file {
+        Some(ref cache) => cache.is_valid(),
+        None => false
+    };
+    let (cmd_string, log_descriptions, log_files) = if reuse_cache {
+        let path = &cached_bam_file.as_ref().unwrap().path;
+        info!("Reusing previously cached BAM file {}", path);
+        let cat_log = tempfile::NamedTempFile::new()
+            .expect("Failed to create cached BAM reading log tempfile");
+        let cmd_string = format!(
+            "set -e -o pipefail; cat '{}' 2>{} > {:?}",
+            path,
+            cat_log.path().to_str().expect("Failed to convert tempfile path to str"),
+            fifo_path);
+        (cmd_string, vec!["cat of cached BAM file".to_string()], vec![cat_log])
+    } else {
+        if let Some(ref cache) = cached_bam_file {
+            cache.invalidate();
+        }
+        generate_shard_mapping_command(
+            mapping_program,
+            reference,
+            read1_paths,
+            read2_paths,
+            read_format,
+            threads,
+            cached_bam_file.as_ref().map(|cache| cache.path.as_str()),
+            discard_unmapped,
+            &fifo_path,
+            &tmp_dir,
+            mapping_options)
+    };
+    let mut cmd = std::process::Command::new("bash");
+    cmd
+        .arg("-c")
+        .arg(&cmd_string)
+        .stderr(std::process::Stdio::piped());
+
+    debug!("Starting mapping processes");
+    let pre_processes = vec![cmd];
+    let command_strings = vec![format!("bash -c \"{}\"", cmd_string)];
+    let mut processes = vec![];
+    let mut i = 0;
+    for mut preprocess in pre_processes {
+        debug!("Running mapping command: {}", command_strings[i]);
+        i += 1;
+        processes.push(preprocess
+            .spawn()
+            .expect("Unable to execute bash"));
+    }
+    let reader = match bam::Reader::from_path(&fifo_path) {
+        Ok(reader) => reader,
+        Err(upstream_error) => {
+            error!("Failed to correctly find or parse BAM file at {:?}: {}", 
+                fifo_path,
+                upstream_error);
+            complete_processes(
+                processes, 
+                command_strings, 
+                log_descriptions,
+                log_files,
+                Some(tmp_dir)
+            );
+            panic!("Failure to find or parse BAM file, cannot continue");
+        }
+    };
+    return (reader, ShardMapping {
+        processes: processes,
+        command_strings: command_strings,
+        log_file_descriptions: log_descriptions,
+        log_files: log_files,
+        tempdir: tmp_dir,
+        pending_cache_manifest: match reuse_cache {
+            true => None,
+            false => cached_bam_file,
+        },
+    });
+}
+
+// Command mapping reads and sorting them by read name into the FIFO, also
+// writing them to the cached BAM file if there is one. Returns the command
+// along with descriptions of the log files it writes to, and those files.
+fn generate_shard_mapping_command(
+    mapping_program: MappingProgram,
+    reference: &str,
+    read1_paths: &[&str],
+    read2_paths: &[&str],
+    read_format: ReadFormat,
+    threads: u16,
+    cached_bam_file: Option<&str>,
+    discard_unmapped: bool,
+    fifo_path: &std::path::Path,
+    tmp_dir: &TempDir,
+    mapping_options: Option<&str>)
+    -> (String, Vec<String>, Vec<tempfile::NamedTempFile>) {
+
     let mapping_log = tempfile::NamedTempFile::new()
         .expect(&format!("Failed to create {:?} log tempfile", mapping_program));
     let samtools2_log = tempfile::NamedTempFile::new()
@@ -549,14 +1103,13 @@ pub fn generate_named_sharded_bam_readers_from_reads(
         },
         None => format!("> {:?}", fifo_path)
     };
-
     let mapping_command = build_mapping_command(
         mapping_program, 
         read_format,
         threads,
-        read1_path,
+        read1_paths,
         reference,
-        read2_path,
+        read2_paths,
         mapping_options);
 
     let bwa_sort_prefix = tempfile::Builder::new()
@@ -585,11 +1138,6 @@ pub fn generate_named_sharded_bam_readers_from_reads(
         // Caching (or not)
         cached_bam_file_args);
     debug!("Queuing cmd_string: {}", cmd_string);
-    let mut cmd = std::process::Command::new("bash");
-    cmd
-        .arg("-c")
-        .arg(&cmd_string)
-        .stderr(std::process::Stdio::piped());
 
     let mut log_descriptions = vec![
         format!("{:?}",mapping_program).to_string(),
@@ -600,53 +1148,131 @@ pub fn generate_named_sharded_bam_readers_from_reads(
         log_files.push(samtools_view_cache_log);
     }
 
-    debug!("Starting mapping processes");
-    let pre_processes = vec![cmd];
-    let command_strings = vec![format!("bash -c \"{}\"", cmd_string)];
-    let mut processes = vec![];
-    let mut i = 0;
-    for mut preprocess in pre_processes {
-        debug!("Running mapping command: {}", command_strings[i]);
-        i += 1;
-        processes.push(preprocess
-            .spawn()
-            .expect("Unable to execute bash"));
-    }
-    return match bam::Reader::from_path(&fifo_path) {
-        Ok(reader) => reader,
-        Err(upstream_error) => {
-            error!("Failed to correctly find or parse BAM file at {:?}: {}", 
-                fifo_path,
-                upstream_error);
-            complete_processes(
-                processes, 
-                command_strings, 
-                log_descriptions,
-                log_files,
-                Some(tmp_dir)
-            );
-            panic!("Failure to find or parse BAM file, cannot continue");
-        }
-    };
-        
+    (cmd_string, log_descriptions, log_files)
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use test_bam_writer::*;
+    use tempfile;
+
+    #[test]
+    fn test_shard_alignment_stats_scores() {
+        let both_mapped = ShardAlignmentStats {
+            alignment_score: 150,
+            edit_distance: 10,
+            aligned_bases: 300,
+            num_mapped: 2,
+        };
+        let one_mapped = ShardAlignmentStats {
+            alignment_score: 160,
+            edit_distance: 0,
+            aligned_bases: 150,
+            num_mapped: 1,
+        };
+        // Alignment score does not consider how many reads are mapped
+        assert!(one_mapped.score(ShardSelectionPolicy::AlignmentScore) >
+                both_mapped.score(ShardSelectionPolicy::AlignmentScore));
+        assert!(both_mapped.score(ShardSelectionPolicy::EditDistance) >
+                one_mapped.score(ShardSelectionPolicy::EditDistance));
+        assert!(both_mapped.score(ShardSelectionPolicy::PercentIdentity) >
+                one_mapped.score(ShardSelectionPolicy::PercentIdentity));
+        assert_eq!((2, 1.0 - 10.0/300.0),
+                   both_mapped.score(ShardSelectionPolicy::PercentIdentity));
+        assert_eq!((0, 0.0), ShardAlignmentStats::default().score(
+            ShardSelectionPolicy::PercentIdentity));
+    }
+
+    #[test]
+    fn test_header_is_coordinate_sorted() {
+        assert!(header_is_coordinate_sorted(&bam::HeaderView::from_bytes(
+            b"@HD\tVN:1.5\tSO:coordinate\n@SQ\tSN:seq1\tLN:1000\n")));
+        assert!(!header_is_coordinate_sorted(&bam::HeaderView::from_bytes(
+            b"@HD\tVN:1.5\tSO:queryname\n@SQ\tSN:seq1\tLN:1000\n")));
+        assert!(!header_is_coordinate_sorted(&bam::HeaderView::from_bytes(
+            b"@SQ\tSN:seq1\tLN:1000\n")));
+    }
+
+    #[test]
+    fn test_coordinate_sorted_shards_best_hits() {
+        let gen = CoordinateSortedShardedBamReaderGenerator {
+            stoit_name: "stoiter".to_string(),
+            bam_paths: vec![
+                "tests/data/shard1.coordinate_sorted.bam".to_string(),
+                "tests/data/shard2.coordinate_sorted.bam".to_string(),
+            ],
+            genome_exclusion: &NoExclusionGenomeFilter{},
+            shard_selection: ShardSelection::default(),
+        };
+        let mut reader = gen.start();
+        assert_eq!(7, reader.header().target_count());
+        let mut r = bam::Record::new();
+        let mut num_records = 0;
+        let mut last_tid = -1;
+        while reader.read(&mut r).unwrap() {
+            if !r.is_unmapped() {
+                assert!(r.tid() >= last_tid);
+                last_tid = r.tid();
+            }
+            num_records += 1;
+        }
+        // Each of the 20 read pairs is returned once.
+        assert_eq!(40, num_records);
+        assert_eq!(40, reader.num_detected_primary_alignments());
+    }
+
+    #[test]
+    fn test_best_shard_ties_reproducible() {
+        // Every read aligns equally well to both shards.
+        let td = tempfile::TempDir::new().unwrap();
+        let qnames: Vec<String> = (0..20).map(|i| format!("read{}", i)).collect();
+        let mut bam_paths = vec![];
+        for shard in &["shard1", "shard2"] {
+            let path = td.path().join(format!("{}.bam", shard));
+            let alignments: Vec<TestAlignment> = qnames.iter().map(|qname|
+                TestAlignment::single(qname, 0, 0, 0, vec![Cigar::Match(50)])
+            ).collect();
+            write_test_bam(&path, &[(*shard, 1000)], &alignments);
+            bam_paths.push(path.to_str().unwrap().to_string());
+        }
+
+        let chosen_shards = || -> HashMap<Vec<u8>, usize> {
+            find_best_shard_hits(
+                &bam_paths, &NoExclusionGenomeFilter{}, ShardSelectionPolicy::EditDistance,
+                &mut StdRng::seed_from_u64(42))
+                .into_iter()
+                .map(|(qname, hit)| {
+                    assert!(hit.is_tied());
+                    (qname, hit.shard_index)
+                })
+                .collect()
+        };
+        let first = chosen_shards();
+        assert_eq!(20, first.len());
+        // Ties are broken both ways, but the same way each time.
+        assert!(first.values().any(|i| *i == 0));
+        assert!(first.values().any(|i| *i == 1));
+        for _ in 0..5 {
+            assert_eq!(first, chosen_shards());
+        }
+    }
 
     #[test]
     fn test_shard_hello_world() {
         //This test needs to be revisited. It seems busted.
         let gen = ShardedBamReaderGenerator {
             stoit_name: "stoiter".to_string(),
+            input_paths: vec![],
             read_sorted_bam_readers: vec![
                 bam::Reader::from_path("tests/data/2seqs.fastaVbad_read.bam").unwrap(),
                 bam::Reader::from_path("tests/data/7seqs.fnaVbad_read.bam").unwrap()
             ],
+            shard_mappings: vec![],
             sort_threads: 1,
             genome_exclusion: &NoExclusionGenomeFilter{},
+            shard_selection: ShardSelection::default(),
         };
         let mut reader = gen.start();
         assert_eq!("stoiter".to_string(), reader.stoit_name);
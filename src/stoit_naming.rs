@@ -0,0 +1,119 @@
+use std::process;
+
+use regex::Regex;
+
+lazy_static! {
+    static ref READ_FILE_SUFFIX: Regex = Regex::new(
+        r"(_R?[12](_001)?|\.[12])?(\.(fastq|fq|fasta|fa|fna|bam))?(\.(gz|bz2|zst|xz))?$")
+        .unwrap();
+}
+
+/// How stoits are named, set once from the command line and given to readers
+/// of every type so that they name their stoits in the same way.
+#[derive(Debug, Default)]
+pub struct StoitNaming {
+    template: Option<String>,
+    // Sample names given by the user, in the order of the samples on the
+    // command line
+    sample_names: Vec<String>,
+}
+
+impl StoitNaming {
+    /// Name stoits with a template, where '{reference}' is replaced by the
+    /// name of the reference file (or nothing when no reference is used),
+    /// '{file}' by the name of the read1, BAM or depth file, and '{sample}' by
+    /// the sample name, or if no name was given, the file name with read
+    /// direction and file extensions removed. Any sample names are given to
+    /// the samples on the command line in order, of which there must be
+    /// num_samples.
+    pub fn new(
+        template: Option<&str>,
+        sample_names: Option<Vec<&str>>,
+        num_samples: usize,
+    ) -> StoitNaming {
+        if let Some(template) = template {
+            if !template.contains("{sample}") && !template.contains("{file}") {
+                error!("The stoit name template '{}' must contain {{sample}} or {{file}}, so \
+                        that samples are named differently", template);
+                process::exit(1);
+            }
+        }
+        let sample_names = sample_names.unwrap_or(vec![]);
+        if !sample_names.is_empty() && sample_names.len() != num_samples {
+            error!("{} sample names were given, but there are {} samples",
+                   sample_names.len(), num_samples);
+            process::exit(1);
+        }
+        StoitNaming {
+            template: template.map(|t| t.to_string()),
+            sample_names: sample_names.iter().map(|n| n.to_string()).collect(),
+        }
+    }
+
+    /// The name given by the user to the sample at the given position on the
+    /// command line.
+    pub fn sample_name(&self, sample_index: usize) -> Option<&str> {
+        self.sample_names.get(sample_index).map(|n| n.as_str())
+    }
+
+    /// Name of the stoit of a sample read from the file with the given name,
+    /// with the name of the reference it was mapped to, if included.
+    pub fn stoit_name(
+        &self,
+        reference_name: Option<&str>,
+        file_name: &str,
+        given_name: Option<&str>,
+    ) -> String {
+        match self.template {
+            None => {
+                let sample = given_name.unwrap_or(file_name);
+                match reference_name {
+                    Some(reference) => format!("{}/{}", reference, sample),
+                    None => sample.to_string(),
+                }
+            }
+            Some(ref template) => template
+                .replace("{reference}", reference_name.unwrap_or(""))
+                .replace("{file}", file_name)
+                .replace(
+                    "{sample}",
+                    &match given_name {
+                        Some(name) => name.to_string(),
+                        None => READ_FILE_SUFFIX.replace(file_name, "").to_string(),
+                    },
+                ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_file_suffix() {
+        for (file_name, sample) in &[
+            ("sample_R1.fastq.gz", "sample"),
+            ("sample_S1_L001_R1_001.fastq.gz", "sample_S1_L001"),
+            ("sample.1.fq.gz", "sample"),
+            ("sample_2.fq", "sample"),
+            ("sample.fna.zst", "sample"),
+            ("sample", "sample"),
+        ] {
+            assert_eq!(*sample, READ_FILE_SUFFIX.replace(file_name, ""));
+        }
+    }
+
+    #[test]
+    fn test_stoit_naming() {
+        let default = StoitNaming::default();
+        assert_eq!("ref.fna/s_R1.fq.gz", default.stoit_name(Some("ref.fna"), "s_R1.fq.gz", None));
+        assert_eq!("ref.fna/given", default.stoit_name(Some("ref.fna"), "s_R1.fq.gz", Some("given")));
+        assert_eq!(None, default.sample_name(0));
+
+        let templated = StoitNaming::new(Some("{sample}"), Some(vec!["a", "a"]), 2);
+        assert_eq!("s", templated.stoit_name(Some("ref.fna"), "s_R1.fq.gz", None));
+        assert_eq!(Some("a"), templated.sample_name(1));
+        assert_eq!(None, templated.sample_name(2));
+    }
+}
@@ -0,0 +1,72 @@
+use std;
+
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+/// An alignment to write to a test BAM file. Reads are all A's, and the NM
+/// tag is set to the given edit distance.
+pub struct TestAlignment<'a> {
+    pub qname: &'a str,
+    pub flags: u16,
+    pub tid: i32,
+    pub pos: i32,
+    pub cigar: Vec<Cigar>,
+    pub mtid: i32,
+    pub mpos: i32,
+    pub edit_distance: i64,
+}
+
+impl<'a> TestAlignment<'a> {
+    /// An unpaired alignment with no differences to the reference.
+    pub fn single(qname: &'a str, flags: u16, tid: i32, pos: i32, cigar: Vec<Cigar>) -> TestAlignment<'a> {
+        TestAlignment {
+            qname: qname,
+            flags: flags,
+            tid: tid,
+            pos: pos,
+            cigar: cigar,
+            mtid: -1,
+            mpos: -1,
+            edit_distance: 0,
+        }
+    }
+
+    fn to_record(&self) -> bam::Record {
+        let query_length: u32 = self.cigar.iter().map(|c| match c {
+            Cigar::Match(i) | Cigar::Ins(i) | Cigar::SoftClip(i) |
+            Cigar::Diff(i) | Cigar::Equal(i) => *i,
+            _ => 0,
+        }).sum();
+        let mut record = bam::Record::new();
+        record.set(
+            self.qname.as_bytes(),
+            Some(&CigarString(self.cigar.clone())),
+            &vec![b'A'; query_length as usize],
+            &vec![30; query_length as usize]);
+        record.set_flags(self.flags);
+        record.set_tid(self.tid);
+        record.set_pos(self.pos);
+        record.set_mapq(60);
+        record.set_mtid(self.mtid);
+        record.set_mpos(self.mpos);
+        record.push_aux(b"NM", &Aux::Integer(self.edit_distance));
+        record
+    }
+}
+
+/// Write a BAM file with the given contigs (name and length) and alignments,
+/// which should be sorted by position.
+pub fn write_test_bam(path: &std::path::Path, contigs: &[(&str, u32)], alignments: &[TestAlignment]) {
+    let mut header = bam::header::Header::new();
+    for (name, length) in contigs {
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", name)
+                .push_tag(b"LN", length));
+    }
+    let mut writer = bam::Writer::from_path(path, &header, bam::Format::BAM)
+        .expect("Failed to create test BAM file");
+    for alignment in alignments {
+        writer.write(&alignment.to_record()).expect("Failed to write test BAM record");
+    }
+}
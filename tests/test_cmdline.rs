@@ -41,6 +41,69 @@ mod tests {
             .stdout().doesnt_contain("1\t99\tseq1").unwrap();
     }
 
+    #[test]
+    fn test_filter_from_reads(){
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "filter",
+                "-r",
+                "tests/data/2seqs.fasta",
+                "-c",
+                "tests/data/bad_read.1.fa",
+                "tests/data/bad_read.2.fa",
+                "--min-read-aligned-length",
+                "50",
+                "-o",
+                t]).succeeds().unwrap();
+        Assert::command(&["samtools","view",t])
+            .stdout().contains("\tseq1\t").unwrap();
+    }
+
+    #[test]
+    fn test_filter_from_reads_wrong_number_of_outputs(){
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "filter",
+                "-r",
+                "tests/data/2seqs.fasta",
+                "-c",
+                "tests/data/bad_read.1.fa",
+                "tests/data/bad_read.2.fa",
+                "-o",
+                t,
+                t]).fails().unwrap();
+    }
+
+    #[test]
+    fn test_filter_output_fastq_genome_definition(){
+        let mut tf_definition: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tf_definition, "genome2\tseq2").unwrap();
+        let td = tempfile::TempDir::new().unwrap();
+        let fq1 = td.path().join("out.1.fq.gz");
+        let fq2 = td.path().join("out.2.fq.gz");
+        Assert::main_binary()
+            .with_args(&[
+                "filter",
+                "-b",
+                "tests/data/2seqs.reads_for_seq1_and_seq2.bam",
+                "--output-fastq",
+                fq1.to_str().unwrap(),
+                "--output-fastq-2",
+                fq2.to_str().unwrap(),
+                "--genome-definition",
+                tf_definition.path().to_str().unwrap()]).succeeds().unwrap();
+        Assert::command(&["zcat", fq1.to_str().unwrap()])
+            .stdout().contains("@4\n").unwrap();
+        Assert::command(&["zcat", fq2.to_str().unwrap()])
+            .stdout().contains("@4\n").unwrap();
+        Assert::command(&["zcat", fq1.to_str().unwrap()])
+            .stdout().doesnt_contain("@7\n").unwrap();
+    }
+
     #[test]
     fn test_contig_tempdir_index_creation(){
         let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
@@ -108,6 +171,27 @@ mod tests {
 2seqs.fasta/reads_for_seq1_and_seq2.1.fq.gz\tseq2\t1.2").unwrap();
     }
 
+    #[test]
+    fn test_contig_sample_sheet(){
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tf, "sample\tread1\tread2\tformat\treference").unwrap();
+        for _ in 0..2 {
+            writeln!(tf,
+                     "mysample\ttests/data/reads_for_seq1_and_seq2.1.fq.gz\t\
+                      tests/data/reads_for_seq1_and_seq2.2.fq.gz\tcoupled\t\
+                      tests/data/2seqs.fasta").unwrap();
+        }
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sample-sheet",
+                tf.path().to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout().contains("2seqs.fasta/mysample\tseq1\t")
+            .stdout().doesnt_contain("reads_for_seq1_and_seq2").unwrap();
+    }
+
     #[test]
     #[ignore] // cannot currently take multiple references
     fn test_coupled_reads_input(){
@@ -266,9 +350,66 @@ mod tests {
 7seqs.fna/reads_for_seq1_and_seq2.1.fq.gz	genome4~random_sequence_length_11002	0
 7seqs.fna/reads_for_seq1_and_seq2.1.fq.gz	genome5~seq2	1.2435294
 7seqs.fna/reads_for_seq1_and_seq2.1.fq.gz	genome6~random_sequence_length_11003	0").unwrap();
-        assert!(td.path()
-                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam")
-                .is_file());
+        assert_eq!(1, cached_bam_files(td.path(), "7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.").len());
+    }
+
+    /// BAM files in the cache directory with the given name prefix, which is
+    /// followed by a hash of how they were generated.
+    fn cached_bam_files(directory: &std::path::Path, prefix: &str) -> Vec<std::path::PathBuf> {
+        std::fs::read_dir(directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_str().unwrap();
+                name.starts_with(prefix) && name.ends_with(".bam")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cache_bam_files_reused_when_unchanged(){
+        let td = tempfile::TempDir::new().unwrap();
+        for _ in 0..2 {
+            Assert::main_binary()
+                .with_args(&[
+                    "contig",
+                    "--coupled",
+                    "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                    "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                    "--reference",
+                    "tests/data/7seqs.fna",
+                    "--output-format",
+                    "sparse",
+                    "--bam-file-cache-directory",
+                    td.path().to_str().unwrap()
+                ]).succeeds().stdout().contains(
+                    "7seqs.fna/reads_for_seq1_and_seq2.1.fq.gz	genome2~seq1	1.4117647").unwrap();
+            let cached = cached_bam_files(td.path(), "7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.");
+            assert_eq!(1, cached.len());
+            assert!(std::path::Path::new(
+                &format!("{}.manifest", cached[0].to_str().unwrap())).is_file());
+        }
+        // Changing the mapper caches the BAM file separately
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--mapper",
+                "bwa-mem",
+                "--bam-file-cache-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds().unwrap();
+        let cached = cached_bam_files(td.path(), "7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.");
+        assert_eq!(2, cached.len());
+        let manifests: Vec<String> = cached.iter()
+            .map(|path| std::fs::read_to_string(
+                format!("{}.manifest", path.to_str().unwrap())).unwrap())
+            .collect();
+        assert!(manifests.iter().any(|m| m.contains("mapper\tBWA_MEM")));
+        assert!(manifests.iter().any(|m| m.contains("mapper\tMINIMAP2_SR")));
     }
 
     #[test]
@@ -318,6 +459,194 @@ mod tests {
         assert!(td.path()
                 .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam")
                 .is_file());
+        assert!(td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam.bai")
+                .is_file());
+    }
+
+    #[test]
+    fn test_make_filtered(){
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--min-read-percent-identity",
+                "0.95",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds().unwrap();
+        let bam = td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam");
+        assert!(bam.is_file());
+        assert!(td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam.bai")
+                .is_file());
+        // Filtered BAM files are not caches of all reads
+        assert!(!td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam.manifest")
+                .exists());
+        Assert::command(&["samtools","view","-H",bam.to_str().unwrap()])
+            .stdout().contains("SO:coordinate").unwrap();
+    }
+
+    #[test]
+    fn test_make_parallel_samples(){
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "tests/data/7seqs.reads_for_7.1.fq",
+                "tests/data/7seqs.reads_for_7.2.fq",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--parallel-samples",
+                "2",
+                "--threads",
+                "2",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds().unwrap();
+        assert!(td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam.bai")
+                .is_file());
+        assert!(td.path()
+                .join("7seqs.fna.7seqs.reads_for_7.1.fq.bam.bai")
+                .is_file());
+    }
+
+    #[test]
+    fn test_make_sample_index(){
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "tests/data/7seqs.reads_for_7.1.fq",
+                "tests/data/7seqs.reads_for_7.2.fq",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--sample-index",
+                "2",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds().unwrap();
+        assert!(!td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam")
+                .exists());
+        assert!(td.path()
+                .join("7seqs.fna.7seqs.reads_for_7.1.fq.bam")
+                .is_file());
+    }
+
+    #[test]
+    fn test_make_sample_index_out_of_range(){
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--sample-index",
+                "2",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).fails().unwrap();
+    }
+
+    #[test]
+    fn test_make_dry_run(){
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--dry-run",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds()
+            .stdout().contains("# Mapping number 1, generating")
+            .stdout().contains("minimap2").unwrap();
+        assert!(!td.path()
+                .join("7seqs.fna.reads_for_seq1_and_seq2.1.fq.gz.bam")
+                .exists());
+    }
+
+    #[test]
+    fn test_make_dry_run_bwa_mem(){
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "-p",
+                "bwa-mem",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--dry-run",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds()
+            .stdout().contains("bwa mem")
+            .stdout().contains("'tests/data/7seqs.fna'").unwrap();
+
+        // No BWA index of 2seqs.fasta exists
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "-p",
+                "bwa-mem",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--reference",
+                "tests/data/2seqs.fasta",
+                "--dry-run",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).fails().unwrap();
+    }
+
+    #[test]
+    fn test_make_sample_sheet_dry_run(){
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        for _ in 0..2 {
+            writeln!(tf,
+                     "mysample\ttests/data/reads_for_seq1_and_seq2.1.fq.gz\t\
+                      tests/data/reads_for_seq1_and_seq2.2.fq.gz").unwrap();
+        }
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "make",
+                "--sample-sheet",
+                tf.path().to_str().unwrap(),
+                "--reference",
+                "tests/data/7seqs.fna",
+                "--dry-run",
+                "--output-directory",
+                td.path().to_str().unwrap()
+            ]).succeeds()
+            .stdout().contains("7seqs.fna.mysample.bam")
+            .stdout().contains("gzip -cdf").unwrap();
     }
 
     #[test]
@@ -388,6 +717,71 @@ mod tests {
 7seqs.reads_for_seq1_and_seq2	genome6	0	0").unwrap();
     }
 
+    #[test]
+    fn test_genome_checkpoint_and_resume() {
+        let td = tempfile::TempDir::new().unwrap();
+        let checkpoint_dir = td.path().join("checkpoints");
+        let expected = "Sample	Genome	Relative Abundance (%)	Mean
+7seqs.reads_for_seq1_and_seq2	unmapped	0	NA
+7seqs.reads_for_seq1_and_seq2	genome1	0	0
+7seqs.reads_for_seq1_and_seq2	genome2	53.16792	1.4117647
+7seqs.reads_for_seq1_and_seq2	genome3	0	0
+7seqs.reads_for_seq1_and_seq2	genome4	0	0
+7seqs.reads_for_seq1_and_seq2	genome5	46.832077	1.2435294
+7seqs.reads_for_seq1_and_seq2	genome6	0	0";
+        for resume in &[false, true] {
+            let mut args = vec![
+                "genome",
+                "-m",
+                "relative_abundance",
+                "mean",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "sparse",
+                "-s",
+                "~",
+                "--checkpoint-directory",
+                checkpoint_dir.to_str().unwrap()];
+            if *resume {
+                args.push("--resume");
+            }
+            Assert::main_binary()
+                .with_args(&args)
+                .succeeds().stdout().contains(expected).unwrap();
+            let checkpoint_files: Vec<String> = std::fs::read_dir(&checkpoint_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            assert_eq!(1, checkpoint_files.len());
+            assert!(checkpoint_files[0].starts_with("7seqs.reads_for_seq1_and_seq2."));
+            assert!(checkpoint_files[0].ends_with(".coverm-checkpoint"));
+        }
+    }
+
+    #[test]
+    fn test_contig_checkpoint_not_reused_for_changed_bam() {
+        let td = tempfile::TempDir::new().unwrap();
+        let checkpoint_dir = td.path().join("checkpoints");
+        let bam = td.path().join("sample.bam");
+        for (input, expected) in &[
+            ("tests/data/7seqs.reads_for_seq1_and_seq2.bam", "sample\tgenome5~seq2\t1.2435294\n"),
+            ("tests/data/7seqs.reads_for_seq1.bam", "sample\tgenome5~seq2\t0\n")] {
+            std::fs::copy(input, &bam).unwrap();
+            Assert::main_binary()
+                .with_args(&[
+                    "contig",
+                    "-b",
+                    bam.to_str().unwrap(),
+                    "--output-format",
+                    "sparse",
+                    "--checkpoint-directory",
+                    checkpoint_dir.to_str().unwrap(),
+                    "--resume"])
+                .succeeds().stdout().contains(*expected).unwrap();
+        }
+    }
+
     #[test]
     fn test_contig_dense_output_simple() {
         Assert::main_binary()
@@ -407,6 +801,251 @@ genome5~seq2	1.2435294
 genome6~random_sequence_length_11003	0").unwrap();
     }
 
+    #[test]
+    fn test_contig_sample_sheet_merged_bam_files() {
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        for _ in 0..2 {
+            writeln!(tf, "mysample\ttests/data/7seqs.reads_for_seq1_and_seq2.bam\t\tbam").unwrap();
+        }
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sample-sheet",
+                tf.path().to_str().unwrap(),
+                "--output-format",
+                "dense"]).succeeds().stdout().contains(
+                "Contig	mysample Mean
+genome1~random_sequence_length_11000	0
+genome1~random_sequence_length_11010	0
+genome2~seq1	2.8235295
+genome3~random_sequence_length_11001	0
+genome4~random_sequence_length_11002	0
+genome5~seq2	2.4870589
+genome6~random_sequence_length_11003	0").unwrap();
+    }
+
+    #[test]
+    fn test_contig_sample_names() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--sample-names",
+                "mysample",
+                "--output-format",
+                "dense"]).succeeds().stdout().contains(
+                "Contig	mysample Mean\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--sample-names",
+                "mysample",
+                "othersample"]).fails().unwrap();
+        // Names are given by position, so the same file can be named twice
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--sample-names",
+                "mysample",
+                "othersample",
+                "--output-format",
+                "dense"]).succeeds().stdout().contains(
+                "Contig	mysample Mean	othersample Mean\n").unwrap();
+    }
+
+    #[test]
+    fn test_contig_sample_name_template() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-r",
+                "tests/data/7seqs.fna",
+                "-c",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--sample-name-template",
+                "{sample}",
+                "--output-format",
+                "dense"]).succeeds().stdout().contains(
+                "Contig	reads_for_seq1_and_seq2 Mean\n").unwrap();
+    }
+
+    #[test]
+    fn test_contig_vamb_output() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "vamb"]).succeeds().stdout().is(
+                "contigname	7seqs.reads_for_seq1_and_seq2
+genome1~random_sequence_length_11000	0
+genome1~random_sequence_length_11010	0
+genome2~seq1	1.4117647
+genome3~random_sequence_length_11001	0
+genome4~random_sequence_length_11002	0
+genome5~seq2	1.2435294
+genome6~random_sequence_length_11003	0
+").unwrap();
+    }
+
+    #[test]
+    fn test_contig_maxbin2_output() {
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "maxbin2",
+                "--output-directory",
+                td.path().to_str().unwrap()]).succeeds().stdout().is("").unwrap();
+        assert_eq!(
+            "genome1~random_sequence_length_11000	0
+genome1~random_sequence_length_11010	0
+genome2~seq1	1.4117647
+genome3~random_sequence_length_11001	0
+genome4~random_sequence_length_11002	0
+genome5~seq2	1.2435294
+genome6~random_sequence_length_11003	0
+",
+            std::fs::read_to_string(
+                td.path().join("7seqs.reads_for_seq1_and_seq2.maxbin2.abund")).unwrap());
+    }
+
+    #[test]
+    fn test_contig_maxbin2_output_requires_directory() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "maxbin2"]).fails().unwrap();
+    }
+
+    #[test]
+    fn test_contig_pileup_output() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/2seqs.bad_read.1.bam",
+                "--output-format",
+                "pileup",
+                "--pileup-reference",
+                "tests/data/2seqs.fasta",
+                "--pileup-min-coverage",
+                "1.5"]).succeeds().stdout().contains(
+                "Sample	Contig	Position	Reference	Depth	A	C	G	T	Deletions	Insertions
+2seqs.bad_read.1	seq2	1	G	1	0	0	1	0	0	0
+").unwrap();
+    }
+
+    #[test]
+    fn test_contig_pileup_output_requires_reference() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/2seqs.bad_read.1.bam",
+                "--output-format",
+                "pileup"]).fails().unwrap();
+    }
+
+    #[test]
+    fn test_contig_exclude_regex() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--exclude-regex",
+                "seq2$"]).succeeds().stdout().contains(
+                "genome2~seq1	1.4117647
+genome3~random_sequence_length_11001	0
+genome4~random_sequence_length_11002	0
+genome6~random_sequence_length_11003	0
+").stdout().doesnt_contain("genome5~seq2").unwrap();
+    }
+
+    #[test]
+    fn test_genome_exclude_list_unmapped() {
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tf, "genome5").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "-s",
+                "~",
+                "--exclude-list",
+                tf.path().to_str().unwrap()]).succeeds().stdout().is(
+                "Genome	7seqs.reads_for_seq1_and_seq2 Relative Abundance (%)
+unmapped	50
+genome1	0
+genome2	50
+genome3	0
+genome4	0
+genome6	0
+").unwrap();
+    }
+
+    #[test]
+    fn test_genome_exclude_list_remove() {
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tf, "genome5").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "-s",
+                "~",
+                "--exclude-list",
+                tf.path().to_str().unwrap(),
+                "--excluded-reads",
+                "remove"]).succeeds().stdout().is(
+                "Genome	7seqs.reads_for_seq1_and_seq2 Relative Abundance (%)
+unmapped	0
+genome1	0
+genome2	100
+genome3	0
+genome4	0
+genome6	0
+").unwrap();
+    }
+
+    #[test]
+    fn test_contig_host_depletion() {
+        // All reads map to the host, since it contains the same sequences as
+        // the reference.
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-r",
+                "tests/data/7seqs.fna",
+                "-c",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--host-reference",
+                "tests/data/2seqs.fasta"])
+            .stdout().contains("genome2~seq1	0\n")
+            .stderr().contains(
+                "In sample '7seqs.fna/reads_for_seq1_and_seq2.1.fq.gz', \
+                 removed 24 reads mapped to the host reference")
+            .succeeds().unwrap();
+    }
+
     #[test]
     fn test_genome_dense_output_simple() {
         Assert::main_binary()
@@ -503,6 +1142,52 @@ genome6	0").unwrap();
 ").unwrap();
     }
 
+    #[test]
+    fn test_contig_reads_from_stdin() {
+        let reads = std::fs::read_to_string("tests/data/reads_for_seq1_and_seq2.fna").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--output-format",
+                "sparse",
+                "-r",
+                "tests/data/7seqs.fna",
+                "--single",
+                "-"])
+            .stdin(reads)
+            .succeeds()
+            .stdout().contains(
+                "7seqs.fna/stdin	genome2~seq1	1.4117647\n\
+                 7seqs.fna/stdin	genome3~random_sequence_length_11001	0\n").unwrap();
+    }
+
+    #[test]
+    fn test_contig_zstd_and_bzip2_reads() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--output-format",
+                "sparse",
+                "--min-read-percent-identity-pair",
+                "0.95",
+                "--contig-end-exclusion",
+                "0",
+                "-r",
+                "tests/data/7seqs.fna",
+                "-1",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.zst",
+                "-2",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.bz2",
+                "--proper-pairs-only",
+            ])
+            .succeeds()
+            .stdout().contains(
+                "7seqs.fna/reads_for_seq1_and_seq2.1.fq.zst\tgenome2~seq1\t1.2
+7seqs.fna/reads_for_seq1_and_seq2.1.fq.zst\tgenome3~random_sequence_length_11001\t0
+7seqs.fna/reads_for_seq1_and_seq2.1.fq.zst\tgenome4~random_sequence_length_11002\t0
+7seqs.fna/reads_for_seq1_and_seq2.1.fq.zst\tgenome5~seq2\t1.2").unwrap();
+    }
+
     #[test]
     fn test_bwa_parameters() {
         Assert::main_binary()
@@ -537,47 +1222,129 @@ genome6	0").unwrap();
     }
 
     #[test]
-    fn test_metabat_include_supplementary() {
+    fn test_metabat_include_supplementary() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-m",
+                "metabat",
+                "-b",
+                "tests/data/k141_7.reheadered.bam", // includes a supplementary alignment
+            ]).succeeds().stdout().contains(
+                "contigName	contigLen	totalAvgDepth	k141_7.reheadered.bam	k141_7.reheadered.bam-var
+k141_7	350	0.69	0.69	2.0843215").unwrap();
+    }
+
+
+    #[test]
+    fn test_metabat_97_of_100_bases_should_fail() {
+        // 100 bases match, 3 mismatch. Seems metabat uses > 0.97 not >= 0.97
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-m",
+                "metabat",
+                "-b",
+                "tests/data/k141_2005182.head11.bam", // includes a supplementary alignment
+            ]).succeeds().stdout().contains(
+                "contigName	contigLen	totalAvgDepth	k141_2005182.head11.bam	k141_2005182.head11.bam-var
+k141_2005182	225	1.9333333	1.9333333	0.063063025").unwrap();
+    }
+
+    #[test]
+    fn test_deletions_count_towards_perc_id() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-m",
+                "metabat",
+                "-b",
+                "tests/data/k141_109815.stray_read.bam", // If D not counted, perc ID filters out
+            ]).succeeds().stdout().contains(
+        "contigName	contigLen	totalAvgDepth	k141_109815.stray_read.bam	k141_109815.stray_read.bam-var
+k141_109815	362	0.6273585	0.6273585	0.23488776").unwrap();
+    }
+
+    #[test]
+    fn test_contig_snv_count_against_reference() {
+        // All reads differ from the reference at position 11, and 2 of 6
+        // reads differ at position 3.
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/snv.bam",
+                "-m",
+                "snv_count",
+                "snv_density",
+                "--snv-reference",
+                "tests/data/snv.fna",
+                "--contig-end-exclusion",
+                "0",
+            ]).succeeds().stdout().is(
+                "Contig\tsnv SNVs\tsnv SNVs per kb\n\
+                 contig1\t2\t100\n").unwrap();
+    }
+
+    #[test]
+    fn test_genome_snv_count_against_reference() {
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "-b",
+                "tests/data/snv.bam",
+                "--single-genome",
+                "-m",
+                "snv_count",
+                "--snv-reference",
+                "tests/data/snv.fna",
+                "--contig-end-exclusion",
+                "0",
+            ]).succeeds().stdout().is(
+                "Genome\tsnv SNVs\n\
+                 genome1\t2\n").unwrap();
+    }
+
+    #[test]
+    fn test_snv_count_requires_reference() {
         Assert::main_binary()
             .with_args(&[
                 "contig",
-                "-m",
-                "metabat",
                 "-b",
-                "tests/data/k141_7.reheadered.bam", // includes a supplementary alignment
-            ]).succeeds().stdout().contains(
-                "contigName	contigLen	totalAvgDepth	k141_7.reheadered.bam	k141_7.reheadered.bam-var
-k141_7	350	0.69	0.69	2.0843215").unwrap();
+                "tests/data/snv.bam",
+                "-m",
+                "snv_count",
+            ]).fails().unwrap();
     }
 
-
+    // The expected output here is CoverM's own. There is not yet a regression
+    // suite comparing against outputs of jgi_summarize_bam_contig_depths with
+    // --noIntraDepthVariance and matching options.
     #[test]
-    fn test_metabat_97_of_100_bases_should_fail() {
-        // 100 bases match, 3 mismatch. Seems metabat uses > 0.97 not >= 0.97
+    fn test_metabat_no_intra_depth_variance() {
         Assert::main_binary()
             .with_args(&[
                 "contig",
                 "-m",
                 "metabat",
                 "-b",
-                "tests/data/k141_2005182.head11.bam", // includes a supplementary alignment
-            ]).succeeds().stdout().contains(
-                "contigName	contigLen	totalAvgDepth	k141_2005182.head11.bam	k141_2005182.head11.bam-var
-k141_2005182	225	1.9333333	1.9333333	0.063063025").unwrap();
+                "tests/data/k141_7.reheadered.bam",
+                "--no-intra-depth-variance",
+            ]).succeeds().stdout().is(
+                "contigName	contigLen	totalAvgDepth	k141_7.reheadered.bam
+k141_7	350	0.69	0.69
+").unwrap();
     }
 
     #[test]
-    fn test_deletions_count_towards_perc_id() {
+    fn test_metabat_no_intra_depth_variance_requires_metabat() {
         Assert::main_binary()
             .with_args(&[
                 "contig",
-                "-m",
-                "metabat",
                 "-b",
-                "tests/data/k141_109815.stray_read.bam", // If D not counted, perc ID filters out
-            ]).succeeds().stdout().contains(
-        "contigName	contigLen	totalAvgDepth	k141_109815.stray_read.bam	k141_109815.stray_read.bam-var
-k141_109815	362	0.6273585	0.6273585	0.23488776").unwrap();
+                "tests/data/k141_7.reheadered.bam",
+                "--no-intra-depth-variance",
+            ]).fails().unwrap();
     }
 
     #[test]
@@ -773,9 +1540,7 @@ k141_109815	362	0.6273585	0.6273585	0.23488776").unwrap();
                 "--bam-file-cache-directory",
                 td.path().to_str().unwrap()
             ]).succeeds().unwrap();
-        assert!(td.path()
-                .join("coverm-genome.reads_for_seq1_and_seq2.1.fq.gz.bam")
-                .is_file());
+        assert_eq!(1, cached_bam_files(td.path(), "coverm-genome.reads_for_seq1_and_seq2.1.fq.gz.").len());
     }
 
     #[test]
@@ -824,6 +1589,187 @@ genome2~seq1	0
             .succeeds().unwrap()
     }
 
+    #[test]
+    fn test_sharding_coordinate_sorted_contig() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sharded",
+                "-b",
+                "tests/data/shard1.coordinate_sorted.bam",
+                "tests/data/shard2.coordinate_sorted.bam",
+            ])
+            .stdout().is("Contig	shard1.coordinate_sorted|shard2.coordinate_sorted Mean
+genome3~random_sequence_length_11001	0.110588886
+genome4~random_sequence_length_11002	0.11057869
+genome5~seq2	0
+genome6~random_sequence_length_11003	0.11056851
+genome1~random_sequence_length_11000	0.109861754
+genome1~random_sequence_length_11010	0.110497236
+genome2~seq1	0
+")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_sharding_coordinate_sorted_genome_separator() {
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "--sharded",
+                "-b",
+                "tests/data/shard1.coordinate_sorted.bam",
+                "tests/data/shard2.coordinate_sorted.bam",
+                "-s",
+                "~",
+            ])
+            .stdout().is("Genome	shard1.coordinate_sorted|shard2.coordinate_sorted Relative Abundance (%)
+unmapped	0
+genome3	25.024881
+genome4	25.022575
+genome5	0
+genome6	25.020271
+genome1	24.932274
+genome2	0
+")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_sharding_edit_distance_discard_ties() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sharded",
+                "-b",
+                "tests/data/shard1.bam",
+                "tests/data/shard2.bam",
+                "--deshard-by",
+                "edit-distance",
+                "--discard-deshard-ties",
+                "--seed",
+                "42",
+            ])
+            .stdout().is("Contig	shard1|shard2 Mean
+genome3~random_sequence_length_11001	0.110588886
+genome4~random_sequence_length_11002	0.11057869
+genome5~seq2	0
+genome6~random_sequence_length_11003	0.11056851
+genome1~random_sequence_length_11000	0.109861754
+genome1~random_sequence_length_11010	0.110497236
+genome2~seq1	0
+")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_seed_logged() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sharded",
+                "-b",
+                "tests/data/shard1.bam",
+                "tests/data/shard2.bam",
+                "--seed",
+                "1234",
+            ])
+            .stderr().contains("Using random seed 1234")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_seed_without_sharding() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--seed",
+                "1234",
+            ])
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_provenance_header() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--min-read-aligned-length",
+                "50",
+                "--seed",
+                "1234",
+                "--provenance-header",
+            ])
+            .stdout().contains("# Mode: contig\n\
+                                # Mapper: none (BAM files given)\n\
+                                # Filters: min-read-aligned-length=50 ")
+            .stdout().contains("# Seed: 1234\n")
+            .stdout().contains("# Sample: tests/data/7seqs.reads_for_seq1_and_seq2.bam\n\
+                                Contig\t7seqs.reads_for_seq1_and_seq2 Mean\n")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_provenance_header_bwa_version() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-p",
+                "bwa-mem",
+                "-r",
+                "tests/data/7seqs.fna",
+                "-c",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--provenance-header",
+            ])
+            .stdout().contains("# Mapper: bwa-mem (BWA version 0.7.")
+            .stdout().doesnt_contain("unknown")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_sharding_coordinate_sorted_percent_identity() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sharded",
+                "-b",
+                "tests/data/shard1.coordinate_sorted.bam",
+                "tests/data/shard2.coordinate_sorted.bam",
+                "--deshard-by",
+                "percent-identity",
+                "--discard-deshard-ties",
+            ])
+            .stdout().is("Contig	shard1.coordinate_sorted|shard2.coordinate_sorted Mean
+genome3~random_sequence_length_11001	0.110588886
+genome4~random_sequence_length_11002	0.11057869
+genome5~seq2	0
+genome6~random_sequence_length_11003	0.11056851
+genome1~random_sequence_length_11000	0.109861754
+genome1~random_sequence_length_11010	0.110497236
+genome2~seq1	0
+")
+            .succeeds().unwrap()
+    }
+
+    #[test]
+    fn test_sharding_mixed_sort_orders_fails() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--sharded",
+                "-b",
+                "tests/data/shard1.bam",
+                "tests/data/shard2.coordinate_sorted.bam",
+            ])
+            .fails().unwrap()
+    }
+
     #[test]
     fn test_sharding_no_exclusion_bwa_contig() {
         Assert::main_binary()
@@ -1364,6 +2310,165 @@ genome6~random_sequence_length_11003	0	0	0
             .unwrap();
     }
 
+    #[test]
+    fn test_merge_sparse_and_dense_outputs() {
+        let mut tf1: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        write!(tf1, "Sample\tGenome\tRelative Abundance (%)\tMean\n\
+                     sample1\tunmapped\t50\tNA\n\
+                     sample1\tgenome1\t40\t2\n\
+                     sample1\tgenome2\t10\t0.5\n").unwrap();
+        let mut tf2: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        write!(tf2, "Genome\tsample2 Relative Abundance (%)\tsample2 Mean\n\
+                     unmapped\t0\tNA\n\
+                     genome1\t0\t0\n\
+                     genome2\t100\t3\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "merge",
+                "-i",
+                tf1.path().to_str().unwrap(),
+                tf2.path().to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("Genome\tsample1 Relative Abundance (%)\tsample1 Mean\t\
+                 sample2 Relative Abundance (%)\tsample2 Mean\n\
+                 unmapped\t50\tNA\t0\tNA\n\
+                 genome1\t40\t2\t0\t0\n\
+                 genome2\t10\t0.5\t100\t3\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_merge_mismatched_methods() {
+        let mut tf1: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        write!(tf1, "Sample\tContig\tMean\nsample1\tcontig1\t2\n").unwrap();
+        let mut tf2: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        write!(tf2, "Sample\tContig\tTrimmed Mean\nsample2\tcontig1\t2\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "merge",
+                "-i",
+                tf1.path().to_str().unwrap(),
+                tf2.path().to_str().unwrap(),
+            ])
+            .fails()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_contig_depth_files() {
+        let td = tempfile::TempDir::new().unwrap();
+        let depth = td.path().join("sample1.depth");
+        let mut f = std::fs::File::create(&depth).unwrap();
+        write!(f, "seq1\t1\t2\nseq1\t2\t2\nseq1\t3\t1\nseq1\t4\t1\n\
+                   seq2\t1\t0\nseq2\t2\t0\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--depth-files",
+                depth.to_str().unwrap(),
+                "-m",
+                "mean",
+                "covered_fraction"])
+            .succeeds()
+            .stdout()
+            .is("Sample\tContig\tMean\tCovered Fraction\n\
+                 sample1\tseq1\t1.5\t1\n\
+                 sample1\tseq2\t0\t0\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_genome_depth_files_mosdepth() {
+        let td = tempfile::TempDir::new().unwrap();
+        let depth = td.path().join("sample1.per-base.bed");
+        let mut f = std::fs::File::create(&depth).unwrap();
+        write!(f, "g1~seq1\t0\t2\t4\ng1~seq2\t0\t2\t0\ng2~seq1\t0\t10\t0\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "--depth-files",
+                depth.to_str().unwrap(),
+                "-s",
+                "~",
+                "-m",
+                "mean",
+                "covered_bases"])
+            .succeeds()
+            .stdout()
+            .is("Sample\tGenome\tMean\tCovered Bases\n\
+                 sample1\tg1\t2\t2\n\
+                 sample1\tg2\t0\t0\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_contig_depth_files_rpkm_fails() {
+        let td = tempfile::TempDir::new().unwrap();
+        let depth = td.path().join("sample1.depth");
+        let mut f = std::fs::File::create(&depth).unwrap();
+        write!(f, "seq1\t1\t2\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--depth-files",
+                depth.to_str().unwrap(),
+                "-m",
+                "rpkm"])
+            .fails()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_contig_depth_files_bpkm() {
+        let td = tempfile::TempDir::new().unwrap();
+        let depth = td.path().join("sample1.depth");
+        let mut f = std::fs::File::create(&depth).unwrap();
+        write!(f, "seq1\t1\t2\nseq1\t2\t2\nseq1\t3\t1\nseq1\t4\t1\n\
+                   seq2\t1\t0\nseq2\t2\t0\n").unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--depth-files",
+                depth.to_str().unwrap(),
+                "-m",
+                "bpkm"])
+            .succeeds()
+            .stdout()
+            .is("Contig\tsample1 BPKM\n\
+                 seq1\t250000000\n\
+                 seq2\t0\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_contig_bpkm_requires_long_read() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-m",
+                "bpkm",
+                "-b",
+                "tests/data/7seqs.fnaVbad_read.bam"])
+            .fails()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_contig_long_read_rpkm_fails() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--long-read",
+                "-m",
+                "rpkm",
+                "-b",
+                "tests/data/7seqs.fnaVbad_read.bam"])
+            .fails()
+            .unwrap();
+    }
+
     #[test]
     fn test_remove_minimap2_duplicated_headers_duplicated_sam() {
         Assert::cargo_binary("remove_minimap2_duplicated_headers")